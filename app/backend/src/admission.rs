@@ -0,0 +1,158 @@
+//! Server-wide rules on which image references may be pulled at all,
+//! whoever asks: allowed registries, denied repositories and tags, and
+//! digest pinning. Checked before the per-user [`crate::policy`].
+//!
+//! Patterns are the same globs as in policies. A reference without tag or
+//! digest counts as `:latest`, as it is pulled.
+
+use crate::{
+    config::ReferencesConfig,
+    error::ApiError,
+    policy::glob_regex,
+    reference::ImageReference,
+};
+use regex::Regex;
+
+pub struct Admission {
+    allow_registries: Vec<Regex>,
+    deny: Vec<DenyRule>,
+    require_digest: bool,
+}
+
+struct DenyRule {
+    name: String,
+    repositories: Vec<Regex>,
+    tags: Vec<Regex>,
+    reason: Option<String>,
+}
+
+impl DenyRule {
+    fn matches(&self, image: &ImageReference) -> bool {
+        let repository = format!("{}/{}", image.registry, image.repository);
+        let repository_matches =
+            self.repositories.is_empty() || self.repositories.iter().any(|re| re.is_match(&repository));
+        let tag_matches = self.tags.is_empty()
+            || image.tag.as_deref().is_some_and(|tag| self.tags.iter().any(|re| re.is_match(tag)));
+        repository_matches && tag_matches
+    }
+}
+
+fn compile(what: &str, globs: &[String]) -> anyhow::Result<Vec<Regex>> {
+    globs
+        .iter()
+        .map(|glob| {
+            glob_regex(glob).map_err(|e| anyhow::anyhow!("references: invalid {} pattern '{}': {}", what, glob, e))
+        })
+        .collect()
+}
+
+impl Admission {
+    pub fn build(config: &ReferencesConfig) -> anyhow::Result<Self> {
+        let deny = config
+            .deny
+            .iter()
+            .map(|rule| {
+                Ok(DenyRule {
+                    name: rule.name.clone(),
+                    repositories: compile(&rule.name, &rule.repositories)?,
+                    tags: compile(&rule.name, &rule.tags)?,
+                    reason: rule.reason.clone(),
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self {
+            allow_registries: compile("allow_registries", &config.allow_registries)?,
+            deny,
+            require_digest: config.require_digest,
+        })
+    }
+
+    /// Whether `reference` may be pulled (or uploaded as) on this server.
+    pub fn check(&self, reference: &str) -> Result<(), ApiError> {
+        if self.allow_registries.is_empty() && self.deny.is_empty() && !self.require_digest {
+            return Ok(());
+        }
+        let image = ImageReference::parse(reference).map_err(ApiError::InvalidReference)?;
+        if !self.allow_registries.is_empty() && !self.allow_registries.iter().any(|re| re.is_match(&image.registry)) {
+            return Err(ApiError::ReferenceDenied(format!(
+                "{} is denied by rule 'allow_registries': {} is not an allowed registry",
+                image, image.registry
+            )));
+        }
+        if let Some(rule) = self.deny.iter().find(|rule| rule.matches(&image)) {
+            let reason = rule.reason.as_deref().map(|r| format!(": {r}")).unwrap_or_default();
+            return Err(ApiError::ReferenceDenied(format!("{} is denied by rule '{}'{}", image, rule.name, reason)));
+        }
+        if self.require_digest && image.digest.is_none() {
+            return Err(ApiError::ReferenceDenied(format!(
+                "{} is denied by rule 'require_digest': pin the image with @sha256:...",
+                image
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    const DIGEST: &str = "sha256:0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+    fn admission(toml: &str) -> Admission {
+        Admission::build(&toml::from_str::<Config>(toml).unwrap().references).unwrap()
+    }
+
+    fn denial(result: Result<(), ApiError>) -> String {
+        match result {
+            Err(ApiError::ReferenceDenied(reason)) => reason,
+            other => panic!("expected a denial, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn only_allowed_registries_are_pulled() {
+        let admission = admission("[references]\nallow_registries = [\"docker.io\", \"*.example\"]\n");
+        admission.check("nginx").unwrap();
+        admission.check("registry.example/team/app:1").unwrap();
+        assert_eq!(
+            denial(admission.check("ghcr.io/team/app:1")),
+            "ghcr.io/team/app:1 is denied by rule 'allow_registries': ghcr.io is not an allowed registry"
+        );
+    }
+
+    #[test]
+    fn untagged_references_count_as_latest() {
+        let admission = admission(
+            "[[references.deny]]\nname = \"no-latest\"\ntags = [\"latest\"]\nreason = \"pin a version\"\n",
+        );
+        assert_eq!(
+            denial(admission.check("nginx")),
+            "docker.io/library/nginx:latest is denied by rule 'no-latest': pin a version"
+        );
+        assert!(admission.check("nginx:latest").is_err());
+        admission.check("nginx:1.27").unwrap();
+        admission.check(&format!("nginx@{DIGEST}")).unwrap();
+    }
+
+    #[test]
+    fn the_first_matching_rule_is_named() {
+        let admission = admission(
+            "[[references.deny]]\nname = \"evil\"\nrepositories = [\"docker.io/evil/**\"]\n\
+             [[references.deny]]\nname = \"rc\"\ntags = [\"*-rc*\"]\n",
+        );
+        assert_eq!(denial(admission.check("evil/app:1")), "docker.io/evil/app:1 is denied by rule 'evil'");
+        assert_eq!(denial(admission.check("nginx:2.0-rc1")), "docker.io/library/nginx:2.0-rc1 is denied by rule 'rc'");
+        admission.check("nginx:2.0").unwrap();
+    }
+
+    #[test]
+    fn digests_can_be_required() {
+        let admission = admission("[references]\nrequire_digest = true\n");
+        admission.check(&format!("nginx@{DIGEST}")).unwrap();
+        admission.check(&format!("nginx:1.27@{DIGEST}")).unwrap();
+        let reason = denial(admission.check("nginx:1.27"));
+        assert!(reason.contains("denied by rule 'require_digest'"), "{reason}");
+    }
+}
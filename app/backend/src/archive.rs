@@ -0,0 +1,198 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use tokio::fs;
+
+/// Archive layouts the backend can produce or consume through skopeo.
+///
+/// Every artifact on disk is a single file: directory-based layouts such as
+/// `oci` are packed into a plain tar once skopeo is done writing them, and
+/// unpacked again when they are used as a conversion source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+pub enum ArchiveFormat {
+    #[default]
+    #[serde(rename = "docker-archive")]
+    DockerArchive,
+    #[serde(rename = "oci-archive")]
+    OciArchive,
+    #[serde(rename = "oci", alias = "oci-layout")]
+    OciLayout,
+}
+
+impl ArchiveFormat {
+    pub const ALL: [ArchiveFormat; 3] = [
+        ArchiveFormat::DockerArchive,
+        ArchiveFormat::OciArchive,
+        ArchiveFormat::OciLayout,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ArchiveFormat::DockerArchive => "docker-archive",
+            ArchiveFormat::OciArchive => "oci-archive",
+            ArchiveFormat::OciLayout => "oci",
+        }
+    }
+
+    /// Name of the skopeo transport handling this format.
+    pub fn transport(self) -> &'static str {
+        self.as_str()
+    }
+
+    /// Whether skopeo writes this format as a directory rather than a file.
+    pub fn is_directory(self) -> bool {
+        matches!(self, ArchiveFormat::OciLayout)
+    }
+
+    /// Path skopeo should write to before the artifact is sealed into `artifact`.
+    pub fn staging_path(self, artifact: &Path) -> PathBuf {
+        if self.is_directory() {
+            artifact.with_extension("d")
+        } else {
+            artifact.to_path_buf()
+        }
+    }
+
+    /// skopeo destination string for writing an image named `repo:tag`.
+    pub fn destination(self, staging: &Path, repo: &str, tag: &str) -> String {
+        match self {
+            ArchiveFormat::DockerArchive | ArchiveFormat::OciArchive => {
+                format!("{}:{}:{}:{}", self.transport(), staging.display(), repo, tag)
+            }
+            // The oci transport only takes a single reference name, which ends
+            // up as `org.opencontainers.image.ref.name` in index.json.
+            ArchiveFormat::OciLayout => format!("{}:{}:{}", self.transport(), staging.display(), tag),
+        }
+    }
+
+    /// skopeo source string for reading back an unpacked artifact.
+    pub fn source(self, staging: &Path) -> String {
+        format!("{}:{}", self.transport(), staging.display())
+    }
+
+    /// Turns whatever skopeo wrote at `staging` into the single-file artifact.
+    /// A layout written straight to `artifact` as a tar is sealed already.
+    pub async fn seal(self, staging: &Path, artifact: &Path) -> std::io::Result<()> {
+        if !self.is_directory() || staging == artifact {
+            return Ok(());
+        }
+        let result = pack_dir(staging.to_path_buf(), artifact.to_path_buf()).await;
+        let _ = fs::remove_dir_all(staging).await;
+        result
+    }
+
+    /// Makes a sealed artifact readable by skopeo again, returning the staging path.
+    pub async fn unseal(self, artifact: &Path, scratch: &Path) -> std::io::Result<PathBuf> {
+        if !self.is_directory() {
+            return Ok(artifact.to_path_buf());
+        }
+        let staging = self.staging_path(scratch);
+        unpack_dir(artifact.to_path_buf(), staging.clone()).await?;
+        Ok(staging)
+    }
+}
+
+impl fmt::Display for ArchiveFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for ArchiveFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "docker-archive" => Ok(ArchiveFormat::DockerArchive),
+            "oci-archive" => Ok(ArchiveFormat::OciArchive),
+            "oci" | "oci-layout" => Ok(ArchiveFormat::OciLayout),
+            other => Err(format!(
+                "unsupported format '{}' (expected one of: {})",
+                other,
+                ArchiveFormat::ALL.map(ArchiveFormat::as_str).join(", ")
+            )),
+        }
+    }
+}
+
+async fn pack_dir(dir: PathBuf, out: PathBuf) -> std::io::Result<()> {
+    tokio::task::spawn_blocking(move || {
+        let file = std::fs::File::create(&out)?;
+        let mut builder = tar::Builder::new(file);
+        builder.follow_symlinks(false);
+        builder.append_dir_all(".", &dir)?;
+        builder.into_inner()?.sync_all()
+    })
+    .await
+    .map_err(std::io::Error::other)?
+}
+
+/// Merges single-image tars of `format` into one tar at `out`, each blob
+/// once: docker archives and OCI layouts name their blobs after their
+/// digest. `parts` pairs each tar with its tag, which names its image in an
+/// `oci` layout.
+pub async fn merge(format: ArchiveFormat, parts: Vec<(PathBuf, String)>, out: PathBuf) -> std::io::Result<()> {
+    tokio::task::spawn_blocking(move || {
+        let listing = match format {
+            ArchiveFormat::DockerArchive => "manifest.json",
+            ArchiveFormat::OciArchive | ArchiveFormat::OciLayout => "index.json",
+        };
+        let mut builder = tar::Builder::new(std::fs::File::create(&out)?);
+        let mut written = HashSet::new();
+        let mut images = Vec::new();
+        for (part, tag) in &parts {
+            let mut archive = tar::Archive::new(std::fs::File::open(part)?);
+            for entry in archive.entries()? {
+                let mut entry = entry?;
+                if !entry.header().entry_type().is_file() {
+                    continue;
+                }
+                let name = entry.path()?.to_string_lossy().trim_start_matches("./").to_string();
+                if name == listing {
+                    let listed: serde_json::Value = serde_json::from_reader(&mut entry)?;
+                    let entries = match format {
+                        ArchiveFormat::DockerArchive => listed.as_array().cloned(),
+                        _ => listed["manifests"].as_array().cloned(),
+                    };
+                    for mut image in entries.unwrap_or_default() {
+                        if format == ArchiveFormat::OciLayout {
+                            image["annotations"]["org.opencontainers.image.ref.name"] = tag.as_str().into();
+                        }
+                        images.push(image);
+                    }
+                } else if name == "repositories" {
+                    // The legacy file of a docker archive only names its single image.
+                } else if written.insert(name.clone()) {
+                    let mut header = entry.header().clone();
+                    builder.append_data(&mut header, &name, &mut entry)?;
+                }
+            }
+        }
+        let listed = match format {
+            ArchiveFormat::DockerArchive => serde_json::Value::Array(images),
+            _ => serde_json::json!({ "schemaVersion": 2, "manifests": images }),
+        };
+        let bytes = listed.to_string().into_bytes();
+        let mut header = tar::Header::new_gnu();
+        header.set_size(bytes.len() as u64);
+        header.set_mode(0o644);
+        builder.append_data(&mut header, listing, bytes.as_slice())?;
+        builder.into_inner()?.sync_all()
+    })
+    .await
+    .map_err(std::io::Error::other)?
+}
+
+async fn unpack_dir(archive: PathBuf, dir: PathBuf) -> std::io::Result<()> {
+    tokio::task::spawn_blocking(move || {
+        std::fs::create_dir_all(&dir)?;
+        let file = std::fs::File::open(&archive)?;
+        tar::Archive::new(file).unpack(&dir)
+    })
+    .await
+    .map_err(std::io::Error::other)?
+}
@@ -0,0 +1,439 @@
+use crate::{
+    archive::ArchiveFormat,
+    audit::Caller,
+    auth::Principal,
+    checksum::Sha256Sum,
+    compression::{compress_file, Compression, CompressionOptions},
+    config::Settings,
+    error::ApiError,
+    inspect::{verify_file, ArchiveReport},
+    jobs::visible_owner,
+    make_filename, parse_repo_tag,
+    policy::Action,
+    signing::{manifest_time, ArtifactManifest},
+    store::{Node, Store},
+    valid_ref, AppState,
+};
+use chrono::{DateTime, Utc};
+use axum::{
+    body::Body,
+    extract::{Extension, Json, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::IntoResponse,
+};
+use serde::Deserialize;
+use std::{path::PathBuf, sync::Arc};
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncWriteExt},
+};
+use tokio_stream::StreamExt;
+use uuid::Uuid;
+
+/// A finished archive on disk that can be downloaded or used as a conversion source.
+#[derive(Debug, Clone)]
+pub struct Artifact {
+    pub id: String,
+    pub path: PathBuf,
+    pub filename: String,
+    pub format: ArchiveFormat,
+    pub compression: Compression,
+    pub repo: String,
+    pub tag: String,
+    /// Image reference the artifact was pulled from, empty for uploads.
+    pub reference: String,
+    pub digest: Option<String>,
+    pub sha256: String,
+    pub size: u64,
+    /// Principal that produced the artifact.
+    pub owner: String,
+    pub created: DateTime<Utc>,
+    pub expires: DateTime<Utc>,
+    /// Replica holding the file.
+    pub node: String,
+}
+
+impl Artifact {
+    pub fn manifest(&self) -> ArtifactManifest {
+        ArtifactManifest {
+            r#ref: self.reference.clone(),
+            digest: self.digest.clone(),
+            format: self.format,
+            created: self.created,
+            sha256: self.sha256.clone(),
+            filename: self.filename.clone(),
+        }
+    }
+}
+
+/// Artifact metadata keyed by download id, kept in the [`Store`] so that
+/// downloads survive a restart as long as the files do.
+#[derive(Clone)]
+pub struct ArtifactStore {
+    store: Arc<dyn Store>,
+    node: Arc<Node>,
+}
+
+impl ArtifactStore {
+    pub fn new(store: Arc<dyn Store>, node: Arc<Node>) -> Self {
+        Self { store, node }
+    }
+
+    pub async fn insert(&self, artifact: Artifact) -> Result<(), ApiError> {
+        self.store.insert_artifact(&artifact).await
+    }
+
+    /// The artifact, unless unknown or expired. It may be held by another
+    /// replica.
+    pub async fn get(&self, id: &str) -> Result<Artifact, ApiError> {
+        self.store.artifact(id).await?.ok_or_else(|| ApiError::NotFound("file not found".into()))
+    }
+
+    /// Deletes the artifact file but keeps its metadata (checksum, manifest)
+    /// until it expires.
+    pub async fn discard_file(&self, id: &str) {
+        if let Ok(artifact) = self.get(id).await {
+            if artifact.node == self.node.id {
+                let _ = fs::remove_file(&artifact.path).await;
+            }
+        }
+    }
+
+    /// Forgets expired artifacts and deletes the files of those held here.
+    pub async fn sweep(&self) -> Result<usize, ApiError> {
+        let expired = self.store.expire_artifacts(&self.node.id).await?;
+        for artifact in &expired {
+            let _ = fs::remove_file(&artifact.path).await;
+        }
+        Ok(expired.len())
+    }
+}
+
+pub fn artifact_path(id: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("{}{}.tar", ARTIFACT_PREFIX, id))
+}
+
+const ARTIFACT_PREFIX: &str = "pull-";
+
+/// Number and total size of artifacts on disk, pulls in progress included.
+pub async fn disk_usage() -> (u64, u64) {
+    let Ok(mut entries) = fs::read_dir(std::env::temp_dir()).await else {
+        return (0, 0);
+    };
+    let (mut files, mut bytes) = (0, 0);
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        if entry.file_name().to_string_lossy().starts_with(ARTIFACT_PREFIX) {
+            files += 1;
+            bytes += crate::timeouts::disk_size(entry.path()).await;
+        }
+    }
+    (files, bytes)
+}
+
+/// Artifact `id` if `caller` may see it: their own, or anyone's for those
+/// who may read the audit log, as with jobs.
+pub(crate) async fn visible_artifact(state: &AppState, caller: &Caller, id: &str) -> Result<Artifact, ApiError> {
+    let artifact = state.artifacts.get(id).await?;
+    match visible_owner(state, caller) {
+        Some(owner) if owner != artifact.owner => Err(ApiError::NotFound("file not found".into())),
+        _ => Ok(artifact),
+    }
+}
+
+/// Download ids are UUIDs; anything else could escape the temp directory.
+pub fn valid_id(id: &str) -> bool {
+    !id.is_empty() && id.chars().all(|c| c.is_ascii_hexdigit() || c == '-')
+}
+
+#[derive(Deserialize)]
+pub struct UploadParams {
+    #[serde(default)]
+    format: Option<String>,
+    #[serde(default)]
+    r#ref: Option<String>,
+    /// Seconds to keep the archive, up to `artifacts.max_ttl_secs`.
+    #[serde(default)]
+    ttl_secs: Option<u64>,
+}
+
+// Store an uploaded archive so it can be converted or downloaded by id
+pub async fn upload_artifact(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Query(params): Query<UploadParams>,
+    headers: HeaderMap,
+    body: Body,
+) -> impl IntoResponse {
+    let format = match params.format.as_deref().map(str::parse::<ArchiveFormat>) {
+        None => ArchiveFormat::default(),
+        Some(Ok(f)) => f,
+        Some(Err(e)) => return ApiError::InvalidRequest(e).into_response(),
+    };
+    let settings = state.config.current();
+    let (repo, tag) = match pushed_name(&settings, &principal, params.r#ref.as_deref()) {
+        Ok(name) => name,
+        Err(e) => return e.into_response(),
+    };
+    let max = settings.config.artifacts.max_upload_bytes;
+    let too_large = || ApiError::TooLarge(format!("uploads are limited to {max} bytes")).into_response();
+    let declared = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    if declared.is_some_and(|len| len > max) {
+        return too_large();
+    }
+
+    let id = Uuid::new_v4().to_string();
+    let path = artifact_path(&id);
+    let (size, sha256) = match write_body(&path, body, max).await {
+        Ok(written) => written,
+        Err(e) => {
+            let _ = fs::remove_file(&path).await;
+            if e.kind() == std::io::ErrorKind::FileTooLarge {
+                return too_large();
+            }
+            return ApiError::io("upload error", e).into_response();
+        }
+    };
+
+    let compression = match compression_of(&path).await {
+        Ok(c) => c,
+        Err(e) => {
+            let _ = fs::remove_file(&path).await;
+            return ApiError::io("upload error", e).into_response();
+        }
+    };
+    let filename = make_filename(&repo, &tag, format.as_str(), compression);
+    let created = manifest_time();
+    let stored = state
+        .artifacts
+        .insert(Artifact {
+            id: id.clone(),
+            path: path.clone(),
+            filename: filename.clone(),
+            format,
+            compression,
+            repo,
+            tag,
+            reference: params.r#ref.unwrap_or_default(),
+            digest: None,
+            sha256: sha256.clone(),
+            size,
+            owner: principal.name.clone(),
+            created,
+            node: state.node.id.clone(),
+            expires: created + settings.artifact_ttl_for(params.ttl_secs),
+        })
+        .await;
+    if let Err(e) = stored {
+        let _ = fs::remove_file(&path).await;
+        return e.into_response();
+    }
+
+    let payload = serde_json::json!({
+        "id": id,
+        "filename": filename,
+        "format": format,
+        "size": size,
+        "sha256": sha256,
+    });
+    (StatusCode::CREATED, Json(payload)).into_response()
+}
+
+/// Repository and tag of an archive brought in under `reference`, which the
+/// push policies and the `[references]` rules must allow.
+pub(crate) fn pushed_name(
+    settings: &Settings,
+    principal: &Principal,
+    reference: Option<&str>,
+) -> Result<(String, String), ApiError> {
+    let policy = &settings.policy;
+    match reference.filter(|r| !r.trim().is_empty()) {
+        Some(r) if !valid_ref(r) => Err(ApiError::InvalidReference(r.to_string())),
+        Some(r) => {
+            settings.admission.check(r)?;
+            policy.check(principal, Action::Push, r)?;
+            Ok(parse_repo_tag(r))
+        }
+        None if policy.enabled() => {
+            Err(ApiError::InvalidRequest("uploads need a ref to check against the push policies".into()))
+        }
+        None => Ok(("image".to_string(), "latest".to_string())),
+    }
+}
+
+/// Compression of a stored file, sniffed from its first bytes.
+pub async fn compression_of(path: &std::path::Path) -> std::io::Result<Compression> {
+    let mut head = [0u8; 4];
+    let mut file = fs::File::open(path).await?;
+    let n = file.read(&mut head).await?;
+    Ok(Compression::detect(&head[..n]))
+}
+
+/// Streams the body to `path`, returning its size and SHA-256; fails with
+/// `FileTooLarge` past `max` bytes.
+async fn write_body(path: &std::path::Path, body: Body, max: u64) -> std::io::Result<(u64, String)> {
+    let mut file = fs::File::create(path).await?;
+    let mut stream = body.into_data_stream();
+    let mut size = 0u64;
+    let mut sum = Sha256Sum::default();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(std::io::Error::other)?;
+        if size + chunk.len() as u64 > max {
+            return Err(std::io::ErrorKind::FileTooLarge.into());
+        }
+        file.write_all(&chunk).await?;
+        sum.update(&chunk);
+        size += chunk.len() as u64;
+    }
+    file.flush().await?;
+    Ok((size, sum.finish()))
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum FinalizeError {
+    #[error("failed to pack archive: {0}")]
+    Pack(std::io::Error),
+    #[error("{0}")]
+    Invalid(String),
+    #[error("failed to compress archive: {0}")]
+    Compress(std::io::Error),
+}
+
+/// A validated artifact file and the checksum of its final bytes.
+pub struct Finalized {
+    pub report: ArchiveReport,
+    pub sha256: String,
+}
+
+/// Turns what skopeo wrote at `staging` into the artifact file at `path`:
+/// packs directory layouts, validates every blob, then compresses if asked.
+/// Nothing is left on disk on failure.
+pub async fn finalize(
+    format: ArchiveFormat,
+    staging: &std::path::Path,
+    path: &std::path::Path,
+    compression: CompressionOptions,
+) -> Result<Finalized, FinalizeError> {
+    if let Err(e) = format.seal(staging, path).await {
+        let _ = fs::remove_file(path).await;
+        return Err(FinalizeError::Pack(e));
+    }
+    let report = match verify_file(path.to_path_buf()).await {
+        Ok(report) => report,
+        Err(msg) => {
+            let _ = fs::remove_file(path).await;
+            return Err(FinalizeError::Invalid(msg));
+        }
+    };
+    if compression.kind == Compression::None {
+        let sha256 = report.sha256.clone();
+        return Ok(Finalized { report, sha256 });
+    }
+
+    let compressed = path.with_extension("tar.part");
+    let result = compress_file(path.to_path_buf(), compressed.clone(), compression).await;
+    let result = match result {
+        Ok(sha256) => fs::rename(&compressed, path).await.map(|_| sha256),
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(sha256) => Ok(Finalized { report, sha256 }),
+        Err(e) => {
+            let _ = fs::remove_file(&compressed).await;
+            let _ = fs::remove_file(path).await;
+            Err(FinalizeError::Compress(e))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{auth::hash_api_key, config::Config, tests};
+    use reqwest::StatusCode;
+
+    fn config() -> Config {
+        let keys: String = ["alice", "bob", "admin"]
+            .iter()
+            .map(|name| format!("[[auth.api_keys]]\nname = \"{name}\"\nsha256 = \"{}\"\n", hash_api_key(name)))
+            .collect();
+        let toml = format!(
+            r#"
+            [puller]
+            kind = "native"
+
+            {keys}
+            [[policies]]
+            name = "users"
+            users = ["*"]
+            pull = ["registry.example/*"]
+            push = ["registry.example/*"]
+
+            [[policies]]
+            name = "admins"
+            users = ["admin"]
+            audit = true
+            "#
+        );
+        toml::from_str(&toml).unwrap()
+    }
+
+    fn archive() -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(2);
+        header.set_mode(0o644);
+        builder.append_data(&mut header, "manifest.json", &b"[]"[..]).unwrap();
+        builder.into_inner().unwrap()
+    }
+
+    #[tokio::test]
+    async fn artifacts_are_only_served_to_their_owner_and_auditors() {
+        let url = tests::serve(tests::state(config()).await).await;
+        let client = reqwest::Client::new();
+        let uploaded: serde_json::Value = client
+            .post(format!("{url}/api/artifacts?ref=registry.example/app:1"))
+            .header("x-api-key", "alice")
+            .body(archive())
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let id = uploaded["id"].as_str().unwrap();
+
+        let requests = [
+            ("GET", format!("/api/pull/file/{id}"), ""),
+            ("GET", format!("/api/pull/file/{id}/sha256sum"), ""),
+            ("GET", format!("/api/pull/file/{id}/manifest"), ""),
+            ("GET", format!("/api/artifacts/{id}/inspect"), ""),
+            ("GET", format!("/api/artifacts/{id}/blobs"), ""),
+            ("POST", format!("/api/artifacts/{id}/delta"), r#"{"blobs": []}"#),
+            ("POST", format!("/api/artifacts/{id}/verify"), r#"{"manifest": {"ref": "registry.example/app:1",
+                "format": "docker-archive", "created": "2026-01-01T00:00:00Z", "sha256": "", "filename": "a.tar"}}"#),
+        ];
+        for (method, path, body) in &requests {
+            for (user, expected) in [("bob", StatusCode::NOT_FOUND), ("alice", StatusCode::OK), ("admin", StatusCode::OK)] {
+                let resp = client
+                    .request(method.parse().unwrap(), format!("{url}{path}"))
+                    .header("x-api-key", user)
+                    .header("content-type", "application/json")
+                    .body(body.to_string())
+                    .send()
+                    .await
+                    .unwrap();
+                assert_eq!(resp.status(), expected, "{method} {path} as {user}");
+            }
+        }
+        let rebuilt = client
+            .post(format!("{url}/api/artifacts/{id}/rebuild"))
+            .header("x-api-key", "bob")
+            .body(archive())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(rebuilt.status(), StatusCode::NOT_FOUND);
+    }
+}
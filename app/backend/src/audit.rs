@@ -0,0 +1,535 @@
+//! Append-only audit trail: who pulled which image digest, downloaded which
+//! artifact and fetched which chart index, and when.
+//!
+//! Records are JSON lines appended to `audit.path` by a single writer task.
+//! When the file would grow past `max_bytes` it is rotated to `path.1`
+//! (`path.1` to `path.2`, …) and the oldest beyond `keep` is deleted.
+//! `GET /api/audit` reads every file back, oldest first, as JSON or CSV.
+
+use crate::{auth::Principal, config::AuditConfig, error::ApiError, AppState};
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts, Query, State},
+    http::{header, request::Parts, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    io::{BufRead, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::sync::{mpsc, Mutex};
+
+/// Records returned by one query unless `limit` says otherwise.
+const DEFAULT_LIMIT: usize = 1000;
+const MAX_LIMIT: usize = 100_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Event {
+    Pull,
+    Download,
+    Delta,
+    ChartIndex,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditRecord {
+    pub time: DateTime<Utc>,
+    pub event: Event,
+    pub user: String,
+    pub client_ip: Option<String>,
+    /// `X-Forwarded-For` as received; only as trustworthy as the proxy setting it.
+    pub forwarded_for: Option<String>,
+    pub request_id: Option<String>,
+    /// Image reference, chart repository URL, or the artifact id asked for
+    /// by a download that failed.
+    pub reference: String,
+    pub digest: Option<String>,
+    pub format: Option<String>,
+    pub size: Option<u64>,
+    pub sha256: Option<String>,
+    pub artifact_id: Option<String>,
+    /// `succeeded`, `cancelled`, or the error code the client received.
+    pub outcome: String,
+}
+
+/// Who is behind a request, as the audit trail names them.
+#[derive(Debug, Clone)]
+pub struct Caller {
+    pub principal: Principal,
+    pub ip: Option<String>,
+    pub forwarded_for: Option<String>,
+    pub request_id: Option<String>,
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Caller {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let header = |name| parts.headers.get(name).and_then(|v| v.to_str().ok()).map(str::to_string);
+        Ok(Self {
+            principal: parts.extensions.get::<Principal>().cloned().unwrap_or_else(Principal::anonymous),
+            ip: parts.extensions.get::<ConnectInfo<SocketAddr>>().map(|c| c.0.ip().to_string()),
+            forwarded_for: header("x-forwarded-for"),
+            request_id: header("x-request-id"),
+        })
+    }
+}
+
+/// What a successful pull or download produced.
+#[derive(Debug, Default)]
+pub struct Produced {
+    pub digest: Option<String>,
+    pub size: Option<u64>,
+    pub sha256: Option<String>,
+    pub artifact_id: Option<String>,
+}
+
+/// Handle to the audit trail; cheap to clone. Disabled when no path is set.
+#[derive(Clone, Default)]
+pub struct AuditLog {
+    inner: Option<Arc<Inner>>,
+}
+
+struct Inner {
+    tx: mpsc::UnboundedSender<AuditRecord>,
+    files: Files,
+    /// Held while writing or rotating, so queries never see a half rotation.
+    lock: Arc<Mutex<()>>,
+}
+
+#[derive(Clone)]
+struct Files {
+    path: PathBuf,
+    max_bytes: u64,
+    keep: u32,
+}
+
+impl Files {
+    fn rotated(&self, n: u32) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{n}"));
+        name.into()
+    }
+
+    /// Oldest first.
+    fn all(&self) -> Vec<PathBuf> {
+        let mut files: Vec<PathBuf> = (1..=self.keep).rev().map(|n| self.rotated(n)).collect();
+        files.push(self.path.clone());
+        files
+    }
+
+    fn rotate(&self) -> std::io::Result<()> {
+        let _ = std::fs::remove_file(self.rotated(self.keep));
+        for n in (1..self.keep).rev() {
+            let from = self.rotated(n);
+            if from.exists() {
+                std::fs::rename(&from, self.rotated(n + 1))?;
+            }
+        }
+        if self.keep == 0 {
+            std::fs::remove_file(&self.path)
+        } else {
+            std::fs::rename(&self.path, self.rotated(1))
+        }
+    }
+
+    fn append(&self, line: &[u8]) -> std::io::Result<()> {
+        let size = std::fs::metadata(&self.path).map(|m| m.len()).unwrap_or(0);
+        if size > 0 && size + line.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        let mut file = std::fs::OpenOptions::new().create(true).append(true).open(&self.path)?;
+        file.write_all(line)?;
+        file.sync_data()
+    }
+}
+
+impl AuditLog {
+    pub fn open(config: &AuditConfig) -> anyhow::Result<Self> {
+        let Some(path) = &config.path else {
+            return Ok(Self::default());
+        };
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)
+                .map_err(|e| anyhow::anyhow!("audit: cannot create {}: {}", dir.display(), e))?;
+        }
+        // Fail at startup rather than on the first pull.
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| anyhow::anyhow!("audit: cannot open {}: {}", path.display(), e))?;
+
+        let files = Files { path: path.clone(), max_bytes: config.max_bytes, keep: config.keep };
+        let lock = Arc::new(Mutex::new(()));
+        let (tx, mut rx) = mpsc::unbounded_channel::<AuditRecord>();
+        let writer = (files.clone(), lock.clone());
+        tokio::spawn(async move {
+            let (files, lock) = writer;
+            while let Some(record) = rx.recv().await {
+                let mut line = serde_json::to_vec(&record).unwrap_or_default();
+                line.push(b'\n');
+                let _guard = lock.lock().await;
+                let files = files.clone();
+                match tokio::task::spawn_blocking(move || files.append(&line)).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => tracing::error!("audit: cannot write record: {}", e),
+                    Err(e) => tracing::error!("audit: writer failed: {}", e),
+                }
+            }
+        });
+        Ok(Self { inner: Some(Arc::new(Inner { tx, files, lock })) })
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.inner.as_ref().map(|i| i.files.path.as_path())
+    }
+
+    pub fn record(&self, record: AuditRecord) {
+        tracing::debug!(event = ?record.event, outcome = %record.outcome, "audit: {}", record.reference);
+        if let Some(inner) = &self.inner {
+            let _ = inner.tx.send(record);
+        }
+    }
+
+    fn new_record(caller: &Caller, event: Event, reference: &str, format: Option<&str>) -> AuditRecord {
+        AuditRecord {
+            time: Utc::now(),
+            event,
+            user: caller.principal.name.clone(),
+            client_ip: caller.ip.clone(),
+            forwarded_for: caller.forwarded_for.clone(),
+            request_id: caller.request_id.clone(),
+            reference: reference.to_string(),
+            digest: None,
+            format: format.map(str::to_string),
+            size: None,
+            sha256: None,
+            artifact_id: None,
+            outcome: String::new(),
+        }
+    }
+
+    /// Starts the record of a pull, written when it ends.
+    pub fn pull(&self, caller: Caller, reference: &str, format: &str) -> PullEntry {
+        let record = Self::new_record(&caller, Event::Pull, reference, Some(format));
+        PullEntry { log: self.clone(), caller, record: Some(record) }
+    }
+
+    /// Records a finished event other than a pull.
+    pub fn event(
+        &self,
+        caller: &Caller,
+        event: Event,
+        reference: &str,
+        format: Option<&str>,
+        result: Result<Produced, &ApiError>,
+    ) {
+        let mut record = Self::new_record(caller, event, reference, format);
+        fill(&mut record, result);
+        self.record(record);
+    }
+}
+
+fn fill(record: &mut AuditRecord, result: Result<Produced, &ApiError>) {
+    match result {
+        Ok(produced) => {
+            record.digest = produced.digest;
+            record.size = produced.size;
+            record.sha256 = produced.sha256;
+            record.artifact_id = produced.artifact_id;
+            record.outcome = "succeeded".to_string();
+        }
+        Err(e) => record.outcome = e.code().to_string(),
+    }
+}
+
+/// A pull being recorded. Dropping it unfinished records `cancelled`.
+pub struct PullEntry {
+    log: AuditLog,
+    caller: Caller,
+    record: Option<AuditRecord>,
+}
+
+impl PullEntry {
+    pub fn principal(&self) -> &Principal {
+        &self.caller.principal
+    }
+
+    pub fn succeeded(mut self, produced: Produced) {
+        self.finish(Ok(produced));
+    }
+
+    pub fn failed(mut self, err: &ApiError) {
+        self.finish(Err(err));
+    }
+
+    fn finish(&mut self, result: Result<Produced, &ApiError>) {
+        if let Some(mut record) = self.record.take() {
+            fill(&mut record, result);
+            self.log.record(record);
+        }
+    }
+}
+
+impl Drop for PullEntry {
+    fn drop(&mut self) {
+        if let Some(mut record) = self.record.take() {
+            record.outcome = "cancelled".to_string();
+            self.log.record(record);
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AuditQuery {
+    user: Option<String>,
+    /// Substring of the reference (or chart repository URL).
+    image: Option<String>,
+    event: Option<Event>,
+    outcome: Option<String>,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+    /// Newest matching records kept, oldest first.
+    limit: Option<usize>,
+    /// `json` (default) or `csv`.
+    format: Option<String>,
+}
+
+impl AuditQuery {
+    fn matches(&self, r: &AuditRecord) -> bool {
+        self.user.as_ref().is_none_or(|u| *u == r.user)
+            && self.image.as_ref().is_none_or(|i| r.reference.contains(i.as_str()))
+            && self.event.is_none_or(|e| e == r.event)
+            && self.outcome.as_ref().is_none_or(|o| *o == r.outcome)
+            && self.since.is_none_or(|t| r.time >= t)
+            && self.until.is_none_or(|t| r.time < t)
+    }
+}
+
+fn search(files: &Files, query: &AuditQuery, limit: usize) -> std::io::Result<Vec<AuditRecord>> {
+    let mut found = VecDeque::new();
+    for path in files.all() {
+        let file = match std::fs::File::open(&path) {
+            Ok(f) => f,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        for line in std::io::BufReader::new(file).lines() {
+            let Ok(record) = serde_json::from_str::<AuditRecord>(&line?) else { continue };
+            if query.matches(&record) {
+                if found.len() == limit {
+                    found.pop_front();
+                }
+                found.push_back(record);
+            }
+        }
+    }
+    Ok(found.into())
+}
+
+const CSV_COLUMNS: &str = "time,event,user,client_ip,forwarded_for,request_id,reference,digest,format,size,sha256,artifact_id,outcome";
+
+fn csv_field(value: &str) -> String {
+    // A leading formula character is neutralised for spreadsheet users.
+    let value = if value.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        format!("'{value}")
+    } else {
+        value.to_string()
+    };
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
+
+fn to_csv(records: &[AuditRecord]) -> String {
+    let mut out = String::from(CSV_COLUMNS);
+    out.push('\n');
+    for r in records {
+        let event = serde_json::to_value(r.event).ok().and_then(|v| v.as_str().map(str::to_string)).unwrap_or_default();
+        let fields = [
+            r.time.to_rfc3339(),
+            event,
+            r.user.clone(),
+            r.client_ip.clone().unwrap_or_default(),
+            r.forwarded_for.clone().unwrap_or_default(),
+            r.request_id.clone().unwrap_or_default(),
+            r.reference.clone(),
+            r.digest.clone().unwrap_or_default(),
+            r.format.clone().unwrap_or_default(),
+            r.size.map(|s| s.to_string()).unwrap_or_default(),
+            r.sha256.clone().unwrap_or_default(),
+            r.artifact_id.clone().unwrap_or_default(),
+            r.outcome.clone(),
+        ];
+        let line: Vec<String> = fields.iter().map(|f| csv_field(f)).collect();
+        out.push_str(&line.join(","));
+        out.push('\n');
+    }
+    out
+}
+
+/// `GET /api/audit`: matching records as JSON, or CSV with `format=csv`.
+pub async fn query(State(state): State<AppState>, caller: Caller, Query(query): Query<AuditQuery>) -> Response {
+    if let Err(e) = state.config.current().policy.check_audit(&caller.principal) {
+        return e.into_response();
+    }
+    let Some(inner) = &state.audit.inner else {
+        return ApiError::NotFound("the audit log is disabled (audit.path)".into()).into_response();
+    };
+    let csv = match query.format.as_deref() {
+        None | Some("json") => false,
+        Some("csv") => true,
+        Some(other) => return ApiError::InvalidRequest(format!("unknown format '{other}'")).into_response(),
+    };
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+
+    let records = {
+        let _guard = inner.lock.lock().await;
+        let files = inner.files.clone();
+        match tokio::task::spawn_blocking(move || search(&files, &query, limit)).await {
+            Ok(Ok(records)) => records,
+            Ok(Err(e)) => return ApiError::io("cannot read the audit log", e).into_response(),
+            Err(e) => return ApiError::Internal(format!("audit query failed: {e}")).into_response(),
+        }
+    };
+    tracing::info!(user = %caller.principal.name, records = records.len(), "audit: log queried");
+
+    if csv {
+        let headers = [
+            (header::CONTENT_TYPE, HeaderValue::from_static("text/csv; charset=utf-8")),
+            (header::CONTENT_DISPOSITION, HeaderValue::from_static("attachment; filename=\"audit.csv\"")),
+        ];
+        (StatusCode::OK, headers, to_csv(&records)).into_response()
+    } else {
+        axum::Json(records).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(user: &str, reference: &str, time: &str) -> AuditRecord {
+        AuditRecord {
+            time: time.parse().unwrap(),
+            event: Event::Pull,
+            user: user.to_string(),
+            client_ip: None,
+            forwarded_for: None,
+            request_id: None,
+            reference: reference.to_string(),
+            digest: None,
+            format: Some("docker-archive".to_string()),
+            size: None,
+            sha256: None,
+            artifact_id: None,
+            outcome: "succeeded".to_string(),
+        }
+    }
+
+    fn line(record: &AuditRecord) -> Vec<u8> {
+        let mut line = serde_json::to_vec(record).unwrap();
+        line.push(b'\n');
+        line
+    }
+
+    fn query(value: serde_json::Value) -> AuditQuery {
+        serde_json::from_value(value).unwrap()
+    }
+
+    /// Users in each file, oldest file first, skipping missing ones.
+    fn users(files: &Files) -> Vec<Vec<String>> {
+        files
+            .all()
+            .iter()
+            .filter_map(|path| std::fs::read_to_string(path).ok())
+            .map(|text| text.lines().map(|l| serde_json::from_str::<AuditRecord>(l).unwrap().user).collect())
+            .collect()
+    }
+
+    #[test]
+    fn files_rotate_past_max_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let one = line(&record("u0", "nginx", "2026-01-01T00:00:00Z"));
+        let files = Files { path: dir.path().join("audit.log"), max_bytes: 2 * one.len() as u64, keep: 2 };
+        for i in 0..7 {
+            files.append(&line(&record(&format!("u{i}"), "nginx", "2026-01-01T00:00:00Z"))).unwrap();
+        }
+        // Two records per file; the oldest beyond `keep` are gone.
+        assert_eq!(users(&files), [vec!["u2", "u3"], vec!["u4", "u5"], vec!["u6"]]);
+        assert!(!files.rotated(3).exists());
+
+        let files = Files { path: dir.path().join("single.log"), max_bytes: one.len() as u64, keep: 0 };
+        for i in 0..3 {
+            files.append(&line(&record(&format!("u{i}"), "nginx", "2026-01-01T00:00:00Z"))).unwrap();
+        }
+        assert_eq!(users(&files), [vec!["u2"]]);
+        assert!(!files.rotated(1).exists());
+    }
+
+    #[test]
+    fn queries_filter_by_user_image_and_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = Files { path: dir.path().join("audit.log"), max_bytes: 1024, keep: 5 };
+        let records = [
+            record("alice", "docker.io/library/nginx:1", "2026-01-01T00:00:00Z"),
+            record("bob", "docker.io/library/nginx:2", "2026-01-02T00:00:00Z"),
+            record("alice", "ghcr.io/team/app:1", "2026-01-03T00:00:00Z"),
+            record("alice", "docker.io/library/nginx:3", "2026-01-04T00:00:00Z"),
+        ];
+        for r in &records {
+            files.append(&line(r)).unwrap();
+        }
+        assert!(files.rotated(1).exists());
+        let found = |q: serde_json::Value, limit| -> Vec<String> {
+            search(&files, &query(q), limit).unwrap().into_iter().map(|r| r.reference).collect()
+        };
+
+        let alice = found(serde_json::json!({ "user": "alice" }), 10);
+        assert_eq!(alice, ["docker.io/library/nginx:1", "ghcr.io/team/app:1", "docker.io/library/nginx:3"]);
+        let nginx = found(serde_json::json!({ "user": "alice", "image": "nginx" }), 10);
+        assert_eq!(nginx, ["docker.io/library/nginx:1", "docker.io/library/nginx:3"]);
+        // `since` is inclusive, `until` exclusive.
+        let window = serde_json::json!({ "since": "2026-01-02T00:00:00Z", "until": "2026-01-04T00:00:00Z" });
+        assert_eq!(found(window, 10), ["docker.io/library/nginx:2", "ghcr.io/team/app:1"]);
+        // The newest records are kept.
+        assert_eq!(found(serde_json::json!({}), 1), ["docker.io/library/nginx:3"]);
+    }
+
+    /// Reads back one field written by [`csv_field`].
+    fn unquote(field: &str) -> String {
+        match field.strip_prefix('"').and_then(|f| f.strip_suffix('"')) {
+            Some(inner) => inner.replace("\"\"", "\""),
+            None => field.to_string(),
+        }
+    }
+
+    #[test]
+    fn csv_fields_round_trip_and_are_not_formulas() {
+        for value in ["plain", "a,b", "say \"hi\"", "two\nlines"] {
+            assert_eq!(unquote(&csv_field(value)), value);
+        }
+        assert_eq!(csv_field("a,\"b\""), "\"a,\"\"b\"\"\"");
+        for value in ["=HYPERLINK(\"x\")", "+1", "-1", "@SUM(A1)", "\tx"] {
+            assert_eq!(unquote(&csv_field(value)), format!("'{value}"));
+        }
+
+        let mut r = record("=cmd", "nginx:1", "2026-01-01T00:00:00Z");
+        r.request_id = Some("a,b".to_string());
+        let csv = to_csv(&[r]);
+        let mut lines = csv.lines();
+        assert_eq!(lines.next(), Some(CSV_COLUMNS));
+        let expected = "2026-01-01T00:00:00+00:00,pull,'=cmd,,,\"a,b\",nginx:1,,docker-archive,,,,succeeded";
+        assert_eq!(lines.next(), Some(expected));
+    }
+}
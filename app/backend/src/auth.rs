@@ -0,0 +1,445 @@
+//! Authentication of API requests: static API keys and OIDC bearer tokens.
+//!
+//! Authentication is on as soon as `[auth]` lists a key or an issuer; without
+//! either, requests are served anonymously as before. Routes that must stay
+//! open (probes, metrics, the public signing key) are mounted outside
+//! [`authenticate`].
+//!
+//! Keys are sent as `X-API-Key` or as a bearer token and only their SHA-256
+//! is configured. Tokens are JWTs checked against the issuer's key set,
+//! fetched through OIDC discovery, from `jwks_url`, or read from `jwks_file`.
+//! A request without either may present a [ticket](crate::tickets) instead.
+
+use crate::{
+    config::{AuthConfig, OidcConfig},
+    error::ApiError,
+    tickets, AppState,
+};
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderMap},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use jsonwebtoken::{
+    decode, decode_header,
+    jwk::{Jwk, JwkSet},
+    Algorithm, DecodingKey, Validation,
+};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+
+/// A fetched key set is trusted this long before being fetched again.
+const JWKS_TTL: Duration = Duration::from_secs(3600);
+/// Unknown key ids trigger a refetch (keys rotate), at most this often.
+const JWKS_MIN_REFRESH: Duration = Duration::from_secs(60);
+/// Tolerated clock skew on `exp` and `nbf`.
+const LEEWAY_SECS: u64 = 60;
+/// Asymmetric algorithms only: an HMAC "signed" with a public key proves nothing.
+const ALGORITHMS: &[Algorithm] = &[
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::PS384,
+    Algorithm::PS512,
+    Algorithm::ES256,
+    Algorithm::ES384,
+    Algorithm::EdDSA,
+];
+
+/// Who made a request, available to handlers as a request extension.
+#[derive(Debug, Clone)]
+pub struct Principal {
+    pub name: String,
+    pub groups: Vec<String>,
+    /// `api_key`, `oidc`, `ticket`, `anonymous` when authentication is off,
+    /// or `scheduler` for the scheduled sync runs.
+    pub method: &'static str,
+}
+
+impl Principal {
+    pub fn anonymous() -> Self {
+        Self { name: "anonymous".to_string(), groups: Vec::new(), method: "anonymous" }
+    }
+}
+
+struct ApiKey {
+    name: String,
+    hash: [u8; 32],
+    groups: Vec<String>,
+}
+
+pub struct Authenticator {
+    keys: Vec<ApiKey>,
+    oidc: Option<Oidc>,
+}
+
+struct Oidc {
+    config: OidcConfig,
+    client: reqwest::Client,
+    /// Keys from `jwks_file`; never refetched.
+    fixed: Option<JwkSet>,
+    fetched: RwLock<Option<(JwkSet, Instant)>>,
+}
+
+impl Authenticator {
+    pub fn build(config: &AuthConfig, client: &reqwest::Client) -> anyhow::Result<Self> {
+        let keys = config
+            .api_keys
+            .iter()
+            .map(|k| {
+                let mut hash = [0u8; 32];
+                hex::decode_to_slice(&k.sha256, &mut hash)
+                    .map_err(|e| anyhow::anyhow!("auth.api_keys: '{}': {}", k.name, e))?;
+                Ok(ApiKey { name: k.name.clone(), hash, groups: k.groups.clone() })
+            })
+            .collect::<anyhow::Result<_>>()?;
+        let oidc = match &config.oidc {
+            Some(oidc) => {
+                let fixed = match &oidc.jwks_file {
+                    Some(path) => {
+                        let text = std::fs::read_to_string(path)
+                            .map_err(|e| anyhow::anyhow!("auth.oidc: cannot read {}: {}", path.display(), e))?;
+                        Some(
+                            serde_json::from_str(&text)
+                                .map_err(|e| anyhow::anyhow!("auth.oidc: invalid {}: {}", path.display(), e))?,
+                        )
+                    }
+                    None => None,
+                };
+                Some(Oidc { config: oidc.clone(), client: client.clone(), fixed, fetched: RwLock::new(None) })
+            }
+            None => None,
+        };
+        Ok(Self { keys, oidc })
+    }
+
+    pub fn enabled(&self) -> bool {
+        !self.keys.is_empty() || self.oidc.is_some()
+    }
+
+    pub async fn authenticate(&self, headers: &HeaderMap) -> Result<Principal, ApiError> {
+        let header = |name| headers.get(name).and_then(|v| v.to_str().ok()).map(str::trim);
+        if let Some(key) = header("x-api-key") {
+            return self.api_key(key).ok_or_else(|| unauthorized("invalid API key"));
+        }
+        let Some(authorization) = header("authorization") else {
+            return Err(unauthorized("authentication required"));
+        };
+        let Some(token) = authorization
+            .split_once(' ')
+            .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+            .map(|(_, token)| token.trim())
+        else {
+            return Err(unauthorized("expected a bearer token"));
+        };
+        if let Some(principal) = self.api_key(token) {
+            return Ok(principal);
+        }
+        match &self.oidc {
+            Some(oidc) if token.split('.').count() == 3 => oidc.validate(token).await,
+            _ => Err(unauthorized("invalid API key")),
+        }
+    }
+
+    fn api_key(&self, key: &str) -> Option<Principal> {
+        let hash: [u8; 32] = Sha256::digest(key.as_bytes()).into();
+        self.keys.iter().find(|k| constant_time_eq(&k.hash, &hash)).map(|k| Principal {
+            name: k.name.clone(),
+            groups: k.groups.clone(),
+            method: "api_key",
+        })
+    }
+}
+
+impl Oidc {
+    async fn validate(&self, token: &str) -> Result<Principal, ApiError> {
+        let header = decode_header(token).map_err(|e| unauthorized(&format!("invalid token: {e}")))?;
+        if !ALGORITHMS.contains(&header.alg) {
+            return Err(unauthorized(&format!("invalid token: {:?} is not accepted", header.alg)));
+        }
+        let jwk = self.key(header.kid.as_deref()).await?;
+        let key = DecodingKey::from_jwk(&jwk).map_err(|e| unauthorized(&format!("invalid token: {e}")))?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.leeway = LEEWAY_SECS;
+        validation.set_issuer(&[&self.config.issuer]);
+        validation.set_audience(&[&self.config.audience]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+        let claims = decode::<serde_json::Map<String, Value>>(token, &key, &validation)
+            .map_err(|e| unauthorized(&format!("invalid token: {e}")))?
+            .claims;
+
+        let name = [self.config.username_claim.as_str(), "sub"]
+            .into_iter()
+            .find_map(|c| claims.get(c).and_then(Value::as_str))
+            .unwrap_or_default()
+            .to_string();
+        let groups = match claims.get(&self.config.groups_claim) {
+            Some(Value::Array(values)) => values.iter().filter_map(Value::as_str).map(str::to_string).collect(),
+            Some(Value::String(group)) => vec![group.clone()],
+            _ => Vec::new(),
+        };
+        Ok(Principal { name, groups, method: "oidc" })
+    }
+
+    /// The signing key `kid` (or the only key, for tokens without one).
+    async fn key(&self, kid: Option<&str>) -> Result<Jwk, ApiError> {
+        if let Some(set) = &self.fixed {
+            return find_key(set, kid).ok_or_else(|| unauthorized("invalid token: unknown signing key"));
+        }
+        if let Some((set, at)) = &*self.fetched.read().await {
+            if let Some(jwk) = find_key(set, kid).filter(|_| at.elapsed() < JWKS_TTL) {
+                return Ok(jwk);
+            }
+        }
+
+        let mut fetched = self.fetched.write().await;
+        // Another request may have refreshed while this one waited.
+        let stale = match &*fetched {
+            Some((set, at)) if find_key(set, kid).is_some() && at.elapsed() < JWKS_TTL => false,
+            Some((_, at)) => at.elapsed() >= JWKS_MIN_REFRESH,
+            None => true,
+        };
+        if stale {
+            let set = self.fetch().await.map_err(|e| {
+                tracing::error!("auth: cannot fetch the key set of {}: {}", self.config.issuer, e);
+                ApiError::Upstream("cannot fetch the identity provider's signing keys".to_string())
+            })?;
+            *fetched = Some((set, Instant::now()));
+        }
+        fetched
+            .as_ref()
+            .and_then(|(set, _)| find_key(set, kid))
+            .ok_or_else(|| unauthorized("invalid token: unknown signing key"))
+    }
+
+    async fn fetch(&self) -> Result<JwkSet, reqwest::Error> {
+        let url = match &self.config.jwks_url {
+            Some(url) => url.clone(),
+            None => {
+                let discovery = format!("{}/.well-known/openid-configuration", self.config.issuer.trim_end_matches('/'));
+                let doc: Value = self.client.get(discovery).send().await?.error_for_status()?.json().await?;
+                doc.get("jwks_uri").and_then(Value::as_str).unwrap_or_default().to_string()
+            }
+        };
+        self.client.get(url).send().await?.error_for_status()?.json().await
+    }
+}
+
+fn find_key(set: &JwkSet, kid: Option<&str>) -> Option<Jwk> {
+    match kid {
+        Some(kid) => set.find(kid).cloned(),
+        None if set.keys.len() == 1 => set.keys.first().cloned(),
+        None => None,
+    }
+}
+
+fn constant_time_eq(a: &[u8; 32], b: &[u8; 32]) -> bool {
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn unauthorized(detail: &str) -> ApiError {
+    ApiError::Unauthorized(detail.to_string())
+}
+
+/// Hex SHA-256 of an API key, for `auth.api_keys`.
+pub fn hash_api_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.trim().as_bytes()))
+}
+
+/// Middleware for the protected routes: resolves the caller and stores the
+/// [`Principal`] in the request extensions.
+pub async fn authenticate(State(state): State<AppState>, mut req: Request, next: Next) -> Response {
+    let settings = state.config.current();
+    let principal = if settings.auth.enabled() {
+        // Credentials in headers win over a ticket.
+        let headers = req.headers();
+        let ticket = tickets::presented(req.uri(), headers)
+            .filter(|_| !headers.contains_key("x-api-key") && !headers.contains_key(header::AUTHORIZATION));
+        let authenticated = match ticket {
+            Some(ticket) => tickets::redeem(&state, &ticket).await,
+            None => settings.auth.authenticate(headers).await,
+        };
+        match authenticated {
+            Ok(principal) => principal,
+            Err(e) => {
+                tracing::warn!("authentication failed: {}", e);
+                return e.into_response();
+            }
+        }
+    } else {
+        Principal::anonymous()
+    };
+    tracing::Span::current().record("user", principal.name.as_str());
+    tracing::debug!(method = principal.method, groups = ?principal.groups, "authenticated");
+    req.extensions_mut().insert(principal);
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ApiKeyConfig, Config};
+    use ed25519_dalek::{pkcs8::EncodePrivateKey, SigningKey};
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    const ISSUER: &str = "https://idp.example";
+    const AUDIENCE: &str = "tessark";
+    const KID: &str = "test-key";
+
+    fn signing_key() -> SigningKey {
+        SigningKey::from_bytes(&[3; 32])
+    }
+
+    /// `[auth]` with one API key and an issuer whose keys are read from a
+    /// file, as an air-gapped site would configure it.
+    fn config(dir: &std::path::Path) -> AuthConfig {
+        use base64::Engine as _;
+        let public = signing_key().verifying_key();
+        let jwks = serde_json::json!({ "keys": [{
+            "kty": "OKP",
+            "crv": "Ed25519",
+            "alg": "EdDSA",
+            "kid": KID,
+            "x": base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(public.as_bytes()),
+        }]});
+        let path = dir.join("jwks.json");
+        std::fs::write(&path, jwks.to_string()).unwrap();
+        AuthConfig {
+            api_keys: vec![ApiKeyConfig {
+                name: "ci".into(),
+                sha256: hash_api_key("s3cret-key"),
+                groups: vec!["builders".into()],
+            }],
+            oidc: Some(OidcConfig {
+                issuer: ISSUER.into(),
+                audience: AUDIENCE.into(),
+                jwks_file: Some(path),
+                ..OidcConfig::default()
+            }),
+        }
+    }
+
+    fn now() -> i64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64
+    }
+
+    fn token(claims: Value) -> String {
+        let der = signing_key().to_pkcs8_der().unwrap();
+        let mut header = Header::new(Algorithm::EdDSA);
+        header.kid = Some(KID.into());
+        encode(&header, &claims, &EncodingKey::from_ed_der(der.as_bytes())).unwrap()
+    }
+
+    fn claims() -> serde_json::Map<String, Value> {
+        let claims = serde_json::json!({
+            "iss": ISSUER,
+            "aud": AUDIENCE,
+            "sub": "0b5e1c",
+            "exp": now() + 300,
+            "preferred_username": "alice",
+            "groups": ["ops"],
+        });
+        claims.as_object().unwrap().clone()
+    }
+
+    fn headers(name: &'static str, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, value.parse().unwrap());
+        headers
+    }
+
+    async fn check(auth: &Authenticator, name: &'static str, value: &str) -> Result<Principal, ApiError> {
+        auth.authenticate(&headers(name, value)).await
+    }
+
+    fn rejected(outcome: Result<Principal, ApiError>) -> String {
+        match outcome {
+            Err(ApiError::Unauthorized(detail)) => detail,
+            Err(e) => panic!("unexpected error: {e}"),
+            Ok(p) => panic!("{} was let in", p.name),
+        }
+    }
+
+    #[tokio::test]
+    async fn api_keys_are_matched_by_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let auth = Authenticator::build(&config(dir.path()), &reqwest::Client::new()).unwrap();
+        let principal = check(&auth, "x-api-key", "s3cret-key").await.unwrap();
+        assert_eq!((principal.name.as_str(), principal.method), ("ci", "api_key"));
+        assert_eq!(principal.groups, ["builders"]);
+        assert_eq!(check(&auth, "authorization", "Bearer s3cret-key").await.unwrap().name, "ci");
+
+        assert_eq!(rejected(check(&auth, "x-api-key", "s3cret-kez").await), "invalid API key");
+        assert_eq!(rejected(check(&auth, "authorization", "Bearer wrong").await), "invalid API key");
+        assert_eq!(rejected(check(&auth, "authorization", "Basic Y2k6eA==").await), "expected a bearer token");
+        assert_eq!(rejected(auth.authenticate(&HeaderMap::new()).await), "authentication required");
+    }
+
+    #[tokio::test]
+    async fn tokens_from_the_issuer_are_accepted() {
+        let dir = tempfile::tempdir().unwrap();
+        let auth = Authenticator::build(&config(dir.path()), &reqwest::Client::new()).unwrap();
+        let bearer = format!("Bearer {}", token(Value::Object(claims())));
+        let principal = check(&auth, "authorization", &bearer).await.unwrap();
+        assert_eq!((principal.name.as_str(), principal.method), ("alice", "oidc"));
+        assert_eq!(principal.groups, ["ops"]);
+
+        // No username claim: the subject names the user.
+        let mut anonymous = claims();
+        anonymous.remove("preferred_username");
+        let bearer = format!("Bearer {}", token(Value::Object(anonymous)));
+        assert_eq!(check(&auth, "authorization", &bearer).await.unwrap().name, "0b5e1c");
+    }
+
+    #[tokio::test]
+    async fn expired_or_misdirected_tokens_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let auth = Authenticator::build(&config(dir.path()), &reqwest::Client::new()).unwrap();
+        let cases = [
+            ("exp", serde_json::json!(now() - 3600), "ExpiredSignature"),
+            ("iss", serde_json::json!("https://other.example"), "InvalidIssuer"),
+            ("aud", serde_json::json!("someone-else"), "InvalidAudience"),
+        ];
+        for (claim, value, expected) in cases {
+            let mut claims = claims();
+            claims.insert(claim.into(), value);
+            let bearer = format!("Bearer {}", token(Value::Object(claims)));
+            let detail = rejected(check(&auth, "authorization", &bearer).await);
+            assert!(detail.contains(expected), "{claim}: {detail}");
+        }
+
+        // Within the leeway, a token that just expired still passes.
+        let mut claims = claims();
+        claims.insert("exp".into(), serde_json::json!(now() - 10));
+        let bearer = format!("Bearer {}", token(Value::Object(claims)));
+        assert!(check(&auth, "authorization", &bearer).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn probes_stay_open_when_the_api_is_not() {
+        let dir = tempfile::tempdir().unwrap();
+        let toml = r#"
+            [puller]
+            kind = "native"
+        "#;
+        let mut config: Config = toml::from_str(toml).unwrap();
+        config.auth = self::config(dir.path());
+        let url = crate::tests::serve(crate::tests::state(config).await).await;
+
+        let client = reqwest::Client::new();
+        for path in ["/health", "/ready", "/api/health", "/api/ready", "/metrics"] {
+            let resp = client.get(format!("{url}{path}")).send().await.unwrap();
+            assert_eq!(resp.status(), 200, "{path}");
+        }
+        let resp = client.get(format!("{url}/api/jobs")).send().await.unwrap();
+        assert_eq!(resp.status(), 401);
+        let resp = client.get(format!("{url}/api/jobs")).header("x-api-key", "s3cret-key").send().await.unwrap();
+        assert_eq!(resp.status(), 200);
+    }
+}
@@ -0,0 +1,88 @@
+//! Bandwidth limits for pulls, which can be changed while the pull runs.
+//!
+//! Pullers that move the bytes themselves call [`Bandwidth::consume`] as data
+//! comes in; it sleeps as long as needed to keep under the current limit.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::Notify;
+
+/// How far ahead of the limit a pull may get after a pause.
+const BURST: Duration = Duration::from_millis(250);
+
+/// Bytes per second allowed to one pull. Cheap to clone: clones share the
+/// limit, so changing it from another task slows down or speeds up the pull.
+#[derive(Debug, Clone)]
+pub struct Bandwidth(Arc<BandwidthInner>);
+
+#[derive(Debug)]
+struct BandwidthInner {
+    /// Zero means no limit.
+    limit: AtomicU64,
+    /// When the bytes let through so far are paid for.
+    paid_until: Mutex<Instant>,
+    /// Wakes the pulls waiting under the previous limit.
+    changed: Notify,
+}
+
+impl Bandwidth {
+    pub fn new(limit: Option<u64>) -> Self {
+        Self(Arc::new(BandwidthInner {
+            limit: AtomicU64::new(limit.unwrap_or(0)),
+            paid_until: Mutex::new(Instant::now()),
+            changed: Notify::new(),
+        }))
+    }
+
+    pub fn limit(&self) -> Option<u64> {
+        Some(self.0.limit.load(Ordering::Relaxed)).filter(|&l| l > 0)
+    }
+
+    /// Sets the limit, `None` (or zero) lifting it. What was owed under the
+    /// previous limit is forgiven, so the change shows right away.
+    pub fn set(&self, limit: Option<u64>) {
+        self.0.limit.store(limit.unwrap_or(0), Ordering::Relaxed);
+        *self.paid_until() = Instant::now();
+        self.0.changed.notify_waiters();
+    }
+
+    /// Accounts for `n` bytes, waiting until they fit under the limit.
+    pub async fn consume(&self, n: u64) {
+        let Some(limit) = self.limit() else { return };
+        let cost = Duration::from_secs_f64(n as f64 / limit as f64);
+        {
+            let mut paid_until = self.paid_until();
+            let now = Instant::now();
+            let floor = now.checked_sub(BURST).unwrap_or(now);
+            *paid_until = (*paid_until).max(floor) + cost;
+        }
+        loop {
+            let changed = self.0.changed.notified();
+            tokio::pin!(changed);
+            changed.as_mut().enable();
+            let wait = self.paid_until().saturating_duration_since(Instant::now());
+            if wait.is_zero() {
+                return;
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(wait) => return,
+                _ = changed => {}
+            }
+        }
+    }
+
+    fn paid_until(&self) -> std::sync::MutexGuard<'_, Instant> {
+        self.0.paid_until.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Default for Bandwidth {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
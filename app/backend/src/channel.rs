@@ -0,0 +1,383 @@
+//! WebSocket channel on jobs: the events of the jobs a client follows, and
+//! commands to start, cancel and slow them down, over one connection.
+//!
+//! Messages are JSON objects with a `type`. The client sends commands:
+//!
+//! - `pull`: starts a pull, with the fields of `POST /api/pull/stream`, and
+//!   follows it
+//! - `subscribe`, `unsubscribe`: `jobs`, a list of job ids; `after` may map
+//!   job ids to the last event seen, as `Last-Event-ID` does over SSE
+//! - `cancel`: `job`
+//! - `bandwidth`: `job` and `limit`, in bytes per second (`null` lifts it)
+//!
+//! Each command gets an `ok` reply (with the job started by a `pull`) or an
+//! `error` reply holding a problem JSON, both echoing the command's `id` if
+//! it had one. Events of followed jobs come as `event` messages, with the
+//! job, the event's sequence number, and the `event` and `data` sent over SSE.
+//!
+//! Browsers cannot set headers on a WebSocket: a page gets a
+//! [ticket](crate::tickets) first and offers the `jobs` and `ticket.<ticket>`
+//! subprotocols, the server picking `jobs`. Pages of other origins than the
+//! API's own are refused unless listed in `jobs.ws_origins`.
+
+use crate::{
+    audit::Caller,
+    cluster,
+    error::ApiError,
+    jobs::{self, Job, JobEvent, Target},
+    start_pull, tickets, AppState, PullRequestBody,
+};
+use axum::{
+    body::Body,
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Request, State,
+    },
+    http::{header, HeaderMap, HeaderName, Method},
+    response::{IntoResponse, Response},
+};
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tokio::{sync::mpsc, task::JoinHandle};
+
+/// Headers that authenticate the client again on the replica a command is
+/// forwarded to.
+const CREDENTIALS: [HeaderName; 2] = [header::AUTHORIZATION, HeaderName::from_static("x-api-key")];
+
+/// Subprotocol picked when the client offers it.
+const PROTOCOL: &str = "jobs";
+
+/// Largest error body read back from another replica.
+const MAX_PROBLEM_BODY: usize = 64 * 1024;
+
+#[derive(Deserialize)]
+struct Command {
+    id: Option<String>,
+    #[serde(flatten)]
+    action: Action,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Action {
+    Pull(PullRequestBody),
+    Subscribe {
+        jobs: Vec<String>,
+        #[serde(default)]
+        after: HashMap<String, i64>,
+    },
+    Unsubscribe {
+        jobs: Vec<String>,
+    },
+    Cancel {
+        job: String,
+    },
+    Bandwidth {
+        job: String,
+        limit: Option<u64>,
+    },
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Reply {
+    Ok {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        job: Option<String>,
+    },
+    Error {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+        problem: serde_json::Value,
+    },
+    Event {
+        job: String,
+        seq: i64,
+        event: String,
+        data: String,
+    },
+}
+
+/// What a command did: the job it started, and the jobs to follow once it
+/// has been answered.
+#[derive(Default)]
+struct Done {
+    job: Option<String>,
+    follow: Vec<(Job, i64)>,
+}
+
+fn problem(err: &ApiError) -> serde_json::Value {
+    serde_json::to_value(err.problem()).unwrap_or_default()
+}
+
+/// Whether a page of the `Origin` the browser sent may open the channel: one
+/// of the API's own origin, or of `allowed`. Other clients send none.
+fn origin_allowed(headers: &HeaderMap, allowed: &[String]) -> bool {
+    let Some(origin) = headers.get(header::ORIGIN) else {
+        return true;
+    };
+    let Some(origin) = origin.to_str().ok().and_then(|o| url::Url::parse(o).ok()) else {
+        return false;
+    };
+    let host = origin.host_str().unwrap_or_default();
+    let authority = match origin.port() {
+        Some(port) => format!("{host}:{port}"),
+        None => host.to_string(),
+    };
+    let own = headers.get(header::HOST).and_then(|h| h.to_str().ok());
+    own.is_some_and(|own| own.eq_ignore_ascii_case(&authority))
+        || allowed.iter().filter_map(|a| url::Url::parse(a).ok()).any(|a| a.origin() == origin.origin())
+}
+
+pub async fn connect(
+    State(state): State<AppState>,
+    caller: Caller,
+    headers: HeaderMap,
+    ws: WebSocketUpgrade,
+) -> Response {
+    if !origin_allowed(&headers, &state.config.current().config.jobs.ws_origins) {
+        let origin = headers.get(header::ORIGIN).and_then(|o| o.to_str().ok()).unwrap_or("?");
+        return ApiError::Forbidden(format!("origin {origin} may not open the jobs channel")).into_response();
+    }
+    let credentials = CREDENTIALS
+        .iter()
+        .filter_map(|name| headers.get(name).map(|value| (name.clone(), value.clone())))
+        .collect();
+    // The client fails the handshake unless one of the protocols it offered
+    // is picked.
+    let offered = tickets::protocols(&headers).filter(|p| p.starts_with(tickets::PROTOCOL_PREFIX)).map(str::to_string);
+    ws.protocols(std::iter::once(PROTOCOL.to_string()).chain(offered.collect::<Vec<_>>()))
+        .on_upgrade(move |socket| run(socket, Channel { state, caller, credentials, followed: HashMap::new() }))
+}
+
+struct Channel {
+    state: AppState,
+    caller: Caller,
+    /// Headers the client authenticated with; none when it used a ticket.
+    credentials: HeaderMap,
+    /// Tasks sending the events of the jobs followed.
+    followed: HashMap<String, JoinHandle<()>>,
+}
+
+async fn run(socket: WebSocket, mut channel: Channel) {
+    let (mut sink, mut incoming) = socket.split();
+    let (out, mut outgoing) = mpsc::channel::<Reply>(64);
+    let writer = tokio::spawn(async move {
+        while let Some(reply) = outgoing.recv().await {
+            let text = serde_json::to_string(&reply).unwrap_or_default();
+            if sink.send(Message::Text(text)).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    while let Some(Ok(message)) = incoming.next().await {
+        let text = match message {
+            Message::Text(text) => text,
+            Message::Close(_) => break,
+            // Pings are answered by axum.
+            _ => continue,
+        };
+        let (id, done) = match serde_json::from_str::<Command>(&text) {
+            Ok(command) => (command.id, channel.run(command.action).await),
+            Err(e) => (None, Err(problem(&ApiError::InvalidRequest(format!("invalid command: {e}"))))),
+        };
+        let reply = match &done {
+            Ok(done) => Reply::Ok { id, job: done.job.clone() },
+            Err(problem) => Reply::Error { id, problem: problem.clone() },
+        };
+        if out.send(reply).await.is_err() {
+            break;
+        }
+        for (job, after) in done.map(|done| done.follow).unwrap_or_default() {
+            channel.follow(job, after, out.clone());
+        }
+    }
+
+    for (_, task) in channel.followed.drain() {
+        task.abort();
+    }
+    writer.abort();
+}
+
+impl Channel {
+    async fn run(&mut self, action: Action) -> Result<Done, serde_json::Value> {
+        let state = &self.state;
+        match action {
+            Action::Pull(body) => {
+                let (id, _events) = start_pull(state.clone(), self.caller.clone(), body)
+                    .await
+                    .map_err(|e| problem(&e))?;
+                let job = jobs::visible_job(state, &self.caller, &id).await.map_err(|e| problem(&e))?;
+                Ok(Done { job: Some(id), follow: vec![(job, 0)] })
+            }
+            Action::Subscribe { jobs, after } => {
+                let mut follow = Vec::new();
+                let followed = |id: &String| self.followed.get(id).is_some_and(|task| !task.is_finished());
+                for id in jobs.iter().filter(|id| !followed(id)) {
+                    let job = jobs::visible_job(state, &self.caller, id).await.map_err(|e| problem(&e))?;
+                    follow.push((job, after.get(id).copied().unwrap_or(0)));
+                }
+                Ok(Done { follow, ..Done::default() })
+            }
+            Action::Unsubscribe { jobs } => {
+                for id in &jobs {
+                    if let Some(task) = self.followed.remove(id) {
+                        task.abort();
+                    }
+                }
+                Ok(Done::default())
+            }
+            Action::Cancel { job } => {
+                match jobs::running_job(state, &self.caller, &job).await.map_err(|e| problem(&e))? {
+                    Target::Here => {
+                        state.jobs.cancel(&job).map_err(|e| problem(&e))?;
+                        tracing::info!(job = %job, user = %self.caller.principal.name, "jobs: cancelled");
+                    }
+                    Target::Replica(node) => {
+                        self.forward(&node, format!("/api/jobs/{job}/cancel"), serde_json::json!({})).await?;
+                    }
+                }
+                Ok(Done::default())
+            }
+            Action::Bandwidth { job, limit } => {
+                match jobs::running_job(state, &self.caller, &job).await.map_err(|e| problem(&e))? {
+                    Target::Here => state.jobs.set_bandwidth(&job, limit).map_err(|e| problem(&e))?,
+                    Target::Replica(node) => {
+                        let body = serde_json::json!({ "limit": limit });
+                        self.forward(&node, format!("/api/jobs/{job}/bandwidth"), body).await?;
+                    }
+                }
+                Ok(Done::default())
+            }
+        }
+    }
+
+    /// Sends the events of `job` after `after` to the client.
+    fn follow(&mut self, job: Job, after: i64, out: mpsc::Sender<Reply>) {
+        let id = job.id.clone();
+        let jobs = self.state.jobs.clone();
+        let task = tokio::spawn(async move {
+            let wrap = |seq, event: JobEvent| Reply::Event {
+                job: job.id.clone(),
+                seq,
+                event: event.event,
+                data: event.data,
+            };
+            jobs.follow(&job, after, out, wrap).await;
+        });
+        self.followed.insert(id, task);
+    }
+
+    /// Posts `body` to `path` on replica `node`, as the client.
+    async fn forward(&self, node: &str, path: String, body: serde_json::Value) -> Result<(), serde_json::Value> {
+        // A client that came with a ticket used it up: the replica gets one of its own.
+        let path = if self.credentials.is_empty() && self.state.config.current().auth.enabled() {
+            let ticket = tickets::issue(&self.state, &self.caller.principal).await.map_err(|e| problem(&e))?;
+            format!("{path}?ticket={ticket}")
+        } else {
+            path
+        };
+        let mut request = Request::builder()
+            .method(Method::POST)
+            .uri(path)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .map_err(|e| problem(&ApiError::Internal(format!("cannot build request: {e}"))))?;
+        request.headers_mut().extend(self.credentials.clone());
+        let response = cluster::forward(&self.state, node, request).await;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        let bytes = axum::body::to_bytes(response.into_body(), MAX_PROBLEM_BODY).await.unwrap_or_default();
+        Err(serde_json::from_slice(&bytes)
+            .unwrap_or_else(|_| problem(&ApiError::Network(format!("replica {node} answered {status}")))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{auth::hash_api_key, tests};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        pairs.iter().map(|(name, value)| (HeaderName::from_static(name), value.parse().unwrap())).collect()
+    }
+
+    #[test]
+    fn only_the_own_and_listed_origins_may_connect() {
+        let allowed = ["https://ui.example".to_string()];
+        let check = |pairs: &[(&'static str, &'static str)]| origin_allowed(&headers(pairs), &allowed);
+        assert!(check(&[("host", "api.example:8080")]));
+        assert!(check(&[("host", "api.example:8080"), ("origin", "http://api.example:8080")]));
+        assert!(check(&[("host", "api.example"), ("origin", "https://ui.example")]));
+        assert!(check(&[("host", "api.example"), ("origin", "https://ui.example:443")]));
+        assert!(!check(&[("host", "api.example"), ("origin", "https://evil.example")]));
+        assert!(!check(&[("host", "api.example"), ("origin", "http://ui.example")]));
+        assert!(!check(&[("host", "api.example"), ("origin", "null")]));
+    }
+
+    /// Status line and headers answering a WebSocket handshake on `path`.
+    async fn handshake(url: &str, path: &str, extra: &str) -> String {
+        let host = url.trim_start_matches("http://");
+        let mut stream = tokio::net::TcpStream::connect(host).await.unwrap();
+        let request = format!(
+            "GET {path} HTTP/1.1\r\nHost: {host}\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\
+             Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n{extra}\r\n"
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            let mut byte = [0u8];
+            if stream.read(&mut byte).await.unwrap() == 0 {
+                break;
+            }
+            head.push(byte[0]);
+        }
+        String::from_utf8(head).unwrap().to_ascii_lowercase()
+    }
+
+    #[tokio::test]
+    async fn browsers_connect_with_a_ticket_from_an_allowed_origin() {
+        let config = format!(
+            "[puller]\nkind = \"native\"\n\n[jobs]\nws_origins = [\"https://ui.example\"]\n\n\
+             [[auth.api_keys]]\nname = \"alice\"\nsha256 = \"{}\"\n",
+            hash_api_key("alice")
+        );
+        let url = tests::serve(tests::state(toml::from_str(&config).unwrap()).await).await;
+        let ticket = || async {
+            let issued: serde_json::Value = reqwest::Client::new()
+                .post(format!("{url}/api/tickets"))
+                .header("x-api-key", "alice")
+                .send()
+                .await
+                .unwrap()
+                .json()
+                .await
+                .unwrap();
+            issued["ticket"].as_str().unwrap().to_string()
+        };
+
+        assert!(handshake(&url, "/api/jobs/ws", "").await.starts_with("http/1.1 401"));
+        let t = ticket().await;
+        let answer = handshake(&url, "/api/jobs/ws", &format!("Sec-WebSocket-Protocol: jobs, ticket.{t}\r\n")).await;
+        assert!(answer.starts_with("http/1.1 101"), "{answer}");
+        assert!(answer.contains("sec-websocket-protocol: jobs\r\n"), "{answer}");
+        // Used up.
+        let answer = handshake(&url, "/api/jobs/ws", &format!("Sec-WebSocket-Protocol: jobs, ticket.{t}\r\n")).await;
+        assert!(answer.starts_with("http/1.1 401"), "{answer}");
+
+        let t = ticket().await;
+        let from = |origin: &str| format!("Origin: {origin}\r\n");
+        let answer = handshake(&url, &format!("/api/jobs/ws?ticket={t}"), &from("https://ui.example")).await;
+        assert!(answer.starts_with("http/1.1 101"), "{answer}");
+        let t = ticket().await;
+        let answer = handshake(&url, &format!("/api/jobs/ws?ticket={t}"), &from("https://evil.example")).await;
+        assert!(answer.starts_with("http/1.1 403"), "{answer}");
+    }
+}
@@ -0,0 +1,259 @@
+//! Classification of pull failures.
+//!
+//! skopeo only reports failures as text on stderr, and the native client
+//! reports transport errors the same way, so both are matched against the
+//! phrases the containers/image library and reqwest actually produce. The
+//! corpus in `testdata/skopeo-stderr.txt` pins the mapping down.
+
+use crate::puller::PullError;
+
+/// Seconds suggested to the client when a registry rate-limits without
+/// saying for how long (Docker Hub never does through skopeo).
+pub const DEFAULT_RATE_LIMIT_RETRY: u64 = 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    ImageNotFound,
+    AuthFailed,
+    RateLimited { retry_after: Option<u64> },
+    Tls,
+    Dns,
+    /// The manifest list has no image for the requested platform.
+    PlatformMismatch,
+    DiskFull,
+    /// Connection-level trouble or a 5xx from the registry.
+    Network,
+    InvalidReference,
+    /// The puller binary itself could not be run.
+    ToolMissing,
+    Corrupt,
+    LocalIo,
+    Unknown,
+}
+
+impl Failure {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Failure::ImageNotFound => "image_not_found",
+            Failure::AuthFailed => "auth_failed",
+            Failure::RateLimited { .. } => "rate_limited",
+            Failure::Tls => "tls",
+            Failure::Dns => "dns",
+            Failure::PlatformMismatch => "platform_mismatch",
+            Failure::DiskFull => "disk_full",
+            Failure::Network => "network",
+            Failure::InvalidReference => "invalid_reference",
+            Failure::ToolMissing => "tool_missing",
+            Failure::Corrupt => "corrupt",
+            Failure::LocalIo => "local_io",
+            Failure::Unknown => "unknown",
+        }
+    }
+
+    /// Whether the same pull may succeed if attempted again later.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, Failure::RateLimited { .. } | Failure::Network)
+    }
+}
+
+pub fn classify(e: &PullError) -> Failure {
+    match e {
+        PullError::Spawn(_) => Failure::ToolMissing,
+        PullError::Registry { status: 404, .. } => Failure::ImageNotFound,
+        PullError::Registry { status: 401 | 403, .. } => Failure::AuthFailed,
+        PullError::Registry { status: 429, retry_after, .. } => Failure::RateLimited {
+            retry_after: Some(retry_after.unwrap_or(DEFAULT_RATE_LIMIT_RETRY)),
+        },
+        PullError::Registry { status: 500..=599, .. } => Failure::Network,
+        PullError::Registry { message, .. } => classify_message(message),
+        PullError::Invalid(_) => Failure::Corrupt,
+        PullError::Io(e) if e.kind() == std::io::ErrorKind::StorageFull => Failure::DiskFull,
+        PullError::Io(_) => Failure::LocalIo,
+        PullError::Failed(stderr) => classify_message(stderr),
+    }
+}
+
+const TOOL_MISSING: &[&str] = &["executable file not found", "skopeo: not found", "command not found"];
+const RATE_LIMITED: &[&str] = &["toomanyrequests", "too many requests", "rate limit"];
+const DISK_FULL: &[&str] = &["no space left on device", "disk quota exceeded"];
+const LOCAL_IO: &[&str] = &["permission denied", "read-only file system"];
+const PLATFORM: &[&str] = &["no image found in manifest list"];
+const INVALID_REFERENCE: &[&str] = &["invalid reference format", "error parsing image name"];
+const DNS: &[&str] = &[
+    "no such host",
+    "server misbehaving",
+    "temporary failure in name resolution",
+    "name or service not known",
+    "failed to lookup address",
+    "dns error",
+];
+const NETWORK: &[&str] = &[
+    "connection reset",
+    "connection refused",
+    "broken pipe",
+    "i/o timeout",
+    "tls handshake timeout",
+    "unexpected eof",
+    "network is unreachable",
+    "no route to host",
+    "internal server error",
+    "bad gateway",
+    "service unavailable",
+    "gateway timeout",
+];
+const TLS: &[&str] = &[
+    "x509:",
+    "tls:",
+    "certificate",
+    "server gave http response to https client",
+];
+/// Only the registry's wording: a bare "denied" would also match local
+/// "permission denied" errors.
+const AUTH: &[&str] = &[
+    "unauthorized",
+    "authentication required",
+    "access denied",
+    "requested access to the resource is denied",
+    "denied: ",
+    "incorrect username or password",
+    "invalid username/password",
+    "forbidden",
+];
+const NOT_FOUND: &[&str] = &[
+    "manifest unknown",
+    "name unknown",
+    "repository not found",
+    "statuscode: 404",
+    "404 (not found)",
+    "404 not found",
+];
+
+/// Classifies a textual failure. Groups are checked from the most specific
+/// to the most generic: "skopeo: not found" is a missing binary, not a
+/// missing image, and a TLS handshake timeout is a network hiccup.
+pub fn classify_message(text: &str) -> Failure {
+    let lower = text.to_lowercase();
+    let any = |needles: &[&str]| needles.iter().any(|n| lower.contains(n));
+
+    if any(TOOL_MISSING) {
+        Failure::ToolMissing
+    } else if any(RATE_LIMITED) || lower.contains("status: 429") {
+        Failure::RateLimited {
+            retry_after: Some(retry_after_hint(&lower).unwrap_or(DEFAULT_RATE_LIMIT_RETRY)),
+        }
+    } else if any(DISK_FULL) {
+        Failure::DiskFull
+    } else if any(LOCAL_IO) {
+        Failure::LocalIo
+    } else if any(PLATFORM) {
+        Failure::PlatformMismatch
+    } else if any(INVALID_REFERENCE) {
+        Failure::InvalidReference
+    } else if any(DNS) {
+        Failure::Dns
+    } else if any(NETWORK) {
+        Failure::Network
+    } else if any(TLS) {
+        Failure::Tls
+    } else if any(AUTH) {
+        Failure::AuthFailed
+    } else if any(NOT_FOUND) {
+        Failure::ImageNotFound
+    } else {
+        Failure::Unknown
+    }
+}
+
+/// Finds "retry-after: N", "retry after N" or "try again in N seconds/minutes"
+/// in an already lowercased message.
+fn retry_after_hint(lower: &str) -> Option<u64> {
+    for marker in ["retry-after:", "retry after", "try again in"] {
+        let Some(pos) = lower.find(marker) else { continue };
+        let rest = lower[pos + marker.len()..].trim_start();
+        let digits: String = rest.chars().take_while(char::is_ascii_digit).collect();
+        let Ok(n) = digits.parse::<u64>() else { continue };
+        let unit = rest[digits.len()..].trim_start();
+        return Some(if unit.starts_with('m') && !unit.starts_with("ms") { n * 60 } else { n });
+    }
+    None
+}
+
+/// The useful part of skopeo's stderr: the `msg="…"` of its last
+/// `level=fatal` line, or the last non-empty line otherwise.
+pub fn summary(stderr: &str) -> String {
+    let Some(line) = stderr.lines().map(str::trim).rfind(|l| !l.is_empty()) else {
+        return String::new();
+    };
+    match line.find("msg=\"") {
+        Some(start) => {
+            let msg = &line[start + 5..];
+            let msg = msg.strip_suffix('"').unwrap_or(msg);
+            msg.replace("\\\"", "\"")
+        }
+        None => line.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Blocks of real stderr, each headed by `=== <class> [retry_after=N]`.
+    const CORPUS: &str = include_str!("../testdata/skopeo-stderr.txt");
+
+    fn corpus() -> Vec<(String, Option<u64>, String)> {
+        let mut cases: Vec<(String, Option<u64>, String)> = Vec::new();
+        for line in CORPUS.lines() {
+            if let Some(header) = line.strip_prefix("=== ") {
+                let mut parts = header.split_whitespace();
+                let class = parts.next().unwrap_or_default().to_string();
+                let retry_after = parts
+                    .find_map(|p| p.strip_prefix("retry_after="))
+                    .map(|v| v.parse().expect("retry_after must be a number"));
+                cases.push((class, retry_after, String::new()));
+            } else if let Some((_, _, body)) = cases.last_mut() {
+                body.push_str(line);
+                body.push('\n');
+            }
+        }
+        cases
+    }
+
+    #[test]
+    fn corpus_is_classified() {
+        let cases = corpus();
+        assert!(cases.len() >= 20, "corpus unexpectedly small");
+        for (class, retry_after, stderr) in cases {
+            let failure = classify_message(&stderr);
+            assert_eq!(failure.as_str(), class, "misclassified:\n{stderr}");
+            if let Failure::RateLimited { retry_after: got } = failure {
+                assert_eq!(got, Some(retry_after.unwrap_or(DEFAULT_RATE_LIMIT_RETRY)), "{stderr}");
+            }
+        }
+    }
+
+    #[test]
+    fn registry_errors_use_status() {
+        let err = |status, retry_after| PullError::Registry {
+            status,
+            code: None,
+            message: "whatever".to_string(),
+            retry_after,
+        };
+        assert_eq!(classify(&err(404, None)), Failure::ImageNotFound);
+        assert_eq!(classify(&err(403, None)), Failure::AuthFailed);
+        assert_eq!(classify(&err(429, Some(12))), Failure::RateLimited { retry_after: Some(12) });
+        assert_eq!(classify(&err(503, None)), Failure::Network);
+    }
+
+    #[test]
+    fn summary_extracts_fatal_message() {
+        let stderr = "time=\"2024-05-02T10:11:12Z\" level=fatal msg=\"initializing source docker://nginx:nope: \
+                      reading manifest nope in docker.io/library/nginx: manifest unknown\"\n";
+        assert_eq!(
+            summary(stderr),
+            "initializing source docker://nginx:nope: reading manifest nope in docker.io/library/nginx: manifest unknown"
+        );
+        assert_eq!(summary("first\nError: \"quoted\"\n\n"), "Error: \"quoted\"");
+    }
+}
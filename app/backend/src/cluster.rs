@@ -0,0 +1,176 @@
+//! Coordination between replicas sharing a database (see [`crate::store`]).
+//!
+//! Jobs and artifacts stay on the replica that produced them. When another
+//! replica gets a request about one of them, it forwards the request there
+//! and relays the answer; the caller is authenticated again by the replica
+//! that answers. Every replica also keeps its node entry fresh and cleans up
+//! after the ones that are gone.
+
+use crate::{
+    artifacts::ArtifactStore,
+    config::ClusterConfig,
+    error::ApiError,
+    jobs::Jobs,
+    store::{Node, Store},
+    webhooks::Notifier,
+    AppState,
+};
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Path, Request, State},
+    http::{header, HeaderName, HeaderValue},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    RequestExt,
+};
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
+
+/// Set on forwarded requests, with the id of the replica that forwarded.
+const FORWARDED_BY: HeaderName = HeaderName::from_static("x-tessark-forwarded-by");
+
+/// How often the node entry is refreshed and leftovers are cleaned up;
+/// well under [`crate::store::NODE_TIMEOUT`].
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Largest JSON body read to find the artifact a request is about.
+const MAX_JSON_BODY: usize = 64 * 1024;
+
+/// This replica: `cluster.node_id`, else the host name, else a random id.
+pub fn node(config: &ClusterConfig) -> Node {
+    let id = config
+        .node_id
+        .clone()
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .filter(|id| !id.trim().is_empty())
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    Node { id, url: config.advertise_url.clone() }
+}
+
+/// Refreshes the node entry, fails the jobs and resumes the webhook
+/// deliveries of replicas that are gone, and deletes expired artifacts and
+/// old jobs, now and then periodically.
+pub fn maintain(
+    store: Arc<dyn Store>,
+    node: Arc<Node>,
+    artifacts: ArtifactStore,
+    jobs: Jobs,
+    webhooks: Notifier,
+    job_retention: Duration,
+) {
+    tokio::spawn(async move {
+        let mut tick = tokio::time::interval(MAINTENANCE_INTERVAL);
+        loop {
+            tick.tick().await;
+            if let Err(e) = store.heartbeat(&node).await {
+                tracing::warn!("cluster: cannot refresh node entry: {}", e);
+            }
+            if let Err(e) = jobs.fail_orphans(false).await {
+                tracing::warn!("jobs: cannot fail orphaned jobs: {}", e);
+            }
+            if let Err(e) = webhooks.resume(false).await {
+                tracing::warn!("webhooks: cannot resume deliveries: {}", e);
+            }
+            match artifacts.sweep().await {
+                Ok(0) => {}
+                Ok(n) => tracing::debug!("artifacts: {} expired", n),
+                Err(e) => tracing::warn!("artifacts: sweep failed: {}", e),
+            }
+            let before = chrono::Utc::now() - job_retention;
+            if let Err(e) = jobs.purge(before).await {
+                tracing::warn!("jobs: purge failed: {}", e);
+            }
+        }
+    });
+}
+
+/// Middleware for the routes about one artifact, named by `:id` in the
+/// path or by `id` in a JSON body: requests about an artifact held by
+/// another replica are forwarded to it.
+pub async fn route_artifact(State(state): State<AppState>, mut request: Request, next: Next) -> Response {
+    if !state.store.shared() {
+        return next.run(request).await;
+    }
+    let id = match request.extract_parts::<Path<HashMap<String, String>>>().await {
+        Ok(Path(params)) if params.contains_key("id") => params["id"].clone(),
+        _ => {
+            let (parts, body) = request.into_parts();
+            let bytes = match axum::body::to_bytes(body, MAX_JSON_BODY).await {
+                Ok(bytes) => bytes,
+                Err(e) => return ApiError::InvalidRequest(format!("cannot read body: {e}")).into_response(),
+            };
+            let id = serde_json::from_slice::<serde_json::Value>(&bytes)
+                .ok()
+                .and_then(|v| v.get("id").and_then(|id| id.as_str()).map(str::to_string));
+            request = Request::from_parts(parts, Body::from(bytes));
+            match id {
+                Some(id) => id,
+                None => return next.run(request).await,
+            }
+        }
+    };
+    match state.artifacts.get(&id).await {
+        Ok(artifact) if artifact.node != state.node.id => forward(&state, &artifact.node, request).await,
+        _ => next.run(request).await,
+    }
+}
+
+/// Sends `request` to replica `node` and relays its response.
+pub async fn forward(state: &AppState, node: &str, request: Request) -> Response {
+    if let Some(by) = request.headers().get(&FORWARDED_BY) {
+        // Only the replica named in the store should have been asked.
+        return ApiError::Internal(format!(
+            "request forwarded by {} for replica {}, which is not this one ({})",
+            by.to_str().unwrap_or("?"),
+            node,
+            state.node.id
+        ))
+        .into_response();
+    }
+    let base = match state.store.node_url(node).await {
+        Ok(Some(url)) => url,
+        Ok(None) => return ApiError::NotFound(format!("replica {node} holding it is gone")).into_response(),
+        Err(e) => return e.into_response(),
+    };
+
+    let (parts, body) = request.into_parts();
+    let path = parts.uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    let url = format!("{}{}", base.trim_end_matches('/'), path);
+    let mut headers = parts.headers;
+    for name in [header::HOST, header::CONNECTION, header::CONTENT_LENGTH, header::TRANSFER_ENCODING] {
+        headers.remove(name);
+    }
+    if let Some(ConnectInfo(addr)) = parts.extensions.get::<ConnectInfo<SocketAddr>>() {
+        let chain = match headers.get("x-forwarded-for").and_then(|v| v.to_str().ok()) {
+            Some(previous) => format!("{}, {}", previous, addr.ip()),
+            None => addr.ip().to_string(),
+        };
+        if let Ok(value) = HeaderValue::from_str(&chain) {
+            headers.insert("x-forwarded-for", value);
+        }
+    }
+    if let Ok(value) = HeaderValue::from_str(&state.node.id) {
+        headers.insert(FORWARDED_BY, value);
+    }
+    tracing::debug!(node, %url, "cluster: forwarding request");
+
+    let sent = state
+        .client
+        .request(parts.method, &url)
+        .headers(headers)
+        .body(reqwest::Body::wrap_stream(body.into_data_stream()))
+        .send()
+        .await;
+    let upstream = match sent {
+        Ok(response) => response,
+        Err(e) => return ApiError::Network(format!("replica {node} unreachable: {e}")).into_response(),
+    };
+    let mut response = Response::builder().status(upstream.status());
+    for (name, value) in upstream.headers() {
+        if name != header::CONNECTION && name != header::TRANSFER_ENCODING {
+            response = response.header(name, value);
+        }
+    }
+    response
+        .body(Body::from_stream(upstream.bytes_stream()))
+        .unwrap_or_else(|e| ApiError::Internal(format!("cannot relay response: {e}")).into_response())
+}
@@ -0,0 +1,278 @@
+use crate::{archive::ArchiveFormat, checksum::HashingWriter};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    io::{BufRead, BufReader, Read, Write},
+    path::PathBuf,
+    str::FromStr,
+};
+
+/// Compression applied to a whole exported archive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+impl Compression {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    /// Suffix appended after `.tar` in download filenames.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Compression::None => "",
+            Compression::Gzip => ".gz",
+            Compression::Zstd => ".zst",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Compression::None => "application/x-tar",
+            Compression::Gzip => "application/gzip",
+            Compression::Zstd => "application/zstd",
+        }
+    }
+
+    fn levels(self) -> std::ops::RangeInclusive<i32> {
+        match self {
+            Compression::None => 0..=0,
+            Compression::Gzip => 1..=9,
+            Compression::Zstd => 1..=19,
+        }
+    }
+
+    fn default_level(self) -> i32 {
+        match self {
+            Compression::None => 0,
+            Compression::Gzip => 6,
+            Compression::Zstd => 3,
+        }
+    }
+
+    /// Recognises a compressed stream from its first bytes.
+    pub fn detect(head: &[u8]) -> Compression {
+        if head.starts_with(&GZIP_MAGIC) {
+            Compression::Gzip
+        } else if head.starts_with(&ZSTD_MAGIC) {
+            Compression::Zstd
+        } else {
+            Compression::None
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "" | "none" => Ok(Compression::None),
+            "gzip" | "gz" => Ok(Compression::Gzip),
+            "zstd" | "zst" => Ok(Compression::Zstd),
+            other => Err(format!("unsupported compression '{}' (expected none, gzip or zstd)", other)),
+        }
+    }
+}
+
+/// Archive compression requested by a client, with its validated level.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CompressionOptions {
+    pub kind: Compression,
+    pub level: i32,
+}
+
+impl CompressionOptions {
+    pub fn parse(kind: Option<&str>, level: Option<i32>) -> Result<Self, String> {
+        let kind: Compression = kind.unwrap_or_default().parse()?;
+        let level = match level {
+            None => kind.default_level(),
+            Some(_) if kind == Compression::None => 0,
+            Some(l) if kind.levels().contains(&l) => l,
+            Some(l) => {
+                let range = kind.levels();
+                return Err(format!(
+                    "{} level {} out of range ({}-{})",
+                    kind,
+                    l,
+                    range.start(),
+                    range.end()
+                ));
+            }
+        };
+        Ok(Self { kind, level })
+    }
+}
+
+/// Compression settings of an export: the archive itself and, for OCI
+/// output, the layers skopeo writes into it.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ExportCompression {
+    pub archive: CompressionOptions,
+    pub layers: Option<Compression>,
+}
+
+impl ExportCompression {
+    pub fn parse(
+        format: ArchiveFormat,
+        archive: Option<&str>,
+        level: Option<i32>,
+        layers: Option<&str>,
+    ) -> Result<Self, String> {
+        let archive = CompressionOptions::parse(archive, level)?;
+        let layers = match layers.map(str::parse::<Compression>).transpose()? {
+            None | Some(Compression::None) => None,
+            Some(_) if format == ArchiveFormat::DockerArchive => {
+                return Err("layer compression is only supported for OCI output".to_string());
+            }
+            Some(kind) => Some(kind),
+        };
+        Ok(Self { archive, layers })
+    }
+}
+
+/// Streaming encoder for one of the supported compressions.
+pub enum StreamEncoder<W: Write> {
+    Plain(W),
+    Gzip(flate2::write::GzEncoder<W>),
+    Zstd(zstd::stream::write::Encoder<'static, W>),
+}
+
+impl<W: Write> StreamEncoder<W> {
+    pub fn new(inner: W, opts: CompressionOptions) -> std::io::Result<Self> {
+        Ok(match opts.kind {
+            Compression::None => StreamEncoder::Plain(inner),
+            Compression::Gzip => {
+                let level = flate2::Compression::new(opts.level as u32);
+                StreamEncoder::Gzip(flate2::write::GzEncoder::new(inner, level))
+            }
+            Compression::Zstd => StreamEncoder::Zstd(zstd::stream::write::Encoder::new(inner, opts.level)?),
+        })
+    }
+
+    /// Writes the compression trailer and hands back the inner writer.
+    pub fn finish(self) -> std::io::Result<W> {
+        match self {
+            StreamEncoder::Plain(w) => Ok(w),
+            StreamEncoder::Gzip(e) => e.finish(),
+            StreamEncoder::Zstd(e) => e.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for StreamEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            StreamEncoder::Plain(w) => w.write(buf),
+            StreamEncoder::Gzip(e) => e.write(buf),
+            StreamEncoder::Zstd(e) => e.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            StreamEncoder::Plain(w) => w.flush(),
+            StreamEncoder::Gzip(e) => e.flush(),
+            StreamEncoder::Zstd(e) => e.flush(),
+        }
+    }
+}
+
+/// Compresses `src` into `dst` chunk by chunk, returning the SHA-256 of the output.
+pub async fn compress_file(src: PathBuf, dst: PathBuf, opts: CompressionOptions) -> std::io::Result<String> {
+    tokio::task::spawn_blocking(move || {
+        let mut input = std::fs::File::open(&src)?;
+        let output = HashingWriter::new(std::fs::File::create(&dst)?);
+        let mut encoder = StreamEncoder::new(output, opts)?;
+        std::io::copy(&mut input, &mut encoder)?;
+        let mut output = encoder.finish()?;
+        output.flush()?;
+        Ok(output.finish())
+    })
+    .await
+    .map_err(std::io::Error::other)?
+}
+
+/// Wraps a reader so gzip or zstd input is transparently decompressed.
+pub fn decoded<'a, R: BufRead + 'a>(mut reader: R) -> std::io::Result<Box<dyn Read + 'a>> {
+    let kind = Compression::detect(reader.fill_buf()?);
+    Ok(match kind {
+        Compression::None => Box::new(reader),
+        Compression::Gzip => Box::new(flate2::bufread::MultiGzDecoder::new(reader)),
+        Compression::Zstd => Box::new(zstd::stream::read::Decoder::with_buffer(reader)?),
+    })
+}
+
+/// Writes a decompressed copy of `src` to `dst`, for tools that need a plain tar.
+pub async fn decompress_file(src: PathBuf, dst: PathBuf) -> std::io::Result<()> {
+    tokio::task::spawn_blocking(move || {
+        let input = BufReader::new(std::fs::File::open(&src)?);
+        let mut output = std::fs::File::create(&dst)?;
+        std::io::copy(&mut decoded(input)?, &mut output)?;
+        output.sync_all()
+    })
+    .await
+    .map_err(std::io::Error::other)?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn levels_are_checked_per_compression() {
+        let gzip = CompressionOptions::parse(Some("gzip"), None).unwrap();
+        assert_eq!((gzip.kind, gzip.level), (Compression::Gzip, 6));
+        assert_eq!(CompressionOptions::parse(Some("zst"), Some(19)).unwrap().level, 19);
+        assert_eq!(CompressionOptions::parse(Some("gzip"), Some(10)).unwrap_err(), "gzip level 10 out of range (1-9)");
+        assert_eq!(CompressionOptions::parse(Some("gzip"), Some(0)).unwrap_err(), "gzip level 0 out of range (1-9)");
+        assert_eq!(CompressionOptions::parse(Some("zstd"), Some(20)).unwrap_err(), "zstd level 20 out of range (1-19)");
+        // A level means nothing without compression.
+        assert_eq!(CompressionOptions::parse(None, Some(42)).unwrap().level, 0);
+        assert!(CompressionOptions::parse(Some("brotli"), None).is_err());
+    }
+
+    #[test]
+    fn layer_compression_needs_oci_output() {
+        let docker = ExportCompression::parse(ArchiveFormat::DockerArchive, None, None, Some("gzip"));
+        assert!(docker.is_err());
+        let oci = ExportCompression::parse(ArchiveFormat::OciArchive, Some("zstd"), None, Some("gzip")).unwrap();
+        assert_eq!((oci.archive.kind, oci.layers), (Compression::Zstd, Some(Compression::Gzip)));
+    }
+
+    #[test]
+    fn encoded_streams_are_detected_and_decoded() {
+        let data = b"tar bytes ".repeat(1000);
+        for kind in [Compression::None, Compression::Gzip, Compression::Zstd] {
+            let opts = CompressionOptions::parse(Some(kind.as_str()), None).unwrap();
+            let mut encoder = StreamEncoder::new(Vec::new(), opts).unwrap();
+            encoder.write_all(&data).unwrap();
+            let encoded = encoder.finish().unwrap();
+            assert_eq!(Compression::detect(&encoded), kind);
+
+            let mut decoded_bytes = Vec::new();
+            decoded(encoded.as_slice()).unwrap().read_to_end(&mut decoded_bytes).unwrap();
+            assert_eq!(decoded_bytes, data);
+        }
+        assert_eq!(Compression::detect(&GZIP_MAGIC[..1]), Compression::None);
+        assert_eq!(Compression::detect(b""), Compression::None);
+    }
+}
@@ -0,0 +1,946 @@
+//! Service configuration: a TOML file, overridden by environment variables.
+//!
+//! The file is optional; without it the defaults below apply, adjusted by the
+//! same variables the service always read (`PULLER`, `PULL_TIMEOUT_SECS`, …).
+//! A running server reloads it on SIGHUP and when the file changes. Jobs keep
+//! the [`Settings`] snapshot they started with, so a reload never disturbs a
+//! pull in flight: the old puller lives until its last job is done.
+
+use crate::{
+    admission::Admission,
+    auth::{Authenticator, Principal},
+    policy::Policy,
+    puller::{self, Credentials, Puller},
+    reference::ImageReference,
+    retry::RetryPolicy,
+    sync::Syncs,
+    telemetry::redact,
+    timeouts::TimeoutConfig,
+    webhooks::Webhooks,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+/// How often the config file is checked for changes.
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub puller: PullerConfig,
+    pub retry: RetryConfig,
+    pub timeouts: TimeoutsConfig,
+    pub artifacts: ArtifactsConfig,
+    pub signing: SigningConfig,
+    pub audit: AuditConfig,
+    pub database: DatabaseConfig,
+    pub cluster: ClusterConfig,
+    pub jobs: JobsConfig,
+    pub webhooks: WebhooksConfig,
+    pub auth: AuthConfig,
+    pub references: ReferencesConfig,
+    pub policies: Vec<PolicyConfig>,
+    pub registries: Vec<RegistryConfig>,
+    pub syncs: Vec<SyncConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Only read at startup.
+    pub port: u16,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self { port: 8080 }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PullerConfig {
+    /// `skopeo` or `native`.
+    pub kind: String,
+    pub skopeo_path: String,
+    /// Platform the native client picks out of manifest lists.
+    pub platform: String,
+}
+
+impl Default for PullerConfig {
+    fn default() -> Self {
+        Self { kind: "skopeo".to_string(), skopeo_path: "skopeo".to_string(), platform: "linux/amd64".to_string() }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryConfig {
+    pub retries: u32,
+    pub base_ms: u64,
+    pub max_ms: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self { retries: 3, base_ms: 1000, max_ms: 30_000 }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeoutsConfig {
+    pub job_secs: u64,
+    pub max_job_secs: u64,
+    pub idle_secs: u64,
+    pub max_idle_secs: u64,
+    pub readiness_secs: u64,
+}
+
+impl Default for TimeoutsConfig {
+    fn default() -> Self {
+        Self { job_secs: 300, max_job_secs: 3600, idle_secs: 120, max_idle_secs: 900, readiness_secs: 5 }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ArtifactsConfig {
+    /// How long a finished artifact stays available for download.
+    pub ttl_secs: u64,
+    /// Longest an upload or a rebuild may ask to be kept, as a delta baseline.
+    pub max_ttl_secs: u64,
+    /// Largest archive `POST /api/artifacts` accepts.
+    pub max_upload_bytes: u64,
+}
+
+impl Default for ArtifactsConfig {
+    fn default() -> Self {
+        Self { ttl_secs: 600, max_ttl_secs: 7 * 24 * 3600, max_upload_bytes: 10 << 30 }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SigningConfig {
+    /// Only read at startup.
+    pub key_path: Option<PathBuf>,
+}
+
+/// Only read at startup.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditConfig {
+    /// JSON lines file; no audit trail without one.
+    pub path: Option<PathBuf>,
+    /// Size at which the file is rotated.
+    pub max_bytes: u64,
+    /// Rotated files kept besides the current one.
+    pub keep: u32,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self { path: None, max_bytes: 100 * 1024 * 1024, keep: 10 }
+    }
+}
+
+/// Only read at startup.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    /// SQLite file holding jobs and artifact metadata; kept in memory, and
+    /// lost on restart, without one.
+    pub path: Option<PathBuf>,
+    /// `postgres://` URL of a database shared by all replicas, instead of
+    /// the SQLite file.
+    pub url: Option<String>,
+    /// How long finished jobs and their events are kept.
+    pub job_retention_secs: u64,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self { path: None, url: None, job_retention_secs: 7 * 24 * 3600 }
+    }
+}
+
+/// How this replica is known to the others sharing `database.url`. Only
+/// read at startup.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClusterConfig {
+    /// Unique name of this replica; the host name by default.
+    pub node_id: Option<String>,
+    /// Base URL the other replicas reach this one at, e.g. `http://10.0.0.7:8080`.
+    pub advertise_url: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JobsConfig {
+    /// Streaming pulls and conversions running at once, across all replicas
+    /// sharing the database; 0 for no limit.
+    pub max_running: u32,
+    /// Origins, e.g. `https://ui.example`, whose pages may open the jobs
+    /// WebSocket besides the API's own.
+    pub ws_origins: Vec<String>,
+}
+
+/// Where job outcomes are posted; see [`crate::webhooks`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebhooksConfig {
+    /// Called for every job that ends.
+    pub endpoints: Vec<WebhookConfig>,
+    /// Host globs the `callback` of a job may point to; callbacks are refused
+    /// when empty.
+    pub callback_hosts: Vec<String>,
+    /// Retries of a delivery after the first attempt.
+    pub retries: u32,
+    pub base_ms: u64,
+    pub max_ms: u64,
+    /// For one attempt, response included.
+    pub timeout_secs: u64,
+}
+
+impl Default for WebhooksConfig {
+    fn default() -> Self {
+        Self {
+            endpoints: Vec::new(),
+            callback_hosts: Vec::new(),
+            retries: 6,
+            base_ms: 5000,
+            max_ms: 300_000,
+            timeout_secs: 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebhookConfig {
+    /// Named in the delivery log.
+    pub name: String,
+    pub url: String,
+    /// Key of the HMAC-SHA256 signature of each payload.
+    pub secret: Option<String>,
+    /// Read at every (re)load, e.g. from a mounted Secret.
+    pub secret_file: Option<PathBuf>,
+    /// `succeeded`, `failed`, `cancelled`; all when empty.
+    pub events: Vec<String>,
+}
+
+/// Who may call the API. Leaving both lists empty keeps the API open.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub api_keys: Vec<ApiKeyConfig>,
+    pub oidc: Option<OidcConfig>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ApiKeyConfig {
+    /// Who the key belongs to, as shown in logs.
+    pub name: String,
+    /// Hex SHA-256 of the key, as printed by `--hash-api-key`.
+    pub sha256: String,
+    pub groups: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OidcConfig {
+    /// Expected `iss`; its discovery document gives the JWKS URL.
+    pub issuer: String,
+    /// Expected `aud`.
+    pub audience: String,
+    /// Overrides discovery.
+    pub jwks_url: Option<String>,
+    /// Static key set read at every (re)load, instead of fetching one.
+    pub jwks_file: Option<PathBuf>,
+    /// Claim naming the user; `sub` when absent from a token.
+    pub username_claim: String,
+    pub groups_claim: String,
+}
+
+impl Default for OidcConfig {
+    fn default() -> Self {
+        Self {
+            issuer: String::new(),
+            audience: String::new(),
+            jwks_url: None,
+            jwks_file: None,
+            username_claim: "preferred_username".to_string(),
+            groups_claim: "groups".to_string(),
+        }
+    }
+}
+
+/// Server-wide rules on image references; see [`crate::admission`].
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReferencesConfig {
+    /// Registry hosts images may come from; all when empty.
+    pub allow_registries: Vec<String>,
+    pub deny: Vec<DenyRuleConfig>,
+    /// Only accept references pinned with `@sha256:`.
+    pub require_digest: bool,
+}
+
+/// Refuses references whose repository and tag both match.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DenyRuleConfig {
+    /// Named in the error.
+    pub name: String,
+    /// `registry/repository` globs; any repository when empty.
+    pub repositories: Vec<String>,
+    /// Tag globs, e.g. `latest`; any tag (or none) when empty.
+    pub tags: Vec<String>,
+    pub reason: Option<String>,
+}
+
+/// What the users and groups it names may do; see [`crate::policy`].
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PolicyConfig {
+    pub name: String,
+    /// User names as authenticated (key names, token usernames); `*` for everyone.
+    pub users: Vec<String>,
+    pub groups: Vec<String>,
+    /// Repositories that may be pulled, e.g. `docker.io/library/*`.
+    pub pull: Vec<String>,
+    /// Repositories archives may be uploaded as.
+    pub push: Vec<String>,
+    /// Repositories that may be pulled with the credentials of `[[registries]]`.
+    pub credentials: Vec<String>,
+    /// Helm repositories, as `host/path`.
+    pub charts: Vec<String>,
+    /// May read the audit log.
+    pub audit: bool,
+    /// May look at the sync jobs and start them.
+    pub syncs: bool,
+}
+
+/// Per-registry settings, matched on the registry host of a reference.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RegistryConfig {
+    /// Registry host as written in references, e.g. `docker.io` or `localhost:5000`.
+    pub host: String,
+    /// Spoken to over plain HTTP.
+    pub insecure: bool,
+    /// Tried in order before the registry itself.
+    pub mirrors: Vec<String>,
+    /// Used when a request brings no credentials of its own.
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Read at every (re)load, e.g. from a mounted Secret.
+    pub password_file: Option<PathBuf>,
+}
+
+/// An image set kept fresh on a schedule; see [`crate::sync`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SyncConfig {
+    /// Named in the API and the run history.
+    pub name: String,
+    /// Five-field cron expression, in UTC.
+    pub schedule: String,
+    /// References synced as written.
+    pub images: Vec<String>,
+    /// Repositories whose matching tags are synced.
+    pub repositories: Vec<SyncRepositoryConfig>,
+    /// `registry/path` the images are copied under; exported as artifacts
+    /// when unset.
+    pub destination: Option<String>,
+    /// Of the artifacts.
+    pub format: String,
+    pub compression: Option<String>,
+    /// How long the artifacts are kept; `artifacts.ttl_secs` when unset.
+    pub artifact_ttl_secs: Option<u64>,
+}
+
+impl Default for SyncConfig {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            schedule: String::new(),
+            images: Vec::new(),
+            repositories: Vec::new(),
+            destination: None,
+            format: "docker-archive".to_string(),
+            compression: None,
+            artifact_ttl_secs: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SyncRepositoryConfig {
+    /// `registry/path`, without tag.
+    pub repository: String,
+    /// Tag globs; every tag when empty.
+    pub tags: Vec<String>,
+}
+
+impl Config {
+    /// Reads `path` (if any), then applies environment overrides.
+    pub fn load(path: Option<&Path>) -> anyhow::Result<Self> {
+        let mut config = match path {
+            Some(path) => {
+                let text = std::fs::read_to_string(path)
+                    .map_err(|e| anyhow::anyhow!("cannot read {}: {}", path.display(), e))?;
+                toml::from_str(&text).map_err(|e| anyhow::anyhow!("invalid {}: {}", path.display(), e))?
+            }
+            None => Config::default(),
+        };
+        config.apply_env()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn apply_env(&mut self) -> anyhow::Result<()> {
+        fn var(name: &str) -> Option<String> {
+            std::env::var(name).ok().map(|v| v.trim().to_string()).filter(|v| !v.is_empty())
+        }
+        fn num<T: std::str::FromStr>(name: &str, field: &mut T) -> anyhow::Result<()>
+        where
+            T::Err: std::fmt::Display,
+        {
+            if let Some(v) = var(name) {
+                *field = v.parse().map_err(|e| anyhow::anyhow!("{name}: {e}"))?;
+            }
+            Ok(())
+        }
+
+        num("PORT", &mut self.server.port)?;
+        if let Some(v) = var("PULLER") {
+            self.puller.kind = v;
+        }
+        if let Some(v) = var("SKOPEO_PATH") {
+            self.puller.skopeo_path = v;
+        }
+        if let Some(v) = var("PULL_PLATFORM") {
+            self.puller.platform = v;
+        }
+        if let Some(v) = var("AUDIT_LOG") {
+            self.audit.path = Some(PathBuf::from(v));
+        }
+        if let Some(v) = var("DATABASE_PATH") {
+            self.database.path = Some(PathBuf::from(v));
+        }
+        if let Some(v) = var("DATABASE_URL") {
+            self.database.url = Some(v);
+        }
+        if let Some(v) = var("NODE_ID") {
+            self.cluster.node_id = Some(v);
+        }
+        if let Some(v) = var("ADVERTISE_URL") {
+            self.cluster.advertise_url = Some(v);
+        }
+        if let Some(v) = var("SIGNING_KEY_PATH") {
+            self.signing.key_path = Some(PathBuf::from(v));
+        }
+        if let Some(v) = var("WS_ORIGINS") {
+            self.jobs.ws_origins = v.split(',').map(str::trim).filter(|o| !o.is_empty()).map(str::to_string).collect();
+        }
+        for host in var("INSECURE_REGISTRIES").unwrap_or_default().split(',').map(str::trim) {
+            if !host.is_empty() {
+                self.registry_mut(host).insecure = true;
+            }
+        }
+        num("PULL_RETRIES", &mut self.retry.retries)?;
+        num("PULL_RETRY_BASE_MS", &mut self.retry.base_ms)?;
+        num("PULL_RETRY_MAX_MS", &mut self.retry.max_ms)?;
+        num("PULL_TIMEOUT_SECS", &mut self.timeouts.job_secs)?;
+        num("PULL_TIMEOUT_MAX_SECS", &mut self.timeouts.max_job_secs)?;
+        num("PULL_IDLE_TIMEOUT_SECS", &mut self.timeouts.idle_secs)?;
+        num("PULL_IDLE_TIMEOUT_MAX_SECS", &mut self.timeouts.max_idle_secs)?;
+        num("READINESS_TIMEOUT_SECS", &mut self.timeouts.readiness_secs)?;
+        num("ARTIFACT_TTL_SECS", &mut self.artifacts.ttl_secs)?;
+        num("ARTIFACT_TTL_MAX_SECS", &mut self.artifacts.max_ttl_secs)?;
+        num("MAX_UPLOAD_BYTES", &mut self.artifacts.max_upload_bytes)?;
+        num("JOB_RETENTION_SECS", &mut self.database.job_retention_secs)?;
+        num("MAX_RUNNING_JOBS", &mut self.jobs.max_running)?;
+        num("WEBHOOK_RETRIES", &mut self.webhooks.retries)?;
+        num("WEBHOOK_TIMEOUT_SECS", &mut self.webhooks.timeout_secs)?;
+        if let Some(v) = var("OIDC_ISSUER") {
+            self.auth.oidc.get_or_insert_with(Default::default).issuer = v;
+        }
+        if let Some(oidc) = &mut self.auth.oidc {
+            if let Some(v) = var("OIDC_AUDIENCE") {
+                oidc.audience = v;
+            }
+            if let Some(v) = var("OIDC_JWKS_URL") {
+                oidc.jwks_url = Some(v);
+            }
+            if let Some(v) = var("OIDC_JWKS_FILE") {
+                oidc.jwks_file = Some(PathBuf::from(v));
+            }
+        }
+        Ok(())
+    }
+
+    fn registry_mut(&mut self, host: &str) -> &mut RegistryConfig {
+        if let Some(i) = self.registries.iter().position(|r| r.host == host) {
+            return &mut self.registries[i];
+        }
+        self.registries.push(RegistryConfig { host: host.to_string(), ..Default::default() });
+        self.registries.last_mut().expect("just pushed")
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if !matches!(self.puller.kind.as_str(), "skopeo" | "native") {
+            anyhow::bail!("puller.kind must be skopeo or native, not '{}'", self.puller.kind);
+        }
+        if self.audit.max_bytes == 0 {
+            anyhow::bail!("audit.max_bytes must be positive");
+        }
+        if self.database.job_retention_secs == 0 {
+            anyhow::bail!("database.job_retention_secs must be positive");
+        }
+        if let Some(url) = &self.database.url {
+            if self.database.path.is_some() {
+                anyhow::bail!("database: set either path (SQLite) or url (Postgres), not both");
+            }
+            if !url.starts_with("postgres://") && !url.starts_with("postgresql://") {
+                anyhow::bail!("database.url must be a postgres:// URL");
+            }
+            match self.cluster.advertise_url.as_deref().map(url::Url::parse) {
+                Some(Ok(u)) if matches!(u.scheme(), "http" | "https") => {}
+                Some(_) => anyhow::bail!("cluster.advertise_url must be an http(s) URL"),
+                None => anyhow::bail!("database.url needs cluster.advertise_url, where the other replicas reach this one"),
+            }
+        }
+        if self.artifacts.ttl_secs == 0 {
+            anyhow::bail!("artifacts.ttl_secs must be positive");
+        }
+        if self.artifacts.ttl_secs > self.artifacts.max_ttl_secs {
+            anyhow::bail!("artifacts.ttl_secs exceeds artifacts.max_ttl_secs");
+        }
+        if self.artifacts.max_upload_bytes == 0 {
+            anyhow::bail!("artifacts.max_upload_bytes must be positive");
+        }
+        self.timeouts().validate()?;
+        self.retry_policy().validate()?;
+        self.validate_webhooks()?;
+        self.validate_syncs()?;
+        for origin in &self.jobs.ws_origins {
+            match url::Url::parse(origin) {
+                Ok(u) if matches!(u.scheme(), "http" | "https") && u.path() == "/" && u.query().is_none() => {}
+                _ => anyhow::bail!("jobs.ws_origins: '{origin}' is not an origin (scheme://host[:port])"),
+            }
+        }
+        let mut names = std::collections::HashSet::new();
+        for key in &self.auth.api_keys {
+            if key.name.is_empty() || !names.insert(&key.name) {
+                anyhow::bail!("auth.api_keys: names must be set and unique ('{}')", key.name);
+            }
+            if key.sha256.len() != 64 || !key.sha256.bytes().all(|b| b.is_ascii_hexdigit()) {
+                anyhow::bail!("auth.api_keys: '{}' needs a hex SHA-256 (see --hash-api-key)", key.name);
+            }
+        }
+        if let Some(oidc) = &self.auth.oidc {
+            if oidc.issuer.is_empty() || oidc.audience.is_empty() {
+                anyhow::bail!("auth.oidc needs both issuer and audience");
+            }
+            if oidc.jwks_url.is_some() && oidc.jwks_file.is_some() {
+                anyhow::bail!("auth.oidc sets both jwks_url and jwks_file");
+            }
+        }
+        let mut names = std::collections::HashSet::new();
+        for rule in &self.references.deny {
+            if rule.name.is_empty() || !names.insert(&rule.name) {
+                anyhow::bail!("references.deny: names must be set and unique ('{}')", rule.name);
+            }
+            if rule.repositories.is_empty() && rule.tags.is_empty() {
+                anyhow::bail!("references.deny: '{}' would deny everything; give repositories or tags", rule.name);
+            }
+        }
+        let deny_patterns = self.references.deny.iter().flat_map(|r| r.repositories.iter().chain(&r.tags));
+        if self.references.allow_registries.iter().chain(deny_patterns).any(|g| g.trim().is_empty()) {
+            anyhow::bail!("references: empty pattern");
+        }
+        let mut names = std::collections::HashSet::new();
+        for p in &self.policies {
+            if p.name.is_empty() || !names.insert(&p.name) {
+                anyhow::bail!("policies: names must be set and unique ('{}')", p.name);
+            }
+            if p.users.is_empty() && p.groups.is_empty() {
+                anyhow::bail!("policies: '{}' names no users or groups", p.name);
+            }
+            let patterns = [&p.pull, &p.push, &p.credentials, &p.charts];
+            if patterns.iter().any(|globs| globs.iter().any(|g| g.trim().is_empty())) {
+                anyhow::bail!("policies: '{}' has an empty pattern", p.name);
+            }
+        }
+        let mut seen = std::collections::HashSet::new();
+        for r in &self.registries {
+            if r.host.is_empty() || r.host.contains('/') {
+                anyhow::bail!("registries: invalid host '{}'", r.host);
+            }
+            if !seen.insert(&r.host) {
+                anyhow::bail!("registries: '{}' is listed twice", r.host);
+            }
+            if r.mirrors.iter().any(|m| m.is_empty() || m.contains('/')) {
+                anyhow::bail!("registries: invalid mirror for '{}'", r.host);
+            }
+            if r.password.is_some() && r.password_file.is_some() {
+                anyhow::bail!("registries: '{}' sets both password and password_file", r.host);
+            }
+            if r.username.is_some() != (r.password.is_some() || r.password_file.is_some()) {
+                anyhow::bail!("registries: '{}' needs both a username and a password", r.host);
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_webhooks(&self) -> anyhow::Result<()> {
+        let w = &self.webhooks;
+        if w.timeout_secs == 0 {
+            anyhow::bail!("webhooks.timeout_secs must be positive");
+        }
+        if w.base_ms > w.max_ms {
+            anyhow::bail!("webhooks.base_ms must not exceed webhooks.max_ms");
+        }
+        if w.callback_hosts.iter().any(|g| g.trim().is_empty()) {
+            anyhow::bail!("webhooks.callback_hosts: empty pattern");
+        }
+        let mut names = std::collections::HashSet::new();
+        for e in &w.endpoints {
+            if e.name.is_empty() || !names.insert(&e.name) {
+                anyhow::bail!("webhooks.endpoints: names must be set and unique ('{}')", e.name);
+            }
+            match url::Url::parse(&e.url) {
+                Ok(u) if matches!(u.scheme(), "http" | "https") => {}
+                _ => anyhow::bail!("webhooks.endpoints: '{}' needs an http(s) url", e.name),
+            }
+            if e.secret.is_some() == e.secret_file.is_some() {
+                anyhow::bail!("webhooks.endpoints: '{}' needs either secret or secret_file", e.name);
+            }
+            let known = |ev: &&String| matches!(ev.as_str(), "succeeded" | "failed" | "cancelled");
+            if let Some(event) = e.events.iter().find(|ev| !known(ev)) {
+                anyhow::bail!("webhooks.endpoints: '{}' has unknown event '{}'", e.name, event);
+            }
+        }
+        Ok(())
+    }
+
+    /// The schedules, references and globs are checked as they are compiled
+    /// ([`Syncs::build`]).
+    fn validate_syncs(&self) -> anyhow::Result<()> {
+        let mut names = std::collections::HashSet::new();
+        for s in &self.syncs {
+            let valid = |c: char| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.');
+            if s.name.is_empty() || !s.name.chars().all(valid) || !names.insert(&s.name) {
+                anyhow::bail!(
+                    "syncs: names must be set, unique and made of letters, digits, '-', '_' or '.' ('{}')",
+                    s.name
+                );
+            }
+            if s.images.is_empty() && s.repositories.is_empty() {
+                anyhow::bail!("syncs: '{}' names no images or repositories", s.name);
+            }
+            if s.artifact_ttl_secs == Some(0) {
+                anyhow::bail!("syncs: '{}' artifact_ttl_secs must be positive", s.name);
+            }
+            if s.destination.is_some() && (s.compression.is_some() || s.artifact_ttl_secs.is_some()) {
+                anyhow::bail!(
+                    "syncs: '{}' copies to a registry: compression and artifact_ttl_secs do not apply",
+                    s.name
+                );
+            }
+        }
+        Ok(())
+    }
+
+    pub fn timeouts(&self) -> TimeoutConfig {
+        let t = &self.timeouts;
+        TimeoutConfig {
+            job: Duration::from_secs(t.job_secs),
+            idle: Duration::from_secs(t.idle_secs),
+            readiness: Duration::from_secs(t.readiness_secs),
+            max_job: Duration::from_secs(t.max_job_secs),
+            max_idle: Duration::from_secs(t.max_idle_secs),
+        }
+    }
+
+    pub fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy::new(
+            self.retry.retries,
+            Duration::from_millis(self.retry.base_ms),
+            Duration::from_millis(self.retry.max_ms),
+        )
+    }
+
+    pub fn registry(&self, host: &str) -> Option<&RegistryConfig> {
+        self.registries.iter().find(|r| r.host == host)
+    }
+}
+
+/// What a job needs from the configuration, built once per (re)load.
+pub struct Settings {
+    pub config: Config,
+    pub puller: Arc<dyn Puller>,
+    pub timeouts: TimeoutConfig,
+    pub artifact_ttl: Duration,
+    pub auth: Authenticator,
+    pub admission: Admission,
+    pub policy: Policy,
+    pub webhooks: Webhooks,
+    pub syncs: Syncs,
+    /// Default credentials per registry host, passwords already read.
+    credentials: Vec<(String, Credentials)>,
+}
+
+impl Settings {
+    pub fn build(config: Config, client: &reqwest::Client) -> anyhow::Result<Self> {
+        let mut credentials = Vec::new();
+        for r in &config.registries {
+            let Some(username) = &r.username else { continue };
+            let password = match (&r.password, &r.password_file) {
+                (Some(p), _) => p.clone(),
+                (None, Some(file)) => std::fs::read_to_string(file)
+                    .map_err(|e| anyhow::anyhow!("registries: cannot read {}: {}", file.display(), e))?
+                    .trim()
+                    .to_string(),
+                (None, None) => continue,
+            };
+            if let Some(creds) = Credentials::from_parts(Some(username), Some(&password)) {
+                credentials.push((r.host.clone(), creds));
+            }
+        }
+        Ok(Self {
+            puller: puller::build(&config, client)?,
+            timeouts: config.timeouts(),
+            artifact_ttl: Duration::from_secs(config.artifacts.ttl_secs),
+            auth: Authenticator::build(&config.auth, client)?,
+            admission: Admission::build(&config.references)?,
+            policy: Policy::build(&config.policies)?,
+            webhooks: Webhooks::build(&config.webhooks)?,
+            syncs: Syncs::build(&config.syncs)?,
+            credentials,
+            config,
+        })
+    }
+
+    /// How long to keep an artifact whose request asked for `secs`, clamped
+    /// to `artifacts.max_ttl_secs`.
+    pub fn artifact_ttl_for(&self, secs: Option<u64>) -> Duration {
+        secs.map_or(self.artifact_ttl, |s| Duration::from_secs(s.clamp(1, self.config.artifacts.max_ttl_secs)))
+    }
+
+    /// Credentials configured for the registry of `reference`, if any and
+    /// if `who` may use them.
+    pub fn credentials_for(&self, who: &Principal, reference: &str) -> Option<Credentials> {
+        let image = ImageReference::parse(reference).ok()?;
+        if !self.policy.allows_credentials(who, reference) {
+            tracing::debug!(user = %who.name, "policy: not using the stored credentials for {}", reference);
+            return None;
+        }
+        self.registry_credentials(&image)
+    }
+
+    /// Credentials configured for the registry of `image`, whoever asks.
+    pub fn registry_credentials(&self, image: &ImageReference) -> Option<Credentials> {
+        self.credentials.iter().find(|(host, _)| *host == image.registry).map(|(_, c)| c.clone())
+    }
+}
+
+/// The current [`Settings`], swapped as a whole on reload.
+#[derive(Clone)]
+pub struct LiveConfig {
+    current: Arc<RwLock<Arc<Settings>>>,
+    path: Option<PathBuf>,
+    client: reqwest::Client,
+}
+
+impl LiveConfig {
+    pub fn new(settings: Settings, path: Option<PathBuf>, client: reqwest::Client) -> Self {
+        Self { current: Arc::new(RwLock::new(Arc::new(settings))), path, client }
+    }
+
+    /// Snapshot to use for the whole of one job.
+    pub fn current(&self) -> Arc<Settings> {
+        self.current.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Loads the file again. A broken file is reported and ignored: the
+    /// previous settings stay in force.
+    pub fn reload(&self) -> anyhow::Result<()> {
+        let config = Config::load(self.path.as_deref())?;
+        let old = self.current();
+        let restart_only = (&config.server, &config.signing, &config.audit, &config.database, &config.cluster);
+        let running =
+            (&old.config.server, &old.config.signing, &old.config.audit, &old.config.database, &old.config.cluster);
+        if restart_only != running {
+            tracing::warn!(
+                "config: server, signing, audit, database and cluster settings only take effect after a restart"
+            );
+        }
+        let settings = Settings::build(config, &self.client)?;
+        tracing::info!("config: reloaded (puller {})", settings.puller.name());
+        *self.current.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(settings);
+        Ok(())
+    }
+
+    /// Reloads on SIGHUP and whenever the file content changes.
+    pub fn watch(self) {
+        tokio::spawn(async move {
+            #[cfg(unix)]
+            let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()).ok();
+            let mut ticks = tokio::time::interval(WATCH_INTERVAL);
+            let mut seen = self.file_content().await;
+            loop {
+                #[cfg(unix)]
+                let hup = async {
+                    match hangup.as_mut() {
+                        Some(s) => s.recv().await,
+                        None => std::future::pending().await,
+                    }
+                };
+                #[cfg(not(unix))]
+                let hup = std::future::pending::<Option<()>>();
+
+                tokio::select! {
+                    _ = hup => tracing::info!("config: SIGHUP received"),
+                    _ = ticks.tick() => {
+                        let content = self.file_content().await;
+                        if content == seen {
+                            continue;
+                        }
+                        seen = content;
+                    }
+                }
+                if let Err(e) = self.reload() {
+                    tracing::error!("config: reload failed, keeping previous settings: {}", redact(&e.to_string()));
+                }
+            }
+        });
+    }
+
+    async fn file_content(&self) -> Option<Vec<u8>> {
+        match &self.path {
+            Some(path) => tokio::fs::read(path).await.ok(),
+            None => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Tests reading the environment, which is shared by the whole process.
+    static ENV: Mutex<()> = Mutex::new(());
+
+    fn parse(toml: &str) -> Config {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn invalid_configurations_are_refused() {
+        let cases = [
+            ("[puller]\nkind = \"docker\"", "puller.kind"),
+            ("[audit]\nmax_bytes = 0", "audit.max_bytes"),
+            ("[database]\njob_retention_secs = 0", "job_retention_secs"),
+            ("[database]\npath = \"a.db\"\nurl = \"postgres://db\"", "either path"),
+            ("[database]\nurl = \"mysql://db\"", "postgres:// URL"),
+            ("[database]\nurl = \"postgres://db\"\n[cluster]\nadvertise_url = \"ftp://a\"", "http(s) URL"),
+            ("[database]\nurl = \"postgres://db\"", "needs cluster.advertise_url"),
+            ("[artifacts]\nttl_secs = 0", "ttl_secs must be positive"),
+            ("[artifacts]\nttl_secs = 20\nmax_ttl_secs = 10", "exceeds artifacts.max_ttl_secs"),
+            ("[artifacts]\nmax_upload_bytes = 0", "max_upload_bytes"),
+            ("[jobs]\nws_origins = [\"https://ui.example/app\"]", "not an origin"),
+            ("[[auth.api_keys]]\nname = \"\"\nsha256 = \"\"", "names must be set"),
+            ("[[auth.api_keys]]\nname = \"ci\"\nsha256 = \"abc\"", "hex SHA-256"),
+            ("[auth.oidc]\nissuer = \"https://idp\"", "issuer and audience"),
+            (
+                "[auth.oidc]\nissuer = \"https://idp\"\naudience = \"a\"\njwks_url = \"https://k\"\njwks_file = \"k\"",
+                "both jwks_url and jwks_file",
+            ),
+            ("[[references.deny]]\nname = \"\"\ntags = [\"latest\"]", "references.deny: names"),
+            ("[[references.deny]]\nname = \"all\"", "would deny everything"),
+            ("[references]\nallow_registries = [\" \"]", "references: empty pattern"),
+            ("[[policies]]\nname = \"\"\nusers = [\"*\"]", "policies: names"),
+            ("[[policies]]\nname = \"p\"", "names no users or groups"),
+            ("[[policies]]\nname = \"p\"\nusers = [\"*\"]\npull = [\"\"]", "has an empty pattern"),
+            ("[[registries]]\nhost = \"a/b\"", "invalid host"),
+            ("[[registries]]\nhost = \"a\"\n[[registries]]\nhost = \"a\"", "listed twice"),
+            ("[[registries]]\nhost = \"a\"\nmirrors = [\"\"]", "invalid mirror"),
+            (
+                "[[registries]]\nhost = \"a\"\nusername = \"u\"\npassword = \"p\"\npassword_file = \"f\"",
+                "both password and password_file",
+            ),
+            ("[[registries]]\nhost = \"a\"\nusername = \"u\"", "both a username and a password"),
+            ("[webhooks]\ntimeout_secs = 0", "webhooks.timeout_secs"),
+            ("[webhooks]\nbase_ms = 10\nmax_ms = 5", "webhooks.base_ms"),
+            ("[webhooks]\ncallback_hosts = [\"\"]", "callback_hosts: empty pattern"),
+            ("[[webhooks.endpoints]]\nname = \"\"\nurl = \"https://h\"\nsecret = \"s\"", "endpoints: names"),
+            ("[[webhooks.endpoints]]\nname = \"ci\"\nurl = \"ftp://h\"\nsecret = \"s\"", "http(s) url"),
+            ("[[webhooks.endpoints]]\nname = \"ci\"\nurl = \"https://h\"", "either secret or secret_file"),
+            (
+                "[[webhooks.endpoints]]\nname = \"ci\"\nurl = \"https://h\"\nsecret = \"s\"\nevents = [\"started\"]",
+                "unknown event",
+            ),
+            ("[[syncs]]\nname = \"a b\"\nimages = [\"nginx\"]", "syncs: names"),
+            ("[[syncs]]\nname = \"a\"", "names no images or repositories"),
+            ("[[syncs]]\nname = \"a\"\nimages = [\"nginx\"]\nartifact_ttl_secs = 0", "artifact_ttl_secs must be"),
+            (
+                "[[syncs]]\nname = \"a\"\nimages = [\"nginx\"]\ndestination = \"r.example/m\"\ncompression = \"gzip\"",
+                "copies to a registry",
+            ),
+        ];
+        for (toml, expected) in cases {
+            let err = parse(toml).validate().expect_err(toml).to_string();
+            assert!(err.contains(expected), "{toml}: {err}");
+        }
+        parse("").validate().unwrap();
+    }
+
+    #[test]
+    fn environment_variables_override_the_file() {
+        let _env = ENV.lock().unwrap_or_else(|e| e.into_inner());
+        let mut config = parse("[jobs]\nmax_running = 2\n[[registries]]\nhost = \"a.example\"");
+        std::env::set_var("MAX_RUNNING_JOBS", "5");
+        std::env::set_var("INSECURE_REGISTRIES", "a.example, b.example");
+        let applied = config.apply_env();
+        std::env::remove_var("MAX_RUNNING_JOBS");
+        std::env::remove_var("INSECURE_REGISTRIES");
+        applied.unwrap();
+        assert_eq!(config.jobs.max_running, 5);
+        let insecure: Vec<_> = config.registries.iter().filter(|r| r.insecure).map(|r| r.host.as_str()).collect();
+        assert_eq!(insecure, ["a.example", "b.example"]);
+    }
+
+    #[test]
+    fn a_broken_file_keeps_the_running_settings() {
+        let _env = ENV.lock().unwrap_or_else(|e| e.into_inner());
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(&path, "[puller]\nkind = \"native\"\n[jobs]\nmax_running = 2\n").unwrap();
+        let client = reqwest::Client::new();
+        let settings = Settings::build(Config::load(Some(&path)).unwrap(), &client).unwrap();
+        let live = LiveConfig::new(settings, Some(path.clone()), client);
+
+        std::fs::write(&path, "[puller]\nkind = \"native\"\n[jobs]\nmax_running = \"many\"\n").unwrap();
+        assert!(live.reload().is_err());
+        std::fs::write(&path, "[puller]\nkind = \"docker\"\n").unwrap();
+        assert!(live.reload().is_err());
+        assert_eq!(live.current().config.jobs.max_running, 2);
+
+        std::fs::write(&path, "[puller]\nkind = \"native\"\n[jobs]\nmax_running = 3\n").unwrap();
+        live.reload().unwrap();
+        assert_eq!(live.current().config.jobs.max_running, 3);
+    }
+}
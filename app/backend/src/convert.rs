@@ -0,0 +1,178 @@
+use crate::{
+    archive::ArchiveFormat,
+    artifacts::{artifact_path, finalize, visible_artifact, Artifact},
+    audit::Caller,
+    compression::{decompress_file, Compression, CompressionOptions},
+    error::{send_error, ApiError},
+    jobs::{self, JobKind, NewJob},
+    make_filename,
+    remove_staging,
+    signing::manifest_time,
+    skopeo::{self, run_with_progress, send_event, EventSender},
+    timeouts::{disk_size, supervise, Activity},
+    webhooks::Callback,
+    AppState,
+};
+use axum::{
+    extract::{Json, State},
+    response::{sse::Sse, IntoResponse, Response},
+};
+use serde::Deserialize;
+use std::time::Instant;
+use tokio::{fs, process::Command};
+use tracing::Instrument;
+use uuid::Uuid;
+
+#[derive(Deserialize)]
+pub struct ConvertRequestBody {
+    id: String,
+    format: String,
+    /// Where to post the outcome of the job.
+    #[serde(default)]
+    callback: Option<Callback>,
+    /// Seconds for the whole conversion, clamped to the admin maximum.
+    #[serde(default)]
+    timeout: Option<u64>,
+    /// Seconds without progress before the conversion is abandoned.
+    #[serde(default)]
+    idle_timeout: Option<u64>,
+}
+
+// Re-encode an existing artifact into another archive format (SSE progress)
+pub async fn convert_stream(
+    State(state): State<AppState>,
+    caller: Caller,
+    Json(body): Json<ConvertRequestBody>,
+) -> Response {
+    let source = visible_artifact(&state, &caller, &body.id).await.map_err(|e| match e {
+        ApiError::NotFound(_) => ApiError::NotFound(format!("artifact not found: {}", body.id)),
+        e => e,
+    });
+    let job = NewJob {
+        id: Uuid::new_v4().to_string(),
+        kind: JobKind::Convert,
+        reference: source.as_ref().map(|s| s.reference.clone()).unwrap_or_default(),
+        format: body.format.clone(),
+        owner: caller.principal.name.clone(),
+    };
+    let uid = job.id.clone();
+    let max_running = state.config.current().config.jobs.max_running;
+    let handle = match state.jobs.start(job, max_running, body.callback.clone()).await {
+        Ok(handle) => handle,
+        Err(e) => return Sse::new(jobs::refused(&e)).into_response(),
+    };
+    let tx = handle.sender();
+    let job_id = handle.id().to_string();
+
+    let rx = handle.spawn(async move {
+        send_event(&tx, "start", "starting").await;
+        let converted = match source {
+            Ok(source) => run_conversion(&state, source, &body, uid, &caller, &tx).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = converted {
+            send_error(&tx, &e).await;
+            return;
+        }
+        send_event(&tx, "end", "done").await;
+    }.instrument(tracing::Span::current()));
+
+    jobs::stream(&job_id, rx)
+}
+
+async fn run_conversion(
+    state: &AppState,
+    source: Artifact,
+    body: &ConvertRequestBody,
+    uid: String,
+    caller: &Caller,
+    tx: &EventSender,
+) -> Result<(), ApiError> {
+    let target: ArchiveFormat = body.format.parse().map_err(ApiError::InvalidRequest)?;
+    let settings = state.config.current();
+
+    let out = artifact_path(&uid);
+    let staging = target.staging_path(&out);
+    let scratch = std::env::temp_dir().join(format!("convert-{}.tar", uid));
+
+    // skopeo only reads plain tars: undo any archive compression first.
+    let plain = if source.compression == Compression::None {
+        source.path.clone()
+    } else {
+        if let Err(e) = decompress_file(source.path.clone(), scratch.clone()).await {
+            let _ = fs::remove_file(&scratch).await;
+            return Err(ApiError::io("failed to decompress source artifact", e));
+        }
+        scratch.clone()
+    };
+    let unsealed = source.format.unseal(&plain, &scratch).await;
+    if plain == scratch && source.format.is_directory() {
+        let _ = fs::remove_file(&scratch).await;
+    }
+    let src = unsealed.map_err(|e| ApiError::io("failed to unpack source artifact", e))?;
+
+    // The native puller only reads registries: conversions always run skopeo,
+    // from puller.skopeo_path, whichever puller is configured.
+    let mut cmd = Command::new(&settings.config.puller.skopeo_path);
+    cmd.arg("copy")
+        .arg(source.format.source(&src))
+        .arg(target.destination(&staging, &source.repo, &source.tag));
+
+    let span = skopeo::span("convert", &source.reference, target.as_str());
+    let started = Instant::now();
+    let limits = settings.timeouts.for_request(body.timeout, body.idle_timeout);
+    let activity = Activity::default();
+    let copy = run_with_progress(cmd, Some(tx), &activity).instrument(span.clone());
+    let copied = match supervise(copy, limits, &activity, &staging).await {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(e)) => Err(ApiError::pull(&source.reference, e)),
+        Err(expired) => Err(ApiError::Timeout(expired.describe(&source.reference))),
+    };
+    skopeo::finish(&span, started, disk_size(staging.clone()).await, copied.is_ok());
+    if src != source.path {
+        remove_staging(&src).await;
+    }
+    if let Err(e) = copied {
+        remove_staging(&staging).await;
+        return Err(e);
+    }
+
+    let finalized = finalize(target, &staging, &out, CompressionOptions::default()).await?;
+
+    let filename = make_filename(&source.repo, &source.tag, target.as_str(), Compression::None);
+    let size = fs::metadata(&out).await.map(|m| m.len()).unwrap_or_default();
+    let created = manifest_time();
+    let stored = state
+        .artifacts
+        .insert(Artifact {
+            id: uid.clone(),
+            path: out.clone(),
+            filename: filename.clone(),
+            format: target,
+            compression: Compression::None,
+            repo: source.repo,
+            tag: source.tag,
+            reference: source.reference,
+            digest: finalized.report.image_digest(),
+            sha256: finalized.sha256.clone(),
+            size,
+            owner: caller.principal.name.clone(),
+            created,
+            node: state.node.id.clone(),
+            expires: created + settings.artifact_ttl,
+        })
+        .await;
+    if let Err(e) = stored {
+        let _ = fs::remove_file(&out).await;
+        return Err(e);
+    }
+
+    let ready_payload = serde_json::json!({
+        "id": uid,
+        "filename": filename,
+        "sha256": finalized.sha256,
+    })
+    .to_string();
+    send_event(tx, "ready", ready_payload).await;
+    Ok(())
+}
@@ -0,0 +1,196 @@
+//! Five-field cron expressions, `minute hour day-of-month month day-of-week`,
+//! evaluated in UTC.
+//!
+//! A field takes `*`, a number, a range `a-b`, a step `*/n`, `a-b/n` or
+//! `a/n`, or a comma-separated list of those. Months and weekdays also take
+//! their three-letter English names, and Sunday is 0 or 7. As in Vixie cron,
+//! when both day fields are restricted a day matching either one matches.
+//! `@hourly`, `@daily`, `@weekly`, `@monthly` and `@yearly` are shorthands.
+
+use chrono::{DateTime, Datelike, NaiveDate, Timelike, Utc};
+
+const MONTHS: [&str; 12] = ["jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec"];
+const WEEKDAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+/// Days searched for the next run: 29 February comes back within 8 years.
+const SEARCH_DAYS: u32 = 8 * 366;
+
+/// A parsed expression; each field is a bit set of the values it allows.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schedule {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Whether the day fields were given as other than `*`.
+    days_restricted: bool,
+    weekdays_restricted: bool,
+}
+
+impl Schedule {
+    pub fn parse(expr: &str) -> Result<Self, String> {
+        let expr = match expr.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            other => other,
+        };
+        let fields: Vec<&str> = expr.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields.as_slice() else {
+            return Err(format!("'{expr}' needs 5 fields (minute hour day-of-month month day-of-week)"));
+        };
+        let mut weekdays = field("day-of-week", weekday, 0, 7, &WEEKDAYS)?;
+        // Sunday is both 0 and 7.
+        if weekdays & (1 << 7) != 0 {
+            weekdays |= 1;
+        }
+        Ok(Self {
+            minutes: field("minute", minute, 0, 59, &[])?,
+            hours: field("hour", hour, 0, 23, &[])?,
+            days: field("day-of-month", day, 1, 31, &[])?,
+            months: field("month", month, 1, 12, &MONTHS)?,
+            weekdays,
+            days_restricted: !day.starts_with('*'),
+            weekdays_restricted: !weekday.starts_with('*'),
+        })
+    }
+
+    /// Whether the minute of `time` is one the schedule runs at.
+    pub fn matches(&self, time: DateTime<Utc>) -> bool {
+        self.day_matches(time.date_naive()) && has(self.hours, time.hour()) && has(self.minutes, time.minute())
+    }
+
+    /// The first minute after `time` the schedule runs at, if any: a
+    /// schedule such as `0 0 31 2 *` never does.
+    pub fn next_after(&self, time: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let start = time.with_second(0)?.with_nanosecond(0)? + chrono::Duration::minutes(1);
+        let mut day = start.date_naive();
+        let (mut from_hour, mut from_minute) = (start.hour(), start.minute());
+        for _ in 0..SEARCH_DAYS {
+            if self.day_matches(day) {
+                for hour in (from_hour..24).filter(|h| has(self.hours, *h)) {
+                    let first = if hour == from_hour { from_minute } else { 0 };
+                    if let Some(minute) = (first..60).find(|m| has(self.minutes, *m)) {
+                        return Some(day.and_hms_opt(hour, minute, 0)?.and_utc());
+                    }
+                }
+            }
+            day = day.succ_opt()?;
+            (from_hour, from_minute) = (0, 0);
+        }
+        None
+    }
+
+    fn day_matches(&self, day: NaiveDate) -> bool {
+        if !has(self.months, day.month()) {
+            return false;
+        }
+        let by_day = has(self.days, day.day());
+        let by_weekday = has(self.weekdays, day.weekday().num_days_from_sunday());
+        if self.days_restricted && self.weekdays_restricted {
+            by_day || by_weekday
+        } else {
+            by_day && by_weekday
+        }
+    }
+}
+
+fn has(set: u64, value: u32) -> bool {
+    set & (1 << value) != 0
+}
+
+/// The values `text` allows between `min` and `max`, as a bit set. `names`
+/// stand for the values from `min` on.
+fn field(name: &str, text: &str, min: u32, max: u32, names: &[&str]) -> Result<u64, String> {
+    let value = |s: &str| -> Result<u32, String> {
+        let n = match names.iter().position(|n| n.eq_ignore_ascii_case(s)) {
+            Some(i) => i as u32 + min,
+            None => s.parse().map_err(|_| format!("{name}: '{s}' is not a number"))?,
+        };
+        if n < min || n > max {
+            return Err(format!("{name}: {n} is not within {min}-{max}"));
+        }
+        Ok(n)
+    };
+    let mut set = 0u64;
+    for part in text.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => {
+                let step: u32 = step.parse().map_err(|_| format!("{name}: invalid step in '{part}'"))?;
+                if step == 0 {
+                    return Err(format!("{name}: step 0 in '{part}'"));
+                }
+                (range, Some(step))
+            }
+            None => (part, None),
+        };
+        let (low, high) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((low, high)) => (value(low)?, value(high)?),
+            // `a/n` runs from `a` to the end.
+            None if step.is_some() => (value(range)?, max),
+            None => {
+                let n = value(range)?;
+                (n, n)
+            }
+        };
+        if low > high {
+            return Err(format!("{name}: empty range '{range}'"));
+        }
+        for n in (low..=high).step_by(step.unwrap_or(1) as usize) {
+            set |= 1 << n;
+        }
+    }
+    Ok(set)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(text: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(text).unwrap().with_timezone(&Utc)
+    }
+
+    fn next(expr: &str, after: &str) -> String {
+        let next = Schedule::parse(expr).unwrap().next_after(at(after)).unwrap();
+        next.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+    }
+
+    #[test]
+    fn finds_the_next_run() {
+        assert_eq!(next("0 2 * * *", "2026-03-01T01:59:30Z"), "2026-03-01T02:00:00Z");
+        assert_eq!(next("0 2 * * *", "2026-03-01T02:00:00Z"), "2026-03-02T02:00:00Z");
+        assert_eq!(next("*/15 * * * *", "2026-03-01T10:07:00Z"), "2026-03-01T10:15:00Z");
+        assert_eq!(next("30 4 1,15 * *", "2026-03-02T00:00:00Z"), "2026-03-15T04:30:00Z");
+        assert_eq!(next("0 0 * * sun", "2026-03-02T00:00:00Z"), "2026-03-08T00:00:00Z");
+        assert_eq!(next("0 0 * * 7", "2026-03-02T00:00:00Z"), "2026-03-08T00:00:00Z");
+        assert_eq!(next("@monthly", "2026-12-31T23:59:00Z"), "2027-01-01T00:00:00Z");
+        assert_eq!(next("0 12 29 feb *", "2026-03-01T00:00:00Z"), "2028-02-29T12:00:00Z");
+    }
+
+    #[test]
+    fn either_day_field_matches_when_both_are_set() {
+        let schedule = Schedule::parse("0 0 13 * fri").unwrap();
+        // A Friday that is not the 13th, and a 13th that is not a Friday.
+        assert!(schedule.matches(at("2026-03-06T00:00:00Z")));
+        assert!(schedule.matches(at("2026-04-13T00:00:00Z")));
+        assert!(!schedule.matches(at("2026-03-07T00:00:00Z")));
+        // A step on `*` leaves the field unrestricted.
+        let schedule = Schedule::parse("0 0 */2 * mon").unwrap();
+        assert!(!schedule.matches(at("2026-03-03T00:00:00Z")));
+        assert!(schedule.matches(at("2026-03-09T00:00:00Z")));
+    }
+
+    #[test]
+    fn rejects_invalid_expressions() {
+        let invalid = ["", "* * * *", "60 * * * *", "* 24 * * *", "* * 0 * *", "*/0 * * * *", "5-1 * * * *", "* * * foo *"];
+        for expr in invalid {
+            assert!(Schedule::parse(expr).is_err(), "{expr} parsed");
+        }
+        assert_eq!(Schedule::parse("0 0 31 2 *").unwrap().next_after(at("2026-01-01T00:00:00Z")), None);
+    }
+}
@@ -0,0 +1,513 @@
+//! Delta exports, for air-gapped sites that already hold an earlier bundle
+//! and should only be sent what changed since.
+//!
+//! The receiving site lists the blobs of what it holds
+//! (`GET /api/artifacts/:id/blobs`). Given that list as baseline,
+//! `POST /api/artifacts/:id/delta` downloads a tar with only the files of the
+//! artifact the baseline lacks: new layers, configs and manifests, plus the
+//! listings (`manifest.json`, `index.json`, `oci-layout`). It starts with
+//! `delta.json`, which names the files left out and their digests. Back on
+//! the receiving site, `POST /api/artifacts/:id/rebuild` takes the delta as
+//! body and puts the full archive back together from it and baseline
+//! artifact `:id`, then checks it like a pull before storing it.
+//!
+//! Files are matched by the digest they are named after, whatever the
+//! layout: a docker-archive baseline can feed an OCI delta as long as the
+//! blobs are the same bytes.
+
+use crate::{
+    archive::ArchiveFormat,
+    artifacts::{artifact_path, finalize, pushed_name, Artifact},
+    audit::{self, Caller, Produced},
+    checksum::HashingWriter,
+    compression::{self, Compression, CompressionOptions},
+    error::ApiError,
+    inspect::{digest_from_name, normalize},
+    make_filename, open_artifact,
+    signing::manifest_time,
+    AppState, CHECKSUM_HEADER,
+};
+use axum::{
+    body::Body,
+    extract::{Json, Path, Query, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    io::{self, BufReader, Read, Seek, SeekFrom, Write},
+};
+use tokio::fs;
+use tokio_stream::StreamExt;
+use tokio_util::io::{ReaderStream, StreamReader, SyncIoBridge};
+use uuid::Uuid;
+
+/// First file of every delta.
+const LISTING: &str = "delta.json";
+
+/// The blobs of an artifact, as listed by `GET /api/artifacts/:id/blobs`;
+/// sent back as is, it is the baseline of a delta.
+#[derive(Serialize, Deserialize)]
+pub struct BlobList {
+    #[serde(default)]
+    id: String,
+    #[serde(default)]
+    reference: String,
+    #[serde(default)]
+    format: String,
+    #[serde(default)]
+    sha256: String,
+    blobs: Vec<String>,
+}
+
+/// `delta.json`: what the delta was made from, and the files it left out.
+#[derive(Serialize, Deserialize)]
+struct Listing {
+    format: ArchiveFormat,
+    reference: String,
+    repo: String,
+    tag: String,
+    /// Files to take from the baseline, found there by digest.
+    reused: Vec<Reused>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Reused {
+    name: String,
+    digest: String,
+    size: u64,
+}
+
+/// A delta written to an unnamed temporary file, rewound.
+struct Delta {
+    file: std::fs::File,
+    listing: Listing,
+    size: u64,
+    sha256: String,
+}
+
+fn open_tar(path: &std::path::Path) -> io::Result<tar::Archive<Box<dyn Read>>> {
+    let file = std::fs::File::open(path)?;
+    Ok(tar::Archive::new(compression::decoded(BufReader::new(file))?))
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Digests of the files of the archive at `path` named after their digest.
+fn blobs_of(path: &std::path::Path) -> io::Result<Vec<String>> {
+    let mut blobs = BTreeSet::new();
+    for entry in open_tar(path)?.entries()? {
+        let entry = entry?;
+        if entry.header().entry_type().is_file() {
+            blobs.extend(digest_from_name(&entry.path()?.to_string_lossy()));
+        }
+    }
+    Ok(blobs.into_iter().collect())
+}
+
+/// Copies `entry` under `name`; files, directories and links only.
+fn copy_entry<W: Write, R: Read>(
+    builder: &mut tar::Builder<W>,
+    name: &str,
+    entry: &mut tar::Entry<R>,
+) -> io::Result<()> {
+    let mut header = entry.header().clone();
+    let kind = header.entry_type();
+    if kind.is_symlink() || kind.is_hard_link() {
+        match entry.link_name()? {
+            Some(target) => builder.append_link(&mut header, name, &target),
+            None => Ok(()),
+        }
+    } else if kind.is_file() || kind.is_dir() {
+        builder.append_data(&mut header, name, entry)
+    } else {
+        Ok(())
+    }
+}
+
+/// Writes to `out` the delta of the archive at `path` against `baseline`.
+fn write_delta(
+    path: &std::path::Path,
+    mut listing: Listing,
+    baseline: &HashSet<String>,
+    mut out: std::fs::File,
+) -> io::Result<Delta> {
+    for entry in open_tar(path)?.entries()? {
+        let entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let name = normalize(&entry.path()?.to_string_lossy());
+        if let Some(digest) = digest_from_name(&name).filter(|d| baseline.contains(d)) {
+            listing.reused.push(Reused { name, digest, size: entry.header().size()? });
+        }
+    }
+    let reused: HashSet<String> = listing.reused.iter().map(|r| r.name.clone()).collect();
+
+    let mut builder = tar::Builder::new(HashingWriter::new(&mut out));
+    let bytes = serde_json::to_vec(&listing)?;
+    let mut header = tar::Header::new_gnu();
+    header.set_size(bytes.len() as u64);
+    header.set_mode(0o644);
+    builder.append_data(&mut header, LISTING, bytes.as_slice())?;
+    for entry in open_tar(path)?.entries()? {
+        let mut entry = entry?;
+        let name = normalize(&entry.path()?.to_string_lossy());
+        if !name.is_empty() && !reused.contains(&name) {
+            copy_entry(&mut builder, &name, &mut entry)?;
+        }
+    }
+    let written = builder.into_inner()?;
+    let size = written.written();
+    let sha256 = written.finish();
+    out.seek(SeekFrom::Start(0))?;
+    Ok(Delta { file: out, listing, size, sha256 })
+}
+
+/// Writes to `out` the archive `delta` was made from, taking the files it
+/// left out from the archive at `baseline`.
+fn rebuild<R: Read>(delta: R, baseline: &std::path::Path, out: &std::path::Path) -> io::Result<Listing> {
+    let unreadable = |e: io::Error| invalid(format!("unreadable delta: {e}"));
+    let mut archive = tar::Archive::new(compression::decoded(BufReader::new(delta)).map_err(unreadable)?);
+    let mut entries = archive.entries().map_err(unreadable)?;
+    let listing: Listing = match entries.next() {
+        Some(entry) => {
+            let mut entry = entry.map_err(unreadable)?;
+            if normalize(&entry.path()?.to_string_lossy()) != LISTING {
+                return Err(invalid(format!("not a delta: {LISTING} must come first")));
+            }
+            serde_json::from_reader(&mut entry).map_err(|e| invalid(format!("{LISTING} is malformed: {e}")))?
+        }
+        None => return Err(invalid("the delta is empty".into())),
+    };
+
+    let mut builder = tar::Builder::new(std::fs::File::create(out)?);
+    for entry in entries {
+        let mut entry = entry.map_err(unreadable)?;
+        let name = normalize(&entry.path()?.to_string_lossy());
+        if !name.is_empty() {
+            copy_entry(&mut builder, &name, &mut entry)?;
+        }
+    }
+    let mut wanted: HashMap<&str, Vec<&str>> = HashMap::new();
+    for reused in &listing.reused {
+        wanted.entry(&reused.digest).or_default().push(&reused.name);
+    }
+    for entry in open_tar(baseline)?.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let Some(digest) = digest_from_name(&entry.path()?.to_string_lossy()) else {
+            continue;
+        };
+        let Some(names) = wanted.remove(digest.as_str()) else {
+            continue;
+        };
+        let mut header = entry.header().clone();
+        builder.append_data(&mut header, names[0], &mut entry)?;
+        // The same blob under other names, as in a docker archive listing a layer twice.
+        for name in &names[1..] {
+            let mut link = tar::Header::new_gnu();
+            link.set_entry_type(tar::EntryType::Link);
+            link.set_mode(0o644);
+            builder.append_link(&mut link, name, names[0])?;
+        }
+    }
+    if let Some(digest) = wanted.keys().next() {
+        return Err(invalid(format!(
+            "the baseline lacks {} of the blobs the delta leaves out, {} among them",
+            wanted.len(),
+            digest
+        )));
+    }
+    builder.into_inner()?.sync_all()?;
+    Ok(listing)
+}
+
+/// `GET /api/artifacts/:id/blobs`: the blobs of an artifact, to send as the
+/// baseline of a delta.
+pub async fn list_blobs(State(state): State<AppState>, caller: Caller, Path(id): Path<String>) -> Response {
+    let (artifact, _, _) = match open_artifact(&state, &caller, &id).await {
+        Ok(opened) => opened,
+        Err(e) => return e.into_response(),
+    };
+    let path = artifact.path.clone();
+    let blobs = tokio::task::spawn_blocking(move || blobs_of(&path))
+        .await
+        .map_err(io::Error::other)
+        .and_then(|r| r);
+    match blobs {
+        Ok(blobs) => Json(BlobList {
+            id,
+            reference: artifact.reference,
+            format: artifact.format.as_str().to_string(),
+            sha256: artifact.sha256,
+            blobs,
+        })
+        .into_response(),
+        Err(e) => ApiError::io("cannot read the artifact", e).into_response(),
+    }
+}
+
+/// `POST /api/artifacts/:id/delta`: downloads what artifact `:id` holds
+/// beyond the baseline in the body. The artifact stays available.
+pub async fn export_delta(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path(id): Path<String>,
+    Json(baseline): Json<BlobList>,
+) -> Response {
+    let made = make_delta(&state, &caller, &id, baseline).await;
+    match &made {
+        Ok((artifact, delta)) => state.audit.event(
+            &caller,
+            audit::Event::Delta,
+            &artifact.reference,
+            Some(artifact.format.as_str()),
+            Ok(Produced {
+                digest: artifact.digest.clone(),
+                size: Some(delta.size),
+                sha256: Some(delta.sha256.clone()),
+                artifact_id: Some(id.clone()),
+            }),
+        ),
+        Err(e) => state.audit.event(&caller, audit::Event::Delta, &id, None, Err(e)),
+    }
+    let (artifact, delta) = match made {
+        Ok(made) => made,
+        Err(e) => return e.into_response(),
+    };
+
+    let stem = artifact.filename.split(".tar").next().unwrap_or("delta");
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(Compression::None.content_type()));
+    headers.insert(header::CONTENT_LENGTH, HeaderValue::from(delta.size));
+    if let Ok(v) = HeaderValue::from_str(&format!("attachment; filename=\"{stem}.delta.tar\"")) {
+        headers.insert(header::CONTENT_DISPOSITION, v);
+    }
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    if let Ok(v) = HeaderValue::from_str(&delta.sha256) {
+        headers.insert(CHECKSUM_HEADER, v);
+    }
+    headers.insert("x-delta-reused", HeaderValue::from(delta.listing.reused.len()));
+    let body = Body::from_stream(ReaderStream::new(fs::File::from_std(delta.file)));
+    (StatusCode::OK, headers, body).into_response()
+}
+
+async fn make_delta(
+    state: &AppState,
+    caller: &Caller,
+    id: &str,
+    baseline: BlobList,
+) -> Result<(Artifact, Delta), ApiError> {
+    let (artifact, _, _) = open_artifact(state, caller, id).await?;
+    let listing = Listing {
+        format: artifact.format,
+        reference: artifact.reference.clone(),
+        repo: artifact.repo.clone(),
+        tag: artifact.tag.clone(),
+        reused: Vec::new(),
+    };
+    let path = artifact.path.clone();
+    let baseline: HashSet<String> = baseline.blobs.into_iter().collect();
+    let delta = tokio::task::spawn_blocking(move || write_delta(&path, listing, &baseline, tempfile::tempfile()?))
+        .await
+        .map_err(io::Error::other)
+        .and_then(|r| r)
+        .map_err(|e| ApiError::io("cannot write the delta", e))?;
+    Ok((artifact, delta))
+}
+
+#[derive(Deserialize)]
+pub struct RebuildParams {
+    /// Seconds to keep the rebuilt archive, the baseline of the next delta.
+    #[serde(default)]
+    ttl_secs: Option<u64>,
+}
+
+/// `POST /api/artifacts/:id/rebuild`: stores the archive rebuilt from the
+/// delta in the body and baseline artifact `:id`, like an upload.
+pub async fn rebuild_artifact(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path(id): Path<String>,
+    Query(params): Query<RebuildParams>,
+    body: Body,
+) -> Response {
+    match rebuild_stored(&state, &caller, &id, params.ttl_secs, body).await {
+        Ok(payload) => (StatusCode::CREATED, Json(payload)).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+async fn rebuild_stored(
+    state: &AppState,
+    caller: &Caller,
+    baseline_id: &str,
+    ttl_secs: Option<u64>,
+    body: Body,
+) -> Result<serde_json::Value, ApiError> {
+    let (baseline, _, _) = open_artifact(state, caller, baseline_id).await?;
+    let id = Uuid::new_v4().to_string();
+    let out = artifact_path(&id);
+
+    let stream = body.into_data_stream().map(|chunk| chunk.map_err(io::Error::other));
+    let reader = SyncIoBridge::new(StreamReader::new(stream));
+    let (path, written) = (baseline.path.clone(), out.clone());
+    let rebuilt = tokio::task::spawn_blocking(move || rebuild(reader, &path, &written))
+        .await
+        .map_err(io::Error::other)
+        .and_then(|r| r);
+    let listing = match rebuilt {
+        Ok(listing) => listing,
+        Err(e) => {
+            let _ = fs::remove_file(&out).await;
+            return Err(match e.kind() {
+                io::ErrorKind::InvalidData => ApiError::InvalidArchive(e.to_string()),
+                _ => ApiError::io("cannot rebuild the archive", e),
+            });
+        }
+    };
+    let settings = state.config.current();
+    let reference = Some(listing.reference.as_str()).filter(|r| !r.is_empty());
+    if let Err(e) = pushed_name(&settings, &caller.principal, reference) {
+        let _ = fs::remove_file(&out).await;
+        return Err(e);
+    }
+
+    let format = listing.format;
+    let finalized = finalize(format, &out, &out, CompressionOptions::default()).await?;
+    let filename = make_filename(&listing.repo, &listing.tag, format.as_str(), Compression::None);
+    let size = fs::metadata(&out).await.map(|m| m.len()).unwrap_or_default();
+    let created = manifest_time();
+    let stored = state
+        .artifacts
+        .insert(Artifact {
+            id: id.clone(),
+            path: out.clone(),
+            filename: filename.clone(),
+            format,
+            compression: Compression::None,
+            repo: listing.repo,
+            tag: listing.tag,
+            reference: listing.reference,
+            digest: finalized.report.image_digest(),
+            sha256: finalized.sha256.clone(),
+            size,
+            owner: caller.principal.name.clone(),
+            created,
+            expires: created + settings.artifact_ttl_for(ttl_secs),
+            node: state.node.id.clone(),
+        })
+        .await;
+    if let Err(e) = stored {
+        let _ = fs::remove_file(&out).await;
+        return Err(e);
+    }
+
+    Ok(serde_json::json!({
+        "id": id,
+        "filename": filename,
+        "format": format,
+        "size": size,
+        "sha256": finalized.sha256,
+        "baseline": baseline_id,
+        "reused": listing.reused.len(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::{Digest, Sha256};
+
+    fn sha(data: &[u8]) -> String {
+        hex::encode(Sha256::digest(data))
+    }
+
+    /// A docker archive of one image with `layers`, and its files by name.
+    fn docker_archive(path: &std::path::Path, layers: &[&[u8]]) -> HashMap<String, Vec<u8>> {
+        let diff_ids: Vec<String> = layers.iter().map(|l| format!("sha256:{}", sha(l))).collect();
+        let config = serde_json::json!({ "rootfs": { "type": "layers", "diff_ids": diff_ids } }).to_string();
+        let config_name = format!("{}.json", sha(config.as_bytes()));
+        let layer_names: Vec<String> = layers.iter().map(|l| format!("{}.tar", sha(l))).collect();
+        let manifest = serde_json::json!([{ "Config": config_name, "RepoTags": ["app:2"], "Layers": layer_names }]);
+        let mut files = HashMap::from([
+            (config_name, config.into_bytes()),
+            ("manifest.json".to_string(), manifest.to_string().into_bytes()),
+        ]);
+        files.extend(layer_names.into_iter().zip(layers.iter().map(|l| l.to_vec())));
+
+        let mut builder = tar::Builder::new(std::fs::File::create(path).unwrap());
+        for (name, data) in &files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            builder.append_data(&mut header, name, data.as_slice()).unwrap();
+        }
+        builder.into_inner().unwrap();
+        files
+    }
+
+    fn files_of(path: &std::path::Path) -> HashMap<String, Vec<u8>> {
+        let mut files = HashMap::new();
+        for entry in open_tar(path).unwrap().entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data).unwrap();
+            files.insert(normalize(&entry.path().unwrap().to_string_lossy()), data);
+        }
+        files
+    }
+
+    fn listing() -> Listing {
+        Listing {
+            format: ArchiveFormat::DockerArchive,
+            reference: "registry.example/app:2".into(),
+            repo: "app".into(),
+            tag: "2".into(),
+            reused: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn rebuilds_the_full_archive_from_a_delta() {
+        let dir = tempfile::tempdir().unwrap();
+        let (full, old, out) = (dir.path().join("full.tar"), dir.path().join("old.tar"), dir.path().join("out.tar"));
+        let files = docker_archive(&full, &[b"base layer", b"new layer"]);
+        docker_archive(&old, &[b"base layer"]);
+
+        let baseline: HashSet<String> = blobs_of(&old).unwrap().into_iter().collect();
+        let delta = write_delta(&full, listing(), &baseline, tempfile::tempfile().unwrap()).unwrap();
+        let reused: Vec<&str> = delta.listing.reused.iter().map(|r| r.digest.as_str()).collect();
+        assert_eq!(reused, [format!("sha256:{}", sha(b"base layer"))]);
+        assert_eq!(delta.size, delta.file.metadata().unwrap().len());
+
+        let listing = rebuild(delta.file, &old, &out).unwrap();
+        assert_eq!(listing.reference, "registry.example/app:2");
+        assert_eq!(files_of(&out), files);
+        let finalized = finalize(ArchiveFormat::DockerArchive, &out, &out, CompressionOptions::default()).await;
+        assert!(finalized.is_ok_and(|f| f.report.valid));
+    }
+
+    #[test]
+    fn rebuild_fails_when_the_baseline_lacks_a_reused_blob() {
+        let dir = tempfile::tempdir().unwrap();
+        let (full, old, out) = (dir.path().join("full.tar"), dir.path().join("old.tar"), dir.path().join("out.tar"));
+        docker_archive(&full, &[b"base layer", b"new layer"]);
+        docker_archive(&old, &[b"other layer"]);
+
+        let baseline = HashSet::from([format!("sha256:{}", sha(b"base layer"))]);
+        let delta = write_delta(&full, listing(), &baseline, tempfile::tempfile().unwrap()).unwrap();
+        let Err(err) = rebuild(delta.file, &old, &out) else { panic!("rebuilt without the reused blob") };
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("the baseline lacks 1 of the blobs"), "{err}");
+
+        let Err(err) = rebuild(io::Cursor::new(b"not a tar".repeat(100)), &old, &out) else { panic!("not a delta") };
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,294 @@
+//! Error model shared by every endpoint and by SSE `error` events.
+//!
+//! Errors are serialised as RFC 9457 `application/problem+json` with a stable
+//! `code` the frontend localises, the human `detail`, and whether retrying
+//! the same request may succeed.
+
+use crate::{
+    artifacts::FinalizeError,
+    classify::{classify, summary, Failure},
+    inspect::InspectError,
+    puller::PullError,
+    skopeo::EventSender,
+};
+use axum::{
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ApiError {
+    #[error("{0}")]
+    InvalidRequest(String),
+    #[error("invalid image reference: {0}")]
+    InvalidReference(String),
+    /// Missing or invalid API credentials.
+    #[error("{0}")]
+    Unauthorized(String),
+    /// Refused by the server-wide reference rules, whoever asks.
+    #[error("{0}")]
+    ReferenceDenied(String),
+    /// Authenticated, but not allowed by the policies.
+    #[error("{0}")]
+    Forbidden(String),
+    #[error("{0}")]
+    NotFound(String),
+    #[error("image not found: {0}")]
+    ImageNotFound(String),
+    #[error("access denied to registry for {0}")]
+    RegistryAuthFailed(String),
+    #[error("{detail}")]
+    RateLimited { detail: String, retry_after: Option<u64> },
+    #[error("{0}")]
+    Tls(String),
+    /// The registry host name does not resolve.
+    #[error("{0}")]
+    Dns(String),
+    /// Connection-level failure or a 5xx from the registry.
+    #[error("{0}")]
+    Network(String),
+    /// The image exists, but not for the platform being pulled.
+    #[error("{0}")]
+    PlatformMismatch(String),
+    #[error("{0}")]
+    Timeout(String),
+    #[error("{0}")]
+    DiskFull(String),
+    /// Pulled content that does not match its digests.
+    #[error("{0}")]
+    CorruptImage(String),
+    /// A tar sent by the client that is not a usable image archive.
+    #[error("{0}")]
+    InvalidArchive(String),
+    /// A request body over the configured limit.
+    #[error("{0}")]
+    TooLarge(String),
+    #[error("{0}")]
+    PullerUnavailable(String),
+    #[error("{0}")]
+    Upstream(String),
+    /// Too many jobs running already.
+    #[error("{0}")]
+    Busy(String),
+    /// The server stopped while the job was running.
+    #[error("{0}")]
+    Interrupted(String),
+    /// Some of the images of a job failed, the others went through.
+    #[error("{0}")]
+    PartialFailure(String),
+    #[error("{0}")]
+    Internal(String),
+}
+
+/// The `application/problem+json` body, also sent as SSE `error` data.
+#[derive(Debug, Serialize)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub title: &'static str,
+    pub status: u16,
+    pub code: &'static str,
+    pub detail: String,
+    pub retryable: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_after: Option<u64>,
+}
+
+impl ApiError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::InvalidRequest(_) => "invalid_request",
+            ApiError::InvalidReference(_) => "invalid_reference",
+            ApiError::Unauthorized(_) => "unauthorized",
+            ApiError::ReferenceDenied(_) => "reference_denied",
+            ApiError::Forbidden(_) => "forbidden",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::ImageNotFound(_) => "image_not_found",
+            ApiError::RegistryAuthFailed(_) => "registry_auth_failed",
+            ApiError::RateLimited { .. } => "rate_limited",
+            ApiError::Tls(_) => "tls_error",
+            ApiError::Dns(_) => "dns_error",
+            ApiError::Network(_) => "network_error",
+            ApiError::PlatformMismatch(_) => "platform_mismatch",
+            ApiError::Timeout(_) => "timeout",
+            ApiError::DiskFull(_) => "disk_full",
+            ApiError::CorruptImage(_) => "corrupt_image",
+            ApiError::InvalidArchive(_) => "invalid_archive",
+            ApiError::TooLarge(_) => "too_large",
+            ApiError::PullerUnavailable(_) => "puller_unavailable",
+            ApiError::Upstream(_) => "upstream_error",
+            ApiError::Busy(_) => "too_many_jobs",
+            ApiError::Interrupted(_) => "interrupted",
+            ApiError::PartialFailure(_) => "partial_failure",
+            ApiError::Internal(_) => "internal_error",
+        }
+    }
+
+    fn title(&self) -> &'static str {
+        match self {
+            ApiError::InvalidRequest(_) => "Invalid request",
+            ApiError::InvalidReference(_) => "Invalid image reference",
+            ApiError::Unauthorized(_) => "Authentication required",
+            ApiError::ReferenceDenied(_) => "Reference not allowed",
+            ApiError::Forbidden(_) => "Not allowed",
+            ApiError::NotFound(_) => "Not found",
+            ApiError::ImageNotFound(_) => "Image not found",
+            ApiError::RegistryAuthFailed(_) => "Registry authentication failed",
+            ApiError::RateLimited { .. } => "Rate limited by registry",
+            ApiError::Tls(_) => "TLS error",
+            ApiError::Dns(_) => "Registry host not found",
+            ApiError::Network(_) => "Network error",
+            ApiError::PlatformMismatch(_) => "Platform not available",
+            ApiError::Timeout(_) => "Timeout",
+            ApiError::DiskFull(_) => "Disk full",
+            ApiError::CorruptImage(_) => "Corrupt image",
+            ApiError::InvalidArchive(_) => "Invalid archive",
+            ApiError::TooLarge(_) => "Too large",
+            ApiError::PullerUnavailable(_) => "Puller unavailable",
+            ApiError::Upstream(_) => "Upstream error",
+            ApiError::Busy(_) => "Too many jobs",
+            ApiError::Interrupted(_) => "Interrupted",
+            ApiError::PartialFailure(_) => "Partially failed",
+            ApiError::Internal(_) => "Internal error",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::InvalidRequest(_) | ApiError::InvalidReference(_) => StatusCode::BAD_REQUEST,
+            ApiError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            ApiError::NotFound(_) | ApiError::ImageNotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::ReferenceDenied(_) | ApiError::Forbidden(_) | ApiError::RegistryAuthFailed(_) => {
+                StatusCode::FORBIDDEN
+            }
+            ApiError::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            ApiError::DiskFull(_) => StatusCode::INSUFFICIENT_STORAGE,
+            ApiError::InvalidArchive(_) | ApiError::PlatformMismatch(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::PullerUnavailable(_) | ApiError::Busy(_) | ApiError::Interrupted(_) => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            ApiError::Tls(_)
+            | ApiError::Dns(_)
+            | ApiError::Network(_)
+            | ApiError::CorruptImage(_)
+            | ApiError::Upstream(_)
+            | ApiError::PartialFailure(_) => StatusCode::BAD_GATEWAY,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Whether sending the same request again may succeed.
+    pub fn retryable(&self) -> bool {
+        matches!(
+            self,
+            ApiError::RateLimited { .. }
+                | ApiError::Timeout(_)
+                | ApiError::Network(_)
+                | ApiError::Busy(_)
+                | ApiError::Interrupted(_)
+        )
+    }
+
+    pub fn problem(&self) -> Problem {
+        Problem {
+            kind: format!("urn:tessark:error:{}", self.code()),
+            title: self.title(),
+            status: self.status().as_u16(),
+            code: self.code(),
+            detail: self.to_string(),
+            retryable: self.retryable(),
+            retry_after: match self {
+                ApiError::RateLimited { retry_after, .. } => *retry_after,
+                _ => None,
+            },
+        }
+    }
+
+    /// Error for an I/O failure on our side, singling out a full disk.
+    pub fn io(context: &str, e: std::io::Error) -> Self {
+        if e.kind() == std::io::ErrorKind::StorageFull {
+            ApiError::DiskFull(format!("{context}: {e}"))
+        } else {
+            ApiError::Internal(format!("{context}: {e}"))
+        }
+    }
+
+    /// Maps a failed pull of `reference` to the error the client sees.
+    pub fn pull(reference: &str, e: PullError) -> Self {
+        let failure = classify(&e);
+        let detail = match &e {
+            PullError::Failed(stderr) => summary(stderr),
+            other => other.to_string(),
+        };
+        tracing::warn!(
+            reference,
+            class = failure.as_str(),
+            "pull failed: {}",
+            crate::telemetry::redact(e.to_string().trim())
+        );
+        match (failure, e) {
+            (_, PullError::Io(e)) => ApiError::io("failed to write archive", e),
+            (Failure::ToolMissing, PullError::Spawn(e)) if e.kind() == std::io::ErrorKind::NotFound => {
+                ApiError::PullerUnavailable("skopeo not found (ENOENT)".to_string())
+            }
+            (Failure::ToolMissing, PullError::Spawn(e)) => {
+                ApiError::PullerUnavailable(format!("failed to spawn skopeo: {e}"))
+            }
+            (Failure::ToolMissing, _) => ApiError::PullerUnavailable(detail),
+            (Failure::ImageNotFound, _) => ApiError::ImageNotFound(reference.to_string()),
+            (Failure::AuthFailed, _) => ApiError::RegistryAuthFailed(reference.to_string()),
+            (Failure::RateLimited { retry_after }, _) => ApiError::RateLimited { detail, retry_after },
+            (Failure::Tls, _) => ApiError::Tls(detail),
+            (Failure::Dns, _) => ApiError::Dns(detail),
+            (Failure::PlatformMismatch, _) => ApiError::PlatformMismatch(detail),
+            (Failure::DiskFull, _) => ApiError::DiskFull(detail),
+            (Failure::Network, _) => ApiError::Network(detail),
+            (Failure::InvalidReference, _) => ApiError::InvalidReference(detail),
+            (Failure::Corrupt, _) => ApiError::CorruptImage(format!("{detail} ({reference})")),
+            (Failure::LocalIo | Failure::Unknown, _) => {
+                ApiError::Upstream(format!("failed to pull {}: {}", reference, detail))
+            }
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let problem = self.problem();
+        let mut response = (self.status(), serde_json::to_string(&problem).unwrap_or_default()).into_response();
+        let headers = response.headers_mut();
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/problem+json"));
+        if let Some(secs) = problem.retry_after {
+            headers.insert(header::RETRY_AFTER, HeaderValue::from(secs));
+        }
+        if matches!(self, ApiError::Unauthorized(_)) {
+            headers.insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+        }
+        response
+    }
+}
+
+impl From<FinalizeError> for ApiError {
+    fn from(e: FinalizeError) -> Self {
+        match e {
+            FinalizeError::Invalid(msg) => ApiError::CorruptImage(msg),
+            FinalizeError::Pack(e) => ApiError::io("failed to pack archive", e),
+            FinalizeError::Compress(e) => ApiError::io("failed to compress archive", e),
+        }
+    }
+}
+
+impl From<InspectError> for ApiError {
+    fn from(e: InspectError) -> Self {
+        ApiError::InvalidArchive(e.to_string())
+    }
+}
+
+/// Sends `err` as an SSE `error` event carrying the problem JSON.
+pub async fn send_error(tx: &EventSender, err: &ApiError) {
+    let payload = serde_json::to_string(&err.problem()).unwrap_or_default();
+    crate::skopeo::send_event(tx, "error", payload).await;
+}
@@ -0,0 +1,656 @@
+use crate::{
+    artifacts::visible_artifact, audit::Caller, checksum::HashingReader, compression, error::ApiError, AppState,
+};
+use axum::{
+    body::Body,
+    extract::{Json, Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::IntoResponse,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    io::{BufReader, Read},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+use tokio_stream::StreamExt;
+use tokio_util::io::{StreamReader, SyncIoBridge};
+
+/// Entries up to this size that look like JSON are kept in memory so manifests
+/// and configs can be parsed once the whole archive has been read.
+const MAX_JSON_ENTRY: u64 = 4 * 1024 * 1024;
+
+/// Bound on the JSON kept from one archive: past it, further entries are only
+/// hashed, and reported as not JSON if a manifest points at them.
+const MAX_JSON_TOTAL: u64 = 64 * 1024 * 1024;
+
+/// What an image archive contains, and whether every blob matches its digest.
+#[derive(Debug, Serialize)]
+pub struct ArchiveReport {
+    /// `docker` for `manifest.json` archives, `oci` for OCI layouts.
+    pub layout: &'static str,
+    /// Checksum of the archive file itself.
+    pub sha256: String,
+    pub images: Vec<ImageEntry>,
+    pub blobs_checked: usize,
+    pub problems: Vec<String>,
+    pub valid: bool,
+}
+
+impl ArchiveReport {
+    /// Digest identifying the (first) image: its manifest digest for OCI
+    /// layouts, its config digest (image id) for docker archives.
+    pub fn image_digest(&self) -> Option<String> {
+        let image = self.images.first()?;
+        Some(image.manifest_digest.clone().unwrap_or_else(|| image.config_digest.clone()))
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ImageEntry {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub manifest_digest: Option<String>,
+    pub repo_tags: Vec<String>,
+    pub config_digest: String,
+    pub layers: Vec<LayerEntry>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LayerEntry {
+    pub digest: String,
+    pub size: u64,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum InspectError {
+    #[error("unreadable archive: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("not an image archive: neither manifest.json nor index.json found")]
+    UnknownLayout,
+}
+
+/// A regular file seen while walking the tar.
+struct Entry {
+    digest: String,
+    size: u64,
+    json: Option<Vec<u8>>,
+}
+
+#[derive(Default)]
+struct Walk {
+    entries: HashMap<String, Entry>,
+    links: HashMap<String, String>,
+}
+
+impl Walk {
+    fn get(&self, name: &str) -> Option<&Entry> {
+        let name = normalize(name);
+        let target = self.links.get(&name).cloned().unwrap_or(name);
+        self.entries.get(&target)
+    }
+}
+
+/// Reads an image archive in one pass and checks it against its own manifests.
+///
+/// Works on any reader, so the same check runs on artifacts on disk and on
+/// archives uploaded straight from the request body.
+pub fn inspect<R: Read>(reader: R) -> Result<ArchiveReport, InspectError> {
+    let mut hashing = HashingReader::new(reader);
+    let walk = walk_tar(compression::decoded(BufReader::new(&mut hashing))?, MAX_JSON_TOTAL)?;
+    // The tar reader stops at the end-of-archive marker; hash the padding too.
+    std::io::copy(&mut hashing, &mut std::io::sink())?;
+    let sha256 = hashing.finish();
+
+    let mut report = if walk.get("manifest.json").is_some() {
+        check_docker(&walk)
+    } else if walk.get("index.json").is_some() {
+        check_oci(&walk)
+    } else {
+        return Err(InspectError::UnknownLayout);
+    };
+    report.sha256 = sha256;
+    Ok(report)
+}
+
+pub async fn inspect_file(path: PathBuf) -> Result<ArchiveReport, InspectError> {
+    tokio::task::spawn_blocking(move || inspect(std::fs::File::open(path)?))
+        .await
+        .map_err(std::io::Error::other)?
+}
+
+/// Validation run after every skopeo copy, before an archive is handed out.
+pub async fn verify_file(path: PathBuf) -> Result<ArchiveReport, String> {
+    let report = inspect_file(path).await.map_err(|e| e.to_string())?;
+    if !report.valid {
+        return Err(format!("archive validation failed: {}", report.problems.join("; ")));
+    }
+    Ok(report)
+}
+
+fn walk_tar<R: Read>(reader: R, json_budget: u64) -> std::io::Result<Walk> {
+    let mut walk = Walk::default();
+    let mut json_left = json_budget;
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = normalize(&entry.path()?.to_string_lossy());
+        let kind = entry.header().entry_type();
+        if kind.is_symlink() || kind.is_hard_link() {
+            if let Some(target) = entry.link_name()? {
+                let target = target.to_string_lossy();
+                // Symlinks are relative to the entry, hard links to the archive root.
+                let resolved = match (kind.is_symlink(), name.rsplit_once('/')) {
+                    (true, Some((dir, _))) => normalize(&format!("{dir}/{target}")),
+                    _ => normalize(&target),
+                };
+                walk.links.insert(name, resolved);
+            }
+            continue;
+        }
+        if !kind.is_file() {
+            continue;
+        }
+
+        let size = entry.header().size()?;
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0u8; 64 * 1024];
+        let mut keep: Option<Vec<u8>> = None;
+        let mut first = true;
+        loop {
+            let n = entry.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            if first {
+                first = false;
+                let looks_json = buf[..n]
+                    .iter()
+                    .find(|b| !b.is_ascii_whitespace())
+                    .is_some_and(|b| *b == b'{' || *b == b'[');
+                if looks_json && size <= MAX_JSON_ENTRY && size <= json_left {
+                    json_left -= size;
+                    keep = Some(Vec::with_capacity(size as usize));
+                }
+            }
+            hasher.update(&buf[..n]);
+            if let Some(k) = keep.as_mut() {
+                k.extend_from_slice(&buf[..n]);
+            }
+        }
+        let digest = format!("sha256:{}", hex::encode(hasher.finalize()));
+        walk.entries.insert(name, Entry { digest, size, json: keep });
+    }
+    Ok(walk)
+}
+
+pub(crate) fn normalize(name: &str) -> String {
+    let mut parts: Vec<&str> = Vec::new();
+    for part in name.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            p => parts.push(p),
+        }
+    }
+    parts.join("/")
+}
+
+/// `sha256:<hex>` when an archive file is named after its digest (docker-archive
+/// configs and layers, OCI blobs).
+pub(crate) fn digest_from_name(name: &str) -> Option<String> {
+    let file = name.rsplit('/').next().unwrap_or(name);
+    let stem = file.split('.').next().unwrap_or(file);
+    let hex = if stem.len() == 64 { stem } else { file };
+    (hex.len() == 64 && hex.bytes().all(|b| b.is_ascii_hexdigit())).then(|| format!("sha256:{hex}"))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct DockerManifestEntry {
+    config: String,
+    #[serde(default)]
+    repo_tags: Option<Vec<String>>,
+    #[serde(default)]
+    layers: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct ImageConfig {
+    #[serde(default)]
+    rootfs: Option<RootFs>,
+}
+
+#[derive(Deserialize)]
+struct RootFs {
+    #[serde(default)]
+    diff_ids: Vec<String>,
+}
+
+fn check_docker(walk: &Walk) -> ArchiveReport {
+    let mut problems = Vec::new();
+    let mut images = Vec::new();
+    let mut checked = 0;
+
+    let manifest: Vec<DockerManifestEntry> = match parse_json(walk, "manifest.json") {
+        Ok(m) => m,
+        Err(e) => return report("docker", images, checked, vec![e]),
+    };
+
+    for item in manifest {
+        let Some(config) = walk.get(&item.config) else {
+            problems.push(format!("config {} is missing", item.config));
+            continue;
+        };
+        checked += 1;
+        if let Some(declared) = digest_from_name(&item.config) {
+            if declared != config.digest {
+                problems.push(format!(
+                    "config {} has digest {}, expected {}",
+                    item.config, config.digest, declared
+                ));
+            }
+        }
+        let diff_ids = parse_json::<ImageConfig>(walk, &item.config)
+            .ok()
+            .and_then(|c| c.rootfs)
+            .map(|r| r.diff_ids)
+            .unwrap_or_default();
+        if diff_ids.len() != item.layers.len() {
+            problems.push(format!(
+                "config {} lists {} layers, manifest lists {}",
+                item.config,
+                diff_ids.len(),
+                item.layers.len()
+            ));
+        }
+
+        let mut layers = Vec::new();
+        for (i, layer_path) in item.layers.iter().enumerate() {
+            let Some(layer) = walk.get(layer_path) else {
+                problems.push(format!("layer {} is missing", layer_path));
+                continue;
+            };
+            checked += 1;
+            let declared = diff_ids.get(i).cloned().or_else(|| digest_from_name(layer_path));
+            if let Some(declared) = declared {
+                if declared != layer.digest {
+                    problems.push(format!(
+                        "layer {} has digest {}, expected {}",
+                        layer_path, layer.digest, declared
+                    ));
+                }
+            }
+            layers.push(LayerEntry { digest: layer.digest.clone(), size: layer.size });
+        }
+
+        images.push(ImageEntry {
+            manifest_digest: None,
+            repo_tags: item.repo_tags.unwrap_or_default(),
+            config_digest: config.digest.clone(),
+            layers,
+        });
+    }
+
+    report("docker", images, checked, problems)
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Descriptor {
+    #[serde(default)]
+    media_type: String,
+    digest: String,
+    #[serde(default)]
+    size: Option<u64>,
+    #[serde(default)]
+    annotations: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct OciIndex {
+    #[serde(default)]
+    manifests: Vec<Descriptor>,
+}
+
+#[derive(Deserialize)]
+struct OciManifest {
+    #[serde(default)]
+    config: Option<Descriptor>,
+    #[serde(default)]
+    layers: Vec<Descriptor>,
+    #[serde(default)]
+    manifests: Option<Vec<Descriptor>>,
+}
+
+const REF_NAME: &str = "org.opencontainers.image.ref.name";
+
+fn check_oci(walk: &Walk) -> ArchiveReport {
+    let mut problems = Vec::new();
+    let mut images = Vec::new();
+    let mut checked = 0;
+
+    if walk.get("oci-layout").is_none() {
+        problems.push("oci-layout file is missing".to_string());
+    }
+    let index: OciIndex = match parse_json(walk, "index.json") {
+        Ok(i) => i,
+        Err(e) => return report("oci", images, checked, vec![e]),
+    };
+
+    // Every blob must hash to the name it is stored under, referenced or not.
+    for (name, entry) in &walk.entries {
+        if let Some(declared) = blob_digest(name) {
+            checked += 1;
+            if declared != entry.digest {
+                problems.push(format!("blob {} has digest {}", declared, entry.digest));
+            }
+        }
+    }
+
+    let mut pending: Vec<(Descriptor, Vec<String>)> = index
+        .manifests
+        .into_iter()
+        .map(|d| {
+            let tags = d.annotations.get(REF_NAME).cloned().into_iter().collect();
+            (d, tags)
+        })
+        .collect();
+
+    while let Some((desc, tags)) = pending.pop() {
+        if check_descriptor(walk, &desc, &mut problems).is_none() {
+            continue;
+        }
+        let manifest: OciManifest = match parse_json(walk, &blob_path(&desc.digest)) {
+            Ok(m) => m,
+            Err(e) => {
+                problems.push(e);
+                continue;
+            }
+        };
+        if let Some(children) = manifest.manifests {
+            // Manifest list: every platform image is checked on its own.
+            pending.extend(children.into_iter().map(|c| (c, tags.clone())));
+            continue;
+        }
+        let Some(config) = manifest.config else {
+            problems.push(format!("manifest {} has no config ({})", desc.digest, desc.media_type));
+            continue;
+        };
+        check_descriptor(walk, &config, &mut problems);
+
+        let layers = manifest
+            .layers
+            .iter()
+            .filter_map(|l| check_descriptor(walk, l, &mut problems))
+            .map(|e| LayerEntry { digest: e.digest.clone(), size: e.size })
+            .collect();
+
+        images.push(ImageEntry {
+            manifest_digest: Some(desc.digest.clone()),
+            repo_tags: tags,
+            config_digest: config.digest,
+            layers,
+        });
+    }
+
+    report("oci", images, checked, problems)
+}
+
+fn blob_path(digest: &str) -> String {
+    format!("blobs/{}", digest.replacen(':', "/", 1))
+}
+
+fn blob_digest(name: &str) -> Option<String> {
+    let rest = name.strip_prefix("blobs/sha256/")?;
+    (!rest.contains('/')).then(|| format!("sha256:{rest}"))
+}
+
+/// Checks that the blob a descriptor points at exists with the declared size.
+/// Its digest was already compared against its file name.
+fn check_descriptor<'a>(walk: &'a Walk, desc: &Descriptor, problems: &mut Vec<String>) -> Option<&'a Entry> {
+    let Some(entry) = walk.get(&blob_path(&desc.digest)) else {
+        problems.push(format!("blob {} is missing", desc.digest));
+        return None;
+    };
+    if let Some(size) = desc.size.filter(|s| *s != entry.size) {
+        problems.push(format!("blob {} has size {}, expected {}", desc.digest, entry.size, size));
+    }
+    Some(entry)
+}
+
+fn parse_json<T: serde::de::DeserializeOwned>(walk: &Walk, name: &str) -> Result<T, String> {
+    let entry = walk.get(name).ok_or_else(|| format!("{} is missing", name))?;
+    let bytes = entry.json.as_deref().ok_or_else(|| format!("{} is not JSON", name))?;
+    serde_json::from_slice(bytes).map_err(|e| format!("{} is malformed: {}", name, e))
+}
+
+fn report(layout: &'static str, images: Vec<ImageEntry>, checked: usize, problems: Vec<String>) -> ArchiveReport {
+    ArchiveReport {
+        layout,
+        sha256: String::new(),
+        images,
+        blobs_checked: checked,
+        valid: problems.is_empty(),
+        problems,
+    }
+}
+
+fn inspect_response(result: Result<ArchiveReport, InspectError>) -> axum::response::Response {
+    match result {
+        Ok(report) => (StatusCode::OK, Json(report)).into_response(),
+        Err(e) => ApiError::from(e).into_response(),
+    }
+}
+
+// Inspect an artifact produced by a pull, a conversion or an upload
+pub async fn inspect_artifact(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    let artifact = match visible_artifact(&state, &caller, &id).await {
+        Ok(artifact) => artifact,
+        Err(e) => return e.into_response(),
+    };
+    inspect_response(inspect_file(artifact.path).await)
+}
+
+// Inspect a tar sent as the request body, without storing it; the body is
+// bounded by artifacts.max_upload_bytes like an upload
+pub async fn inspect_upload(State(state): State<AppState>, headers: HeaderMap, body: Body) -> impl IntoResponse {
+    let max = state.config.current().config.artifacts.max_upload_bytes;
+    let too_large = || ApiError::TooLarge(format!("uploads are limited to {max} bytes")).into_response();
+    let declared = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    if declared.is_some_and(|len| len > max) {
+        return too_large();
+    }
+
+    // The decoders may wrap the error the stream returns, so whether the body
+    // ran over is read from the count rather than from the error.
+    let received = Arc::new(AtomicU64::new(0));
+    let counted = received.clone();
+    let stream = body.into_data_stream().map(move |chunk| {
+        let chunk = chunk.map_err(std::io::Error::other)?;
+        if counted.fetch_add(chunk.len() as u64, Ordering::Relaxed) + chunk.len() as u64 > max {
+            return Err(std::io::Error::from(std::io::ErrorKind::FileTooLarge));
+        }
+        Ok(chunk)
+    });
+    let reader = SyncIoBridge::new(StreamReader::new(stream));
+    let result = tokio::task::spawn_blocking(move || inspect(reader))
+        .await
+        .map_err(|e| InspectError::Io(std::io::Error::other(e)))
+        .and_then(|r| r);
+    if received.load(Ordering::Relaxed) > max {
+        return too_large();
+    }
+    inspect_response(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn sha(data: &[u8]) -> String {
+        format!("sha256:{}", hex::encode(Sha256::digest(data)))
+    }
+
+    fn tar(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (name, data) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            builder.append_data(&mut header, name, *data).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    fn config(layers: &[&[u8]]) -> Vec<u8> {
+        let diff_ids: Vec<String> = layers.iter().map(|l| sha(l)).collect();
+        serde_json::json!({ "rootfs": { "type": "layers", "diff_ids": diff_ids } }).to_string().into_bytes()
+    }
+
+    /// A docker archive of one image; `stored` replaces the layer bytes.
+    fn docker_archive(layer: &[u8], stored: &[u8]) -> Vec<u8> {
+        let config = config(&[layer]);
+        let config_name = format!("{}.json", &sha(&config)[7..]);
+        let layer_name = format!("{}.tar", &sha(layer)[7..]);
+        let manifest = serde_json::json!([{
+            "Config": config_name,
+            "RepoTags": ["app:1"],
+            "Layers": [layer_name],
+        }])
+        .to_string();
+        tar(&[(&config_name, &config), (&layer_name, stored), ("manifest.json", manifest.as_bytes())])
+    }
+
+    /// An OCI layout of one image, without the blobs named in `missing`.
+    fn oci_layout(layer: &[u8], missing: &[&str]) -> Vec<u8> {
+        let config = config(&[layer]);
+        let manifest = serde_json::json!({
+            "schemaVersion": 2,
+            "config": {
+                "mediaType": "application/vnd.oci.image.config.v1+json",
+                "digest": sha(&config),
+                "size": config.len(),
+            },
+            "layers": [{
+                "mediaType": "application/vnd.oci.image.layer.v1.tar",
+                "digest": sha(layer),
+                "size": layer.len(),
+            }],
+        })
+        .to_string()
+        .into_bytes();
+        let index = serde_json::json!({
+            "schemaVersion": 2,
+            "manifests": [{
+                "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                "digest": sha(&manifest),
+                "size": manifest.len(),
+                "annotations": { REF_NAME: "1" },
+            }],
+        })
+        .to_string();
+        let blobs = [("config", config), ("layer", layer.to_vec()), ("manifest", manifest)];
+        let names: Vec<(String, Vec<u8>)> = blobs
+            .into_iter()
+            .filter(|(kind, _)| !missing.contains(kind))
+            .map(|(_, data)| (blob_path(&sha(&data)), data))
+            .collect();
+        let mut files: Vec<(&str, &[u8])> = vec![
+            ("oci-layout", br#"{"imageLayoutVersion":"1.0.0"}"#),
+            ("index.json", index.as_bytes()),
+        ];
+        files.extend(names.iter().map(|(n, d)| (n.as_str(), d.as_slice())));
+        tar(&files)
+    }
+
+    #[test]
+    fn valid_docker_archive() {
+        let report = inspect(Cursor::new(docker_archive(b"layer bytes", b"layer bytes"))).unwrap();
+        assert_eq!(report.layout, "docker");
+        assert!(report.valid, "{:?}", report.problems);
+        assert_eq!(report.blobs_checked, 2);
+        assert_eq!(report.images[0].repo_tags, ["app:1"]);
+        assert_eq!(report.images[0].layers[0].digest, sha(b"layer bytes"));
+    }
+
+    #[test]
+    fn valid_oci_layout() {
+        let report = inspect(Cursor::new(oci_layout(b"layer bytes", &[]))).unwrap();
+        assert_eq!(report.layout, "oci");
+        assert!(report.valid, "{:?}", report.problems);
+        assert_eq!(report.blobs_checked, 3);
+        assert_eq!(report.images[0].repo_tags, ["1"]);
+        assert!(report.image_digest().is_some());
+    }
+
+    #[test]
+    fn truncated_tar_is_unreadable() {
+        let archive = docker_archive(b"layer bytes", b"layer bytes");
+        let truncated = &archive[..700];
+        assert!(matches!(inspect(Cursor::new(truncated)), Err(InspectError::Io(_))));
+        assert!(matches!(inspect(Cursor::new(tar(&[("README", b"hi")]))), Err(InspectError::UnknownLayout)));
+    }
+
+    #[test]
+    fn layer_not_matching_its_digest_is_reported() {
+        let report = inspect(Cursor::new(docker_archive(b"layer bytes", b"tampered!!!"))).unwrap();
+        assert!(!report.valid);
+        assert!(report.problems.iter().any(|p| p.contains("has digest")), "{:?}", report.problems);
+    }
+
+    #[test]
+    fn missing_blobs_are_reported() {
+        let report = inspect(Cursor::new(oci_layout(b"layer bytes", &["layer"]))).unwrap();
+        assert!(!report.valid);
+        assert_eq!(report.problems, [format!("blob {} is missing", sha(b"layer bytes"))]);
+
+        let report = inspect(Cursor::new(oci_layout(b"layer bytes", &["manifest"]))).unwrap();
+        assert!(!report.valid);
+        assert!(report.images.is_empty());
+    }
+
+    #[test]
+    fn json_kept_in_memory_is_bounded() {
+        let files: [(&str, &[u8]); 3] = [("a.json", b"{\"a\": 1}"), ("b.json", b"{\"b\": 2}"), ("c.json", b"[3]")];
+        let walk = walk_tar(Cursor::new(tar(&files)), 18).unwrap();
+        assert!(walk.get("a.json").unwrap().json.is_some());
+        assert!(walk.get("b.json").unwrap().json.is_some());
+        let c = walk.get("c.json").unwrap();
+        assert_eq!((c.json.as_ref(), c.digest.clone()), (None, sha(b"[3]")));
+    }
+
+    #[tokio::test]
+    async fn inspected_uploads_are_bounded() {
+        let config = "[puller]\nkind = \"native\"\n\n[artifacts]\nmax_upload_bytes = 4096\n";
+        let url = crate::tests::serve(crate::tests::state(toml::from_str(config).unwrap()).await).await;
+        let client = reqwest::Client::new();
+        let layer = vec![7u8; 4096];
+        let archive = docker_archive(&layer, &layer);
+        let inspect = |body: reqwest::Body| client.post(format!("{url}/api/inspect")).body(body).send();
+
+        let declared = inspect(archive.clone().into()).await.unwrap();
+        assert_eq!(declared.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        // Chunked, without a Content-Length.
+        let chunks = archive.chunks(1024).map(|c| Ok::<_, std::io::Error>(c.to_vec())).collect::<Vec<_>>();
+        let streamed = inspect(reqwest::Body::wrap_stream(tokio_stream::iter(chunks))).await.unwrap();
+        assert_eq!(streamed.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let small = tar(&[("README", b"hi")]);
+        assert_eq!(inspect(small.into()).await.unwrap().status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+}
@@ -0,0 +1,615 @@
+//! Jobs started through the streaming endpoints (pulls, conversions and
+//! repositories), and the runs of the syncs ([`crate::sync`]).
+//!
+//! Every event a job sends is written to the [`Store`] before it is relayed
+//! to the SSE client, so the job can be looked at after the connection
+//! closed, and from any replica when the store is shared. Jobs still running
+//! when their replica stops are marked failed, by the replica itself on its
+//! next start or by the others once it stopped refreshing its node entry.
+//!
+//! Each event carries its sequence number as SSE id. A client that lost the
+//! stream reattaches with `GET /api/jobs/:id/events`, which replays the stored
+//! events after `Last-Event-ID` and then follows the job until it ends.
+//! The WebSocket channel ([`crate::channel`]) follows jobs the same way.
+//!
+//! Once a job ended, its outcome is posted to the webhooks that want it
+//! ([`crate::webhooks`]).
+
+use crate::{
+    archive::ArchiveFormat,
+    artifacts::artifact_path,
+    bandwidth::Bandwidth,
+    audit::Caller,
+    cluster,
+    error::ApiError,
+    remove_staging,
+    skopeo::EventSender,
+    store::{JobFilter, Node, Outcome, Store},
+    webhooks::{Callback, Notifier},
+    AppState,
+};
+use axum::{
+    extract::{FromRequest, Json, Path, Query, Request, State},
+    http::{HeaderMap, HeaderName, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    sync::{broadcast, mpsc},
+    task::AbortHandle,
+};
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
+
+const DEFAULT_LIMIT: u32 = 100;
+const MAX_LIMIT: u32 = 1000;
+
+/// Response header of the streaming endpoints naming the job they started.
+pub const JOB_ID: HeaderName = HeaderName::from_static("x-job-id");
+
+/// Events kept for followers that fall behind; past that they are read
+/// again from the store.
+const FOLLOW_BUFFER: usize = 256;
+
+/// How often the store is read to follow a job running on another replica.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// SSE events of a job, as returned by the streaming endpoints.
+pub type EventStream = ReceiverStream<Result<Event, Infallible>>;
+
+/// One event of a job, as sent over SSE.
+#[derive(Debug, Clone)]
+pub struct JobEvent {
+    pub event: String,
+    pub data: String,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum JobKind {
+    Pull,
+    Convert,
+    Sync,
+    Repository,
+}
+
+impl JobKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            JobKind::Pull => "pull",
+            JobKind::Convert => "convert",
+            JobKind::Sync => "sync",
+            JobKind::Repository => "repository",
+        }
+    }
+}
+
+/// A job about to start; its id is the id its artifact will get.
+#[derive(Debug, Clone)]
+pub struct NewJob {
+    pub id: String,
+    pub kind: JobKind,
+    pub reference: String,
+    pub format: String,
+    pub owner: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Job {
+    pub id: String,
+    pub kind: String,
+    pub reference: String,
+    pub format: String,
+    pub owner: String,
+    /// `running`, `succeeded`, `failed` or `cancelled`.
+    pub state: String,
+    /// Problem JSON of a failed job.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub artifact_id: Option<String>,
+    pub created: DateTime<Utc>,
+    pub updated: DateTime<Utc>,
+    /// Replica running the job, or that ran it.
+    pub node: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StoredEvent {
+    pub seq: i64,
+    pub event: String,
+    pub data: String,
+    pub time: DateTime<Utc>,
+}
+
+/// What an event changes in the job row.
+fn outcome(event: &JobEvent) -> Option<Outcome> {
+    match event.event.as_str() {
+        "ready" => {
+            let artifact_id = serde_json::from_str::<serde_json::Value>(&event.data)
+                .ok()
+                .and_then(|v| v.get("id").and_then(|id| id.as_str()).map(str::to_string));
+            Some(Outcome { state: "succeeded", error: None, artifact_id })
+        }
+        "error" => Some(Outcome { state: "failed", error: Some(event.data.clone()), artifact_id: None }),
+        "cancelled" => Some(Outcome { state: "cancelled", error: None, artifact_id: None }),
+        _ => None,
+    }
+}
+
+fn sse_event(seq: i64, event: &JobEvent) -> Event {
+    Event::default().id(seq.to_string()).event(&event.event).data(&event.data)
+}
+
+/// An event with its sequence number in the job.
+type Numbered = (i64, JobEvent);
+
+/// A job running on this replica.
+struct Running {
+    abort: AbortHandle,
+    cancelled: CancellationToken,
+    /// Set when the puller can limit the job's bandwidth.
+    bandwidth: Option<Bandwidth>,
+}
+
+#[derive(Clone)]
+pub struct Jobs {
+    store: Arc<dyn Store>,
+    node: Arc<Node>,
+    running: Arc<Mutex<HashMap<String, Running>>>,
+    /// Events of the jobs of this replica, as they are stored.
+    live: Arc<Mutex<HashMap<String, broadcast::Sender<Numbered>>>>,
+    webhooks: Notifier,
+}
+
+/// A recorded job whose work is about to be spawned.
+pub struct JobHandle {
+    jobs: Jobs,
+    id: String,
+    tx: EventSender,
+    events: EventStream,
+    cancelled: CancellationToken,
+    bandwidth: Option<Bandwidth>,
+}
+
+impl JobHandle {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Sender for the job's events.
+    pub fn sender(&self) -> EventSender {
+        self.tx.clone()
+    }
+
+    /// Lets the job's bandwidth limit be changed while it runs.
+    pub fn limit_bandwidth(&mut self, bandwidth: Bandwidth) {
+        self.bandwidth = Some(bandwidth);
+    }
+
+    /// Runs `work` until it is done or the job is cancelled, and returns the
+    /// SSE stream of the job's events.
+    pub fn spawn<F>(self, work: F) -> EventStream
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let mut running = self.jobs.running.lock().unwrap_or_else(|e| e.into_inner());
+        let task = tokio::spawn(work);
+        let job = Running { abort: task.abort_handle(), cancelled: self.cancelled, bandwidth: self.bandwidth };
+        running.insert(self.id.clone(), job);
+        drop(running);
+        let (jobs, id) = (self.jobs, self.id);
+        tokio::spawn(async move {
+            let _ = task.await;
+            jobs.running.lock().unwrap_or_else(|e| e.into_inner()).remove(&id);
+        });
+        self.events
+    }
+}
+
+impl Jobs {
+    pub fn new(store: Arc<dyn Store>, node: Arc<Node>, webhooks: Notifier) -> Self {
+        Self { store, node, running: Arc::default(), live: Arc::default(), webhooks }
+    }
+
+    /// Records `job`, unless `max_running` jobs already run. Its events are
+    /// stored, then relayed to the SSE stream; they keep being stored if the
+    /// client goes away. Its outcome is also posted to `callback`.
+    pub async fn start(
+        &self,
+        job: NewJob,
+        max_running: u32,
+        callback: Option<Callback>,
+    ) -> Result<JobHandle, ApiError> {
+        if let Some(callback) = &callback {
+            self.webhooks.check_callback(callback)?;
+        }
+        self.store.create_job(&job, &self.node.id, max_running).await?;
+        let (tx, mut rx) = mpsc::channel::<JobEvent>(64);
+        let (sse, sse_rx) = mpsc::channel::<Result<Event, Infallible>>(64);
+        let cancelled = CancellationToken::new();
+        let (store, token) = (self.store.clone(), cancelled.clone());
+        let (id, format) = (job.id.clone(), job.format);
+        let (live, _) = broadcast::channel(FOLLOW_BUFFER);
+        self.live.lock().unwrap_or_else(|e| e.into_inner()).insert(id.clone(), live.clone());
+        let followed = self.live.clone();
+        let webhooks = self.webhooks.clone();
+        tokio::spawn(
+            async move {
+                let mut seq = 0;
+                let mut finished = false;
+                loop {
+                    // Tasks of the job may still hold a sender after it was
+                    // cancelled: stop listening right away.
+                    let event = tokio::select! {
+                        event = rx.recv() => event,
+                        _ = token.cancelled() => None,
+                    };
+                    let Some(event) = event else { break };
+                    seq += 1;
+                    let outcome = outcome(&event);
+                    finished |= outcome.is_some();
+                    if let Err(e) = store.append_event(&id, seq, &event, outcome).await {
+                        tracing::warn!(job = %id, "jobs: cannot record event: {}", e);
+                    }
+                    let _ = sse.send(Ok(sse_event(seq, &event))).await;
+                    let _ = live.send((seq, event));
+                }
+                if !finished {
+                    let event = if token.is_cancelled() {
+                        remove_partial(&id, &format).await;
+                        JobEvent { event: "cancelled".into(), data: "cancelled".into() }
+                    } else {
+                        let err = ApiError::Internal("the job stopped without a result".into());
+                        JobEvent { event: "error".into(), data: problem_json(&err) }
+                    };
+                    seq += 1;
+                    if let Err(e) = store.append_event(&id, seq, &event, outcome(&event)).await {
+                        tracing::warn!(job = %id, "jobs: cannot record event: {}", e);
+                    }
+                    let _ = sse.send(Ok(sse_event(seq, &event))).await;
+                    let _ = live.send((seq, event));
+                }
+                // Followers see the stream end once the last sender is gone.
+                followed.lock().unwrap_or_else(|e| e.into_inner()).remove(&id);
+                drop((live, sse));
+                webhooks.job_ended(&id, callback).await;
+            }
+            .instrument(tracing::Span::current()),
+        );
+        let events = ReceiverStream::new(sse_rx);
+        Ok(JobHandle { jobs: self.clone(), id: job.id, tx, events, cancelled, bandwidth: None })
+    }
+
+    /// Live events of job `id`, if it runs on this replica.
+    fn subscribe(&self, id: &str) -> Option<broadcast::Receiver<Numbered>> {
+        self.live.lock().unwrap_or_else(|e| e.into_inner()).get(id).map(|live| live.subscribe())
+    }
+
+    /// Sends the events of `job` after `after` to `tx`, as made by `wrap`,
+    /// until the job ends or `tx` is closed. Jobs of this replica are
+    /// followed live, those of another one by reading the shared store.
+    pub(crate) async fn follow<T, W>(&self, job: &Job, after: i64, tx: mpsc::Sender<T>, wrap: W)
+    where
+        T: Send,
+        W: Fn(i64, JobEvent) -> T + Send + Sync,
+    {
+        // Subscribe first: what is stored after the store is read is still
+        // received live.
+        let live = self.subscribe(&job.id);
+        let mut last = after;
+        if !self.replay(&job.id, &tx, &wrap, &mut last).await {
+            return;
+        }
+        if let Some(mut live) = live {
+            loop {
+                let received = tokio::select! {
+                    received = live.recv() => received,
+                    _ = tx.closed() => return,
+                };
+                match received {
+                    Ok((seq, event)) if seq > last => {
+                        last = seq;
+                        if tx.send(wrap(seq, event)).await.is_err() {
+                            return;
+                        }
+                    }
+                    Ok(_) => {}
+                    // Fell behind: what was missed is in the store.
+                    Err(broadcast::error::RecvError::Lagged(_)) => {
+                        if !self.replay(&job.id, &tx, &wrap, &mut last).await {
+                            return;
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return,
+                }
+            }
+        }
+        if job.state != "running" || job.node == self.node.id {
+            return;
+        }
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(POLL_INTERVAL) => {}
+                _ = tx.closed() => return,
+            }
+            // Read the state first: the events read next include the last.
+            let running = matches!(self.store.job(&job.id).await, Ok(Some(job)) if job.state == "running");
+            if !self.replay(&job.id, &tx, &wrap, &mut last).await || !running {
+                return;
+            }
+        }
+    }
+
+    /// Sends the stored events of job `id` after `last`; false once `tx` is
+    /// closed or the store cannot be read.
+    async fn replay<T, W>(&self, id: &str, tx: &mpsc::Sender<T>, wrap: &W, last: &mut i64) -> bool
+    where
+        W: Fn(i64, JobEvent) -> T,
+    {
+        let stored = match self.store.job_events(id).await {
+            Ok(stored) => stored,
+            Err(e) => {
+                tracing::warn!(job = %id, "jobs: cannot read events: {}", e);
+                return false;
+            }
+        };
+        let after = *last;
+        for event in stored.into_iter().filter(|e| e.seq > after) {
+            *last = event.seq;
+            let seq = event.seq;
+            if tx.send(wrap(seq, JobEvent { event: event.event, data: event.data })).await.is_err() {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Stops job `id`, which runs on this replica.
+    pub(crate) fn cancel(&self, id: &str) -> Result<(), ApiError> {
+        let running = self.running.lock().unwrap_or_else(|e| e.into_inner());
+        let job = running.get(id).ok_or_else(|| ApiError::InvalidRequest(format!("job {id} is not running")))?;
+        job.cancelled.cancel();
+        job.abort.abort();
+        Ok(())
+    }
+
+    /// Changes the bandwidth limit of job `id`, which runs on this replica.
+    pub(crate) fn set_bandwidth(&self, id: &str, limit: Option<u64>) -> Result<(), ApiError> {
+        let running = self.running.lock().unwrap_or_else(|e| e.into_inner());
+        let job = running.get(id).ok_or_else(|| ApiError::InvalidRequest(format!("job {id} is not running")))?;
+        let Some(bandwidth) = &job.bandwidth else {
+            return Err(ApiError::InvalidRequest(format!(
+                "job {id} cannot be slowed down: its puller does not limit bandwidth"
+            )));
+        };
+        bandwidth.set(limit);
+        Ok(())
+    }
+
+    /// Marks as failed the jobs left running by replicas that are gone and,
+    /// at startup, by this one; their partial files here are removed.
+    pub async fn fail_orphans(&self, startup: bool) -> Result<usize, ApiError> {
+        let err = ApiError::Interrupted("the server restarted while the job was running".into());
+        let restarted = startup.then_some(self.node.id.as_str());
+        let jobs = self.store.fail_orphans(restarted, &problem_json(&err)).await?;
+        for job in &jobs {
+            tracing::warn!(job = %job.id, node = %job.node, reference = %job.reference, "jobs: interrupted, marked failed");
+            if job.node == self.node.id || !self.store.shared() {
+                remove_partial(&job.id, &job.format).await;
+            }
+            // The callback of the job was only known to its replica.
+            self.webhooks.job_ended(&job.id, None).await;
+        }
+        Ok(jobs.len())
+    }
+
+    /// Forgets finished jobs last updated before `before`.
+    pub async fn purge(&self, before: DateTime<Utc>) -> Result<usize, ApiError> {
+        self.store.purge_jobs(before).await
+    }
+}
+
+/// Response of a streaming endpoint: the SSE events of job `id`.
+pub fn stream(id: &str, events: EventStream) -> Response {
+    let sse = Sse::new(events).keep_alive(KeepAlive::new().interval(Duration::from_secs(15)));
+    ([(JOB_ID, id.to_string())], sse).into_response()
+}
+
+/// SSE stream made of the single `error` event of a job that was refused.
+pub fn refused(err: &ApiError) -> EventStream {
+    let (tx, rx) = mpsc::channel(1);
+    let _ = tx.try_send(Ok(Event::default().event("error").data(problem_json(err))));
+    ReceiverStream::new(rx)
+}
+
+/// Removes what an unfinished job left in the temp directory.
+pub(crate) async fn remove_partial(id: &str, format: &str) {
+    let out = artifact_path(id);
+    if let Ok(fmt) = format.parse::<ArchiveFormat>() {
+        remove_staging(&fmt.staging_path(&out)).await;
+    }
+    let _ = tokio::fs::remove_file(&out).await;
+    let _ = tokio::fs::remove_file(std::env::temp_dir().join(format!("convert-{id}.tar"))).await;
+}
+
+fn problem_json(err: &ApiError) -> String {
+    serde_json::to_string(&err.problem()).unwrap_or_default()
+}
+
+#[derive(Deserialize)]
+pub struct JobsQuery {
+    state: Option<String>,
+    limit: Option<u32>,
+}
+
+/// Whose jobs `caller` may see: everyone's if they may read the audit log,
+/// else only their own.
+pub(crate) fn visible_owner(state: &AppState, caller: &Caller) -> Option<String> {
+    let settings = state.config.current();
+    match settings.policy.check_audit(&caller.principal) {
+        Ok(()) => None,
+        Err(_) => Some(caller.principal.name.clone()),
+    }
+}
+
+pub async fn list_jobs(State(state): State<AppState>, caller: Caller, Query(query): Query<JobsQuery>) -> Response {
+    if let Some(s) = query.state.as_deref() {
+        if !matches!(s, "running" | "succeeded" | "failed" | "cancelled") {
+            return ApiError::InvalidRequest(format!("unknown state '{s}'")).into_response();
+        }
+    }
+    let filter = JobFilter {
+        owner: visible_owner(&state, &caller),
+        state: query.state,
+        limit: query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT),
+    };
+    match state.jobs.store.jobs(filter).await {
+        Ok(jobs) => Json(jobs).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Job `id` if `caller` may see it.
+pub(crate) async fn visible_job(state: &AppState, caller: &Caller, id: &str) -> Result<Job, ApiError> {
+    let owner = visible_owner(state, caller);
+    match state.jobs.store.job(id).await? {
+        Some(job) if owner.as_ref().is_none_or(|o| *o == job.owner) => Ok(job),
+        _ => Err(ApiError::NotFound(format!("job not found: {id}"))),
+    }
+}
+
+pub async fn get_job(State(state): State<AppState>, caller: Caller, Path(id): Path<String>) -> Response {
+    let job = match visible_job(&state, &caller, &id).await {
+        Ok(job) => job,
+        Err(e) => return e.into_response(),
+    };
+    match state.jobs.store.job_events(&id).await {
+        Ok(events) => Json(serde_json::json!({ "job": job, "events": events })).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Where a command on a running job is carried out.
+pub(crate) enum Target {
+    Here,
+    /// On the replica running the job.
+    Replica(String),
+}
+
+/// Where job `id`, which must be running and visible to `caller`, runs.
+pub(crate) async fn running_job(state: &AppState, caller: &Caller, id: &str) -> Result<Target, ApiError> {
+    let job = visible_job(state, caller, id).await?;
+    if job.state != "running" {
+        return Err(ApiError::InvalidRequest(format!("job {} is not running ({})", id, job.state)));
+    }
+    if job.node != state.jobs.node.id && state.jobs.store.shared() {
+        return Ok(Target::Replica(job.node));
+    }
+    Ok(Target::Here)
+}
+
+/// Cancels a running job, forwarding the request to the replica running it.
+pub async fn cancel_job(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path(id): Path<String>,
+    request: Request,
+) -> Response {
+    match running_job(&state, &caller, &id).await {
+        Err(e) => e.into_response(),
+        Ok(Target::Replica(node)) => cluster::forward(&state, &node, request).await,
+        Ok(Target::Here) => match state.jobs.cancel(&id) {
+            Ok(()) => {
+                tracing::info!(job = %id, user = %caller.principal.name, "jobs: cancelled");
+                StatusCode::ACCEPTED.into_response()
+            }
+            Err(e) => e.into_response(),
+        },
+    }
+}
+
+#[derive(Deserialize)]
+pub struct BandwidthBody {
+    /// Bytes per second; none lifts the limit.
+    pub limit: Option<u64>,
+}
+
+/// Changes the bandwidth limit of a running pull, forwarding the request to
+/// the replica running it.
+pub async fn set_job_bandwidth(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path(id): Path<String>,
+    request: Request,
+) -> Response {
+    match running_job(&state, &caller, &id).await {
+        Err(e) => e.into_response(),
+        Ok(Target::Replica(node)) => cluster::forward(&state, &node, request).await,
+        Ok(Target::Here) => {
+            let body = match Json::<BandwidthBody>::from_request(request, &state).await {
+                Ok(Json(body)) => body,
+                Err(rejection) => return ApiError::InvalidRequest(rejection.body_text()).into_response(),
+            };
+            match state.jobs.set_bandwidth(&id, body.limit) {
+                Ok(()) => StatusCode::NO_CONTENT.into_response(),
+                Err(e) => e.into_response(),
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct EventsQuery {
+    /// Same as `Last-Event-ID`, for clients that cannot set headers.
+    after: Option<i64>,
+}
+
+/// Sequence number after which to replay, from `Last-Event-ID` or `after`.
+fn replay_after(headers: &HeaderMap, query: &EventsQuery) -> Result<i64, ApiError> {
+    match headers.get("last-event-id") {
+        Some(value) => value
+            .to_str()
+            .ok()
+            .and_then(|v| v.trim().parse().ok())
+            .ok_or_else(|| ApiError::InvalidRequest("Last-Event-ID must be an event id of the job".into())),
+        None => Ok(query.after.unwrap_or(0)),
+    }
+}
+
+/// Replays the events of job `id` after `Last-Event-ID`, then follows it
+/// until it ends. A running job is followed on the replica running it.
+pub async fn follow_job(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path(id): Path<String>,
+    Query(query): Query<EventsQuery>,
+    request: Request,
+) -> Response {
+    let job = match visible_job(&state, &caller, &id).await {
+        Ok(job) => job,
+        Err(e) => return e.into_response(),
+    };
+    let after = match replay_after(request.headers(), &query) {
+        Ok(after) => after,
+        Err(e) => return e.into_response(),
+    };
+    if job.state == "running" && job.node != state.jobs.node.id && state.jobs.store.shared() {
+        return cluster::forward(&state, &job.node, request).await;
+    }
+    let (tx, rx) = mpsc::channel(64);
+    let (jobs, id) = (state.jobs.clone(), job.id.clone());
+    tokio::spawn(async move { jobs.follow(&job, after, tx, |seq, event| Ok(sse_event(seq, &event))).await });
+    stream(&id, ReceiverStream::new(rx))
+}
@@ -1,67 +1,253 @@
+mod admission;
+mod archive;
+mod artifacts;
+mod audit;
+mod auth;
+mod bandwidth;
+mod channel;
+mod convert;
+mod checksum;
+mod classify;
+mod compression;
+mod cluster;
+mod config;
+mod cron;
+mod delta;
+mod error;
+mod inspect;
+mod jobs;
+mod metrics;
+mod policy;
+mod puller;
+mod reference;
+mod registry;
+mod repository;
+mod retry;
+mod signing;
+mod skopeo;
+mod store;
+#[cfg(unix)]
+mod streaming;
+mod sync;
+mod telemetry;
+mod tickets;
+mod timeouts;
+mod webhooks;
+
+use archive::ArchiveFormat;
+use bandwidth::Bandwidth;
+use artifacts::{artifact_path, Artifact, ArtifactStore, FinalizeError};
+use compression::{Compression, CompressionOptions, ExportCompression};
+use config::{Config, LiveConfig, Settings};
+use error::{send_error, ApiError};
+use jobs::{JobKind, Jobs, NewJob};
+use audit::{AuditLog, Caller, Produced, PullEntry};
+use metrics::PullMetrics;
+use policy::Action;
+use puller::{Credentials, PullJob, Puller};
+use timeouts::{supervise, Activity, Limits};
+use signing::ManifestSigner;
+use store::{Node, Store};
+use webhooks::{Callback, Notifier};
 use axum::{
     body::Body,
     extract::{Json, Path, Query},
     http::{HeaderMap, HeaderValue, StatusCode},
-    response::IntoResponse,
-    response::sse::{Event, Sse},
+    response::{sse::Sse, IntoResponse, Response},
     routing::{get, post},
     Router,
 };
-use base64::Engine as _;
+use futures_util::StreamExt;
 use regex::Regex;
 use serde::Deserialize;
-use std::{env, path::PathBuf, time::Duration};
-use tokio::{fs, process::Command, time::timeout};
-use tokio::io::{AsyncBufReadExt, BufReader};
-use std::process::Stdio;
-use tokio::sync::mpsc;
-use tokio_stream::wrappers::ReceiverStream;
-use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::{env, path::PathBuf, sync::Arc, time::Duration};
+use tokio::{fs, time::timeout};
 use tokio_util::io::ReaderStream;
+use tracing::Instrument;
 use uuid::Uuid;
-#[cfg(unix)]
-use std::os::unix::fs::PermissionsExt;
 
 #[derive(Clone)]
 struct AppState {
-    skopeo_path: String,
     client: reqwest::Client,
+    artifacts: ArtifactStore,
+    jobs: Jobs,
+    store: Arc<dyn Store>,
+    /// This replica.
+    node: Arc<Node>,
+    signer: ManifestSigner,
+    config: LiveConfig,
+    audit: AuditLog,
 }
 
+pub(crate) const CHECKSUM_HEADER: &str = "x-checksum-sha256";
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    eprintln!("Starting helmer-api...");
-    let skopeo_path = env::var("SKOPEO_PATH").unwrap_or_else(|_| "skopeo".to_string());
-    eprintln!("Skopeo path: {}", skopeo_path);
+    telemetry::init()?;
+    let mut config_path = env::var("CONFIG_FILE").ok().filter(|v| !v.is_empty()).map(PathBuf::from);
+    let mut check_only = false;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" => config_path = Some(args.next().ok_or_else(|| anyhow::anyhow!("--config needs a path"))?.into()),
+            "--check-config" => check_only = true,
+            "--hash-api-key" => {
+                let mut key = String::new();
+                std::io::stdin().read_line(&mut key)?;
+                println!("{}", auth::hash_api_key(&key));
+                return Ok(());
+            }
+            other => anyhow::bail!(
+                "unknown argument '{}' (expected --config <path>, --check-config or --hash-api-key)",
+                other
+            ),
+        }
+    }
+
+    tracing::info!("starting helmer-api");
+    let config = Config::load(config_path.as_deref())?;
+    tracing::info!(
+        "config: {}",
+        config_path.as_deref().map(|p| p.display().to_string()).unwrap_or_else(|| "defaults and environment".into())
+    );
     let client = reqwest::Client::builder()
         .user_agent("helmer-api/0.1")
         .build()?;
-    eprintln!("HTTP client created");
+    let signer = match &config.signing.key_path {
+        Some(path) => ManifestSigner::from_file(path)?,
+        None => ManifestSigner::default(),
+    };
+    let port = config.server.port;
+    let settings = Settings::build(config, &client)?;
+    if check_only {
+        // The resolved configuration is not printed: it holds passwords and
+        // webhook secrets, and this runs in CI logs.
+        println!("configuration is valid");
+        return Ok(());
+    }
+
+    tracing::info!(
+        "manifest signing: {}",
+        signer.key_id().map(|id| format!("ed25519 key {id}")).unwrap_or_else(|| "disabled".into())
+    );
+    let config = LiveConfig::new(settings, config_path, client.clone());
+    let settings = config.current();
+    tracing::info!("puller: {}", settings.puller.name());
+    if !settings.auth.enabled() {
+        tracing::warn!("authentication disabled: anyone reaching the API can pull images");
+    }
+    tracing::info!(
+        "pull timeouts: job {}s (max {}s), idle {}s (max {}s)",
+        settings.timeouts.job.as_secs(),
+        settings.timeouts.max_job.as_secs(),
+        settings.timeouts.idle.as_secs(),
+        settings.timeouts.max_idle.as_secs()
+    );
+
+    let audit = AuditLog::open(&settings.config.audit)?;
+    match audit.path() {
+        Some(path) => tracing::info!("audit log: {}", path.display()),
+        None => tracing::warn!("audit log disabled (audit.path)"),
+    }
+    // Artifacts live in the temp directory, which may be a fresh volume.
+    std::fs::create_dir_all(env::temp_dir())?;
+    let database = &settings.config.database;
+    let store = store::open(database).await?;
+    let node = Arc::new(cluster::node(&settings.config.cluster));
+    match (&database.url, &database.path) {
+        (Some(_), _) => tracing::info!("database: {} shared by all replicas, this one is node {}", store.name(), node.id),
+        (None, Some(path)) => tracing::info!("database: {} {}", store.name(), path.display()),
+        (None, None) => tracing::warn!("database in memory (database.path): jobs and downloads are lost on restart"),
+    }
+    store.heartbeat(&node).await?;
+    let artifacts = ArtifactStore::new(store.clone(), node.clone());
+    let webhooks = Notifier::new(store.clone(), node.clone(), config.clone(), client.clone());
+    let jobs = Jobs::new(store.clone(), node.clone(), webhooks.clone());
+    // Before failing orphans, whose deliveries are sent right away.
+    webhooks.resume(true).await?;
+    jobs.fail_orphans(true).await?;
+    cluster::maintain(
+        store.clone(),
+        node.clone(),
+        artifacts.clone(),
+        jobs.clone(),
+        webhooks,
+        Duration::from_secs(database.job_retention_secs),
+    );
+
+    config.clone().watch();
+
+    let state = AppState {
+        client,
+        artifacts,
+        jobs,
+        store,
+        node,
+        signer,
+        config,
+        audit,
+    };
+    sync::schedule(state.clone());
 
-    let state = AppState { skopeo_path, client };
+    let app = router(state);
 
-    let app = Router::new()
+    let addr = std::net::SocketAddr::from(([0, 0, 0, 0], port));
+    tracing::info!("binding to {}", addr);
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    tracing::info!("listening on http://{}", addr);
+    axum::serve(listener, app.into_make_service_with_connect_info::<std::net::SocketAddr>()).await?;
+    tracing::info!("server stopped");
+    Ok(())
+}
+
+/// Every route: the API behind authentication, the probes and metrics open.
+fn router(state: AppState) -> Router {
+    // Requests about one artifact are served by the replica holding its file.
+    let held = Router::new()
+        .route("/api/pull/file/:id", get(download_file))
+        .route("/api/pull/file/:id/sha256sum", get(signing::sha256sum))
+        .route("/api/pull/file/:id/manifest", get(signing::signed_manifest))
+        .route("/api/artifacts/:id/verify", post(signing::verify_artifact))
+        .route("/api/artifacts/:id/inspect", get(inspect::inspect_artifact))
+        .route("/api/artifacts/:id/blobs", get(delta::list_blobs))
+        .route("/api/artifacts/:id/delta", post(delta::export_delta))
+        .route("/api/artifacts/:id/rebuild", post(delta::rebuild_artifact))
+        .route("/api/convert/stream", post(convert::convert_stream))
+        .route_layer(axum::middleware::from_fn_with_state(state.clone(), cluster::route_artifact));
+    let api = Router::new()
         .route("/api/fetchIndex", get(fetch_index))
         .route("/api/pull", get(pull_image).post(pull_image_post))
         .route("/api/pull/stream", post(pull_image_stream))
-        .route("/api/pull/file/:id", get(download_file))
+        .route("/api/repository/stream", post(repository::repository_stream))
+        .route("/api/artifacts", post(artifacts::upload_artifact))
+        .route("/api/inspect", post(inspect::inspect_upload))
+        .route("/api/audit", get(audit::query))
+        .route("/api/jobs", get(jobs::list_jobs))
+        .route("/api/jobs/:id", get(jobs::get_job))
+        .route("/api/jobs/:id/cancel", post(jobs::cancel_job))
+        .route("/api/jobs/:id/events", get(jobs::follow_job))
+        .route("/api/jobs/:id/bandwidth", post(jobs::set_job_bandwidth))
+        .route("/api/jobs/ws", get(channel::connect))
+        .route("/api/tickets", post(tickets::create_ticket))
+        .route("/api/webhooks/deliveries", get(webhooks::list_deliveries))
+        .route("/api/syncs", get(sync::list_syncs))
+        .route("/api/syncs/:name/runs", get(sync::list_runs))
+        .route("/api/syncs/:name/run", post(sync::run_sync))
+        .merge(held)
+        .route_layer(axum::middleware::from_fn_with_state(state.clone(), auth::authenticate))
+        // Public by nature: verifying a manifest needs no account.
+        .route("/api/signing-key", get(signing::public_key))
+        .with_state(state.clone());
+    // Probes and scrapes poll every few seconds: keep them out of the request log.
+    let probes = Router::new()
         .route("/health", get(health_check))
         .route("/ready", get(readiness_check))
         // API-prefixed aliases (for Docker healthchecks, etc.)
         .route("/api/health", get(health_check))
         .route("/api/ready", get(readiness_check))
+        .route("/metrics", get(metrics::render))
         .with_state(state);
-
-    let port = env::var("PORT").unwrap_or_else(|_| "8080".into()).parse::<u16>()?;
-    eprintln!("Parsed port: {}", port);
-    let addr = std::net::SocketAddr::from(([0, 0, 0, 0], port));
-    eprintln!("Binding to {}", addr);
-    let listener = tokio::net::TcpListener::bind(addr).await?;
-    eprintln!("listening on http://{}", addr);
-    axum::serve(listener, app).await?;
-    eprintln!("Server stopped");
-    Ok(())
+    telemetry::request_ids(telemetry::trace(api).merge(probes))
 }
 
 #[derive(Deserialize)]
@@ -71,15 +257,16 @@ struct FetchIndexParams {
 
 async fn fetch_index(
     axum::extract::State(state): axum::extract::State<AppState>,
+    caller: Caller,
     Query(params): Query<FetchIndexParams>,
 ) -> impl IntoResponse {
     // Validate URL: only http/https
     let Ok(mut url) = url::Url::parse(&params.url) else {
-        return (StatusCode::BAD_REQUEST, "Invalid URL").into_response();
+        return ApiError::InvalidRequest("invalid URL".into()).into_response();
     };
     match url.scheme() {
         "http" | "https" => {}
-        _ => return (StatusCode::BAD_REQUEST, "Invalid scheme").into_response(),
+        _ => return ApiError::InvalidRequest("invalid scheme".into()).into_response(),
     }
     // Normalize to index.yaml if not present
     let path = url.path().to_string();
@@ -88,19 +275,21 @@ async fn fetch_index(
         p.push_str("/index.yaml");
         url.set_path(&p);
     }
-
-    let res = state.client.get(url.clone()).send().await;
-    let Ok(resp) = res else {
-        return (StatusCode::BAD_GATEWAY, "Upstream fetch failed").into_response();
+    let fetched = fetch_chart_index(&state, &caller, &url).await;
+    let produced = fetched.as_ref().map(|text| Produced {
+        size: Some(text.len() as u64),
+        sha256: Some({
+            let mut sum = checksum::Sha256Sum::default();
+            sum.update(text.as_bytes());
+            sum.finish()
+        }),
+        ..Default::default()
+    });
+    state.audit.event(&caller, audit::Event::ChartIndex, url.as_str(), None, produced);
+    let text = match fetched {
+        Ok(text) => text,
+        Err(e) => return e.into_response(),
     };
-    if !resp.status().is_success() {
-        return (
-            StatusCode::BAD_GATEWAY,
-            format!("Upstream error: {}", resp.status()),
-        )
-            .into_response();
-    }
-    let text = resp.text().await.unwrap_or_default();
     let mut headers = HeaderMap::new();
     headers.insert(
         axum::http::header::CONTENT_TYPE,
@@ -109,6 +298,20 @@ async fn fetch_index(
     (StatusCode::OK, headers, text).into_response()
 }
 
+async fn fetch_chart_index(state: &AppState, caller: &Caller, url: &url::Url) -> Result<String, ApiError> {
+    state.config.current().policy.check_chart_repo(&caller.principal, url)?;
+    let started = std::time::Instant::now();
+    let res = state.client.get(url.clone()).send().await;
+    metrics::fetched_index(started, res.as_ref().ok().map(|r| r.status()));
+    let Ok(resp) = res else {
+        return Err(ApiError::Upstream("upstream fetch failed".into()));
+    };
+    if !resp.status().is_success() {
+        return Err(ApiError::Upstream(format!("upstream error: {}", resp.status())));
+    }
+    Ok(resp.text().await.unwrap_or_default())
+}
+
 #[derive(Deserialize)]
 struct PullParams {
     r#ref: String,
@@ -118,6 +321,18 @@ struct PullParams {
     username: Option<String>,
     #[serde(default)]
     password: Option<String>,
+    #[serde(default)]
+    compression: Option<String>,
+    #[serde(default)]
+    compression_level: Option<i32>,
+    #[serde(default)]
+    layer_compression: Option<String>,
+    /// Seconds for the whole pull, clamped to the admin maximum.
+    #[serde(default)]
+    timeout: Option<u64>,
+    /// Seconds without progress before the pull is abandoned.
+    #[serde(default)]
+    idle_timeout: Option<u64>,
 }
 
 #[derive(Deserialize)]
@@ -129,13 +344,32 @@ struct PullRequestBody {
     username: Option<String>,
     #[serde(default)]
     password: Option<String>,
+    #[serde(default)]
+    compression: Option<String>,
+    #[serde(default)]
+    compression_level: Option<i32>,
+    #[serde(default)]
+    layer_compression: Option<String>,
+    /// Seconds for the whole pull, clamped to the admin maximum.
+    #[serde(default)]
+    timeout: Option<u64>,
+    /// Seconds without progress before the pull is abandoned.
+    #[serde(default)]
+    idle_timeout: Option<u64>,
+    /// Bytes per second, for pullers that can limit it; can be changed
+    /// while the job runs.
+    #[serde(default)]
+    bandwidth: Option<u64>,
+    /// Where to post the outcome of the job.
+    #[serde(default)]
+    callback: Option<Callback>,
 }
 
 fn default_format() -> String {
     "docker-archive".to_string()
 }
 
-fn valid_ref(s: &str) -> bool {
+pub(crate) fn valid_ref(s: &str) -> bool {
     // letters, digits, slash, dot, colon, @, underscore, dash
     // same as JS: /^[A-Za-z0-9./:@_\-]+$/
     static PATTERN: once_cell::sync::Lazy<Regex> = once_cell::sync::Lazy::new(|| {
@@ -144,7 +378,7 @@ fn valid_ref(s: &str) -> bool {
     PATTERN.is_match(s)
 }
 
-fn parse_repo_tag(reference: &str) -> (String, String) {
+pub(crate) fn parse_repo_tag(reference: &str) -> (String, String) {
     // Remove transport prefix if any (e.g., docker://)
     let ref_no_transport = reference
         .strip_prefix("docker://")
@@ -158,85 +392,47 @@ fn parse_repo_tag(reference: &str) -> (String, String) {
     };
 
     if let Some((name_part, _digest)) = after_registry.split_once('@') {
-        let repo = name_part.split('/').last().unwrap_or("image").to_string();
+        let repo = name_part.split('/').next_back().unwrap_or("image").to_string();
         return (repo, "latest".to_string());
     }
 
     if let Some(i) = after_registry.rfind(':') {
         let name_part = &after_registry[..i];
         let tag = &after_registry[i + 1..];
-        let repo = name_part.split('/').last().unwrap_or("image").to_string();
+        let repo = name_part.split('/').next_back().unwrap_or("image").to_string();
         (repo, if tag.is_empty() { "latest".into() } else { tag.into() })
     } else {
-        let repo = after_registry.split('/').last().unwrap_or("image").to_string();
+        let repo = after_registry.split('/').next_back().unwrap_or("image").to_string();
         (repo, "latest".into())
     }
 }
 
-fn extract_registry(reference: &str) -> String {
-    let ref_no_transport = reference
-        .strip_prefix("docker://")
-        .or_else(|| reference.split_once("://").map(|(_, r)| r))
-        .unwrap_or(reference);
-
-    let first = ref_no_transport
-        .split('/')
-        .next()
-        .unwrap_or("docker.io")
-        .trim();
-
-    if first.contains('.') || first.contains(':') || first == "localhost" {
-        first.to_string()
-    } else {
-        "docker.io".to_string()
-    }
-}
-
-async fn maybe_write_authfile(
-    reference: &str,
-    username: Option<&str>,
-    password: Option<&str>,
-) -> std::io::Result<Option<PathBuf>> {
-    let Some(username) = username.map(str::trim).filter(|v| !v.is_empty()) else {
-        return Ok(None);
-    };
-    let Some(password) = password.map(str::trim).filter(|v| !v.is_empty()) else {
-        return Ok(None);
-    };
-
-    let registry = extract_registry(reference);
-    let auth = base64::engine::general_purpose::STANDARD.encode(format!("{username}:{password}"));
-
-    let mut auths = serde_json::Map::new();
-    auths.insert(registry.clone(), serde_json::json!({ "auth": auth.clone() }));
-    if registry == "docker.io" {
-        auths.insert("https://index.docker.io/v1/".to_string(), serde_json::json!({ "auth": auth }));
-    }
-
-    let auth_json = serde_json::json!({ "auths": auths }).to_string();
-    let auth_path = std::env::temp_dir().join(format!("skopeo-auth-{}.json", Uuid::new_v4()));
-    fs::write(&auth_path, auth_json).await?;
-
-    #[cfg(unix)]
-    {
-        let perms = std::fs::Permissions::from_mode(0o600);
-        let _ = fs::set_permissions(&auth_path, perms).await;
-    }
-
-    Ok(Some(auth_path))
-}
-
 // GET endpoint (backwards compatible, credentials in query params - less secure)
 async fn pull_image(
     axum::extract::State(state): axum::extract::State<AppState>,
+    caller: Caller,
     Query(params): Query<PullParams>,
 ) -> impl IntoResponse {
+    let entry = state.audit.pull(caller, &params.r#ref, &params.format);
+    let compression = match export_compression(
+        &params.format,
+        params.compression.as_deref(),
+        params.compression_level,
+        params.layer_compression.as_deref(),
+    ) {
+        Ok(c) => c,
+        Err(e) => return rejected(entry, ApiError::InvalidRequest(e)),
+    };
+    let settings = state.config.current();
+    let limits = settings.timeouts.for_request(params.timeout, params.idle_timeout);
     do_pull_image(
-        state,
+        &state,
+        entry,
         params.r#ref,
         params.format,
-        params.username,
-        params.password,
+        Credentials::from_parts(params.username.as_deref(), params.password.as_deref()),
+        compression,
+        limits,
     )
     .await
 }
@@ -244,140 +440,146 @@ async fn pull_image(
 // POST endpoint with secure credentials in body
 async fn pull_image_post(
     axum::extract::State(state): axum::extract::State<AppState>,
+    caller: Caller,
     Json(body): Json<PullRequestBody>,
 ) -> impl IntoResponse {
+    let entry = state.audit.pull(caller, &body.r#ref, &body.format);
+    let compression = match export_compression(
+        &body.format,
+        body.compression.as_deref(),
+        body.compression_level,
+        body.layer_compression.as_deref(),
+    ) {
+        Ok(c) => c,
+        Err(e) => return rejected(entry, ApiError::InvalidRequest(e)),
+    };
+    let settings = state.config.current();
+    let limits = settings.timeouts.for_request(body.timeout, body.idle_timeout);
     do_pull_image(
-        state,
+        &state,
+        entry,
         body.r#ref,
         body.format,
-        body.username,
-        body.password,
+        Credentials::from_parts(body.username.as_deref(), body.password.as_deref()),
+        compression,
+        limits,
     )
     .await
 }
 
-fn temp_tar_path(id: &str) -> std::path::PathBuf {
-    std::env::temp_dir().join(format!("pull-{}.tar", id))
+fn export_compression(
+    format: &str,
+    compression: Option<&str>,
+    level: Option<i32>,
+    layers: Option<&str>,
+) -> Result<ExportCompression, String> {
+    let fmt = format.parse::<ArchiveFormat>().unwrap_or_default();
+    ExportCompression::parse(fmt, compression, level, layers)
 }
 
-fn make_filename(repo: &str, tag: &str, fmt: &str) -> String {
-    format!("{}-{}-{}.tar", repo, tag, fmt)
+pub(crate) fn make_filename(repo: &str, tag: &str, fmt: &str, compression: Compression) -> String {
+    format!("{}-{}-{}.tar{}", repo, tag, fmt, compression.extension())
 }
 
 // Common implementation for both GET and POST
 async fn do_pull_image(
-    state: AppState,
+    state: &AppState,
+    entry: PullEntry,
     reference: String,
     format: String,
-    username: Option<String>,
-    password: Option<String>,
+    credentials: Option<Credentials>,
+    compression: ExportCompression,
+    limits: Limits,
 ) -> axum::response::Response {
+    let settings = state.config.current();
     // Validate reference
     if reference.trim().is_empty() {
-        return (StatusCode::BAD_REQUEST, "Missing ref").into_response();
+        return rejected(entry, ApiError::InvalidReference("missing ref".into()));
     }
 
     if !valid_ref(&reference) {
-        return (StatusCode::BAD_REQUEST, "Invalid ref").into_response();
+        return rejected(entry, ApiError::InvalidReference(reference));
+    }
+    if let Err(e) = settings
+        .admission
+        .check(&reference)
+        .and_then(|()| settings.policy.check(entry.principal(), Action::Pull, &reference))
+    {
+        return rejected(entry, e);
     }
 
-    let fmt = match format.as_str() {
-        "docker-archive" | "oci-archive" => format.clone(),
-        _ => "docker-archive".to_string(),
+    // Recorded as a job, so that it counts towards `max_running` like the
+    // streamed pulls.
+    let uid = Uuid::new_v4().to_string();
+    let job = NewJob {
+        id: uid.clone(),
+        kind: JobKind::Pull,
+        reference: reference.clone(),
+        format: format.clone(),
+        owner: entry.principal().name.clone(),
+    };
+    let handle = match state.jobs.start(job, settings.config.jobs.max_running, None).await {
+        Ok(handle) => handle,
+        Err(e) => return rejected(entry, e),
     };
 
-    let uid = Uuid::new_v4().to_string();
-    let tmp_tar = temp_tar_path(&uid);
+    let fmt = format.parse::<ArchiveFormat>().unwrap_or_default();
+    let credentials = credentials.or_else(|| settings.credentials_for(entry.principal(), &reference));
+    let record = PullRecord { metrics: PullMetrics::start(&reference), audit: entry };
+    let pull = DirectPull { record, job: handle.sender() };
+    skopeo::send_event(&pull.job, "start", "starting").await;
+
+    let tmp_tar = artifact_path(&uid);
+    let staging = fmt.staging_path(&tmp_tar);
     let (repo, tag) = parse_repo_tag(&reference);
-    let dest = format!("{}:{}:{}:{}", fmt, tmp_tar.display(), repo, tag);
-    let authfile_path = match maybe_write_authfile(
-        &reference,
-        username.as_deref(),
-        password.as_deref(),
-    )
-    .await
-    {
-        Ok(path) => path,
-        Err(e) => {
-            let _ = fs::remove_file(&tmp_tar).await;
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Failed to prepare auth file: {e}"),
-            )
-                .into_response();
-        }
+    let job = PullJob {
+        reference: reference.clone(),
+        format: fmt,
+        staging: staging.clone(),
+        repo: repo.clone(),
+        tag: tag.clone(),
+        credentials,
+        layer_compression: compression.layers,
+        activity: Activity::default(),
+        bandwidth: Bandwidth::default(),
     };
 
-    let mut cmd = Command::new(&state.skopeo_path);
-    cmd.arg("copy");
-
-    if let Some(path) = &authfile_path {
-        cmd.arg("--src-authfile").arg(path);
+    // docker-archive is streamed as it is written. skopeo stages oci-archive
+    // in a temporary directory anyway, and directory layouts have to be
+    // complete before they can be packed: those are served once finished,
+    // with their checksum in a header rather than a trailer.
+    #[cfg(unix)]
+    if fmt == ArchiveFormat::DockerArchive {
+        let filename = make_filename(&repo, &tag, fmt.as_str(), compression.archive.kind);
+        return stream_pull_image(settings.puller.clone(), job, compression, filename, limits, pull).await;
     }
 
-    cmd.arg(format!("docker://{}", reference)).arg(&dest);
-
-    let result = timeout(Duration::from_secs(300), cmd.output()).await;
-    let output = match result {
-        Err(_) => {
-            let _ = fs::remove_file(&tmp_tar).await;
-            if let Some(path) = &authfile_path {
-                let _ = fs::remove_file(path).await;
-            }
-            return (StatusCode::GATEWAY_TIMEOUT, format!("Timeout while copying image: {}", reference)).into_response();
+    match supervise(settings.puller.pull(&job, None), limits, &job.activity, &staging).await {
+        Err(expired) => {
+            remove_staging(&staging).await;
+            return pull_failed(pull, ApiError::Timeout(expired.describe(&reference))).await;
         }
         Ok(Err(e)) => {
-            let _ = fs::remove_file(&tmp_tar).await;
-            if let Some(path) = &authfile_path {
-                let _ = fs::remove_file(path).await;
-            }
-            if e.kind() == std::io::ErrorKind::NotFound {
-                return (StatusCode::NOT_IMPLEMENTED, "skopeo not found (ENOENT)").into_response();
-            }
-            return (
-                StatusCode::BAD_GATEWAY,
-                format!("Failed to spawn skopeo: {}", e),
-            )
-                .into_response();
-        }
-        Ok(Ok(out)) => out,
-    };
-    if let Some(path) = &authfile_path {
-        let _ = fs::remove_file(path).await;
-    }
-
-    if !output.status.success() {
-        let _ = fs::remove_file(&tmp_tar).await;
-        let stderr = String::from_utf8_lossy(&output.stderr).to_lowercase();
-        if stderr.contains("manifest unknown")
-            || stderr.contains("not found")
-            || stderr.contains("name unknown")
-        {
-            return (StatusCode::NOT_FOUND, format!("Image not found: {}", reference)).into_response();
+            remove_staging(&staging).await;
+            return pull_failed(pull, ApiError::pull(&reference, e)).await;
         }
-        if stderr.contains("denied")
-            || stderr.contains("unauthorized")
-            || stderr.contains("authentication required")
-        {
-            return (StatusCode::FORBIDDEN, format!("Access denied to registry for: {}", reference)).into_response();
-        }
-        return (
-            StatusCode::BAD_GATEWAY,
-            format!("skopeo error for {}: {}", reference, String::from_utf8_lossy(&output.stderr)),
-        )
-            .into_response();
+        Ok(Ok(())) => {}
     }
 
+    let finalized = match artifacts::finalize(fmt, &staging, &tmp_tar, compression.archive).await {
+        Ok(f) => f,
+        Err(FinalizeError::Invalid(msg)) => {
+            return pull_failed(pull, ApiError::CorruptImage(format!("{} ({})", msg, reference))).await;
+        }
+        Err(e) => return pull_failed(pull, ApiError::from(e)).await,
+    };
+
     // Get file size for Content-Length header
     let file_size = match fs::metadata(&tmp_tar).await {
         Ok(meta) => meta.len(),
         Err(e) => {
             let _ = fs::remove_file(&tmp_tar).await;
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Failed to get file metadata: {}", e),
-            )
-                .into_response();
+            return pull_failed(pull, ApiError::io("failed to get file metadata", e)).await;
         }
     };
 
@@ -386,11 +588,7 @@ async fn do_pull_image(
         Ok(f) => f,
         Err(e) => {
             let _ = fs::remove_file(&tmp_tar).await;
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Failed to open archive: {}", e),
-            )
-                .into_response();
+            return pull_failed(pull, ApiError::io("failed to open archive", e)).await;
         }
     };
 
@@ -400,19 +598,20 @@ async fn do_pull_image(
 
     // Schedule file deletion after a delay to allow streaming to complete
     let tmp_clone = tmp_tar.clone();
+    let ttl = settings.artifact_ttl;
     tokio::spawn(async move {
         // Give generous time for streaming to finish before cleanup
-        tokio::time::sleep(Duration::from_secs(600)).await;
+        tokio::time::sleep(ttl).await;
         let _ = fs::remove_file(&tmp_clone).await;
     });
 
     // Generate filename
-    let filename = make_filename(&repo, &tag, &fmt);
+    let filename = make_filename(&repo, &tag, fmt.as_str(), compression.archive.kind);
 
     let mut headers = HeaderMap::new();
     headers.insert(
         axum::http::header::CONTENT_TYPE,
-        HeaderValue::from_static("application/x-tar"),
+        HeaderValue::from_static(compression.archive.kind.content_type()),
     );
     headers.insert(
         axum::http::header::CONTENT_LENGTH,
@@ -423,10 +622,158 @@ async fn do_pull_image(
         HeaderValue::from_str(&format!("attachment; filename=\"{}\"", filename)).unwrap_or(HeaderValue::from_static("attachment")),
     );
     headers.insert(axum::http::header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    if let Ok(v) = HeaderValue::from_str(&finalized.sha256) {
+        headers.insert(CHECKSUM_HEADER, v);
+    }
+
+    pull.succeeded(Produced {
+        digest: finalized.report.image_digest(),
+        size: Some(file_size),
+        sha256: Some(finalized.sha256),
+        artifact_id: None,
+    })
+    .await;
+    (StatusCode::OK, headers, body).into_response()
+}
+
+/// The metrics and audit record of one pull, closed together.
+pub(crate) struct PullRecord {
+    pub metrics: PullMetrics,
+    pub audit: PullEntry,
+}
+
+impl PullRecord {
+    fn succeeded(self, produced: Produced) {
+        self.metrics.succeeded();
+        self.audit.succeeded(produced);
+    }
+
+    fn failed(self, err: &ApiError) {
+        self.metrics.failed(err);
+        self.audit.failed(err);
+    }
+}
+
+/// A pull answered with the archive itself (`/api/pull`), and the job that
+/// holds its place among the running ones until it ends.
+struct DirectPull {
+    record: PullRecord,
+    job: skopeo::EventSender,
+}
+
+impl DirectPull {
+    async fn succeeded(self, produced: Produced) {
+        let ready = serde_json::json!({ "digest": produced.digest, "size": produced.size, "sha256": produced.sha256 });
+        skopeo::send_event(&self.job, "ready", ready.to_string()).await;
+        self.record.succeeded(produced);
+    }
+
+    async fn failed(self, err: &ApiError) {
+        send_error(&self.job, err).await;
+        self.record.failed(err);
+    }
+}
+
+/// Records a failed pull and answers with its error.
+async fn pull_failed(pull: DirectPull, err: ApiError) -> axum::response::Response {
+    pull.failed(&err).await;
+    err.into_response()
+}
+
+/// Records a pull refused before it started and answers with the reason.
+fn rejected(entry: PullEntry, err: ApiError) -> axum::response::Response {
+    entry.failed(&err);
+    err.into_response()
+}
+
+/// Sync pull of a docker archive: the puller writes into a FIFO and the
+/// response body follows it chunk by chunk. The checksum is only known at the
+/// end, so it is sent as an HTTP trailer.
+#[cfg(unix)]
+async fn stream_pull_image(
+    puller: Arc<dyn Puller>,
+    job: PullJob,
+    compression: ExportCompression,
+    filename: String,
+    limits: Limits,
+    record: DirectPull,
+) -> axum::response::Response {
+    use streaming::Started;
+
+    if let Err(e) = streaming::make_fifo(&job.staging) {
+        return pull_failed(record, ApiError::io("failed to create pipe", e)).await;
+    }
+
+    let reference = job.reference.clone();
+    let fifo = job.staging.clone();
+    let activity = job.activity.clone();
+    let pull = Box::pin(async move { puller.pull(&job, None).await }.instrument(tracing::Span::current()));
+    let body = match streaming::start(pull, fifo, compression.archive, limits, activity).await {
+        Started::Streaming(body, finished) => {
+            // The outcome is only known once the last chunk has gone out.
+            tokio::spawn(
+                async move {
+                    let outcome = finished.await.unwrap_or_else(|_| {
+                        streaming::Outcome::Io(std::io::Error::other("stream ended without an outcome"))
+                    });
+                    match outcome {
+                        streaming::Outcome::Done(streamed) => {
+                            record
+                                .succeeded(Produced {
+                                    digest: streamed.digest,
+                                    size: Some(streamed.size),
+                                    sha256: Some(streamed.sha256),
+                                    artifact_id: None,
+                                })
+                                .await
+                        }
+                        outcome => {
+                            let err = streaming_error(&reference, outcome)
+                                .unwrap_or_else(|| ApiError::Internal("stream ended".into()));
+                            record.failed(&err).await;
+                        }
+                    }
+                }
+                .instrument(tracing::Span::current()),
+            );
+            body
+        }
+        Started::Failed(outcome) => {
+            // Done without a first chunk: the puller wrote nothing.
+            let err = streaming_error(&reference, outcome)
+                .unwrap_or_else(|| ApiError::Upstream(format!("no data produced for {}", reference)));
+            return pull_failed(record, err).await;
+        }
+    };
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        axum::http::header::CONTENT_TYPE,
+        HeaderValue::from_static(compression.archive.kind.content_type()),
+    );
+    headers.insert(
+        axum::http::header::CONTENT_DISPOSITION,
+        HeaderValue::from_str(&format!("attachment; filename=\"{}\"", filename)).unwrap_or(HeaderValue::from_static("attachment")),
+    );
+    headers.insert(axum::http::header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    headers.insert(axum::http::header::TRAILER, HeaderValue::from_static(CHECKSUM_HEADER));
 
     (StatusCode::OK, headers, body).into_response()
 }
 
+/// The error behind the outcome of a streamed pull, if it failed.
+#[cfg(unix)]
+fn streaming_error(reference: &str, outcome: streaming::Outcome) -> Option<ApiError> {
+    use streaming::Outcome;
+    match outcome {
+        Outcome::Done(_) => None,
+        Outcome::TimedOut(expired) => Some(ApiError::Timeout(expired.describe(reference))),
+        Outcome::Failed(e) => Some(ApiError::pull(reference, e)),
+        Outcome::Invalid(msg) => Some(ApiError::CorruptImage(format!("{} ({})", msg, reference))),
+        Outcome::Io(e) => Some(ApiError::io("failed to read archive", e)),
+    }
+}
+
 async fn health_check() -> impl IntoResponse {
     (StatusCode::OK, "OK")
 }
@@ -434,205 +781,416 @@ async fn health_check() -> impl IntoResponse {
 async fn readiness_check(
     axum::extract::State(state): axum::extract::State<AppState>,
 ) -> impl IntoResponse {
-    let mut cmd = Command::new(&state.skopeo_path);
-    cmd.arg("--version");
-    
-    match timeout(Duration::from_secs(5), cmd.output()).await {
-        Ok(Ok(output)) if output.status.success() => {
-            (StatusCode::OK, "Ready")
-        },
-        _ => {
-            (StatusCode::SERVICE_UNAVAILABLE, "skopeo not available")
-        }
+    let settings = state.config.current();
+    match timeout(settings.timeouts.readiness, settings.puller.ready()).await {
+        Ok(Ok(())) => (StatusCode::OK, "Ready").into_response(),
+        Ok(Err(e)) => ApiError::PullerUnavailable(e).into_response(),
+        Err(_) => ApiError::PullerUnavailable(format!("{} puller did not answer in time", settings.puller.name()))
+            .into_response(),
     }
 }
 
 // Streaming pull with progress (SSE-like)
 async fn pull_image_stream(
     axum::extract::State(state): axum::extract::State<AppState>,
+    caller: Caller,
     Json(body): Json<PullRequestBody>,
-) -> Sse<ReceiverStream<Result<Event, std::convert::Infallible>>> {
+) -> Response {
+    match start_pull(state, caller, body).await {
+        Ok((id, events)) => jobs::stream(&id, events),
+        Err(e @ ApiError::Busy(_)) => Sse::new(jobs::refused(&e)).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Starts a pull job, returning its id and its events. The reference, admission rules and policies are checked
+/// first: a refused pull never becomes a job.
+async fn start_pull(
+    state: AppState,
+    caller: Caller,
+    body: PullRequestBody,
+) -> Result<(String, jobs::EventStream), ApiError> {
     let reference = body.r#ref;
     let format = body.format;
     let username = body.username;
     let password = body.password;
-
-    let (tx, rx) = mpsc::channel::<Result<Event, std::convert::Infallible>>(64);
-    let last_error: Arc<Mutex<Option<String>>> = Arc::new(Mutex::new(None));
-
-    tokio::spawn(async move {
-        let _ = tx.send(Ok(Event::default().event("start").data("starting"))).await;
-
-        if reference.trim().is_empty() || !valid_ref(&reference) {
-            let _ = tx.send(Ok(Event::default().event("error").data("invalid reference"))).await;
-            return;
+    let compression = export_compression(
+        &format,
+        body.compression.as_deref(),
+        body.compression_level,
+        body.layer_compression.as_deref(),
+    );
+    let settings = state.config.current();
+    let limits = settings.timeouts.for_request(body.timeout, body.idle_timeout);
+    let job = NewJob {
+        id: Uuid::new_v4().to_string(),
+        kind: JobKind::Pull,
+        reference: reference.clone(),
+        format: format.clone(),
+        owner: caller.principal.name.clone(),
+    };
+    let uid = job.id.clone();
+    let entry = state.audit.pull(caller, &reference, &format);
+    let admitted = if reference.trim().is_empty() || !valid_ref(&reference) {
+        Err(ApiError::InvalidReference(reference.clone()))
+    } else {
+        settings
+            .admission
+            .check(&reference)
+            .and_then(|()| settings.policy.check(entry.principal(), Action::Pull, &reference))
+    };
+    let compression = match admitted.and_then(|()| compression.map_err(ApiError::InvalidRequest)) {
+        Ok(c) => c,
+        Err(e) => {
+            entry.failed(&e);
+            return Err(e);
         }
-
-        let fmt = match format.as_str() {
-            "docker-archive" | "oci-archive" => format.clone(),
-            _ => "docker-archive".to_string(),
-        };
-
-        let uid = Uuid::new_v4().to_string();
-        let tmp_tar = temp_tar_path(&uid);
-        let (repo, tag) = parse_repo_tag(&reference);
-        let dest = format!("{}:{}:{}:{}", fmt, tmp_tar.display(), repo, tag);
-        let authfile_path = match maybe_write_authfile(
-            &reference,
-            username.as_deref(),
-            password.as_deref(),
-        )
-        .await
-        {
-            Ok(path) => path,
-            Err(e) => {
-                let _ = tx
-                    .send(Ok(Event::default().event("error").data(format!(
-                        "authfile error: {e}"
-                    ))))
-                    .await;
-                return;
-            }
-        };
-
-        let mut cmd = Command::new(&state.skopeo_path);
-        cmd.arg("copy");
-
-        if let Some(path) = &authfile_path {
-            cmd.arg("--src-authfile").arg(path);
-            let _ = tx
-                .send(Ok(Event::default().event("auth").data("using credentials")))
-                .await;
+    };
+    let mut handle = match state.jobs.start(job, settings.config.jobs.max_running, body.callback).await {
+        Ok(handle) => handle,
+        Err(e) => {
+            entry.failed(&e);
+            return Err(e);
         }
+    };
+    let bandwidth = Bandwidth::new(body.bandwidth);
+    if settings.puller.limits_bandwidth() {
+        handle.limit_bandwidth(bandwidth.clone());
+    }
+    let tx = handle.sender();
+    let job_id = handle.id().to_string();
 
-        cmd.arg(format!("docker://{}", reference))
-            .arg(&dest)
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped());
+    let rx = handle.spawn(async move {
+        skopeo::send_event(&tx, "start", "starting").await;
 
-        let mut child = match cmd.spawn() {
-            Ok(c) => c,
-            Err(e) => {
-                if let Some(path) = &authfile_path {
-                    let _ = fs::remove_file(path).await;
-                }
-                let _ = tx.send(Ok(Event::default().event("error").data(format!("spawn error: {e}")))).await;
-                return;
-            }
+        let fmt = format.parse::<ArchiveFormat>().unwrap_or_default();
+        let credentials = Credentials::from_parts(username.as_deref(), password.as_deref())
+            .or_else(|| settings.credentials_for(entry.principal(), &reference));
+        let owner = entry.principal().name.clone();
+        let pull = PullRecord { metrics: PullMetrics::start(&reference), audit: entry };
+        let (repo, tag) = parse_repo_tag(&reference);
+        let job = PullJob {
+            reference: reference.clone(),
+            format: fmt,
+            staging: fmt.staging_path(&artifact_path(&uid)),
+            repo,
+            tag,
+            credentials,
+            layer_compression: compression.layers,
+            activity: Activity::default(),
+            bandwidth,
         };
-
-        if let Some(stdout) = child.stdout.take() {
-            let tx_out = tx.clone();
-            tokio::spawn(async move {
-                let mut lines = BufReader::new(stdout).lines();
-                while let Ok(Some(line)) = lines.next_line().await {
-                    let _ = tx_out.send(Ok(Event::default().event("progress").data(line))).await;
-                }
-            });
+        if job.credentials.is_some() {
+            skopeo::send_event(&tx, "auth", "using credentials").await;
         }
 
-        if let Some(stderr) = child.stderr.take() {
-            let tx_err = tx.clone();
-            let last_err = last_error.clone();
-            tokio::spawn(async move {
-                let mut lines = BufReader::new(stderr).lines();
-                while let Ok(Some(line)) = lines.next_line().await {
-                    {
-                        let mut guard = last_err.lock().await;
-                        *guard = Some(line.clone());
-                    }
-                    let _ = tx_err.send(Ok(Event::default().event("progress").data(line))).await;
-                }
-            });
-        }
-
-        let status = match child.wait().await {
-            Ok(s) => s,
-            Err(e) => {
-                if let Some(path) = &authfile_path {
-                    let _ = fs::remove_file(path).await;
-                }
-                let _ = tx.send(Ok(Event::default().event("error").data(format!("wait error: {e}")))).await;
+        let kept = NewArtifact { id: uid.clone(), owner, ttl: settings.artifact_ttl, compression: compression.archive };
+        let artifact = match pull_artifact(&state, &settings, &job, kept, limits, &tx).await {
+            Ok(artifact) => artifact,
+            Err(err) => {
+                pull.failed(&err);
+                send_error(&tx, &err).await;
                 return;
             }
         };
-        if let Some(path) = &authfile_path {
-            let _ = fs::remove_file(path).await;
-        }
-
-        if !status.success() {
-            // Try to read stderr for better message
-            let msg = if let Some(err) = last_error.lock().await.clone() {
-                err
-            } else {
-                "skopeo failed".to_string()
-            };
-            let _ = tx.send(Ok(Event::default().event("error").data(msg))).await;
-            let _ = fs::remove_file(&tmp_tar).await;
-            return;
-        }
+        let (filename, sha256) = (artifact.filename, artifact.sha256);
+        pull.succeeded(Produced {
+            digest: artifact.digest,
+            size: Some(artifact.size),
+            sha256: Some(sha256.clone()),
+            artifact_id: Some(uid.clone()),
+        });
 
-        let filename = make_filename(&repo, &tag, &fmt);
         // Inform client archive ready with download id+filename
         let ready_payload = serde_json::json!({
             "id": uid,
             "filename": filename,
+            "sha256": sha256,
         })
         .to_string();
-        let _ = tx.send(Ok(Event::default().event("ready").data(ready_payload))).await;
-
-        // keep file for download; schedule cleanup in 10 minutes
-        let path_clone = tmp_tar.clone();
-        tokio::spawn(async move {
-            tokio::time::sleep(Duration::from_secs(600)).await;
-            let _ = fs::remove_file(&path_clone).await;
-        });
+        skopeo::send_event(&tx, "ready", ready_payload).await;
+        skopeo::send_event(&tx, "end", "done").await;
+    }.instrument(tracing::Span::current()));
 
-        let _ = tx.send(Ok(Event::default().event("end").data("done"))).await;
-    });
+    Ok((job_id, rx))
+}
 
-    Sse::new(ReceiverStream::new(rx))
-        .keep_alive(axum::response::sse::KeepAlive::new().interval(Duration::from_secs(15)))
+/// What the archive of a pull kept as an artifact becomes.
+pub(crate) struct NewArtifact {
+    pub id: String,
+    pub owner: String,
+    pub ttl: Duration,
+    pub compression: CompressionOptions,
 }
 
-async fn download_file(Path(id): Path<String>) -> impl IntoResponse {
-    if id.contains('/') || id.contains("..") {
-        return (StatusCode::BAD_REQUEST, "invalid id").into_response();
-    }
-    let path = temp_tar_path(&id);
-    if !path.exists() {
-        return (StatusCode::NOT_FOUND, "file not found").into_response();
+/// Pulls `job` under `limits`, progress going to `tx`, and keeps the archive
+/// as artifact `kept.id`. `job.staging` must be the staging path of that
+/// artifact; nothing is left behind on failure.
+pub(crate) async fn pull_artifact(
+    state: &AppState,
+    settings: &Settings,
+    job: &PullJob,
+    kept: NewArtifact,
+    limits: Limits,
+    tx: &skopeo::EventSender,
+) -> Result<Artifact, ApiError> {
+    pull_staged(settings, job, limits, tx).await?;
+
+    let path = artifact_path(&kept.id);
+    let finalized = artifacts::finalize(job.format, &job.staging, &path, kept.compression).await?;
+    let size = fs::metadata(&path).await.ok().map(|m| m.len());
+    let created = signing::manifest_time();
+    let artifact = Artifact {
+        id: kept.id,
+        path: path.clone(),
+        filename: make_filename(&job.repo, &job.tag, job.format.as_str(), kept.compression.kind),
+        format: job.format,
+        compression: kept.compression.kind,
+        repo: job.repo.clone(),
+        tag: job.tag.clone(),
+        reference: job.reference.clone(),
+        digest: finalized.report.image_digest(),
+        sha256: finalized.sha256,
+        size: size.unwrap_or_default(),
+        owner: kept.owner,
+        created,
+        expires: created + kept.ttl,
+        node: state.node.id.clone(),
+    };
+    if let Err(err) = state.artifacts.insert(artifact.clone()).await {
+        let _ = fs::remove_file(&path).await;
+        return Err(err);
     }
+    Ok(artifact)
+}
 
-    let meta = match fs::metadata(&path).await {
-        Ok(m) => m,
-        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("metadata error: {e}")).into_response(),
+/// Pulls `job` to its staging path under `limits`, progress going to `tx`;
+/// the staging is removed on failure.
+pub(crate) async fn pull_staged(
+    settings: &Settings,
+    job: &PullJob,
+    limits: Limits,
+    tx: &skopeo::EventSender,
+) -> Result<(), ApiError> {
+    let pulled = supervise(settings.puller.pull(job, Some(tx)), limits, &job.activity, &job.staging).await;
+    let failure = match pulled {
+        Ok(Ok(())) => None,
+        Ok(Err(e)) => Some(ApiError::pull(&job.reference, e)),
+        Err(expired) => Some(ApiError::Timeout(expired.describe(&job.reference))),
     };
+    match failure {
+        Some(err) => {
+            remove_staging(&job.staging).await;
+            Err(err)
+        }
+        None => Ok(()),
+    }
+}
 
-    let file = match fs::File::open(&path).await {
-        Ok(f) => f,
-        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("open error: {e}")).into_response(),
+/// Removes a partially written skopeo destination, file or directory.
+pub(crate) async fn remove_staging(staging: &std::path::Path) {
+    if staging.is_dir() {
+        let _ = fs::remove_dir_all(staging).await;
+    } else {
+        let _ = fs::remove_file(staging).await;
+    }
+}
+
+async fn download_file(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    caller: Caller,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    let opened = open_artifact(&state, &caller, &id).await;
+    match &opened {
+        Ok((artifact, meta, _)) => state.audit.event(
+            &caller,
+            audit::Event::Download,
+            &artifact.reference,
+            Some(artifact.format.as_str()),
+            Ok(Produced {
+                digest: artifact.digest.clone(),
+                size: Some(meta.len()),
+                sha256: Some(artifact.sha256.clone()),
+                artifact_id: Some(id.clone()),
+            }),
+        ),
+        Err(e) => state.audit.event(&caller, audit::Event::Download, &id, None, Err(e)),
+    }
+    let (artifact, meta, file) = match opened {
+        Ok(opened) => opened,
+        Err(e) => return e.into_response(),
     };
 
-    let stream = ReaderStream::new(file);
+    let stream = ReaderStream::new(file).inspect(|chunk| {
+        if let Ok(bytes) = chunk {
+            metrics::downloaded(bytes.len());
+        }
+    });
     let body = Body::from_stream(stream);
 
-    // delete after serve
-    let path_clone = path.clone();
+    // delete after serve; the checksum and manifest stay available until expiry
+    let store = state.artifacts.clone();
     tokio::spawn(async move {
         tokio::time::sleep(Duration::from_secs(2)).await;
-        let _ = fs::remove_file(&path_clone).await;
+        store.discard_file(&id).await;
     });
 
-    let filename = path.file_name().and_then(|s| s.to_str()).unwrap_or("archive.tar");
+    let filename = artifact.filename;
     let mut headers = HeaderMap::new();
-    headers.insert(axum::http::header::CONTENT_TYPE, HeaderValue::from_static("application/x-tar"));
+    headers.insert(axum::http::header::CONTENT_TYPE, HeaderValue::from_static(artifact.compression.content_type()));
     headers.insert(axum::http::header::CONTENT_LENGTH, HeaderValue::from_str(&meta.len().to_string()).unwrap_or(HeaderValue::from_static("0")));
     headers.insert(
         axum::http::header::CONTENT_DISPOSITION,
         HeaderValue::from_str(&format!("attachment; filename=\"{}\"", filename)).unwrap_or(HeaderValue::from_static("attachment")),
     );
     headers.insert(axum::http::header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    if let Ok(v) = HeaderValue::from_str(&artifact.sha256) {
+        headers.insert(CHECKSUM_HEADER, v);
+    }
 
     (StatusCode::OK, headers, body).into_response()
 }
+
+/// Artifact `id` and its file, if `caller` may see it.
+async fn open_artifact(
+    state: &AppState,
+    caller: &Caller,
+    id: &str,
+) -> Result<(Artifact, std::fs::Metadata, fs::File), ApiError> {
+    if !artifacts::valid_id(id) {
+        return Err(ApiError::InvalidRequest("invalid id".into()));
+    }
+    let artifact = artifacts::visible_artifact(state, caller, id).await?;
+    if !artifact.path.exists() {
+        return Err(ApiError::NotFound("file not found".into()));
+    }
+    let meta = fs::metadata(&artifact.path).await.map_err(|e| ApiError::io("metadata error", e))?;
+    let file = fs::File::open(&artifact.path).await.map_err(|e| ApiError::io("open error", e))?;
+    Ok((artifact, meta, file))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// The state `main` would build from `config`, with an in-memory database.
+    pub(crate) async fn state(config: Config) -> AppState {
+        let client = reqwest::Client::new();
+        let settings = Settings::build(config, &client).unwrap();
+        let store = store::open(&settings.config.database).await.unwrap();
+        let node = Arc::new(cluster::node(&settings.config.cluster));
+        store.heartbeat(&node).await.unwrap();
+        let audit = AuditLog::open(&settings.config.audit).unwrap();
+        let config = LiveConfig::new(settings, None, client.clone());
+        let webhooks = Notifier::new(store.clone(), node.clone(), config.clone(), client.clone());
+        AppState {
+            artifacts: ArtifactStore::new(store.clone(), node.clone()),
+            jobs: Jobs::new(store.clone(), node.clone(), webhooks),
+            client,
+            store,
+            node,
+            signer: ManifestSigner::default(),
+            config,
+            audit,
+        }
+    }
+
+    /// Serves every route on a free local port, returning its base URL.
+    pub(crate) async fn serve(state: AppState) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let app = router(state).into_make_service_with_connect_info::<std::net::SocketAddr>();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        url
+    }
+
+    #[tokio::test]
+    async fn refused_pulls_answer_an_error_and_start_no_job() {
+        let config = format!(
+            r#"
+            [puller]
+            kind = "native"
+
+            [[auth.api_keys]]
+            name = "alice"
+            sha256 = "{}"
+
+            [[policies]]
+            name = "users"
+            users = ["*"]
+            pull = ["registry.example/*"]
+            "#,
+            auth::hash_api_key("alice")
+        );
+        let url = serve(state(toml::from_str(&config).unwrap()).await).await;
+        let client = reqwest::Client::new();
+        for (reference, status, code) in [
+            ("other.example/app:1", StatusCode::FORBIDDEN, "forbidden"),
+            ("not a reference", StatusCode::BAD_REQUEST, "invalid_reference"),
+        ] {
+            let resp = client
+                .post(format!("{url}/api/pull/stream"))
+                .header("x-api-key", "alice")
+                .json(&serde_json::json!({ "ref": reference }))
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), status, "{reference}");
+            let problem: serde_json::Value = resp.json().await.unwrap();
+            assert_eq!(problem["code"], code, "{reference}");
+        }
+        let jobs: Vec<serde_json::Value> =
+            client.get(format!("{url}/api/jobs")).header("x-api-key", "alice").send().await.unwrap().json().await.unwrap();
+        assert!(jobs.is_empty());
+    }
+
+    #[tokio::test]
+    async fn direct_pulls_count_as_running_jobs() {
+        let config = format!(
+            r#"
+            [puller]
+            kind = "native"
+
+            [jobs]
+            max_running = 1
+
+            [[auth.api_keys]]
+            name = "alice"
+            sha256 = "{}"
+            "#,
+            auth::hash_api_key("alice")
+        );
+        let state = state(toml::from_str(&config).unwrap()).await;
+        let store = state.store.clone();
+        let url = serve(state).await;
+        let client = reqwest::Client::new();
+        let pull = || client.get(format!("{url}/api/pull?ref=127.0.0.1:9/app:1")).header("x-api-key", "alice").send();
+
+        // Nothing listens on the registry: the pull fails, and so does its job.
+        assert!(!pull().await.unwrap().status().is_success());
+        let mut jobs = Vec::new();
+        for _ in 0..50 {
+            jobs = store.jobs(store::JobFilter { owner: None, state: None, limit: 10 }).await.unwrap();
+            if jobs.iter().all(|job| job.state != "running") {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(jobs.len(), 1);
+        assert_eq!((jobs[0].kind.as_str(), jobs[0].state.as_str()), ("pull", "failed"));
+
+        let running = NewJob {
+            id: "running".to_string(),
+            kind: JobKind::Convert,
+            reference: "registry.example/app:1".to_string(),
+            format: "oci-archive".to_string(),
+            owner: "bob".to_string(),
+        };
+        store.create_job(&running, "elsewhere", 0).await.unwrap();
+        let resp = pull().await.unwrap();
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let problem: serde_json::Value = resp.json().await.unwrap();
+        assert_eq!(problem["code"], "too_many_jobs");
+    }
+}
@@ -0,0 +1,178 @@
+//! Prometheus metrics, served as text on `/metrics`.
+//!
+//! Pulls are counted once per request, whichever puller and API path ran
+//! them, and failures carry the same `code` the client receives. Registry
+//! labels come from user input, so only the first [`MAX_REGISTRIES`] hosts
+//! seen get their own series; later ones are folded into `other`.
+
+use crate::{artifacts, error::ApiError, reference::ImageReference};
+use axum::{
+    http::{header, HeaderValue, StatusCode},
+    response::IntoResponse,
+};
+use once_cell::sync::Lazy;
+use prometheus::{
+    register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge, Encoder,
+    HistogramVec, IntCounter, IntCounterVec, IntGauge, TextEncoder,
+};
+use std::{collections::HashSet, sync::Mutex, time::Instant};
+
+const MAX_REGISTRIES: usize = 50;
+
+static PULLS_STARTED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!("helmer_pulls_started_total", "Pulls started.", &["registry"]).unwrap()
+});
+static PULLS_SUCCEEDED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!("helmer_pulls_succeeded_total", "Pulls that produced an artifact.", &["registry"])
+        .unwrap()
+});
+static PULLS_FAILED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "helmer_pulls_failed_total",
+        "Failed pulls, by error code (`cancelled` when the client went away).",
+        &["registry", "class"]
+    )
+    .unwrap()
+});
+static PULL_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "helmer_pull_duration_seconds",
+        "Time from the start of a pull to its artifact or error.",
+        &["registry", "outcome"],
+        vec![1.0, 5.0, 15.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1200.0, 1800.0, 3600.0]
+    )
+    .unwrap()
+});
+static PULLS_IN_FLIGHT: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "helmer_pulls_in_flight",
+        "Pulls running now. Pulls are not queued: this is also the backlog."
+    )
+    .unwrap()
+});
+static SKOPEO_PROCESSES: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!("helmer_skopeo_processes", "skopeo processes running now.").unwrap()
+});
+static DOWNLOAD_BYTES: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!("helmer_download_bytes_total", "Bytes sent by /api/pull/file/:id.").unwrap()
+});
+static ARTIFACT_BYTES: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!("helmer_artifact_disk_bytes", "Disk used by artifacts and pulls in progress.").unwrap()
+});
+static ARTIFACT_FILES: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!("helmer_artifact_files", "Artifacts and pulls in progress on disk.").unwrap()
+});
+static FETCH_INDEX_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "helmer_fetch_index_upstream_duration_seconds",
+        "Latency of chart index fetches, by upstream HTTP status (`error` if none).",
+        &["status"],
+        vec![0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0]
+    )
+    .unwrap()
+});
+
+/// The registry label for `reference`.
+fn registry_label(reference: &str) -> String {
+    static SEEN: Lazy<Mutex<HashSet<String>>> = Lazy::new(Default::default);
+    let Ok(image) = ImageReference::parse(reference) else {
+        return "invalid".to_string();
+    };
+    let mut seen = SEEN.lock().unwrap_or_else(|e| e.into_inner());
+    if seen.contains(&image.registry) || seen.len() < MAX_REGISTRIES {
+        seen.insert(image.registry.clone());
+        image.registry
+    } else {
+        "other".to_string()
+    }
+}
+
+/// One pull being measured. Dropping it unfinished counts as `cancelled`.
+pub struct PullMetrics {
+    registry: String,
+    started: Instant,
+    done: bool,
+}
+
+impl PullMetrics {
+    pub fn start(reference: &str) -> Self {
+        let registry = registry_label(reference);
+        PULLS_STARTED.with_label_values(&[&registry]).inc();
+        PULLS_IN_FLIGHT.inc();
+        Self { registry, started: Instant::now(), done: false }
+    }
+
+    pub fn succeeded(mut self) {
+        PULLS_SUCCEEDED.with_label_values(&[&self.registry]).inc();
+        self.finish("succeeded");
+    }
+
+    pub fn failed(mut self, err: &ApiError) {
+        self.fail(err.code());
+    }
+
+    fn fail(&mut self, class: &str) {
+        PULLS_FAILED.with_label_values(&[&self.registry, class]).inc();
+        self.finish("failed");
+    }
+
+    fn finish(&mut self, outcome: &str) {
+        self.done = true;
+        PULLS_IN_FLIGHT.dec();
+        PULL_DURATION
+            .with_label_values(&[&self.registry, outcome])
+            .observe(self.started.elapsed().as_secs_f64());
+    }
+}
+
+impl Drop for PullMetrics {
+    fn drop(&mut self) {
+        if !self.done {
+            self.fail("cancelled");
+        }
+    }
+}
+
+/// Counts a skopeo process for as long as it is alive.
+pub struct SkopeoProcess(());
+
+impl SkopeoProcess {
+    pub fn start() -> Self {
+        SKOPEO_PROCESSES.inc();
+        Self(())
+    }
+}
+
+impl Drop for SkopeoProcess {
+    fn drop(&mut self) {
+        SKOPEO_PROCESSES.dec();
+    }
+}
+
+pub fn downloaded(bytes: usize) {
+    DOWNLOAD_BYTES.inc_by(bytes as u64);
+}
+
+/// Records one upstream fetch of a chart index; `status` is `None` when no
+/// response came back.
+pub fn fetched_index(started: Instant, status: Option<StatusCode>) {
+    let status = status.map(|s| s.as_u16().to_string()).unwrap_or_else(|| "error".to_string());
+    FETCH_INDEX_DURATION.with_label_values(&[&status]).observe(started.elapsed().as_secs_f64());
+}
+
+pub async fn render() -> impl IntoResponse {
+    // Series appear on first use otherwise; scrapers prefer zeros to gaps.
+    Lazy::force(&PULLS_IN_FLIGHT);
+    Lazy::force(&SKOPEO_PROCESSES);
+    Lazy::force(&DOWNLOAD_BYTES);
+    let (files, bytes) = artifacts::disk_usage().await;
+    ARTIFACT_FILES.set(files as i64);
+    ARTIFACT_BYTES.set(bytes as i64);
+
+    let mut body = Vec::new();
+    if let Err(e) = TextEncoder::new().encode(&prometheus::gather(), &mut body) {
+        return ApiError::Internal(format!("failed to encode metrics: {e}")).into_response();
+    }
+    let content_type = HeaderValue::from_static("text/plain; version=0.0.4; charset=utf-8");
+    (StatusCode::OK, [(header::CONTENT_TYPE, content_type)], body).into_response()
+}
@@ -0,0 +1,309 @@
+//! Authorization: which callers may pull, push or chart-browse what.
+//!
+//! `[[policies]]` entries name users and groups and grant them glob patterns
+//! per action. A caller gets the union of every policy naming them; a caller
+//! named by none may do nothing. Without any policy the service stays open,
+//! as before authorization existed.
+//!
+//! Image patterns are matched against `registry/repository` as parsed from
+//! the reference (`nginx` is `docker.io/library/nginx`), chart patterns
+//! against `host/path` of the repository URL. `*` stays within one path
+//! segment, `**` crosses them, and a bare host covers everything on it.
+
+use crate::{auth::Principal, config::PolicyConfig, error::ApiError, reference::ImageReference};
+use regex::Regex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Pull,
+    /// Bringing an image in under a reference (archive uploads).
+    Push,
+    /// Pulling with the registry credentials from the configuration.
+    UseCredentials,
+    /// Fetching a Helm chart repository index.
+    FetchCharts,
+}
+
+impl Action {
+    fn verb(self) -> &'static str {
+        match self {
+            Action::Pull => "pull",
+            Action::Push => "push",
+            Action::UseCredentials => "use the stored credentials for",
+            Action::FetchCharts => "fetch charts from",
+        }
+    }
+}
+
+pub struct Policy {
+    rules: Vec<Rule>,
+}
+
+struct Rule {
+    name: String,
+    users: Vec<String>,
+    groups: Vec<String>,
+    pull: Patterns,
+    push: Patterns,
+    credentials: Patterns,
+    charts: Patterns,
+    audit: bool,
+    syncs: bool,
+}
+
+/// Globs compiled to anchored regexes, kept with their source for messages.
+struct Patterns(Vec<(String, Regex)>);
+
+impl Patterns {
+    fn compile(policy: &str, field: &str, globs: &[String]) -> anyhow::Result<Self> {
+        globs
+            .iter()
+            .map(|glob| {
+                glob_regex(glob)
+                    .map(|re| (glob.clone(), re))
+                    .map_err(|e| anyhow::anyhow!("policies: '{}': invalid {} pattern '{}': {}", policy, field, glob, e))
+            })
+            .collect::<anyhow::Result<_>>()
+            .map(Patterns)
+    }
+
+    fn matches(&self, target: &str) -> bool {
+        self.0.iter().any(|(_, re)| re.is_match(target))
+    }
+}
+
+pub(crate) fn glob_regex(glob: &str) -> Result<Regex, regex::Error> {
+    let mut re = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '*' {
+            if chars.peek() == Some(&'*') {
+                chars.next();
+                re.push_str(".*");
+            } else {
+                re.push_str("[^/]*");
+            }
+        } else {
+            re.push_str(&regex::escape(&c.to_string()));
+        }
+    }
+    if !glob.contains('/') {
+        re.push_str("(/.*)?");
+    }
+    re.push('$');
+    Regex::new(&re)
+}
+
+impl Policy {
+    pub fn build(config: &[PolicyConfig]) -> anyhow::Result<Self> {
+        let rules = config
+            .iter()
+            .map(|p| {
+                Ok(Rule {
+                    name: p.name.clone(),
+                    users: p.users.clone(),
+                    groups: p.groups.clone(),
+                    pull: Patterns::compile(&p.name, "pull", &p.pull)?,
+                    push: Patterns::compile(&p.name, "push", &p.push)?,
+                    credentials: Patterns::compile(&p.name, "credentials", &p.credentials)?,
+                    charts: Patterns::compile(&p.name, "charts", &p.charts)?,
+                    audit: p.audit,
+                    syncs: p.syncs,
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self { rules })
+    }
+
+    pub fn enabled(&self) -> bool {
+        !self.rules.is_empty()
+    }
+
+    /// Whether `who` may apply `action` to the image `reference`.
+    pub fn check(&self, who: &Principal, action: Action, reference: &str) -> Result<(), ApiError> {
+        if !self.enabled() {
+            return Ok(());
+        }
+        let image = ImageReference::parse(reference).map_err(ApiError::InvalidReference)?;
+        self.decide(who, action, &format!("{}/{}", image.registry, image.repository))
+    }
+
+    /// Like [`Policy::check`] for a Helm repository URL.
+    pub fn check_chart_repo(&self, who: &Principal, url: &url::Url) -> Result<(), ApiError> {
+        if !self.enabled() {
+            return Ok(());
+        }
+        let host = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{host}:{port}"),
+            (Some(host), None) => host.to_string(),
+            (None, _) => return Err(ApiError::InvalidRequest("invalid URL".into())),
+        };
+        let path = url.path().trim_end_matches("/index.yaml").trim_end_matches("/index.yml");
+        self.decide(who, Action::FetchCharts, &format!("{}{}", host, path.trim_end_matches('/')))
+    }
+
+    /// Whether `who` may read the audit log.
+    pub fn check_audit(&self, who: &Principal) -> Result<(), ApiError> {
+        if !self.enabled() || self.rules.iter().any(|r| r.audit && r.applies_to(who)) {
+            return Ok(());
+        }
+        Err(ApiError::Forbidden(format!("'{}' may not read the audit log: no policy sets audit = true", who.name)))
+    }
+
+    /// Whether `who` may look at the sync jobs and start them.
+    pub fn check_syncs(&self, who: &Principal) -> Result<(), ApiError> {
+        if !self.enabled() || self.rules.iter().any(|r| r.syncs && r.applies_to(who)) {
+            return Ok(());
+        }
+        Err(ApiError::Forbidden(format!("'{}' may not manage sync jobs: no policy sets syncs = true", who.name)))
+    }
+
+    /// Whether `who` may use configured credentials for `reference`; no
+    /// error, the pull just goes ahead without them.
+    pub fn allows_credentials(&self, who: &Principal, reference: &str) -> bool {
+        self.check(who, Action::UseCredentials, reference).is_ok()
+    }
+
+    fn decide(&self, who: &Principal, action: Action, target: &str) -> Result<(), ApiError> {
+        let applicable: Vec<&Rule> = self.rules.iter().filter(|r| r.applies_to(who)).collect();
+        if applicable.iter().any(|r| r.patterns(action).matches(target)) {
+            return Ok(());
+        }
+        let reason = if applicable.is_empty() {
+            format!("no policy applies to '{}'", who.name)
+        } else {
+            let granted: Vec<String> = applicable
+                .iter()
+                .map(|r| {
+                    let globs: Vec<&str> = r.patterns(action).0.iter().map(|(g, _)| g.as_str()).collect();
+                    match globs.as_slice() {
+                        [] => format!("'{}' grants nothing", r.name),
+                        globs => format!("'{}' allows {}", r.name, globs.join(", ")),
+                    }
+                })
+                .collect();
+            format!("policy {}", granted.join("; "))
+        };
+        Err(ApiError::Forbidden(format!("'{}' may not {} {}: {}", who.name, action.verb(), target, reason)))
+    }
+}
+
+impl Rule {
+    fn applies_to(&self, who: &Principal) -> bool {
+        self.users.iter().any(|u| u == "*" || *u == who.name) || self.groups.iter().any(|g| who.groups.contains(g))
+    }
+
+    fn patterns(&self, action: Action) -> &Patterns {
+        match action {
+            Action::Pull => &self.pull,
+            Action::Push => &self.push,
+            Action::UseCredentials => &self.credentials,
+            Action::FetchCharts => &self.charts,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn policy(toml: &str) -> Policy {
+        Policy::build(&toml::from_str::<Config>(toml).unwrap().policies).unwrap()
+    }
+
+    fn user(name: &str, groups: &[&str]) -> Principal {
+        Principal { name: name.to_string(), groups: groups.iter().map(|g| g.to_string()).collect(), method: "api_key" }
+    }
+
+    fn denial(result: Result<(), ApiError>) -> String {
+        match result {
+            Err(ApiError::Forbidden(reason)) => reason,
+            other => panic!("expected a denial, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn globs_stay_within_a_segment_unless_doubled() {
+        let one = glob_regex("docker.io/library/*").unwrap();
+        assert!(one.is_match("docker.io/library/nginx"));
+        assert!(!one.is_match("docker.io/library/nginx/extra"));
+        let any = glob_regex("docker.io/**").unwrap();
+        assert!(any.is_match("docker.io/library/nginx/extra"));
+        assert!(!any.is_match("ghcr.io/library/nginx"));
+
+        // A bare host covers everything on it, and only that host.
+        let host = glob_regex("registry.example").unwrap();
+        assert!(host.is_match("registry.example"));
+        assert!(host.is_match("registry.example/team/app"));
+        assert!(!host.is_match("registry.example.evil/app"));
+        assert!(glob_regex("*.example").unwrap().is_match("ghcr.example/a/b"));
+    }
+
+    #[test]
+    fn contractors_are_limited_to_library_images() {
+        let policy = policy(
+            "[[policies]]\nname = \"contractors\"\ngroups = [\"contractors\"]\npull = [\"docker.io/library/*\"]\n",
+        );
+        let bob = user("bob", &["contractors"]);
+        policy.check(&bob, Action::Pull, "nginx").unwrap();
+        policy.check(&bob, Action::Pull, "docker.io/library/nginx:1.27").unwrap();
+        let reason = denial(policy.check(&bob, Action::Pull, "docker.io/evil/nginx"));
+        assert_eq!(
+            reason,
+            "'bob' may not pull docker.io/evil/nginx: policy 'contractors' allows docker.io/library/*"
+        );
+        let reason = denial(policy.check(&bob, Action::Push, "nginx"));
+        assert!(reason.ends_with("policy 'contractors' grants nothing"), "{reason}");
+    }
+
+    #[test]
+    fn callers_get_the_union_of_their_policies() {
+        let policy = policy(
+            "[[policies]]\nname = \"everyone\"\nusers = [\"*\"]\npull = [\"docker.io/library/*\"]\n\
+             [[policies]]\nname = \"team\"\ngroups = [\"team\"]\npull = [\"ghcr.io/team/**\"]\naudit = true\n",
+        );
+        let alice = user("alice", &["team"]);
+        policy.check(&alice, Action::Pull, "nginx").unwrap();
+        policy.check(&alice, Action::Pull, "ghcr.io/team/app/api").unwrap();
+        policy.check_audit(&alice).unwrap();
+
+        // `users = ["*"]` names everyone, but grants only its own patterns.
+        let carol = user("carol", &[]);
+        policy.check(&carol, Action::Pull, "nginx").unwrap();
+        let reason = denial(policy.check(&carol, Action::Pull, "ghcr.io/team/app"));
+        assert!(reason.ends_with("policy 'everyone' allows docker.io/library/*"), "{reason}");
+        assert!(policy.check_audit(&carol).is_err());
+    }
+
+    #[test]
+    fn callers_named_by_no_policy_may_do_nothing() {
+        let policy = policy("[[policies]]\nname = \"ops\"\nusers = [\"alice\"]\npull = [\"**\"]\n");
+        let reason = denial(policy.check(&user("mallory", &["ops"]), Action::Pull, "nginx"));
+        assert_eq!(reason, "'mallory' may not pull docker.io/library/nginx: no policy applies to 'mallory'");
+        assert!(policy.check_syncs(&user("alice", &[])).is_err());
+
+        // Without any policy everything stays open.
+        let open = Policy::build(&[]).unwrap();
+        open.check(&user("mallory", &[]), Action::Push, "nginx").unwrap();
+        open.check_audit(&user("mallory", &[])).unwrap();
+    }
+
+    #[test]
+    fn chart_repositories_are_matched_without_scheme_or_index() {
+        let policy = policy(
+            "[[policies]]\nname = \"charts\"\nusers = [\"*\"]\ncharts = [\"charts.example:8443/stable\"]\n",
+        );
+        let alice = user("alice", &[]);
+        for url in [
+            "https://charts.example:8443/stable",
+            "https://charts.example:8443/stable/",
+            "http://charts.example:8443/stable/index.yaml",
+        ] {
+            policy.check_chart_repo(&alice, &url.parse().unwrap()).unwrap();
+        }
+        let reason = denial(policy.check_chart_repo(&alice, &"https://charts.example/stable".parse().unwrap()));
+        assert!(reason.starts_with("'alice' may not fetch charts from charts.example/stable:"), "{reason}");
+        assert!(policy.check_chart_repo(&alice, &"https://charts.example:8443/incubator".parse().unwrap()).is_err());
+    }
+}
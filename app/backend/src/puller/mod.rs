@@ -0,0 +1,128 @@
+//! Getting an image out of a registry and into an archive on disk, or into
+//! another registry.
+//!
+//! Two implementations exist: [`SkopeoPuller`] shells out to skopeo, while
+//! [`NativePuller`] speaks the OCI distribution API itself. Which one a
+//! deployment uses is set by `puller.kind` in the configuration.
+
+mod native;
+mod skopeo;
+
+pub use native::NativePuller;
+pub use skopeo::SkopeoPuller;
+
+use crate::{
+    archive::ArchiveFormat, bandwidth::Bandwidth, compression::Compression, config::Config,
+    skopeo::EventSender, timeouts::Activity,
+};
+use async_trait::async_trait;
+use std::{path::PathBuf, sync::Arc};
+
+/// Everything a puller needs to write one image.
+#[derive(Debug, Clone)]
+pub struct PullJob {
+    pub reference: String,
+    pub format: ArchiveFormat,
+    /// Destination path: a file, a FIFO being streamed, or a layout directory.
+    pub staging: PathBuf,
+    pub repo: String,
+    pub tag: String,
+    pub credentials: Option<Credentials>,
+    /// Recompress layers on the way in (OCI output only).
+    pub layer_compression: Option<Compression>,
+    /// Touched as data comes in, for the idle timeout.
+    pub activity: Activity,
+    /// Applied to the data coming in, by pullers that [`Puller::limits_bandwidth`].
+    pub bandwidth: Bandwidth,
+}
+
+/// Everything a puller needs to copy one image into another registry.
+#[derive(Debug, Clone)]
+pub struct MirrorJob {
+    pub reference: String,
+    pub credentials: Option<Credentials>,
+    /// Where the image goes, as `registry/repository:tag`.
+    pub destination: String,
+    pub destination_credentials: Option<Credentials>,
+    pub activity: Activity,
+    pub bandwidth: Bandwidth,
+}
+
+#[derive(Debug, Clone)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl Credentials {
+    /// Credentials from optional form fields; both must be non-blank.
+    pub fn from_parts(username: Option<&str>, password: Option<&str>) -> Option<Self> {
+        let username = username.map(str::trim).filter(|v| !v.is_empty())?;
+        let password = password.map(str::trim).filter(|v| !v.is_empty())?;
+        Some(Self { username: username.to_string(), password: password.to_string() })
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PullError {
+    #[error("spawn error: {0}")]
+    Spawn(std::io::Error),
+    /// A failure reported by the tool as text (skopeo's stderr).
+    #[error("{0}")]
+    Failed(String),
+    /// A failure reported by the registry over HTTP.
+    #[error("registry returned {status}: {message}")]
+    Registry {
+        status: u16,
+        code: Option<String>,
+        message: String,
+        /// Seconds from a `Retry-After` header, when the registry sent one.
+        retry_after: Option<u64>,
+    },
+    /// Content that does not match its digest, or cannot be understood.
+    #[error("{0}")]
+    Invalid(String),
+    #[error("i/o error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+#[async_trait]
+pub trait Puller: Send + Sync {
+    /// Short name shown in logs and readiness output.
+    fn name(&self) -> &'static str;
+
+    /// Copies `job.reference` into `job.staging`. When `progress` is set,
+    /// human-readable status lines are sent to it as `progress` events.
+    async fn pull(&self, job: &PullJob, progress: Option<&EventSender>) -> Result<(), PullError>;
+
+    /// Copies the image `job.reference` points to into `job.destination`.
+    /// Like [`Puller::pull`], only the image for the configured platform is
+    /// copied out of a manifest list.
+    async fn mirror(&self, job: &MirrorJob, progress: Option<&EventSender>) -> Result<(), PullError>;
+
+    /// Digest of the manifest `reference` points to, as the registry serves
+    /// it: that of the manifest list for multi-platform images.
+    async fn resolve(&self, reference: &str, credentials: Option<&Credentials>) -> Result<String, PullError>;
+
+    /// Tags of `repository`, written `registry/path`.
+    async fn tags(&self, repository: &str, credentials: Option<&Credentials>) -> Result<Vec<String>, PullError>;
+
+    /// Whether the puller can currently do its job.
+    async fn ready(&self) -> Result<(), String>;
+
+    /// Whether the puller applies [`PullJob::bandwidth`]; skopeo moves the
+    /// data itself and cannot be slowed down.
+    fn limits_bandwidth(&self) -> bool {
+        false
+    }
+}
+
+/// Builds the puller selected by `puller.kind` (`skopeo`, the default, or
+/// `native`), with the configured retries, mirrors and insecure registries.
+pub fn build(config: &Config, client: &reqwest::Client) -> anyhow::Result<Arc<dyn Puller>> {
+    match config.puller.kind.as_str() {
+        "skopeo" => Ok(Arc::new(SkopeoPuller::new(config)?)),
+        "native" => Ok(Arc::new(NativePuller::new(config, client.clone())?)),
+        other => anyhow::bail!("unknown puller '{}' (expected skopeo or native)", other),
+    }
+}
@@ -0,0 +1,793 @@
+//! Pulls straight from the registry and writes the archive without skopeo.
+//!
+//! Blobs are downloaded in parallel into a scratch directory and verified
+//! against their digests; the archive is then written sequentially, so the
+//! destination may be a FIFO just like with skopeo. Mirroring downloads the
+//! same way, then uploads the blobs the destination lacks and the manifest.
+
+use super::{Credentials, MirrorJob, PullError, PullJob, Puller};
+use crate::{
+    archive::ArchiveFormat,
+    bandwidth::Bandwidth,
+    checksum::{sha256_digest, HashingWriter},
+    compression::{decoded, Compression, CompressionOptions, StreamEncoder},
+    config::Config,
+    reference::ImageReference,
+    registry::{FetchedManifest, Session, DOCKER_MANIFEST, OCI_MANIFEST},
+    retry::{self, RetryPolicy},
+    skopeo::{send_event, EventSender},
+    telemetry::redact,
+    timeouts::Activity,
+};
+use async_trait::async_trait;
+use futures_util::{stream, StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    time::Instant,
+};
+use tracing::{field::Empty, Instrument};
+
+/// Blobs downloaded at the same time for one image.
+const BLOB_CONCURRENCY: usize = 4;
+
+pub struct NativePuller {
+    http: reqwest::Client,
+    /// Registries (and mirrors) spoken to over plain HTTP.
+    plain_http: Vec<String>,
+    /// Mirrors to try first, per registry host.
+    mirrors: HashMap<String, Vec<String>>,
+    platform: Platform,
+    retry: RetryPolicy,
+}
+
+impl NativePuller {
+    pub fn new(config: &Config, http: reqwest::Client) -> anyhow::Result<Self> {
+        let plain_http = config.registries.iter().filter(|r| r.insecure).map(|r| r.host.clone()).collect();
+        let mirrors = config
+            .registries
+            .iter()
+            .filter(|r| !r.mirrors.is_empty())
+            .map(|r| (r.host.clone(), r.mirrors.clone()))
+            .collect();
+        let platform = Platform::parse(&config.puller.platform).map_err(anyhow::Error::msg)?;
+        Ok(Self { http, plain_http, mirrors, platform, retry: config.retry_policy() })
+    }
+}
+
+#[async_trait]
+impl Puller for NativePuller {
+    fn name(&self) -> &'static str {
+        "native"
+    }
+
+    fn limits_bandwidth(&self) -> bool {
+        true
+    }
+
+    async fn pull(&self, job: &PullJob, progress: Option<&EventSender>) -> Result<(), PullError> {
+        let registry = ImageReference::parse(&job.reference).map(|r| r.registry).unwrap_or_default();
+        let span = tracing::info_span!(
+            "native_pull",
+            reference = %job.reference,
+            registry = %registry,
+            format = job.format.as_str(),
+            duration_ms = Empty,
+            bytes = Empty,
+        );
+        let started = Instant::now();
+        let result = self.copy(job, progress).instrument(span.clone()).await;
+        span.record("duration_ms", started.elapsed().as_millis() as u64);
+        span.record("bytes", job.activity.bytes());
+        span.in_scope(|| match &result {
+            Ok(()) => tracing::info!("pull finished"),
+            Err(_) => tracing::warn!("pull failed"),
+        });
+        result
+    }
+
+    async fn mirror(&self, job: &MirrorJob, progress: Option<&EventSender>) -> Result<(), PullError> {
+        let span = tracing::info_span!(
+            "native_mirror",
+            reference = %job.reference,
+            destination = %job.destination,
+            duration_ms = Empty,
+            bytes = Empty,
+        );
+        let started = Instant::now();
+        let result = self.copy_to_registry(job, progress).instrument(span.clone()).await;
+        span.record("duration_ms", started.elapsed().as_millis() as u64);
+        span.record("bytes", job.activity.bytes());
+        span.in_scope(|| match &result {
+            Ok(()) => tracing::info!("mirror finished"),
+            Err(_) => tracing::warn!("mirror failed"),
+        });
+        result
+    }
+
+    async fn resolve(&self, reference: &str, credentials: Option<&Credentials>) -> Result<String, PullError> {
+        let image = ImageReference::parse(reference).map_err(PullError::Invalid)?;
+        // Same order as pulls: what gets pulled is what the mirrors serve.
+        for mirror in self.mirrors.get(&image.registry).map(Vec::as_slice).unwrap_or_default() {
+            let via = ImageReference { registry: mirror.clone(), ..image.clone() };
+            let session = Session::new(self.http.clone(), &via, self.plain_http.contains(mirror), None);
+            match session.manifest(via.manifest_ref()).await {
+                Ok(manifest) => return Ok(manifest.digest),
+                Err(e) => tracing::warn!(mirror = %mirror, "mirror failed for {}: {}", reference, redact(&e.to_string())),
+            }
+        }
+        let session = self.session(&image, credentials.cloned());
+        retry::run(&self.retry, None, || async { Ok(session.manifest(image.manifest_ref()).await?.digest) }).await
+    }
+
+    /// Tags come from the registry itself: mirrors may only hold some.
+    async fn tags(&self, repository: &str, credentials: Option<&Credentials>) -> Result<Vec<String>, PullError> {
+        let image = ImageReference::parse(repository).map_err(PullError::Invalid)?;
+        let session = self.session(&image, credentials.cloned());
+        retry::run(&self.retry, None, || session.tags()).await
+    }
+
+    async fn ready(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+impl NativePuller {
+    async fn copy(&self, job: &PullJob, progress: Option<&EventSender>) -> Result<(), PullError> {
+        let image = ImageReference::parse(&job.reference).map_err(PullError::Invalid)?;
+
+        say(progress, "Getting image source signatures").await;
+        let work = tempfile::Builder::new().prefix("native-pull-").tempdir()?;
+        let (manifest, parsed) = self
+            .download(&image, job.credentials.clone(), &job.activity, &job.bandwidth, work.path(), progress)
+            .await?;
+
+        say(progress, "Writing manifest to image destination").await;
+        let job = job.clone();
+        tokio::task::spawn_blocking(move || {
+            let written = write_image(&job, &manifest, &parsed, work.path());
+            drop(work);
+            written
+        })
+        .await
+        .map_err(std::io::Error::other)?
+    }
+
+    async fn copy_to_registry(&self, job: &MirrorJob, progress: Option<&EventSender>) -> Result<(), PullError> {
+        let image = ImageReference::parse(&job.reference).map_err(PullError::Invalid)?;
+        let target = ImageReference::parse(&job.destination).map_err(PullError::Invalid)?;
+
+        say(progress, "Getting image source signatures").await;
+        let work = tempfile::Builder::new().prefix("native-mirror-").tempdir()?;
+        let (manifest, parsed) = self
+            .download(&image, job.credentials.clone(), &job.activity, &job.bandwidth, work.path(), progress)
+            .await?;
+        let session = self.session(&target, job.destination_credentials.clone()).for_push();
+        let upload = || {
+            push_image(&session, &manifest, &parsed, target.manifest_ref(), work.path(), &job.activity, progress)
+        };
+        retry::run(&self.retry, progress, upload).await
+    }
+
+    /// A session with the registry of `image` itself.
+    fn session(&self, image: &ImageReference, credentials: Option<Credentials>) -> Session {
+        Session::new(self.http.clone(), image, self.plain_http.contains(&image.registry), credentials)
+    }
+
+    /// Resolves `image` and downloads every blob it needs into `dir`, from
+    /// the mirrors first, then from the registry itself. Credentials only go
+    /// to the registry they were given for.
+    async fn download(
+        &self,
+        image: &ImageReference,
+        credentials: Option<Credentials>,
+        activity: &Activity,
+        bandwidth: &Bandwidth,
+        dir: &Path,
+        progress: Option<&EventSender>,
+    ) -> Result<(FetchedManifest, ImageManifest), PullError> {
+        for mirror in self.mirrors.get(&image.registry).map(Vec::as_slice).unwrap_or_default() {
+            let via = ImageReference { registry: mirror.clone(), ..image.clone() };
+            let session = Session::new(self.http.clone(), &via, self.plain_http.contains(mirror), None);
+            match self.fetch(&session, &via, activity, bandwidth, dir, progress).await {
+                Ok(done) => return Ok(done),
+                Err(e) => tracing::warn!(mirror = %mirror, "mirror failed for {}: {}", image, redact(&e.to_string())),
+            }
+        }
+        let session = self.session(image, credentials);
+        // A retry starts over from the manifest but keeps the verified blobs.
+        retry::run(&self.retry, progress, || self.fetch(&session, image, activity, bandwidth, dir, progress)).await
+    }
+
+    /// Resolves the manifest and downloads every blob it needs into `dir`.
+    async fn fetch(
+        &self,
+        session: &Session,
+        image: &ImageReference,
+        activity: &Activity,
+        bandwidth: &Bandwidth,
+        dir: &Path,
+        progress: Option<&EventSender>,
+    ) -> Result<(FetchedManifest, ImageManifest), PullError> {
+        let manifest = self.resolve(session, image).await?;
+        let parsed: ImageManifest = serde_json::from_slice(&manifest.bytes)
+            .map_err(|e| PullError::Invalid(format!("invalid image manifest: {}", e)))?;
+
+        let mut seen = HashSet::new();
+        let blobs: Vec<Descriptor> = std::iter::once(&parsed.config)
+            .chain(&parsed.layers)
+            .filter(|d| seen.insert(d.digest.clone()))
+            .cloned()
+            .collect();
+        stream::iter(blobs)
+            .map(|desc| async move { fetch_blob(session, &desc, dir, activity, bandwidth, progress).await })
+            .buffer_unordered(BLOB_CONCURRENCY)
+            .try_collect::<Vec<()>>()
+            .await?;
+        Ok((manifest, parsed))
+    }
+
+    /// Fetches the image manifest, picking our platform out of a list.
+    async fn resolve(&self, session: &Session, image: &ImageReference) -> Result<FetchedManifest, PullError> {
+        let fetched = session.manifest(image.manifest_ref()).await?;
+        if let Some(pinned) = &image.digest {
+            check_digest(pinned, &fetched)?;
+        }
+        if !fetched.is_index() {
+            return Ok(fetched);
+        }
+
+        let index: Index = serde_json::from_slice(&fetched.bytes)
+            .map_err(|e| PullError::Invalid(format!("invalid manifest list: {}", e)))?;
+        let Some(entry) = index.manifests.iter().find(|m| self.platform.matches(m.platform.as_ref())) else {
+            return Err(PullError::Failed(format!(
+                "no image found in manifest list for architecture {}, variant \"{}\", OS {}",
+                self.platform.architecture,
+                self.platform.variant.as_deref().unwrap_or(""),
+                self.platform.os
+            )));
+        };
+        let child = session.manifest(&entry.digest).await?;
+        check_digest(&entry.digest, &child)?;
+        if child.is_index() {
+            return Err(PullError::Invalid("nested manifest lists are not supported".to_string()));
+        }
+        Ok(child)
+    }
+}
+
+async fn say(progress: Option<&EventSender>, line: impl Into<String>) {
+    if let Some(tx) = progress {
+        send_event(tx, "progress", line).await;
+    }
+}
+
+fn check_digest(expected: &str, fetched: &FetchedManifest) -> Result<(), PullError> {
+    if fetched.digest != expected {
+        return Err(PullError::Invalid(format!(
+            "manifest digest mismatch: expected {}, got {}",
+            expected, fetched.digest
+        )));
+    }
+    Ok(())
+}
+
+/// Where a verified blob lives in the scratch directory.
+fn blob_file(dir: &Path, digest: &str) -> PathBuf {
+    dir.join(digest.replace(':', "-"))
+}
+
+async fn fetch_blob(
+    session: &Session,
+    desc: &Descriptor,
+    dir: &Path,
+    activity: &Activity,
+    bandwidth: &Bandwidth,
+    progress: Option<&EventSender>,
+) -> Result<(), PullError> {
+    if !valid_digest(&desc.digest) {
+        return Err(PullError::Invalid(format!("unsupported digest {}", desc.digest)));
+    }
+    let path = blob_file(dir, &desc.digest);
+    if path.exists() {
+        // Verified by an earlier attempt.
+        return Ok(());
+    }
+    say(progress, format!("Copying blob {}", desc.digest)).await;
+    let partial = path.with_extension("partial");
+    let (size, digest) = session.blob_to_file(&desc.digest, &partial, activity, bandwidth).await?;
+    if size != desc.size {
+        return Err(PullError::Invalid(format!(
+            "size mismatch for {}: expected {} bytes, got {}",
+            desc.digest, desc.size, size
+        )));
+    }
+    if digest != desc.digest {
+        return Err(PullError::Invalid(format!("digest mismatch for {}: got {}", desc.digest, digest)));
+    }
+    tokio::fs::rename(&partial, &path).await?;
+    say(progress, format!("Copying blob {} done", desc.digest)).await;
+    Ok(())
+}
+
+/// Uploads the blobs of `parsed` that `session`'s repository lacks from
+/// `dir`, then the manifest under `reference`.
+async fn push_image(
+    session: &Session,
+    manifest: &FetchedManifest,
+    parsed: &ImageManifest,
+    reference: &str,
+    dir: &Path,
+    activity: &Activity,
+    progress: Option<&EventSender>,
+) -> Result<(), PullError> {
+    let mut seen = HashSet::new();
+    let blobs: Vec<Descriptor> = std::iter::once(&parsed.config)
+        .chain(&parsed.layers)
+        .filter(|d| seen.insert(d.digest.clone()))
+        .cloned()
+        .collect();
+    stream::iter(blobs)
+        .map(|desc| async move {
+            if session.has_blob(&desc.digest).await? {
+                say(progress, format!("Copying blob {} skipped: already exists", desc.digest)).await;
+                return Ok(());
+            }
+            say(progress, format!("Copying blob {}", desc.digest)).await;
+            session.upload_blob(&desc.digest, &blob_file(dir, &desc.digest), activity).await?;
+            say(progress, format!("Copying blob {} done", desc.digest)).await;
+            Ok::<_, PullError>(())
+        })
+        .buffer_unordered(BLOB_CONCURRENCY)
+        .try_collect::<Vec<()>>()
+        .await?;
+    say(progress, "Writing manifest to image destination").await;
+    session.put_manifest(reference, manifest).await
+}
+
+fn valid_digest(digest: &str) -> bool {
+    digest
+        .strip_prefix("sha256:")
+        .is_some_and(|hex| hex.len() == 64 && hex.bytes().all(|b| b.is_ascii_hexdigit()))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Descriptor {
+    #[serde(rename = "mediaType")]
+    media_type: String,
+    digest: String,
+    size: u64,
+    #[serde(flatten)]
+    extra: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+struct ImageManifest {
+    config: Descriptor,
+    layers: Vec<Descriptor>,
+}
+
+#[derive(Deserialize)]
+struct Index {
+    manifests: Vec<IndexEntry>,
+}
+
+#[derive(Deserialize)]
+struct IndexEntry {
+    digest: String,
+    platform: Option<PlatformSpec>,
+}
+
+#[derive(Deserialize)]
+struct PlatformSpec {
+    os: String,
+    architecture: String,
+    variant: Option<String>,
+}
+
+/// Platform picked out of manifest lists, `os/arch[/variant]`.
+#[derive(Debug, Clone)]
+struct Platform {
+    os: String,
+    architecture: String,
+    variant: Option<String>,
+}
+
+impl Platform {
+    fn parse(s: &str) -> Result<Self, String> {
+        let parts: Vec<&str> = s.trim().split('/').collect();
+        match parts.as_slice() {
+            [os, arch] if !os.is_empty() && !arch.is_empty() => {
+                Ok(Self { os: os.to_string(), architecture: arch.to_string(), variant: None })
+            }
+            [os, arch, variant] if !os.is_empty() && !arch.is_empty() && !variant.is_empty() => Ok(Self {
+                os: os.to_string(),
+                architecture: arch.to_string(),
+                variant: Some(variant.to_string()),
+            }),
+            _ => Err(format!("invalid puller.platform '{}' (expected os/arch[/variant])", s)),
+        }
+    }
+
+    fn matches(&self, spec: Option<&PlatformSpec>) -> bool {
+        let Some(spec) = spec else { return false };
+        spec.os == self.os
+            && spec.architecture == self.architecture
+            && match (&self.variant, &spec.variant) {
+                (Some(want), Some(got)) => want == got,
+                _ => true,
+            }
+    }
+}
+
+/// Where archive entries go: a tar stream, or a directory for the OCI layout.
+enum Sink {
+    Tar(tar::Builder<BufWriter<File>>),
+    Dir(PathBuf),
+}
+
+impl Sink {
+    fn open(job: &PullJob) -> std::io::Result<Self> {
+        if job.format.is_directory() {
+            std::fs::create_dir_all(&job.staging)?;
+            return Ok(Sink::Dir(job.staging.clone()));
+        }
+        // May be a FIFO: open blocks until the reading side shows up.
+        let file = File::create(&job.staging)?;
+        Ok(Sink::Tar(tar::Builder::new(BufWriter::new(file))))
+    }
+
+    fn add_file(&mut self, name: &str, src: &Path) -> std::io::Result<()> {
+        match self {
+            Sink::Tar(tar) => {
+                let mut file = File::open(src)?;
+                let mut header = entry_header(file.metadata()?.len());
+                tar.append_data(&mut header, name, &mut file)
+            }
+            Sink::Dir(dir) => {
+                let dst = dir.join(name);
+                if let Some(parent) = dst.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                std::fs::rename(src, &dst).or_else(|_| std::fs::copy(src, &dst).map(|_| ()))
+            }
+        }
+    }
+
+    fn add_bytes(&mut self, name: &str, data: &[u8]) -> std::io::Result<()> {
+        match self {
+            Sink::Tar(tar) => {
+                let mut header = entry_header(data.len() as u64);
+                tar.append_data(&mut header, name, data)
+            }
+            Sink::Dir(dir) => {
+                let dst = dir.join(name);
+                if let Some(parent) = dst.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                std::fs::write(dst, data)
+            }
+        }
+    }
+
+    fn finish(self) -> std::io::Result<()> {
+        match self {
+            Sink::Tar(tar) => tar.into_inner()?.flush(),
+            Sink::Dir(_) => Ok(()),
+        }
+    }
+}
+
+fn entry_header(size: u64) -> tar::Header {
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_size(size);
+    header.set_mode(0o644);
+    header.set_mtime(0);
+    header
+}
+
+fn write_image(
+    job: &PullJob,
+    manifest: &FetchedManifest,
+    parsed: &ImageManifest,
+    work: &Path,
+) -> Result<(), PullError> {
+    match job.format {
+        ArchiveFormat::DockerArchive => write_docker(job, parsed, work),
+        ArchiveFormat::OciArchive | ArchiveFormat::OciLayout => write_oci(job, manifest, parsed, work),
+    }
+}
+
+/// docker-archive: uncompressed layers named after their diff ids, the
+/// config, and a `manifest.json` tying them to `repo:tag`.
+fn write_docker(job: &PullJob, parsed: &ImageManifest, work: &Path) -> Result<(), PullError> {
+    let config_path = blob_file(work, &parsed.config.digest);
+    let config: serde_json::Value = serde_json::from_slice(&std::fs::read(&config_path)?)
+        .map_err(|e| PullError::Invalid(format!("invalid image config: {}", e)))?;
+    let diff_ids: Vec<&str> = config
+        .pointer("/rootfs/diff_ids")
+        .and_then(|v| v.as_array())
+        .map(|ids| ids.iter().filter_map(|v| v.as_str()).collect())
+        .unwrap_or_default();
+
+    let mut layers = Vec::new();
+    for (i, desc) in parsed.layers.iter().enumerate() {
+        let plain = work.join(format!("layer-{}.tar", i));
+        let diff_id = decompress_layer(&blob_file(work, &desc.digest), &plain)?;
+        if let Some(expected) = diff_ids.get(i).filter(|id| **id != diff_id) {
+            return Err(PullError::Invalid(format!(
+                "layer {} does not match config diff_id {} (got {})",
+                desc.digest, expected, diff_id
+            )));
+        }
+        layers.push((diff_id, plain));
+    }
+
+    let mut sink = Sink::open(job)?;
+    let mut names = Vec::new();
+    let mut written = HashSet::new();
+    for (diff_id, path) in &layers {
+        let name = format!("{}.tar", hex_part(diff_id));
+        if written.insert(name.clone()) {
+            sink.add_file(&name, path)?;
+        }
+        names.push(name);
+    }
+    let config_name = format!("{}.json", hex_part(&parsed.config.digest));
+    sink.add_file(&config_name, &config_path)?;
+    let manifest = serde_json::json!([{
+        "Config": config_name,
+        "RepoTags": [format!("{}:{}", job.repo, job.tag)],
+        "Layers": names,
+    }]);
+    sink.add_bytes("manifest.json", manifest.to_string().as_bytes())?;
+    sink.finish()?;
+    Ok(())
+}
+
+/// oci-archive / oci layout: blobs as pulled, under an OCI manifest.
+fn write_oci(
+    job: &PullJob,
+    fetched: &FetchedManifest,
+    parsed: &ImageManifest,
+    work: &Path,
+) -> Result<(), PullError> {
+    let mut layers = Vec::new();
+    for (i, desc) in parsed.layers.iter().enumerate() {
+        let src = blob_file(work, &desc.digest);
+        match job.layer_compression.filter(|kind| needs_recompression(&src, *kind)) {
+            Some(kind) => {
+                let out = work.join(format!("layer-{}.{}", i, kind));
+                let (digest, size) = recompress_layer(&src, &out, kind)?;
+                let mut desc = desc.clone();
+                desc.media_type = format!("application/vnd.oci.image.layer.v1.tar+{}", kind);
+                desc.digest = digest;
+                desc.size = size;
+                layers.push((desc, out));
+            }
+            None => layers.push((desc.clone(), src)),
+        }
+    }
+
+    // Docker manifests and recompressed layers both need a new OCI manifest;
+    // otherwise the original bytes are kept so the digest does not change.
+    let rewrite = fetched.media_type == DOCKER_MANIFEST || job.layer_compression.is_some();
+    let manifest_bytes = if rewrite {
+        let mut manifest: serde_json::Value = serde_json::from_slice(&fetched.bytes)
+            .map_err(|e| PullError::Invalid(format!("invalid image manifest: {}", e)))?;
+        let mut config = parsed.config.clone();
+        config.media_type = oci_media_type(&config.media_type).to_string();
+        manifest["mediaType"] = OCI_MANIFEST.into();
+        manifest["config"] = serde_json::to_value(&config).map_err(std::io::Error::other)?;
+        manifest["layers"] = layers
+            .iter()
+            .map(|(d, _)| {
+                let mut d = d.clone();
+                d.media_type = oci_media_type(&d.media_type).to_string();
+                serde_json::to_value(d)
+            })
+            .collect::<Result<_, _>>()
+            .map_err(std::io::Error::other)?;
+        serde_json::to_vec(&manifest).map_err(std::io::Error::other)?
+    } else {
+        fetched.bytes.clone()
+    };
+    let manifest_digest = sha256_digest(&manifest_bytes);
+
+    // Same naming skopeo uses for these destinations.
+    let ref_name = match job.format {
+        ArchiveFormat::OciLayout => job.tag.clone(),
+        _ => format!("{}:{}", job.repo, job.tag),
+    };
+    let index = serde_json::json!({
+        "schemaVersion": 2,
+        "manifests": [{
+            "mediaType": OCI_MANIFEST,
+            "digest": manifest_digest,
+            "size": manifest_bytes.len(),
+            "annotations": { "org.opencontainers.image.ref.name": ref_name },
+        }],
+    });
+
+    let mut sink = Sink::open(job)?;
+    sink.add_bytes("oci-layout", br#"{"imageLayoutVersion":"1.0.0"}"#)?;
+    sink.add_bytes("index.json", index.to_string().as_bytes())?;
+    let mut written = HashSet::new();
+    let blobs = std::iter::once((parsed.config.digest.clone(), blob_file(work, &parsed.config.digest)))
+        .chain(layers.into_iter().map(|(d, path)| (d.digest, path)));
+    for (digest, path) in blobs {
+        if written.insert(digest.clone()) {
+            sink.add_file(&format!("blobs/sha256/{}", hex_part(&digest)), &path)?;
+        }
+    }
+    sink.add_bytes(&format!("blobs/sha256/{}", hex_part(&manifest_digest)), &manifest_bytes)?;
+    sink.finish()?;
+    Ok(())
+}
+
+fn hex_part(digest: &str) -> &str {
+    digest.split_once(':').map(|(_, hex)| hex).unwrap_or(digest)
+}
+
+/// OCI equivalent of a Docker media type; OCI ones pass through.
+fn oci_media_type(media_type: &str) -> &str {
+    match media_type {
+        "application/vnd.docker.container.image.v1+json" => "application/vnd.oci.image.config.v1+json",
+        "application/vnd.docker.image.rootfs.diff.tar.gzip" => "application/vnd.oci.image.layer.v1.tar+gzip",
+        "application/vnd.docker.image.rootfs.foreign.diff.tar.gzip" => {
+            "application/vnd.oci.image.layer.nondistributable.v1.tar+gzip"
+        }
+        other => other,
+    }
+}
+
+fn needs_recompression(layer: &Path, kind: Compression) -> bool {
+    let mut head = [0u8; 4];
+    let n = File::open(layer).and_then(|mut f| f.read(&mut head)).unwrap_or(0);
+    Compression::detect(&head[..n]) != kind
+}
+
+/// Writes the uncompressed layer, returning its diff id.
+fn decompress_layer(src: &Path, dst: &Path) -> std::io::Result<String> {
+    let mut input = decoded(BufReader::new(File::open(src)?))?;
+    let mut out = HashingWriter::new(BufWriter::new(File::create(dst)?));
+    std::io::copy(&mut input, &mut out)?;
+    out.flush()?;
+    Ok(format!("sha256:{}", out.finish()))
+}
+
+/// Re-encodes a layer with `kind`, returning the new digest and size.
+fn recompress_layer(src: &Path, dst: &Path, kind: Compression) -> std::io::Result<(String, u64)> {
+    let opts = CompressionOptions::parse(Some(kind.as_str()), None).map_err(std::io::Error::other)?;
+    let mut input = decoded(BufReader::new(File::open(src)?))?;
+    let out = HashingWriter::new(BufWriter::new(File::create(dst)?));
+    let mut encoder = StreamEncoder::new(out, opts)?;
+    std::io::copy(&mut input, &mut encoder)?;
+    let mut out = encoder.finish()?;
+    out.flush()?;
+    let digest = format!("sha256:{}", out.finish());
+    Ok((digest, std::fs::metadata(dst)?.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{checksum::sha256_digest, registry::tests::Stub, registry::OCI_INDEX};
+
+    const REPO: &str = "demo/app";
+
+    /// Serves a one-layer image for `architecture`, returning its manifest
+    /// and config digest.
+    fn image(stub: &Stub, architecture: &str, layer: &[u8]) -> (Vec<u8>, String) {
+        let config = serde_json::json!({
+            "architecture": architecture,
+            "os": "linux",
+            "rootfs": { "type": "layers", "diff_ids": [sha256_digest(layer)] },
+        })
+        .to_string();
+        let config_digest = stub.blob(REPO, config.as_bytes());
+        let layer_digest = stub.blob(REPO, layer);
+        let manifest = serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": OCI_MANIFEST,
+            "config": {
+                "mediaType": "application/vnd.oci.image.config.v1+json",
+                "digest": config_digest,
+                "size": config.len(),
+            },
+            "layers": [{
+                "mediaType": "application/vnd.oci.image.layer.v1.tar",
+                "digest": layer_digest,
+                "size": layer.len(),
+            }],
+        });
+        (manifest.to_string().into_bytes(), config_digest)
+    }
+
+    fn puller(host: &str) -> NativePuller {
+        let toml = format!("[retry]\nretries = 0\n\n[[registries]]\nhost = \"{host}\"\ninsecure = true\n");
+        let config: Config = toml::from_str(&toml).unwrap();
+        NativePuller::new(&config, reqwest::Client::new()).unwrap()
+    }
+
+    fn job(reference: String, staging: PathBuf) -> PullJob {
+        PullJob {
+            reference,
+            format: ArchiveFormat::DockerArchive,
+            staging,
+            repo: "app".into(),
+            tag: "1".into(),
+            credentials: None,
+            layer_compression: None,
+            activity: Activity::default(),
+            bandwidth: Bandwidth::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn picks_the_configured_platform_out_of_a_manifest_list() {
+        let stub = Stub::default();
+        let mut entries = Vec::new();
+        let mut amd64_config = String::new();
+        for (architecture, layer) in [("arm64", b"arm64 layer"), ("amd64", b"amd64 layer")] {
+            let (manifest, config) = image(&stub, architecture, layer);
+            let digest = stub.manifest(REPO, architecture, OCI_MANIFEST, &manifest);
+            entries.push(serde_json::json!({
+                "mediaType": OCI_MANIFEST,
+                "digest": digest,
+                "size": manifest.len(),
+                "platform": { "os": "linux", "architecture": architecture },
+            }));
+            if architecture == "amd64" {
+                amd64_config = config;
+            }
+        }
+        let index = serde_json::json!({ "schemaVersion": 2, "mediaType": OCI_INDEX, "manifests": entries });
+        stub.manifest(REPO, "1", OCI_INDEX, index.to_string().as_bytes());
+        let host = stub.serve().await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("out.tar");
+        puller(&host).pull(&job(format!("{host}/{REPO}:1"), out.clone()), None).await.unwrap();
+        let report = crate::inspect::inspect(File::open(&out).unwrap()).unwrap();
+        assert!(report.valid, "{:?}", report.problems);
+        assert_eq!(report.image_digest(), Some(amd64_config));
+        assert_eq!(report.images[0].repo_tags, ["app:1"]);
+    }
+
+    #[tokio::test]
+    async fn blob_not_matching_its_digest_fails() {
+        let stub = Stub::default();
+        let (manifest, _) = image(&stub, "amd64", b"layer bytes");
+        stub.manifest(REPO, "1", OCI_MANIFEST, &manifest);
+        // Same size, other bytes, served under the original digest.
+        let path = format!("/v2/{REPO}/blobs/{}", sha256_digest(b"layer bytes"));
+        stub.put(&path, "application/octet-stream", b"tampered!!!", None);
+        let host = stub.serve().await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let err = puller(&host).pull(&job(format!("{host}/{REPO}:1"), dir.path().join("out.tar")), None).await;
+        match err {
+            Err(PullError::Invalid(msg)) => assert!(msg.contains("digest mismatch"), "{msg}"),
+            Err(e) => panic!("unexpected error: {e}"),
+            Ok(()) => panic!("a tampered blob was accepted"),
+        }
+    }
+
+    #[tokio::test]
+    async fn manifest_not_matching_the_pinned_digest_fails() {
+        let stub = Stub::default();
+        let (manifest, _) = image(&stub, "amd64", b"layer bytes");
+        let pinned = sha256_digest(b"another manifest");
+        stub.put(&format!("/v2/{REPO}/manifests/{pinned}"), OCI_MANIFEST, &manifest, None);
+        let host = stub.serve().await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let err = puller(&host).pull(&job(format!("{host}/{REPO}@{pinned}"), dir.path().join("out.tar")), None).await;
+        assert!(matches!(err, Err(PullError::Invalid(msg)) if msg.starts_with("manifest digest mismatch")));
+    }
+}
@@ -0,0 +1,325 @@
+use super::{Credentials, MirrorJob, PullError, PullJob, Puller};
+use crate::{
+    checksum::sha256_digest,
+    config::Config,
+    metrics::SkopeoProcess,
+    skopeo::{self, record_exit, run_with_progress, EventSender},
+    timeouts::{disk_size, watch_growth, Activity},
+};
+use async_trait::async_trait;
+use base64::Engine as _;
+use serde::{Deserialize, Serialize};
+use std::{io::Write, process::Stdio, time::Instant};
+use tokio::process::Command;
+use tracing::Instrument;
+
+/// Pulls through an external `skopeo copy`.
+pub struct SkopeoPuller {
+    path: String,
+    /// Passed as `--retry-times`: skopeo then backs off and retries the
+    /// registry requests that fail transiently, keeping finished blobs.
+    retry_times: u32,
+    /// Generated registries.conf carrying the configured mirrors and insecure
+    /// registries; removed once the last job using this puller is done.
+    registries_conf: Option<tempfile::NamedTempFile>,
+}
+
+impl SkopeoPuller {
+    pub fn new(config: &Config) -> anyhow::Result<Self> {
+        Ok(Self {
+            path: config.puller.skopeo_path.clone(),
+            retry_times: config.retry.retries,
+            registries_conf: registries_conf(config)?,
+        })
+    }
+}
+
+#[derive(Serialize)]
+struct RegistriesConf {
+    registry: Vec<RegistryEntry>,
+}
+
+#[derive(Serialize)]
+struct RegistryEntry {
+    prefix: String,
+    location: String,
+    insecure: bool,
+    mirror: Vec<MirrorEntry>,
+}
+
+#[derive(Serialize)]
+struct MirrorEntry {
+    location: String,
+    insecure: bool,
+}
+
+/// Writes a containers-registries.conf(5) for the registries that need one.
+/// Without any, skopeo keeps using the system file.
+fn registries_conf(config: &Config) -> anyhow::Result<Option<tempfile::NamedTempFile>> {
+    let insecure = |host: &str| config.registry(host).is_some_and(|r| r.insecure);
+    let registry: Vec<RegistryEntry> = config
+        .registries
+        .iter()
+        .filter(|r| r.insecure || !r.mirrors.is_empty())
+        .map(|r| RegistryEntry {
+            prefix: r.host.clone(),
+            location: r.host.clone(),
+            insecure: r.insecure,
+            mirror: r
+                .mirrors
+                .iter()
+                .map(|m| MirrorEntry { location: m.clone(), insecure: insecure(m) })
+                .collect(),
+        })
+        .collect();
+    if registry.is_empty() {
+        return Ok(None);
+    }
+    let mut file = tempfile::Builder::new().prefix("registries-").suffix(".conf").tempfile()?;
+    file.write_all(toml::to_string(&RegistriesConf { registry })?.as_bytes())?;
+    file.flush()?;
+    Ok(Some(file))
+}
+
+#[async_trait]
+impl Puller for SkopeoPuller {
+    fn name(&self) -> &'static str {
+        "skopeo"
+    }
+
+    async fn pull(&self, job: &PullJob, progress: Option<&EventSender>) -> Result<(), PullError> {
+        let span = skopeo::span("copy", &job.reference, job.format.as_str());
+        let started = Instant::now();
+        let result = self.copy(job, progress).instrument(span.clone()).await;
+        // Archives streamed through a FIFO are counted as they are read.
+        let bytes = match job.activity.bytes() {
+            0 => disk_size(job.staging.clone()).await,
+            n => n,
+        };
+        skopeo::finish(&span, started, bytes, result.is_ok());
+        result
+    }
+
+    async fn mirror(&self, job: &MirrorJob, progress: Option<&EventSender>) -> Result<(), PullError> {
+        let span = skopeo::span("mirror", &job.reference, "registry");
+        let started = Instant::now();
+        let result = self.copy_to_registry(job, progress).instrument(span.clone()).await;
+        skopeo::finish(&span, started, job.activity.bytes(), result.is_ok());
+        result
+    }
+
+    /// `skopeo inspect --raw` prints the manifest as served; its digest is
+    /// that of the bytes.
+    async fn resolve(&self, reference: &str, credentials: Option<&Credentials>) -> Result<String, PullError> {
+        let raw = self
+            .output("inspect", credentials, reference, |cmd| {
+                cmd.arg("--raw").arg(format!("docker://{}", reference));
+            })
+            .instrument(skopeo::span("inspect", reference, "raw"))
+            .await?;
+        Ok(sha256_digest(&raw))
+    }
+
+    async fn tags(&self, repository: &str, credentials: Option<&Credentials>) -> Result<Vec<String>, PullError> {
+        #[derive(Deserialize)]
+        struct Listed {
+            #[serde(rename = "Tags", default)]
+            tags: Vec<String>,
+        }
+        let listed = self
+            .output("list-tags", credentials, repository, |cmd| {
+                cmd.arg(format!("docker://{}", repository));
+            })
+            .instrument(skopeo::span("list-tags", repository, "tags"))
+            .await?;
+        let listed: Listed = serde_json::from_slice(&listed)
+            .map_err(|e| PullError::Failed(format!("unexpected skopeo list-tags output: {}", e)))?;
+        Ok(listed.tags)
+    }
+
+    async fn ready(&self) -> Result<(), String> {
+        let mut cmd = Command::new(&self.path);
+        cmd.arg("--version").kill_on_drop(true);
+        let _process = SkopeoProcess::start();
+        match cmd.output().await {
+            Ok(output) if output.status.success() => Ok(()),
+            _ => Err("skopeo not available".to_string()),
+        }
+    }
+}
+
+impl SkopeoPuller {
+    /// `skopeo <operation>` with the generated registries.conf and retries.
+    fn command(&self, operation: &str) -> Command {
+        let mut cmd = Command::new(&self.path);
+        if let Some(conf) = &self.registries_conf {
+            cmd.arg("--registries-conf").arg(conf.path());
+        }
+        cmd.arg(operation);
+        if self.retry_times > 0 {
+            cmd.arg("--retry-times").arg(self.retry_times.to_string());
+        }
+        cmd.kill_on_drop(true);
+        cmd
+    }
+
+    async fn copy(&self, job: &PullJob, progress: Option<&EventSender>) -> Result<(), PullError> {
+        let authfile = match &job.credentials {
+            Some(creds) => Some(write_authfile(&job.reference, creds)?),
+            None => None,
+        };
+
+        let mut cmd = self.command("copy");
+        if let Some(file) = &authfile {
+            cmd.arg("--src-authfile").arg(file.path());
+        }
+        if let Some(kind) = job.layer_compression {
+            cmd.arg("--dest-compress-format").arg(kind.as_str());
+        }
+        cmd.arg(format!("docker://{}", job.reference))
+            .arg(job.format.destination(&job.staging, &job.repo, &job.tag));
+
+        run(cmd, progress, &job.activity).await
+    }
+
+    async fn copy_to_registry(&self, job: &MirrorJob, progress: Option<&EventSender>) -> Result<(), PullError> {
+        let mut authfiles = Vec::new();
+        let mut cmd = self.command("copy");
+        let sides = [
+            ("--src-authfile", &job.reference, &job.credentials),
+            ("--dest-authfile", &job.destination, &job.destination_credentials),
+        ];
+        for (flag, reference, credentials) in sides {
+            if let Some(creds) = credentials {
+                let file = write_authfile(reference, creds)?;
+                cmd.arg(flag).arg(file.path());
+                authfiles.push(file);
+            }
+        }
+        cmd.arg(format!("docker://{}", job.reference)).arg(format!("docker://{}", job.destination));
+
+        run(cmd, progress, &job.activity).await
+    }
+
+    /// Runs `skopeo <operation>`, with the arguments `args` adds after the
+    /// credentials for `reference`, and returns what it printed.
+    async fn output(
+        &self,
+        operation: &str,
+        credentials: Option<&Credentials>,
+        reference: &str,
+        args: impl FnOnce(&mut Command),
+    ) -> Result<Vec<u8>, PullError> {
+        let authfile = match credentials {
+            Some(creds) => Some(write_authfile(reference, creds)?),
+            None => None,
+        };
+        let mut cmd = self.command(operation);
+        if let Some(file) = &authfile {
+            cmd.arg("--authfile").arg(file.path());
+        }
+        args(&mut cmd);
+        cmd.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped());
+        tracing::debug!(command = ?cmd.as_std(), "running skopeo");
+        let output = {
+            let _process = SkopeoProcess::start();
+            cmd.output().await
+        };
+        drop(authfile);
+        let output = output.map_err(PullError::Spawn)?;
+        record_exit(output.status);
+        if !output.status.success() {
+            return Err(PullError::Failed(String::from_utf8_lossy(&output.stderr).into_owned()));
+        }
+        Ok(output.stdout)
+    }
+}
+
+/// Runs a skopeo copy, forwarding its output to `progress` if set.
+///
+/// skopeo stages oci-archive destinations, and blobs of unknown size, in its
+/// temporary directory, so the destination can stay empty for most of a long
+/// pull: each copy gets its own, whose growth counts as `activity` like the
+/// lines skopeo prints.
+async fn run(mut cmd: Command, progress: Option<&EventSender>, activity: &Activity) -> Result<(), PullError> {
+    let scratch = tempfile::Builder::new().prefix("skopeo-").tempdir()?;
+    cmd.env("TMPDIR", scratch.path());
+    tokio::select! {
+        result = run_with_progress(cmd, progress, activity) => result,
+        () = watch_growth(scratch.path(), activity) => unreachable!("watch_growth never returns"),
+    }
+}
+
+fn extract_registry(reference: &str) -> String {
+    let ref_no_transport = reference
+        .strip_prefix("docker://")
+        .or_else(|| reference.split_once("://").map(|(_, r)| r))
+        .unwrap_or(reference);
+
+    let first = ref_no_transport
+        .split('/')
+        .next()
+        .unwrap_or("docker.io")
+        .trim();
+
+    if first.contains('.') || first.contains(':') || first == "localhost" {
+        first.to_string()
+    } else {
+        "docker.io".to_string()
+    }
+}
+
+/// Writes a private containers-auth.json for the registry of `reference`.
+///
+/// The file is removed when dropped, so credentials do not outlive a job
+/// whose future is dropped while skopeo runs (`kill_on_drop` stops skopeo).
+fn write_authfile(reference: &str, creds: &Credentials) -> std::io::Result<tempfile::NamedTempFile> {
+    let registry = extract_registry(reference);
+    let auth = base64::engine::general_purpose::STANDARD
+        .encode(format!("{}:{}", creds.username, creds.password));
+
+    let mut auths = serde_json::Map::new();
+    auths.insert(registry.clone(), serde_json::json!({ "auth": auth.clone() }));
+    if registry == "docker.io" {
+        auths.insert("https://index.docker.io/v1/".to_string(), serde_json::json!({ "auth": auth }));
+    }
+
+    // Created readable by its owner only.
+    let mut file = tempfile::Builder::new().prefix("skopeo-auth-").suffix(".json").tempfile()?;
+    file.write_all(serde_json::json!({ "auths": auths }).to_string().as_bytes())?;
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn dropping_a_job_removes_its_authfile() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let args = dir.path().join("args");
+        let skopeo = dir.path().join("skopeo");
+        std::fs::write(&skopeo, format!("#!/bin/sh\necho \"$@\" > {}\nsleep 30\n", args.display())).unwrap();
+        std::fs::set_permissions(&skopeo, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let puller = SkopeoPuller { path: skopeo.display().to_string(), retry_times: 0, registries_conf: None };
+        let creds = Credentials { username: "alice".to_string(), password: "secret".to_string() };
+
+        let mut inspect = Box::pin(puller.output("inspect", Some(&creds), "registry.example/app", |_| {}));
+        let authfile = loop {
+            tokio::select! {
+                _ = &mut inspect => panic!("skopeo returned"),
+                () = tokio::time::sleep(Duration::from_millis(20)) => {}
+            }
+            let Ok(line) = std::fs::read_to_string(&args) else { continue };
+            let Some(path) = line.split_whitespace().skip_while(|a| *a != "--authfile").nth(1) else { continue };
+            break std::path::PathBuf::from(path);
+        };
+        assert!(std::fs::read_to_string(&authfile).unwrap().contains("registry.example"));
+        drop(inspect);
+        assert!(!authfile.exists());
+    }
+}
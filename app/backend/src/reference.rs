@@ -0,0 +1,92 @@
+use std::fmt;
+
+pub const DOCKER_HUB: &str = "docker.io";
+const DOCKER_HUB_API: &str = "registry-1.docker.io";
+
+/// A parsed `registry/repository[:tag][@digest]` image reference.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageReference {
+    /// Registry host as written by users (`docker.io` for Docker Hub).
+    pub registry: String,
+    /// Full repository path, including `library/` for official Hub images.
+    pub repository: String,
+    pub tag: Option<String>,
+    pub digest: Option<String>,
+}
+
+impl ImageReference {
+    pub fn parse(reference: &str) -> Result<Self, String> {
+        let s = reference.trim();
+        let s = s.strip_prefix("docker://").unwrap_or(s);
+        if s.is_empty() {
+            return Err("empty reference".to_string());
+        }
+
+        let (name, digest) = match s.split_once('@') {
+            Some((n, d)) => {
+                if !d.starts_with("sha256:") || d.len() != 71 {
+                    return Err(format!("invalid digest: {}", d));
+                }
+                (n, Some(d.to_string()))
+            }
+            None => (s, None),
+        };
+
+        // A colon after the last slash separates the tag; earlier ones are ports.
+        let (name, tag) = match name.rfind(':') {
+            Some(i) if !name[i..].contains('/') => (&name[..i], Some(name[i + 1..].to_string())),
+            _ => (name, None),
+        };
+        if tag.as_deref().is_some_and(str::is_empty) {
+            return Err(format!("empty tag in {}", reference));
+        }
+
+        let (registry, repository) = match name.split_once('/') {
+            Some((first, rest)) if first.contains('.') || first.contains(':') || first == "localhost" => {
+                (first.to_string(), rest.to_string())
+            }
+            _ => (DOCKER_HUB.to_string(), name.to_string()),
+        };
+        if repository.is_empty() {
+            return Err(format!("missing repository in {}", reference));
+        }
+        let repository = if registry == DOCKER_HUB && !repository.contains('/') {
+            format!("library/{}", repository)
+        } else {
+            repository
+        };
+
+        let tag = if tag.is_none() && digest.is_none() { Some("latest".to_string()) } else { tag };
+        Ok(Self { registry, repository, tag, digest })
+    }
+
+    /// Host serving the registry API (Docker Hub's differs from its name).
+    pub fn api_host(&self) -> &str {
+        if self.registry == DOCKER_HUB {
+            DOCKER_HUB_API
+        } else {
+            &self.registry
+        }
+    }
+
+    /// Digest if pinned, tag otherwise: what goes after `/manifests/`.
+    pub fn manifest_ref(&self) -> &str {
+        self.digest
+            .as_deref()
+            .or(self.tag.as_deref())
+            .unwrap_or("latest")
+    }
+}
+
+impl fmt::Display for ImageReference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.registry, self.repository)?;
+        if let Some(tag) = &self.tag {
+            write!(f, ":{}", tag)?;
+        }
+        if let Some(digest) = &self.digest {
+            write!(f, "@{}", digest)?;
+        }
+        Ok(())
+    }
+}
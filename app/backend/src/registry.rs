@@ -0,0 +1,560 @@
+//! Minimal OCI distribution API client: manifests, blobs and tag lists, and
+//! the uploads that copying an image into another registry needs.
+
+use crate::{
+    bandwidth::Bandwidth,
+    checksum::Sha256Sum,
+    puller::{Credentials, PullError},
+    reference::ImageReference,
+    timeouts::Activity,
+};
+use reqwest::{header, Method, RequestBuilder, Response, StatusCode};
+use serde::Deserialize;
+use std::path::Path;
+use tokio::{fs, io::AsyncWriteExt, sync::RwLock};
+use tokio_stream::StreamExt;
+
+pub const OCI_INDEX: &str = "application/vnd.oci.image.index.v1+json";
+pub const OCI_MANIFEST: &str = "application/vnd.oci.image.manifest.v1+json";
+pub const DOCKER_LIST: &str = "application/vnd.docker.distribution.manifest.list.v2+json";
+pub const DOCKER_MANIFEST: &str = "application/vnd.docker.distribution.manifest.v2+json";
+
+/// A manifest as served, with the media type and digest it was served under.
+pub struct FetchedManifest {
+    pub bytes: Vec<u8>,
+    pub media_type: String,
+    pub digest: String,
+}
+
+impl FetchedManifest {
+    pub fn is_index(&self) -> bool {
+        self.media_type == OCI_INDEX || self.media_type == DOCKER_LIST
+    }
+}
+
+/// A connection to one repository, remembering the credentials it earned.
+pub struct Session {
+    http: reqwest::Client,
+    base: String,
+    repository: String,
+    credentials: Option<Credentials>,
+    authorization: RwLock<Option<String>>,
+    /// Scope actions asked of token servers.
+    actions: &'static str,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    token: Option<String>,
+    access_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TagList {
+    #[serde(default)]
+    tags: Option<Vec<String>>,
+}
+
+#[derive(Deserialize)]
+struct ErrorBody {
+    #[serde(default)]
+    errors: Vec<ErrorEntry>,
+}
+
+#[derive(Deserialize)]
+struct ErrorEntry {
+    code: String,
+    #[serde(default)]
+    message: String,
+}
+
+impl Session {
+    pub fn new(
+        http: reqwest::Client,
+        image: &ImageReference,
+        plain_http: bool,
+        credentials: Option<Credentials>,
+    ) -> Self {
+        let scheme = if plain_http { "http" } else { "https" };
+        Self {
+            http,
+            base: format!("{}://{}/v2", scheme, image.api_host()),
+            repository: image.repository.clone(),
+            credentials,
+            authorization: RwLock::new(None),
+            actions: "pull",
+        }
+    }
+
+    /// The same session, asking for the right to push too.
+    pub fn for_push(mut self) -> Self {
+        self.actions = "pull,push";
+        self
+    }
+
+    pub async fn manifest(&self, reference: &str) -> Result<FetchedManifest, PullError> {
+        let url = format!("{}/{}/manifests/{}", self.base, self.repository, reference);
+        let accept = [OCI_INDEX, DOCKER_LIST, OCI_MANIFEST, DOCKER_MANIFEST].join(", ");
+        let resp = self.get(&url, Some(&accept)).await?;
+        let media_type = resp
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.split(';').next().unwrap_or(v).trim().to_string())
+            .unwrap_or_default();
+        let bytes = resp.bytes().await.map_err(transport)?.to_vec();
+        let digest = crate::checksum::sha256_digest(&bytes);
+        // Some registries answer with a generic type: fall back to the body.
+        let media_type = match media_type.as_str() {
+            OCI_INDEX | DOCKER_LIST | OCI_MANIFEST | DOCKER_MANIFEST => media_type,
+            _ => embedded_media_type(&bytes)?,
+        };
+        Ok(FetchedManifest { bytes, media_type, digest })
+    }
+
+    /// Downloads a blob into `path`, returning its size and actual digest.
+    pub async fn blob_to_file(
+        &self,
+        digest: &str,
+        path: &Path,
+        activity: &Activity,
+        bandwidth: &Bandwidth,
+    ) -> Result<(u64, String), PullError> {
+        let url = format!("{}/{}/blobs/{}", self.base, self.repository, digest);
+        let resp = self.get(&url, None).await?;
+        let mut file = fs::File::create(path).await?;
+        let mut sum = Sha256Sum::default();
+        let mut size = 0u64;
+        let mut body = resp.bytes_stream();
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk.map_err(transport)?;
+            sum.update(&chunk);
+            size += chunk.len() as u64;
+            file.write_all(&chunk).await?;
+            activity.advance(chunk.len() as u64);
+            bandwidth.consume(chunk.len() as u64).await;
+        }
+        file.flush().await?;
+        Ok((size, format!("sha256:{}", sum.finish())))
+    }
+
+    /// Every tag of the repository, following the registry's pagination.
+    pub async fn tags(&self) -> Result<Vec<String>, PullError> {
+        let mut url = format!("{}/{}/tags/list?n=1000", self.base, self.repository);
+        let mut tags = Vec::new();
+        loop {
+            let resp = self.get(&url, None).await?;
+            let next = resp
+                .headers()
+                .get(header::LINK)
+                .and_then(|v| v.to_str().ok())
+                .and_then(next_link)
+                .map(|link| self.resolve_url(&link))
+                .transpose()?;
+            let page: TagList = resp.json().await.map_err(transport)?;
+            tags.extend(page.tags.unwrap_or_default());
+            match next {
+                Some(link) => url = link,
+                None => return Ok(tags),
+            }
+        }
+    }
+
+    /// Whether the repository already holds blob `digest`.
+    pub async fn has_blob(&self, digest: &str) -> Result<bool, PullError> {
+        let url = format!("{}/{}/blobs/{}", self.base, self.repository, digest);
+        match self.send(|| self.http.head(&url)).await {
+            Ok(_) => Ok(true),
+            Err(PullError::Registry { status: 404, .. }) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Uploads the file at `path` as blob `digest`, in one request.
+    pub async fn upload_blob(&self, digest: &str, path: &Path, activity: &Activity) -> Result<(), PullError> {
+        let url = format!("{}/{}/blobs/uploads/", self.base, self.repository);
+        let started = self.send(|| self.http.post(&url).header(header::CONTENT_LENGTH, 0)).await?;
+        let location = started
+            .headers()
+            .get(header::LOCATION)
+            .and_then(|v| v.to_str().ok())
+            .ok_or_else(|| PullError::Failed("registry started an upload without a Location".to_string()))?;
+        let mut target = url::Url::parse(&self.resolve_url(location)?)
+            .map_err(|e| PullError::Failed(format!("invalid upload location: {}", e)))?;
+        target.query_pairs_mut().append_pair("digest", digest);
+
+        let file = fs::File::open(path).await?;
+        let size = file.metadata().await?.len();
+        let activity = activity.clone();
+        let body = tokio_util::io::ReaderStream::new(file).map(move |chunk| {
+            if let Ok(chunk) = &chunk {
+                activity.advance(chunk.len() as u64);
+            }
+            chunk
+        });
+        // The session negotiated its authorization starting the upload; a
+        // streamed body cannot be sent twice anyway.
+        let mut req = self
+            .http
+            .put(target)
+            .header(header::CONTENT_TYPE, "application/octet-stream")
+            .header(header::CONTENT_LENGTH, size)
+            .body(reqwest::Body::wrap_stream(body));
+        if let Some(auth) = self.authorization.read().await.clone() {
+            req = req.header(header::AUTHORIZATION, auth);
+        }
+        let resp = req.send().await.map_err(transport)?;
+        if !resp.status().is_success() {
+            return Err(registry_error(resp).await);
+        }
+        Ok(())
+    }
+
+    /// Stores `manifest` under `reference`, a tag or its digest.
+    pub async fn put_manifest(&self, reference: &str, manifest: &FetchedManifest) -> Result<(), PullError> {
+        let url = format!("{}/{}/manifests/{}", self.base, self.repository, reference);
+        self.send(|| {
+            self.http
+                .put(&url)
+                .header(header::CONTENT_TYPE, &manifest.media_type)
+                .body(manifest.bytes.clone())
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// `location` made absolute: registries may answer with a path.
+    fn resolve_url(&self, location: &str) -> Result<String, PullError> {
+        let base = url::Url::parse(&self.base).map_err(|e| PullError::Failed(format!("invalid registry URL: {}", e)))?;
+        base.join(location)
+            .map(String::from)
+            .map_err(|e| PullError::Failed(format!("invalid location '{}': {}", location, e)))
+    }
+
+    async fn get(&self, url: &str, accept: Option<&str>) -> Result<Response, PullError> {
+        self.send(|| {
+            let req = self.http.request(Method::GET, url);
+            match accept {
+                Some(accept) => req.header(header::ACCEPT, accept),
+                None => req,
+            }
+        })
+        .await
+    }
+
+    /// Sends the request `build` makes with whatever authorization the
+    /// registry asks for, negotiated once.
+    async fn send(&self, build: impl Fn() -> RequestBuilder) -> Result<Response, PullError> {
+        let mut retried = false;
+        loop {
+            let mut req = build();
+            if let Some(auth) = self.authorization.read().await.clone() {
+                req = req.header(header::AUTHORIZATION, auth);
+            }
+            let resp = req.send().await.map_err(transport)?;
+
+            if resp.status() == StatusCode::UNAUTHORIZED && !retried {
+                let challenge = resp
+                    .headers()
+                    .get(header::WWW_AUTHENTICATE)
+                    .and_then(|v| v.to_str().ok())
+                    .map(str::to_string);
+                if let Some(challenge) = challenge {
+                    let auth = self.authenticate(&challenge).await?;
+                    *self.authorization.write().await = Some(auth);
+                    retried = true;
+                    continue;
+                }
+            }
+            if !resp.status().is_success() {
+                return Err(registry_error(resp).await);
+            }
+            return Ok(resp);
+        }
+    }
+
+    /// Answers a `WWW-Authenticate` challenge with an `Authorization` value.
+    async fn authenticate(&self, challenge: &str) -> Result<String, PullError> {
+        let (scheme, params) = challenge.split_once(' ').unwrap_or((challenge, ""));
+        let params = parse_challenge(params);
+
+        if scheme.eq_ignore_ascii_case("basic") {
+            let Some(creds) = &self.credentials else {
+                return Err(unauthorized("authentication required"));
+            };
+            use base64::Engine as _;
+            let token = base64::engine::general_purpose::STANDARD
+                .encode(format!("{}:{}", creds.username, creds.password));
+            return Ok(format!("Basic {}", token));
+        }
+        if !scheme.eq_ignore_ascii_case("bearer") {
+            return Err(unauthorized(&format!("unsupported auth scheme {}", scheme)));
+        }
+
+        let realm = params
+            .iter()
+            .find(|(k, _)| k == "realm")
+            .map(|(_, v)| v.clone())
+            .ok_or_else(|| unauthorized("bearer challenge without realm"))?;
+        let mut query: Vec<(&str, String)> = Vec::new();
+        if let Some((_, service)) = params.iter().find(|(k, _)| k == "service") {
+            query.push(("service", service.clone()));
+        }
+        query.push(("scope", format!("repository:{}:{}", self.repository, self.actions)));
+
+        let mut req = self.http.get(&realm).query(&query);
+        if let Some(creds) = &self.credentials {
+            req = req.basic_auth(&creds.username, Some(&creds.password));
+        }
+        let resp = req.send().await.map_err(transport)?;
+        if !resp.status().is_success() {
+            return Err(registry_error(resp).await);
+        }
+        let body: TokenResponse = resp.json().await.map_err(transport)?;
+        body.token
+            .or(body.access_token)
+            .map(|t| format!("Bearer {}", t))
+            .ok_or_else(|| unauthorized("token endpoint returned no token"))
+    }
+}
+
+fn unauthorized(message: &str) -> PullError {
+    PullError::Registry {
+        status: StatusCode::UNAUTHORIZED.as_u16(),
+        code: Some("UNAUTHORIZED".to_string()),
+        message: message.to_string(),
+        retry_after: None,
+    }
+}
+
+fn transport(e: reqwest::Error) -> PullError {
+    PullError::Failed(format!("registry request failed: {}", error_chain(&e)))
+}
+
+/// reqwest hides the interesting part (DNS, TLS, refused) in the source chain.
+pub(crate) fn error_chain(e: &dyn std::error::Error) -> String {
+    let mut msg = e.to_string();
+    let mut source = e.source();
+    while let Some(s) = source {
+        msg.push_str(": ");
+        msg.push_str(&s.to_string());
+        source = s.source();
+    }
+    msg
+}
+
+async fn registry_error(resp: Response) -> PullError {
+    let status = resp.status();
+    let retry_after = resp
+        .headers()
+        .get(header::RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse().ok());
+    let body = resp.text().await.unwrap_or_default();
+    let entry = serde_json::from_str::<ErrorBody>(&body)
+        .ok()
+        .and_then(|b| b.errors.into_iter().next());
+    let (code, message) = match entry {
+        Some(e) => {
+            let message = if e.message.is_empty() { e.code.to_lowercase().replace('_', " ") } else { e.message };
+            (Some(e.code), message)
+        }
+        None => (None, status.canonical_reason().unwrap_or("error").to_lowercase()),
+    };
+    PullError::Registry { status: status.as_u16(), code, message, retry_after }
+}
+
+/// Target of the `rel="next"` entry of a `Link` header.
+fn next_link(header: &str) -> Option<String> {
+    header.split(',').find_map(|entry| {
+        let (target, params) = entry.split_once(';')?;
+        params.contains("rel=\"next\"").then(|| target.trim().trim_start_matches('<').trim_end_matches('>').to_string())
+    })
+}
+
+fn embedded_media_type(bytes: &[u8]) -> Result<String, PullError> {
+    #[derive(Deserialize)]
+    struct Probe {
+        #[serde(rename = "mediaType")]
+        media_type: Option<String>,
+        manifests: Option<serde_json::Value>,
+        #[serde(rename = "schemaVersion")]
+        schema_version: Option<u32>,
+    }
+    let probe: Probe = serde_json::from_slice(bytes)
+        .map_err(|e| PullError::Invalid(format!("invalid manifest: {}", e)))?;
+    if probe.schema_version == Some(1) {
+        return Err(PullError::Invalid("schema 1 manifests are not supported".to_string()));
+    }
+    Ok(probe.media_type.unwrap_or_else(|| {
+        if probe.manifests.is_some() { OCI_INDEX } else { OCI_MANIFEST }.to_string()
+    }))
+}
+
+/// Splits `realm="…",service="…"` into pairs; commas may appear inside quotes.
+fn parse_challenge(params: &str) -> Vec<(String, String)> {
+    let mut out = Vec::new();
+    let mut rest = params.trim();
+    while !rest.is_empty() {
+        let Some((key, after)) = rest.split_once('=') else { break };
+        let key = key.trim().trim_start_matches(',').trim().to_lowercase();
+        let (value, tail) = if let Some(quoted) = after.strip_prefix('"') {
+            match quoted.find('"') {
+                Some(end) => (&quoted[..end], &quoted[end + 1..]),
+                None => (quoted, ""),
+            }
+        } else {
+            match after.find(',') {
+                Some(end) => (&after[..end], &after[end..]),
+                None => (after, ""),
+            }
+        };
+        out.push((key, value.to_string()));
+        rest = tail.trim_start_matches(',').trim();
+    }
+    out
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use axum::{
+        extract::{Request, State},
+        http::HeaderMap,
+        response::IntoResponse,
+    };
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+    };
+
+    const TOKEN: &str = "stub-token";
+
+    /// What the stub answers at one path and query.
+    struct Served {
+        content_type: String,
+        body: Vec<u8>,
+        link: Option<String>,
+    }
+
+    /// An in-process registry behind a bearer challenge, serving whatever
+    /// was put into it and recording the token requests it got.
+    #[derive(Clone, Default)]
+    pub(crate) struct Stub {
+        served: Arc<Mutex<HashMap<String, Served>>>,
+        token_queries: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Stub {
+        pub(crate) fn put(&self, path: &str, content_type: &str, body: &[u8], link: Option<&str>) {
+            let served = Served { content_type: content_type.into(), body: body.to_vec(), link: link.map(Into::into) };
+            self.served.lock().unwrap().insert(path.to_string(), served);
+        }
+
+        /// Serves `body` as a blob of `repository`, under its own digest.
+        pub(crate) fn blob(&self, repository: &str, body: &[u8]) -> String {
+            let digest = crate::checksum::sha256_digest(body);
+            self.put(&format!("/v2/{repository}/blobs/{digest}"), "application/octet-stream", body, None);
+            digest
+        }
+
+        /// Serves manifest `body` under `reference` and under its digest.
+        pub(crate) fn manifest(&self, repository: &str, reference: &str, media_type: &str, body: &[u8]) -> String {
+            let digest = crate::checksum::sha256_digest(body);
+            for r in [reference, digest.as_str()] {
+                self.put(&format!("/v2/{repository}/manifests/{r}"), media_type, body, None);
+            }
+            digest
+        }
+
+        pub(crate) fn token_queries(&self) -> Vec<String> {
+            self.token_queries.lock().unwrap().clone()
+        }
+
+        /// Starts serving on a free local port, returning its `host:port`.
+        pub(crate) async fn serve(&self) -> String {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let host = listener.local_addr().unwrap().to_string();
+            let app = axum::Router::new().fallback(answer).with_state(self.clone());
+            tokio::spawn(async move { axum::serve(listener, app).await });
+            host
+        }
+    }
+
+    async fn answer(State(stub): State<Stub>, req: Request) -> axum::response::Response {
+        let uri = req.uri().clone();
+        let host = req.headers().get(header::HOST).and_then(|v| v.to_str().ok()).unwrap_or_default().to_string();
+        if uri.path() == "/token" {
+            stub.token_queries.lock().unwrap().push(uri.query().unwrap_or_default().to_string());
+            return axum::Json(serde_json::json!({ "token": TOKEN })).into_response();
+        }
+        let bearer = format!("Bearer {TOKEN}");
+        if req.headers().get(header::AUTHORIZATION).and_then(|v| v.to_str().ok()) != Some(bearer.as_str()) {
+            let challenge = format!(r#"Bearer realm="http://{host}/token",service="stub""#);
+            return (StatusCode::UNAUTHORIZED, [(header::WWW_AUTHENTICATE, challenge)]).into_response();
+        }
+        let key = uri.path_and_query().map(|p| p.as_str()).unwrap_or(uri.path());
+        let served = stub.served.lock().unwrap();
+        let Some(found) = served.get(key).or_else(|| served.get(uri.path())) else {
+            let body = r#"{"errors":[{"code":"MANIFEST_UNKNOWN","message":"manifest unknown"}]}"#;
+            return (StatusCode::NOT_FOUND, body).into_response();
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, found.content_type.parse().unwrap());
+        if let Some(link) = &found.link {
+            headers.insert(header::LINK, link.parse().unwrap());
+        }
+        (StatusCode::OK, headers, found.body.clone()).into_response()
+    }
+
+    fn session(host: &str, repository: &str) -> Session {
+        let image = ImageReference::parse(&format!("{host}/{repository}:1")).unwrap();
+        Session::new(reqwest::Client::new(), &image, true, None)
+    }
+
+    #[test]
+    fn challenge_params_keep_commas_inside_quotes() {
+        let header = r#"realm="https://auth.example/token",service=registry.example,scope="repository:a/b:pull,push""#;
+        let params = parse_challenge(header);
+        let expected = [
+            ("realm", "https://auth.example/token"),
+            ("service", "registry.example"),
+            ("scope", "repository:a/b:pull,push"),
+        ];
+        let params: Vec<(&str, &str)> = params.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+        assert_eq!(params, expected);
+    }
+
+    #[test]
+    fn next_link_is_the_rel_next_target() {
+        let header = r#"</v2/demo/app/tags/list?n=2&last=b>; rel="next""#;
+        assert_eq!(next_link(header).as_deref(), Some("/v2/demo/app/tags/list?n=2&last=b"));
+        assert_eq!(next_link(r#"</v2/other>; rel="prev""#), None);
+    }
+
+    #[tokio::test]
+    async fn tags_follow_pagination_behind_a_bearer_challenge() {
+        let stub = Stub::default();
+        let next = r#"</v2/demo/app/tags/list?n=1000&last=b>; rel="next""#;
+        stub.put("/v2/demo/app/tags/list?n=1000", "application/json", br#"{"tags":["a","b"]}"#, Some(next));
+        stub.put("/v2/demo/app/tags/list?n=1000&last=b", "application/json", br#"{"tags":["c"]}"#, None);
+        let host = stub.serve().await;
+
+        let tags = session(&host, "demo/app").tags().await.unwrap();
+        assert_eq!(tags, ["a", "b", "c"]);
+        // The token is asked for once, for the repository, and then reused.
+        assert_eq!(stub.token_queries(), ["service=stub&scope=repository%3Ademo%2Fapp%3Apull"]);
+    }
+
+    #[tokio::test]
+    async fn registry_errors_keep_their_code() {
+        let host = Stub::default().serve().await;
+        match session(&host, "demo/app").manifest("missing").await {
+            Err(PullError::Registry { status: 404, code, .. }) => {
+                assert_eq!(code.as_deref(), Some("MANIFEST_UNKNOWN"))
+            }
+            Err(e) => panic!("unexpected error: {e}"),
+            Ok(_) => panic!("a missing manifest was found"),
+        }
+    }
+}
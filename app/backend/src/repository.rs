@@ -0,0 +1,473 @@
+//! Repository jobs: the tags of one repository, picked by regex or semver
+//! range, exported together into a single bundle or copied to another
+//! registry, like `skopeo sync`.
+//!
+//! Tags read as versions with an optional `v`, missing minor and patch
+//! numbers counting as 0 (`v2.53`, `1.25.3`); a semver range only keeps
+//! those, and pre-releases (`1.26.0-rc.1`, `1.25.3-alpine`) only when the
+//! range names one. `latest` keeps the highest matches, in version order.
+//!
+//! A bundle holds every image in one archive of the requested format, each
+//! blob once; in an `oci` layout each image is named by its tag. A tag that
+//! fails stops a bundle, while copies to a registry go on with the next tag
+//! and end with a `partial_failure` error naming how many failed.
+
+use crate::{
+    archive::{self, ArchiveFormat},
+    artifacts::{artifact_path, finalize, Artifact},
+    audit::{Caller, Produced},
+    bandwidth::Bandwidth,
+    compression::ExportCompression,
+    config::Settings,
+    error::{send_error, ApiError},
+    jobs::{self, JobKind, NewJob},
+    make_filename,
+    metrics::PullMetrics,
+    parse_repo_tag,
+    policy::Action,
+    pull_staged,
+    puller::{Credentials, MirrorJob, PullJob},
+    reference::ImageReference,
+    signing::manifest_time,
+    skopeo::{send_event, EventSender},
+    sync::{tagged, valid_destination},
+    timeouts::{Activity, Expired, Limits},
+    valid_ref,
+    webhooks::Callback,
+    AppState, PullRecord,
+};
+use axum::{
+    extract::{Json, State},
+    response::{sse::Sse, IntoResponse, Response},
+};
+use regex::Regex;
+use semver::{Version, VersionReq};
+use serde::Deserialize;
+use std::sync::Arc;
+use tokio::fs;
+use tracing::Instrument;
+use uuid::Uuid;
+
+#[derive(Deserialize)]
+pub struct RepositoryRequestBody {
+    /// `registry/path`, without tag.
+    repository: String,
+    /// Regex the tags must match; unanchored, as written.
+    #[serde(default)]
+    pattern: Option<String>,
+    /// Semver range the tags must fall in, e.g. `>=1.25 <1.27`.
+    #[serde(default)]
+    semver: Option<String>,
+    /// Keeps only the highest matching versions.
+    #[serde(default)]
+    latest: Option<usize>,
+    /// `registry/path` the tags are copied under instead of being bundled.
+    #[serde(default)]
+    destination: Option<String>,
+    #[serde(default = "crate::default_format")]
+    format: String,
+    #[serde(default)]
+    username: Option<String>,
+    #[serde(default)]
+    password: Option<String>,
+    #[serde(default)]
+    compression: Option<String>,
+    #[serde(default)]
+    compression_level: Option<i32>,
+    #[serde(default)]
+    layer_compression: Option<String>,
+    /// Limits of each tag's pull, in seconds.
+    #[serde(default)]
+    timeout: Option<u64>,
+    #[serde(default)]
+    idle_timeout: Option<u64>,
+    /// Bytes per second, for pullers that can limit it.
+    #[serde(default)]
+    bandwidth: Option<u64>,
+    /// Where to post the outcome of the job.
+    #[serde(default)]
+    callback: Option<Callback>,
+}
+
+/// Which tags a job takes.
+struct TagFilter {
+    pattern: Option<Regex>,
+    range: Option<VersionReq>,
+    latest: Option<usize>,
+}
+
+impl TagFilter {
+    fn parse(body: &RepositoryRequestBody) -> Result<Self, String> {
+        let pattern = body
+            .pattern
+            .as_deref()
+            .map(|p| Regex::new(p).map_err(|e| format!("invalid pattern: {e}")))
+            .transpose()?;
+        let range = body.semver.as_deref().map(version_req).transpose()?;
+        if body.latest == Some(0) {
+            return Err("latest must be positive".into());
+        }
+        Ok(Self { pattern, range, latest: body.latest })
+    }
+
+    /// The matching `tags`, in version order (tags that are not versions
+    /// first, by name).
+    fn select(&self, tags: Vec<String>) -> Vec<String> {
+        let mut matched: Vec<(Option<Version>, String)> = tags
+            .into_iter()
+            .filter(|tag| self.pattern.as_ref().is_none_or(|p| p.is_match(tag)))
+            .map(|tag| (tag_version(&tag), tag))
+            .filter(|(version, _)| match (&self.range, version) {
+                (Some(range), Some(version)) => range.matches(version),
+                (Some(_), None) => false,
+                (None, _) => true,
+            })
+            .collect();
+        matched.sort();
+        let skip = self.latest.map_or(0, |n| matched.len().saturating_sub(n));
+        matched.into_iter().skip(skip).map(|(_, tag)| tag).collect()
+    }
+}
+
+/// A range as people write it: comparators separated by spaces or commas,
+/// an operator possibly apart from its version (`>= 1.25 <1.27`).
+fn version_req(range: &str) -> Result<VersionReq, String> {
+    let mut comparators: Vec<String> = Vec::new();
+    let mut operator = String::new();
+    for token in range.split(|c: char| c.is_whitespace() || c == ',').filter(|t| !t.is_empty()) {
+        if token.chars().all(|c| matches!(c, '<' | '>' | '=' | '~' | '^')) {
+            operator.push_str(token);
+        } else {
+            comparators.push(format!("{}{}", std::mem::take(&mut operator), token.trim_start_matches('v')));
+        }
+    }
+    if !operator.is_empty() || comparators.is_empty() {
+        return Err(format!("invalid semver range '{range}'"));
+    }
+    VersionReq::parse(&comparators.join(", ")).map_err(|e| format!("invalid semver range '{range}': {e}"))
+}
+
+/// `tag` as a version: `v` dropped, minor and patch numbers added if missing.
+fn tag_version(tag: &str) -> Option<Version> {
+    let tag = tag.strip_prefix('v').unwrap_or(tag);
+    let split = tag.find(['-', '+']).unwrap_or(tag.len());
+    let (core, rest) = tag.split_at(split);
+    let missing = 2usize.checked_sub(core.matches('.').count())?;
+    Version::parse(&format!("{core}{}{rest}", ".0".repeat(missing))).ok()
+}
+
+/// Starts a repository job and streams its events (SSE).
+pub async fn repository_stream(
+    State(state): State<AppState>,
+    caller: Caller,
+    Json(body): Json<RepositoryRequestBody>,
+) -> Response {
+    let settings = state.config.current();
+    let job = NewJob {
+        id: Uuid::new_v4().to_string(),
+        kind: JobKind::Repository,
+        reference: body.repository.clone(),
+        format: if body.destination.is_some() { "registry".to_string() } else { body.format.clone() },
+        owner: caller.principal.name.clone(),
+    };
+    let uid = job.id.clone();
+    let mut handle = match state.jobs.start(job, settings.config.jobs.max_running, body.callback.clone()).await {
+        Ok(handle) => handle,
+        Err(e) => return Sse::new(jobs::refused(&e)).into_response(),
+    };
+    let bandwidth = Bandwidth::new(body.bandwidth);
+    if settings.puller.limits_bandwidth() {
+        handle.limit_bandwidth(bandwidth.clone());
+    }
+    let tx = handle.sender();
+    let job_id = handle.id().to_string();
+
+    let rx = handle.spawn(async move {
+        send_event(&tx, "start", "starting").await;
+        let run = Run { state, settings, caller, tx: tx.clone(), bandwidth };
+        if let Err(e) = run.sync(uid, body).await {
+            send_error(&tx, &e).await;
+            return;
+        }
+        send_event(&tx, "end", "done").await;
+    }.instrument(tracing::Span::current()));
+
+    jobs::stream(&job_id, rx)
+}
+
+/// One repository job as it runs.
+struct Run {
+    state: AppState,
+    settings: Arc<Settings>,
+    caller: Caller,
+    tx: EventSender,
+    bandwidth: Bandwidth,
+}
+
+impl Run {
+    async fn sync(&self, uid: String, body: RepositoryRequestBody) -> Result<(), ApiError> {
+        let settings = &self.settings;
+        let repository = body.repository.trim();
+        let image = ImageReference::parse(repository)
+            .ok()
+            .filter(|_| valid_ref(repository) && !tagged(repository))
+            .ok_or_else(|| ApiError::InvalidReference(format!("{repository} (expected registry/path, without tag)")))?;
+        let filter = TagFilter::parse(&body).map_err(ApiError::InvalidRequest)?;
+        if let Some(destination) = &body.destination {
+            if !valid_destination(destination) {
+                return Err(ApiError::InvalidRequest(format!(
+                    "destination '{destination}' must be registry/path, without tag"
+                )));
+            }
+        }
+        let format = body.format.parse::<ArchiveFormat>().map_err(ApiError::InvalidRequest)?;
+        let compression = ExportCompression::parse(
+            format,
+            body.compression.as_deref(),
+            body.compression_level,
+            body.layer_compression.as_deref(),
+        )
+        .map_err(ApiError::InvalidRequest)?;
+        settings.policy.check(&self.caller.principal, Action::Pull, repository)?;
+
+        let credentials = Credentials::from_parts(body.username.as_deref(), body.password.as_deref())
+            .or_else(|| settings.credentials_for(&self.caller.principal, repository));
+        let listed = settings.puller.tags(repository, credentials.as_ref());
+        let tags = match tokio::time::timeout(settings.timeouts.job, listed).await {
+            Ok(Ok(tags)) => filter.select(tags),
+            Ok(Err(e)) => return Err(ApiError::pull(repository, e)),
+            Err(_) => return Err(ApiError::Timeout(format!("timeout while listing the tags of {repository}"))),
+        };
+        if tags.is_empty() {
+            return Err(ApiError::NotFound(format!("no tag of {repository} matches")));
+        }
+        send_event(&self.tx, "tags", serde_json::json!({ "repository": repository, "tags": tags }).to_string()).await;
+
+        let limits = settings.timeouts.for_request(body.timeout, body.idle_timeout);
+        let source = Source { repository, image: &image, tags: &tags, credentials, limits };
+        match &body.destination {
+            Some(destination) => self.copy(&source, destination).await,
+            None => self.bundle(&source, uid, format, compression).await,
+        }
+    }
+
+    /// Pulls every tag, then merges them into artifact `uid`.
+    async fn bundle(
+        &self,
+        source: &Source<'_>,
+        uid: String,
+        format: ArchiveFormat,
+        compression: ExportCompression,
+    ) -> Result<(), ApiError> {
+        // Each tag is pulled as a single tar; a packed layout is an oci-archive.
+        let part_format = match format {
+            ArchiveFormat::DockerArchive => ArchiveFormat::DockerArchive,
+            ArchiveFormat::OciArchive | ArchiveFormat::OciLayout => ArchiveFormat::OciArchive,
+        };
+        let work = tempfile::Builder::new()
+            .prefix("repository-")
+            .tempdir_in(std::env::temp_dir())
+            .map_err(|e| ApiError::io("cannot create a work directory", e))?;
+        let mut parts = Vec::new();
+        for (i, tag) in source.tags.iter().enumerate() {
+            let reference = source.progress(i, &self.tx).await;
+            self.settings.admission.check(&reference)?;
+            let entry = self.state.audit.pull(self.caller.clone(), &reference, format.as_str());
+            let pull = PullRecord { metrics: PullMetrics::start(&reference), audit: entry };
+            let (repo, tag_name) = parse_repo_tag(&reference);
+            let job = PullJob {
+                reference: reference.clone(),
+                format: part_format,
+                staging: work.path().join(format!("{i}.tar")),
+                repo,
+                tag: tag_name,
+                credentials: source.credentials.clone(),
+                layer_compression: compression.layers,
+                activity: Activity::default(),
+                bandwidth: self.bandwidth.clone(),
+            };
+            if let Err(e) = pull_staged(&self.settings, &job, source.limits, &self.tx).await {
+                pull.failed(&e);
+                return Err(e);
+            }
+            let size = fs::metadata(&job.staging).await.ok().map(|m| m.len());
+            pull.succeeded(Produced { digest: None, size, sha256: None, artifact_id: Some(uid.clone()) });
+            let image = serde_json::json!({ "tag": tag, "ref": reference, "outcome": "exported" });
+            send_event(&self.tx, "image", image.to_string()).await;
+            parts.push((job.staging, tag.clone()));
+        }
+
+        send_event(&self.tx, "progress", format!("Writing the bundle of {} images", parts.len())).await;
+        let out = artifact_path(&uid);
+        if let Err(e) = archive::merge(format, parts, out.clone()).await {
+            let _ = fs::remove_file(&out).await;
+            return Err(ApiError::io("cannot write the bundle", e));
+        }
+        drop(work);
+        let finalized = finalize(format, &out, &out, compression.archive).await?;
+
+        let repo = source.image.repository.rsplit('/').next().unwrap_or("image").to_string();
+        let filename = make_filename(&repo, "bundle", format.as_str(), compression.archive.kind);
+        let size = fs::metadata(&out).await.map(|m| m.len()).unwrap_or_default();
+        let created = manifest_time();
+        let stored = self
+            .state
+            .artifacts
+            .insert(Artifact {
+                id: uid.clone(),
+                path: out.clone(),
+                filename: filename.clone(),
+                format,
+                compression: compression.archive.kind,
+                repo,
+                tag: "bundle".to_string(),
+                reference: source.repository.to_string(),
+                digest: None,
+                sha256: finalized.sha256.clone(),
+                size,
+                owner: self.caller.principal.name.clone(),
+                created,
+                expires: created + self.settings.artifact_ttl,
+                node: self.state.node.id.clone(),
+            })
+            .await;
+        if let Err(e) = stored {
+            let _ = fs::remove_file(&out).await;
+            return Err(e);
+        }
+
+        let ready = serde_json::json!({
+            "id": uid,
+            "filename": filename,
+            "sha256": finalized.sha256,
+            "tags": source.tags,
+        });
+        send_event(&self.tx, "ready", ready.to_string()).await;
+        Ok(())
+    }
+
+    /// Copies every tag under `destination`, going on past failures.
+    async fn copy(&self, source: &Source<'_>, destination: &str) -> Result<(), ApiError> {
+        let settings = &self.settings;
+        let target_repository = format!("{}/{}", destination, source.image.repository);
+        let mut failed = 0;
+        for (i, tag) in source.tags.iter().enumerate() {
+            let reference = source.progress(i, &self.tx).await;
+            let target = format!("{target_repository}:{tag}");
+            let copied = match settings
+                .admission
+                .check(&reference)
+                .and_then(|()| settings.policy.check(&self.caller.principal, Action::Push, &target))
+            {
+                Ok(()) => self.copy_tag(source, &reference, &target).await,
+                Err(e) => Err(e),
+            };
+            let image = match copied {
+                Ok(()) => {
+                    serde_json::json!({ "tag": tag, "ref": reference, "outcome": "exported", "destination": target })
+                }
+                Err(e) => {
+                    failed += 1;
+                    serde_json::json!({ "tag": tag, "ref": reference, "outcome": "failed", "error": e.problem() })
+                }
+            };
+            send_event(&self.tx, "image", image.to_string()).await;
+        }
+        if failed > 0 {
+            return Err(ApiError::PartialFailure(format!("{} of {} tags failed", failed, source.tags.len())));
+        }
+        let ready = serde_json::json!({ "destination": target_repository, "tags": source.tags });
+        send_event(&self.tx, "ready", ready.to_string()).await;
+        Ok(())
+    }
+
+    async fn copy_tag(&self, source: &Source<'_>, reference: &str, target: &str) -> Result<(), ApiError> {
+        let settings = &self.settings;
+        let entry = self.state.audit.pull(self.caller.clone(), reference, "registry");
+        let pull = PullRecord { metrics: PullMetrics::start(reference), audit: entry };
+        let job = MirrorJob {
+            reference: reference.to_string(),
+            credentials: source.credentials.clone(),
+            destination: target.to_string(),
+            destination_credentials: settings.credentials_for(&self.caller.principal, target),
+            activity: Activity::default(),
+            bandwidth: self.bandwidth.clone(),
+        };
+        let limit = source.limits.job;
+        let copied = match tokio::time::timeout(limit, settings.puller.mirror(&job, Some(&self.tx))).await {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(e)) => Err(ApiError::pull(reference, e)),
+            Err(_) => Err(ApiError::Timeout(Expired::Job(limit).describe(reference))),
+        };
+        match copied {
+            Ok(()) => {
+                let size = Some(job.activity.bytes());
+                pull.succeeded(Produced { digest: None, size, sha256: None, artifact_id: None });
+                Ok(())
+            }
+            Err(e) => {
+                pull.failed(&e);
+                Err(e)
+            }
+        }
+    }
+}
+
+/// The repository and the tags a job takes from it.
+struct Source<'a> {
+    repository: &'a str,
+    image: &'a ImageReference,
+    tags: &'a [String],
+    credentials: Option<Credentials>,
+    limits: Limits,
+}
+
+impl Source<'_> {
+    /// Announces tag `i` and returns its reference.
+    async fn progress(&self, i: usize, tx: &EventSender) -> String {
+        let reference = format!("{}:{}", self.repository, self.tags[i]);
+        send_event(tx, "progress", format!("[{}/{}] {}", i + 1, self.tags.len(), reference)).await;
+        reference
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn select(pattern: Option<&str>, semver: Option<&str>, latest: Option<usize>, tags: &[&str]) -> Vec<String> {
+        let filter = TagFilter {
+            pattern: pattern.map(|p| Regex::new(p).unwrap()),
+            range: semver.map(|r| version_req(r).unwrap()),
+            latest,
+        };
+        filter.select(tags.iter().map(|t| t.to_string()).collect())
+    }
+
+    #[test]
+    fn keeps_the_latest_versions_in_range() {
+        let tags = [
+            "1.24.9", "1.25.0", "1.25.10", "1.25.2", "1.26.1", "1.26.0-rc.1", "1.27.0", "latest", "1.25.3-alpine",
+        ];
+        assert_eq!(select(None, Some(">=1.25 <1.27"), Some(3), &tags), ["1.25.2", "1.25.10", "1.26.1"]);
+        assert_eq!(select(None, Some(">= 1.25, < 1.26"), None, &tags), ["1.25.0", "1.25.2", "1.25.10"]);
+    }
+
+    #[test]
+    fn reads_short_and_prefixed_versions() {
+        assert_eq!(tag_version("v2.53"), Some(Version::new(2, 53, 0)));
+        assert_eq!(tag_version("7"), Some(Version::new(7, 0, 0)));
+        assert_eq!(tag_version("1.25.3-alpine").map(|v| v.pre.to_string()), Some("alpine".to_string()));
+        assert_eq!(tag_version("latest"), None);
+        assert_eq!(tag_version("1.2.3.4"), None);
+    }
+
+    #[test]
+    fn filters_by_pattern_then_version() {
+        let tags = ["1.0", "1.1-debug", "2.0", "main"];
+        assert_eq!(select(Some(r"^\d"), None, None, &tags), ["1.0", "1.1-debug", "2.0"]);
+        assert_eq!(select(Some("debug|main"), None, Some(1), &tags), ["1.1-debug"]);
+        assert!(version_req(">=").is_err());
+        assert!(version_req("").is_err());
+    }
+}
@@ -0,0 +1,225 @@
+//! Retries of transient pull failures, with exponential backoff and jitter.
+//!
+//! skopeo retries on its own when given `--retry-times`; its warnings are
+//! turned into `retry` events here. The native client retries whole jobs
+//! through [`run`], reusing the blobs it already verified.
+
+use crate::{
+    classify::{classify, classify_message, Failure},
+    puller::PullError,
+    skopeo::{send_event, EventSender},
+    telemetry::redact,
+};
+use rand::Rng;
+use serde::Serialize;
+use std::{future::Future, time::Duration};
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt; 0 disables retrying.
+    pub retries: u32,
+    base: Duration,
+    max: Duration,
+}
+
+/// Data of an SSE `retry` event.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct RetryNotice {
+    /// The retry about to happen, starting at 1.
+    pub attempt: u32,
+    pub max_attempts: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delay_ms: Option<u64>,
+    /// Classifier verdict for the failure being retried.
+    pub reason: &'static str,
+    pub detail: String,
+}
+
+impl RetryPolicy {
+    pub fn new(retries: u32, base: Duration, max: Duration) -> Self {
+        Self { retries, base, max }
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.base > self.max {
+            anyhow::bail!("retry.base_ms must not exceed retry.max_ms");
+        }
+        Ok(())
+    }
+
+    /// Wait before retry number `attempt` (from 1), or `None` to give up.
+    ///
+    /// The backoff doubles from `base` up to `max`, and a random half of it is
+    /// taken off so that replicas hit by the same outage do not retry in step.
+    /// A registry's `Retry-After` is honoured as is, unless it exceeds `max`:
+    /// the client is then better served by the 429 and its hint.
+    pub fn delay(&self, attempt: u32, failure: Failure) -> Option<Duration> {
+        if attempt > self.retries || !failure.is_transient() {
+            return None;
+        }
+        if let Failure::RateLimited { retry_after: Some(secs) } = failure {
+            let hint = Duration::from_secs(secs);
+            return (hint <= self.max).then_some(hint);
+        }
+        let backoff = self.base.saturating_mul(1 << (attempt - 1).min(16)).min(self.max);
+        let half = backoff / 2;
+        Some(half + rand::thread_rng().gen_range(Duration::ZERO..=half))
+    }
+}
+
+/// Runs `op` until it succeeds, fails for good, or retries run out.
+pub async fn run<T, F, Fut>(policy: &RetryPolicy, progress: Option<&EventSender>, mut op: F) -> Result<T, PullError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, PullError>>,
+{
+    let mut attempt = 0;
+    loop {
+        let err = match op().await {
+            Ok(value) => return Ok(value),
+            Err(e) => e,
+        };
+        attempt += 1;
+        let failure = classify(&err);
+        let Some(delay) = policy.delay(attempt, failure) else {
+            return Err(err);
+        };
+        tracing::warn!(
+            class = failure.as_str(),
+            attempt,
+            max_attempts = policy.retries,
+            delay_ms = delay.as_millis() as u64,
+            "pull failed, retrying: {}",
+            redact(&err.to_string())
+        );
+        if let Some(tx) = progress {
+            let notice = RetryNotice {
+                attempt,
+                max_attempts: policy.retries,
+                delay_ms: Some(delay.as_millis() as u64),
+                reason: failure.as_str(),
+                detail: err.to_string(),
+            };
+            send_event(tx, "retry", serde_json::to_string(&notice).unwrap_or_default()).await;
+        }
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Recognises the warning containers/image logs before retrying, e.g.
+/// `level=warning msg="Failed, retrying in 1s ... (1/3). Error: …"`.
+pub fn skopeo_retry_notice(line: &str) -> Option<RetryNotice> {
+    let start = line.find("Failed, retrying in ")?;
+    let rest = &line[start + "Failed, retrying in ".len()..];
+    let (delay, rest) = rest.split_once(" ... (")?;
+    let (counts, rest) = rest.split_once(')')?;
+    let (attempt, max) = counts.split_once('/')?;
+    let detail = rest.split_once("Error: ").map(|(_, e)| e).unwrap_or(rest);
+    let detail = detail.strip_suffix('"').unwrap_or(detail).replace("\\\"", "\"");
+    Some(RetryNotice {
+        attempt: attempt.trim().parse().ok()?,
+        max_attempts: max.trim().parse().ok()?,
+        delay_ms: go_duration_ms(delay),
+        reason: classify_message(&detail).as_str(),
+        detail,
+    })
+}
+
+/// Parses the Go durations skopeo prints: `1s`, `1.5s`, `500ms`, `1m4s`.
+fn go_duration_ms(text: &str) -> Option<u64> {
+    let mut total = 0.0;
+    let mut rest = text.trim();
+    while !rest.is_empty() {
+        let end = rest.find(|c: char| !c.is_ascii_digit() && c != '.')?;
+        let value: f64 = rest[..end].parse().ok()?;
+        rest = &rest[end..];
+        let unit_end = rest.find(|c: char| c.is_ascii_digit()).unwrap_or(rest.len());
+        let factor = match &rest[..unit_end] {
+            "h" => 3_600_000.0,
+            "m" => 60_000.0,
+            "s" => 1000.0,
+            "ms" => 1.0,
+            "µs" | "us" => 0.001,
+            "ns" => 0.000_001,
+            _ => return None,
+        };
+        total += value * factor;
+        rest = &rest[unit_end..];
+    }
+    Some(total.round() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Lines of skopeo output, each headed by `=== <attempt>/<max> delay_ms=N <class>`,
+    /// or `=== none` for lines that are no retry warning.
+    const CORPUS: &str = include_str!("../testdata/skopeo-retry.txt");
+
+    #[test]
+    fn parses_go_durations() {
+        assert_eq!(go_duration_ms("1s"), Some(1000));
+        assert_eq!(go_duration_ms("1.5s"), Some(1500));
+        assert_eq!(go_duration_ms("250ms"), Some(250));
+        assert_eq!(go_duration_ms("1m30.5s"), Some(90_500));
+        assert_eq!(go_duration_ms("1h2m"), Some(3_720_000));
+        assert_eq!(go_duration_ms("1500us"), Some(2));
+        assert_eq!(go_duration_ms("10"), None);
+        assert_eq!(go_duration_ms("3d"), None);
+    }
+
+    #[test]
+    fn corpus_notices_are_recognised() {
+        let mut lines = CORPUS.lines();
+        let mut cases = 0;
+        while let (Some(header), Some(line)) = (lines.next(), lines.next()) {
+            cases += 1;
+            let header = header.strip_prefix("=== ").expect("header line");
+            let notice = skopeo_retry_notice(line);
+            if header == "none" {
+                assert_eq!(notice, None, "{line}");
+                continue;
+            }
+            let mut parts = header.split_whitespace();
+            let (attempt, max) = parts.next().and_then(|c| c.split_once('/')).expect("attempt/max");
+            let delay = parts.next().and_then(|d| d.strip_prefix("delay_ms=")).expect("delay_ms");
+            let notice = notice.unwrap_or_else(|| panic!("not recognised: {line}"));
+            assert_eq!(notice.attempt, attempt.parse::<u32>().unwrap(), "{line}");
+            assert_eq!(notice.max_attempts, max.parse::<u32>().unwrap(), "{line}");
+            assert_eq!(notice.delay_ms, Some(delay.parse().unwrap()), "{line}");
+            assert_eq!(notice.reason, parts.next().expect("class"), "{line}");
+            assert!(!notice.detail.ends_with('"') && !notice.detail.contains("\\\""), "{}", notice.detail);
+        }
+        assert!(cases >= 5, "corpus unexpectedly small");
+    }
+
+    #[test]
+    fn delay_stays_within_backoff_and_cap() {
+        let policy = RetryPolicy::new(8, Duration::from_millis(100), Duration::from_millis(1000));
+        for attempt in 1..=8 {
+            let backoff = Duration::from_millis((100u64 << (attempt - 1)).min(1000));
+            for _ in 0..50 {
+                let delay = policy.delay(attempt, Failure::Network).expect("transient failure is retried");
+                assert!(delay >= backoff / 2 && delay <= backoff, "attempt {attempt}: {delay:?}");
+            }
+        }
+        assert_eq!(policy.delay(9, Failure::Network), None);
+        assert_eq!(policy.delay(1, Failure::AuthFailed), None);
+
+        let huge = RetryPolicy::new(64, Duration::from_secs(1), Duration::from_secs(30));
+        assert!(huge.delay(64, Failure::Network).is_some_and(|d| d <= Duration::from_secs(30)));
+        let none = RetryPolicy::new(3, Duration::ZERO, Duration::ZERO);
+        assert_eq!(none.delay(1, Failure::Network), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn retry_after_is_honoured_up_to_the_cap() {
+        let policy = RetryPolicy::new(3, Duration::from_secs(1), Duration::from_secs(60));
+        let limited = |secs| Failure::RateLimited { retry_after: Some(secs) };
+        assert_eq!(policy.delay(1, limited(30)), Some(Duration::from_secs(30)));
+        assert_eq!(policy.delay(1, limited(60)), Some(Duration::from_secs(60)));
+        assert_eq!(policy.delay(1, limited(61)), None);
+        assert!(RetryPolicy::new(1, Duration::from_secs(2), Duration::from_secs(1)).validate().is_err());
+    }
+}
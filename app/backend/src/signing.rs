@@ -0,0 +1,297 @@
+use crate::{
+    archive::ArchiveFormat,
+    artifacts::visible_artifact,
+    audit::Caller,
+    checksum::{sha256sum_line, HashingWriter},
+    error::ApiError,
+    open_artifact, AppState,
+};
+use axum::{
+    extract::{Json, Path, State},
+    http::{HeaderMap, HeaderValue, StatusCode},
+    response::IntoResponse,
+};
+use base64::Engine as _;
+use chrono::{DateTime, SubsecRound, Utc};
+use ed25519_dalek::{pkcs8::DecodePrivateKey, Signature, Signer as _, SigningKey, Verifier as _};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+const ALGORITHM: &str = "ed25519";
+
+/// What the backend vouches for about an exported artifact.
+///
+/// Field order is part of the signature: the signed bytes are this struct
+/// serialised with `serde_json`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArtifactManifest {
+    pub r#ref: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>,
+    pub format: ArchiveFormat,
+    pub created: DateTime<Utc>,
+    pub sha256: String,
+    pub filename: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SignedManifest {
+    pub manifest: ArtifactManifest,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub algorithm: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+}
+
+/// ed25519 key used to sign artifact manifests, if one is configured.
+#[derive(Clone, Default)]
+pub struct ManifestSigner {
+    key: Option<SigningKey>,
+}
+
+impl ManifestSigner {
+    /// Loads a PKCS#8 PEM key (`openssl genpkey -algorithm ed25519`) or a
+    /// base64-encoded 32-byte seed.
+    pub fn from_file(path: &std::path::Path) -> anyhow::Result<Self> {
+        let raw = std::fs::read_to_string(path)?;
+        let raw = raw.trim();
+        let key = if raw.starts_with("-----BEGIN") {
+            SigningKey::from_pkcs8_pem(raw).map_err(|e| anyhow::anyhow!("invalid PKCS#8 key: {e}"))?
+        } else {
+            let seed = base64::engine::general_purpose::STANDARD.decode(raw)?;
+            let seed: [u8; 32] = seed
+                .try_into()
+                .map_err(|_| anyhow::anyhow!("ed25519 seed must be 32 bytes"))?;
+            SigningKey::from_bytes(&seed)
+        };
+        Ok(Self { key: Some(key) })
+    }
+
+    pub fn key_id(&self) -> Option<String> {
+        self.key.as_ref().map(|k| key_id(k.verifying_key().as_bytes()))
+    }
+
+    pub fn sign(&self, manifest: ArtifactManifest) -> SignedManifest {
+        let Some(key) = &self.key else {
+            return SignedManifest { manifest, algorithm: None, key_id: None, signature: None };
+        };
+        let signature = key.sign(&signed_bytes(&manifest));
+        SignedManifest {
+            manifest,
+            algorithm: Some(ALGORITHM.to_string()),
+            key_id: self.key_id(),
+            signature: Some(base64::engine::general_purpose::STANDARD.encode(signature.to_bytes())),
+        }
+    }
+
+    fn check(&self, signed: &SignedManifest) -> SignatureStatus {
+        let Some(key) = &self.key else {
+            return SignatureStatus::NoKey;
+        };
+        let Some(sig) = &signed.signature else {
+            return SignatureStatus::Unsigned;
+        };
+        if signed.key_id.as_deref().is_some_and(|id| Some(id.to_string()) != self.key_id()) {
+            return SignatureStatus::UnknownKey;
+        }
+        let Ok(bytes) = base64::engine::general_purpose::STANDARD.decode(sig) else {
+            return SignatureStatus::Invalid;
+        };
+        let Ok(signature) = Signature::from_slice(&bytes) else {
+            return SignatureStatus::Invalid;
+        };
+        match key.verifying_key().verify(&signed_bytes(&signed.manifest), &signature) {
+            Ok(()) => SignatureStatus::Valid,
+            Err(_) => SignatureStatus::Invalid,
+        }
+    }
+}
+
+/// Manifest creation time, truncated so it survives a JSON round trip.
+pub fn manifest_time() -> DateTime<Utc> {
+    Utc::now().trunc_subsecs(0)
+}
+
+fn signed_bytes(manifest: &ArtifactManifest) -> Vec<u8> {
+    serde_json::to_vec(manifest).unwrap_or_default()
+}
+
+fn key_id(public_key: &[u8]) -> String {
+    hex::encode(&Sha256::digest(public_key)[..8])
+}
+
+#[derive(Debug, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum SignatureStatus {
+    Valid,
+    Invalid,
+    Unsigned,
+    UnknownKey,
+    /// No signing key is configured here, so signatures cannot be checked.
+    NoKey,
+}
+
+// sha256sum-compatible sidecar for a downloadable artifact
+pub async fn sha256sum(State(state): State<AppState>, caller: Caller, Path(id): Path<String>) -> impl IntoResponse {
+    let artifact = match visible_artifact(&state, &caller, &id).await {
+        Ok(artifact) => artifact,
+        Err(e) => return e.into_response(),
+    };
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        axum::http::header::CONTENT_TYPE,
+        HeaderValue::from_static("text/plain; charset=utf-8"),
+    );
+    let line = sha256sum_line(&artifact.sha256, &artifact.filename);
+    (StatusCode::OK, headers, line).into_response()
+}
+
+// Signed JSON manifest describing a downloadable artifact
+pub async fn signed_manifest(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    let artifact = match visible_artifact(&state, &caller, &id).await {
+        Ok(artifact) => artifact,
+        Err(e) => return e.into_response(),
+    };
+    (StatusCode::OK, Json(state.signer.sign(artifact.manifest()))).into_response()
+}
+
+// Public half of the signing key, for offline verification
+pub async fn public_key(State(state): State<AppState>) -> impl IntoResponse {
+    let Some(key) = &state.signer.key else {
+        return ApiError::NotFound("no signing key configured".into()).into_response();
+    };
+    let public = key.verifying_key();
+    let payload = serde_json::json!({
+        "algorithm": ALGORITHM,
+        "key_id": key_id(public.as_bytes()),
+        "public_key": base64::engine::general_purpose::STANDARD.encode(public.as_bytes()),
+    });
+    (StatusCode::OK, Json(payload)).into_response()
+}
+
+// Check a stored (typically uploaded) artifact against a signed manifest.
+// The file is hashed again rather than trusting the SHA-256 recorded when it
+// was stored; the checksum and the signature are reported separately, and
+// `valid` needs both: without a key here the signature is unverified.
+pub async fn verify_artifact(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path(id): Path<String>,
+    Json(signed): Json<SignedManifest>,
+) -> impl IntoResponse {
+    let (_, _, file) = match open_artifact(&state, &caller, &id).await {
+        Ok(opened) => opened,
+        Err(e) => return e.into_response(),
+    };
+    let mut file = file.into_std().await;
+    let hashed = tokio::task::spawn_blocking(move || {
+        let mut hasher = HashingWriter::new(std::io::sink());
+        std::io::copy(&mut file, &mut hasher)?;
+        Ok::<_, std::io::Error>(hasher.finish())
+    })
+    .await
+    .map_err(std::io::Error::other)
+    .and_then(|r| r);
+    let actual = match hashed {
+        Ok(actual) => actual,
+        Err(e) => return ApiError::io("cannot read the artifact", e).into_response(),
+    };
+    (StatusCode::OK, Json(verification(&state.signer, &signed, &actual))).into_response()
+}
+
+fn verification(signer: &ManifestSigner, signed: &SignedManifest, actual: &str) -> serde_json::Value {
+    let checksum = if actual.eq_ignore_ascii_case(&signed.manifest.sha256) { "match" } else { "mismatch" };
+    let signature = signer.check(signed);
+    serde_json::json!({
+        "valid": checksum == "match" && signature == SignatureStatus::Valid,
+        "checksum": checksum,
+        "sha256": {
+            "expected": signed.manifest.sha256,
+            "actual": actual,
+        },
+        "signature": signature,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signer(seed: u8) -> ManifestSigner {
+        ManifestSigner { key: Some(SigningKey::from_bytes(&[seed; 32])) }
+    }
+
+    fn manifest() -> ArtifactManifest {
+        ArtifactManifest {
+            r#ref: "registry.example/app:1".into(),
+            digest: Some(format!("sha256:{}", "a".repeat(64))),
+            format: ArchiveFormat::DockerArchive,
+            created: manifest_time(),
+            sha256: "b".repeat(64),
+            filename: "app_1.docker-archive.tar".into(),
+        }
+    }
+
+    /// The manifest as a client would send it back: through JSON.
+    fn round_trip(signed: &SignedManifest) -> SignedManifest {
+        serde_json::from_slice(&serde_json::to_vec(signed).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn signed_manifest_verifies_after_a_round_trip() {
+        let signer = signer(7);
+        let signed = round_trip(&signer.sign(manifest()));
+        assert_eq!(signed.key_id, signer.key_id());
+        assert_eq!(signer.check(&signed), SignatureStatus::Valid);
+
+        let report = verification(&signer, &signed, &"B".repeat(64));
+        assert_eq!(report["valid"], true);
+        assert_eq!(report["checksum"], "match");
+        assert_eq!(report["signature"], "valid");
+    }
+
+    #[test]
+    fn seed_file_loads_the_same_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("seed");
+        std::fs::write(&path, base64::engine::general_purpose::STANDARD.encode([7u8; 32])).unwrap();
+        assert_eq!(ManifestSigner::from_file(&path).unwrap().key_id(), signer(7).key_id());
+    }
+
+    #[test]
+    fn checksum_and_signature_are_reported_apart() {
+        let signer = signer(7);
+        let signed = round_trip(&signer.sign(manifest()));
+        let report = verification(&signer, &signed, &"c".repeat(64));
+        assert_eq!(report["valid"], false);
+        assert_eq!(report["checksum"], "mismatch");
+        assert_eq!(report["signature"], "valid");
+
+        let mut tampered = round_trip(&signed);
+        tampered.manifest.r#ref = "registry.example/app:2".into();
+        let report = verification(&signer, &tampered, &"b".repeat(64));
+        assert_eq!(report["checksum"], "match");
+        assert_eq!(report["signature"], "invalid");
+        assert_eq!(report["valid"], false);
+    }
+
+    #[test]
+    fn signatures_need_the_configured_key() {
+        let signed = round_trip(&signer(7).sign(manifest()));
+        assert_eq!(signer(8).check(&signed), SignatureStatus::UnknownKey);
+        let unsigned = ManifestSigner::default().sign(manifest());
+        assert_eq!(signer(7).check(&unsigned), SignatureStatus::Unsigned);
+
+        // Without a key here a matching checksum is not enough.
+        let report = verification(&ManifestSigner::default(), &signed, &"b".repeat(64));
+        assert_eq!(report["checksum"], "match");
+        assert_eq!(report["signature"], "no_key");
+        assert_eq!(report["valid"], false);
+    }
+}
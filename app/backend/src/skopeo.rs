@@ -0,0 +1,155 @@
+use crate::{jobs::JobEvent, metrics::SkopeoProcess, puller::PullError, reference::ImageReference, timeouts::Activity};
+use std::{process::Stdio, sync::Arc, time::Instant};
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    process::Command,
+    sync::{mpsc, Mutex},
+};
+use tracing::{field::Empty, Span};
+
+pub type EventSender = mpsc::Sender<JobEvent>;
+
+pub async fn send_event(tx: &EventSender, event: &str, data: impl Into<String>) {
+    let _ = tx.send(JobEvent { event: event.to_string(), data: data.into() }).await;
+}
+
+/// Span for one skopeo run on `reference`. Run the command inside it: the
+/// exit status is recorded there, the rest by [`finish`].
+pub fn span(operation: &'static str, reference: &str, format: &str) -> Span {
+    let registry = ImageReference::parse(reference).map(|r| r.registry).unwrap_or_default();
+    tracing::info_span!(
+        "skopeo",
+        operation,
+        reference,
+        registry = %registry,
+        format,
+        duration_ms = Empty,
+        exit_status = Empty,
+        bytes = Empty,
+    )
+}
+
+/// Records how a skopeo run went, once it is over.
+pub fn finish(span: &Span, started: Instant, bytes: u64, ok: bool) {
+    span.record("duration_ms", started.elapsed().as_millis() as u64);
+    span.record("bytes", bytes);
+    span.in_scope(|| {
+        if ok {
+            tracing::info!("skopeo finished");
+        } else {
+            tracing::warn!("skopeo failed");
+        }
+    });
+}
+
+/// Records the exit status of a skopeo process on the current span.
+pub fn record_exit(status: std::process::ExitStatus) {
+    match status.code() {
+        Some(code) => Span::current().record("exit_status", code),
+        None => Span::current().record("exit_status", "signal"),
+    };
+}
+
+/// Runs a skopeo command, forwarding every output line as a `progress` event
+/// if `tx` is set. Every line also counts as `activity`: skopeo reports
+/// progress long before some destinations start to grow.
+///
+/// On failure the error is the last line skopeo printed on stderr, which is
+/// where it reports the actual cause.
+pub async fn run_with_progress(
+    mut cmd: Command,
+    tx: Option<&EventSender>,
+    activity: &Activity,
+) -> Result<(), PullError> {
+    // A cancelled job drops this future: skopeo must not outlive it.
+    cmd.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped()).kill_on_drop(true);
+    tracing::debug!(command = ?cmd.as_std(), "running skopeo");
+
+    let mut child = cmd.spawn().map_err(PullError::Spawn)?;
+    let _process = SkopeoProcess::start();
+    let last_error: Arc<Mutex<Option<String>>> = Arc::new(Mutex::new(None));
+
+    let mut readers = Vec::new();
+    if let Some(stdout) = child.stdout.take() {
+        let (tx_out, activity) = (tx.cloned(), activity.clone());
+        readers.push(tokio::spawn(async move {
+            let mut lines = BufReader::new(stdout).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                activity.touch();
+                if let Some(tx) = &tx_out {
+                    send_event(tx, "progress", line).await;
+                }
+            }
+        }));
+    }
+
+    if let Some(stderr) = child.stderr.take() {
+        let (tx_err, activity) = (tx.cloned(), activity.clone());
+        let last_err = last_error.clone();
+        readers.push(tokio::spawn(async move {
+            let mut lines = BufReader::new(stderr).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                activity.touch();
+                if let Some(notice) = crate::retry::skopeo_retry_notice(&line) {
+                    if let Some(tx) = &tx_err {
+                        send_event(tx, "retry", serde_json::to_string(&notice).unwrap_or_default()).await;
+                    }
+                    continue;
+                }
+                {
+                    let mut guard = last_err.lock().await;
+                    *guard = Some(line.clone());
+                }
+                if let Some(tx) = &tx_err {
+                    send_event(tx, "progress", line).await;
+                }
+            }
+        }));
+    }
+
+    let status = child.wait().await.map_err(|e| PullError::Failed(format!("wait error: {e}")))?;
+    record_exit(status);
+    // Drain the pipes so the last stderr line is really the last one.
+    for reader in readers {
+        let _ = reader.await;
+    }
+    if status.success() {
+        return Ok(());
+    }
+
+    let msg = last_error.lock().await.clone();
+    Err(PullError::Failed(msg.unwrap_or_else(|| "skopeo failed".to_string())))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::timeouts::{supervise, Limits};
+    use std::time::Duration;
+
+    fn script(lines: &str) -> Command {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(lines);
+        cmd
+    }
+
+    #[tokio::test]
+    async fn printed_lines_count_as_progress() {
+        let activity = Activity::default();
+        let cmd = script("for i in 1 2 3 4; do echo \"Copying blob $i\"; sleep 0.6; done");
+        let limits = Limits { job: Duration::from_secs(10), idle: Duration::from_millis(1500) };
+        let out = supervise(run_with_progress(cmd, None, &activity), limits, &activity, std::path::Path::new("")).await;
+        assert!(matches!(out, Ok(Ok(()))));
+    }
+
+    #[tokio::test]
+    async fn failure_is_the_last_stderr_line() {
+        let cmd = script("echo 'Copying blob 1'; echo 'time=x level=fatal msg=\"manifest unknown\"' >&2; exit 1");
+        match run_with_progress(cmd, None, &Activity::default()).await {
+            Err(PullError::Failed(msg)) => assert!(msg.ends_with("msg=\"manifest unknown\""), "{msg}"),
+            other => panic!("unexpected outcome: {other:?}"),
+        }
+        let missing = Command::new("/nonexistent/skopeo");
+        assert!(matches!(run_with_progress(missing, None, &Activity::default()).await, Err(PullError::Spawn(_))));
+    }
+}
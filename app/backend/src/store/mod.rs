@@ -0,0 +1,217 @@
+//! Where jobs, their events, webhook deliveries, sync runs, tickets and
+//! artifact metadata are kept.
+//!
+//! [`SqliteStore`] keeps them in a local file (or in memory) for a single
+//! replica. [`PostgresStore`] keeps them in a database shared by all
+//! replicas, each recorded as a node with the URL the others reach it at, so
+//! that any replica can look up a job or artifact and find who holds it.
+//! Which one is used follows `database.path` / `database.url`.
+
+mod postgres;
+mod sqlite;
+
+pub use postgres::PostgresStore;
+pub use sqlite::SqliteStore;
+
+use crate::{
+    artifacts::Artifact,
+    auth::Principal,
+    config::DatabaseConfig,
+    error::ApiError,
+    jobs::{Job, JobEvent, NewJob, StoredEvent},
+    sync::{SyncRun, SyncedImage},
+    webhooks::Delivery,
+};
+use async_trait::async_trait;
+use chrono::{DateTime, SecondsFormat, Utc};
+use std::{collections::HashMap, str::FromStr, sync::Arc, time::Duration};
+
+/// A replica that has not refreshed its node entry for this long is gone:
+/// its jobs are failed and its artifacts forgotten.
+pub const NODE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// This replica, as other replicas see it.
+#[derive(Debug, Clone)]
+pub struct Node {
+    pub id: String,
+    /// Base URL the other replicas forward requests to.
+    pub url: Option<String>,
+}
+
+/// Which jobs to list, most recent first.
+pub struct JobFilter {
+    pub owner: Option<String>,
+    pub state: Option<String>,
+    pub limit: u32,
+}
+
+/// What an event changes in its job.
+pub struct Outcome {
+    pub state: &'static str,
+    /// Problem JSON of a failed job.
+    pub error: Option<String>,
+    pub artifact_id: Option<String>,
+}
+
+/// Which webhook deliveries to list, most recent first.
+pub struct DeliveryFilter {
+    /// Only the callbacks of this user's jobs; all deliveries when none.
+    pub owner: Option<String>,
+    pub job: Option<String>,
+    pub state: Option<String>,
+    pub limit: u32,
+}
+
+/// The result of an attempt at a webhook delivery.
+pub struct Attempt {
+    /// `pending` while retries remain, then `delivered` or `failed`.
+    pub state: &'static str,
+    pub attempts: u32,
+    pub response_status: Option<u16>,
+    pub error: Option<String>,
+}
+
+#[async_trait]
+pub trait Store: Send + Sync {
+    /// Short name shown in logs.
+    fn name(&self) -> &'static str;
+
+    /// Whether other replicas use the same store.
+    fn shared(&self) -> bool;
+
+    /// Records that `node` is alive.
+    async fn heartbeat(&self, node: &Node) -> Result<(), ApiError>;
+
+    /// URL of replica `node`, if it is alive.
+    async fn node_url(&self, node: &str) -> Result<Option<String>, ApiError>;
+
+    async fn insert_artifact(&self, artifact: &Artifact) -> Result<(), ApiError>;
+
+    /// Artifact `id`, unless unknown or expired.
+    async fn artifact(&self, id: &str) -> Result<Option<Artifact>, ApiError>;
+
+    /// Forgets expired artifacts held by `node` or by replicas that are
+    /// gone, returning those of `node` so that their files can be deleted.
+    async fn expire_artifacts(&self, node: &str) -> Result<Vec<Artifact>, ApiError>;
+
+    /// Records `job` as running on `node`, unless `max_running` jobs (no
+    /// limit when 0) already run on the replicas that are alive.
+    async fn create_job(&self, job: &NewJob, node: &str, max_running: u32) -> Result<(), ApiError>;
+
+    /// Stores event `seq` of job `id` and the change it brings.
+    async fn append_event(
+        &self,
+        id: &str,
+        seq: i64,
+        event: &JobEvent,
+        outcome: Option<Outcome>,
+    ) -> Result<(), ApiError>;
+
+    /// Fails, with `problem` as error, the running jobs and sync runs of
+    /// replicas that are gone, and those of `restarted` (this replica, at
+    /// startup). Returns the jobs.
+    async fn fail_orphans(&self, restarted: Option<&str>, problem: &str) -> Result<Vec<Job>, ApiError>;
+
+    /// Forgets finished jobs, and sync runs, last updated before `before`.
+    async fn purge_jobs(&self, before: DateTime<Utc>) -> Result<usize, ApiError>;
+
+    async fn jobs(&self, filter: JobFilter) -> Result<Vec<Job>, ApiError>;
+
+    async fn job(&self, id: &str) -> Result<Option<Job>, ApiError>;
+
+    async fn job_events(&self, id: &str) -> Result<Vec<StoredEvent>, ApiError>;
+
+    /// Records a webhook delivery, pending, as sent by `delivery.node`.
+    async fn insert_delivery(&self, delivery: &Delivery) -> Result<(), ApiError>;
+
+    async fn update_delivery(&self, id: &str, attempt: &Attempt) -> Result<(), ApiError>;
+
+    /// Hands `node` the pending deliveries of replicas that are gone and, at
+    /// `startup`, its own. Returns them.
+    async fn claim_deliveries(&self, node: &str, startup: bool) -> Result<Vec<Delivery>, ApiError>;
+
+    async fn deliveries(&self, filter: DeliveryFilter) -> Result<Vec<Delivery>, ApiError>;
+
+    /// Records `run` as running, unless a run of the same sync still runs or
+    /// another replica already took its slot of the schedule.
+    async fn claim_sync_run(&self, run: &SyncRun) -> Result<bool, ApiError>;
+
+    /// Saves the state, images and error of `run`, and when it finished.
+    async fn update_sync_run(&self, run: &SyncRun) -> Result<(), ApiError>;
+
+    /// Runs of sync `name`, most recent first.
+    async fn sync_runs(&self, name: &str, limit: u32) -> Result<Vec<SyncRun>, ApiError>;
+
+    /// Digest last exported by sync `name`, per reference.
+    async fn sync_digests(&self, name: &str) -> Result<HashMap<String, String>, ApiError>;
+
+    async fn set_sync_digest(&self, name: &str, reference: &str, digest: &str) -> Result<(), ApiError>;
+
+    /// Records a ticket, by its hash, for `principal` until `expires`.
+    async fn insert_ticket(&self, hash: &str, principal: &Principal, expires: DateTime<Utc>) -> Result<(), ApiError>;
+
+    /// Takes ticket `hash`, returning whom it was issued to unless it has
+    /// expired. A ticket is taken once; expired ones are forgotten.
+    async fn redeem_ticket(&self, hash: &str) -> Result<Option<Principal>, ApiError>;
+}
+
+/// Opens the store selected by the configuration: Postgres with
+/// `database.url`, else SQLite at `database.path`, else SQLite in memory.
+pub async fn open(config: &DatabaseConfig) -> anyhow::Result<Arc<dyn Store>> {
+    match (&config.url, &config.path) {
+        (Some(url), _) => Ok(Arc::new(PostgresStore::connect(url).await?)),
+        (None, path) => Ok(Arc::new(SqliteStore::open(path.as_deref())?)),
+    }
+}
+
+/// Times are stored as RFC 3339 UTC text, which sorts chronologically.
+pub fn timestamp(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+/// Nodes seen after this time are alive.
+fn alive_since() -> String {
+    timestamp(Utc::now() - NODE_TIMEOUT)
+}
+
+/// `text` parsed, or a message naming `column`.
+fn parse<T>(column: &str, text: &str) -> Result<T, String>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    text.parse().map_err(|e| format!("{column}: '{text}': {e}"))
+}
+
+fn database_error(e: impl std::fmt::Display) -> ApiError {
+    tracing::error!("database: {}", e);
+    ApiError::Internal(format!("database error: {e}"))
+}
+
+fn too_many_jobs(max_running: u32) -> ApiError {
+    ApiError::Busy(format!("{max_running} jobs are already running, try again later"))
+}
+
+/// Problem JSON stored as a job's error, shown as JSON when it parses.
+fn error_value(text: Option<String>) -> Option<serde_json::Value> {
+    text.map(|text| serde_json::from_str(&text).unwrap_or(serde_json::Value::String(text)))
+}
+
+/// The images of a sync run, stored as a JSON array.
+fn images_value(text: &str) -> Result<Vec<SyncedImage>, String> {
+    serde_json::from_str(text).map_err(|e| format!("images: {e}"))
+}
+
+fn images_text(images: &[SyncedImage]) -> String {
+    serde_json::to_string(images).unwrap_or_else(|_| "[]".to_string())
+}
+
+/// Who a redeemed ticket stands for; groups are stored as a JSON array.
+fn ticket_principal(name: String, groups: &str) -> Result<Principal, String> {
+    let groups = serde_json::from_str(groups).map_err(|e| format!("groups: {e}"))?;
+    Ok(Principal { name, groups, method: "ticket" })
+}
+
+fn groups_text(principal: &Principal) -> String {
+    serde_json::to_string(&principal.groups).unwrap_or_else(|_| "[]".to_string())
+}
@@ -0,0 +1,831 @@
+//! Embedded SQLite database, for a single replica.
+//!
+//! Without `database.path` the database lives in memory, which keeps one
+//! code path and the old behaviour of forgetting everything on restart.
+//! Queries run on the blocking pool, one at a time.
+
+use super::{
+    error_value, groups_text, images_text, images_value, parse, ticket_principal, timestamp, too_many_jobs, Attempt,
+    DeliveryFilter, JobFilter, Node, Outcome, Store,
+};
+use crate::{
+    artifacts::Artifact,
+    auth::Principal,
+    error::ApiError,
+    jobs::{Job, JobEvent, NewJob, StoredEvent},
+    sync::SyncRun,
+    webhooks::Delivery,
+};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use rusqlite::{params, types::Type, Connection, OptionalExtension, Row};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+/// Bumped with every schema change; older databases are migrated on open.
+const SCHEMA_VERSION: i64 = 5;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS jobs (
+    id TEXT PRIMARY KEY,
+    kind TEXT NOT NULL,
+    reference TEXT NOT NULL,
+    format TEXT NOT NULL,
+    owner TEXT NOT NULL,
+    state TEXT NOT NULL,
+    error TEXT,
+    artifact_id TEXT,
+    created TEXT NOT NULL,
+    updated TEXT NOT NULL,
+    node TEXT NOT NULL DEFAULT ''
+);
+CREATE INDEX IF NOT EXISTS jobs_owner ON jobs (owner, created);
+CREATE TABLE IF NOT EXISTS job_events (
+    job_id TEXT NOT NULL REFERENCES jobs (id) ON DELETE CASCADE,
+    seq INTEGER NOT NULL,
+    event TEXT NOT NULL,
+    data TEXT NOT NULL,
+    time TEXT NOT NULL,
+    PRIMARY KEY (job_id, seq)
+);
+CREATE TABLE IF NOT EXISTS artifacts (
+    id TEXT PRIMARY KEY,
+    path TEXT NOT NULL,
+    filename TEXT NOT NULL,
+    format TEXT NOT NULL,
+    compression TEXT NOT NULL,
+    repo TEXT NOT NULL,
+    tag TEXT NOT NULL,
+    reference TEXT NOT NULL,
+    digest TEXT,
+    sha256 TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    owner TEXT NOT NULL,
+    created TEXT NOT NULL,
+    expires TEXT NOT NULL,
+    node TEXT NOT NULL DEFAULT ''
+);
+CREATE INDEX IF NOT EXISTS artifacts_expires ON artifacts (expires);
+CREATE TABLE IF NOT EXISTS deliveries (
+    id TEXT PRIMARY KEY,
+    job_id TEXT NOT NULL REFERENCES jobs (id) ON DELETE CASCADE,
+    owner TEXT NOT NULL,
+    webhook TEXT,
+    url TEXT NOT NULL,
+    event TEXT NOT NULL,
+    payload TEXT NOT NULL,
+    signature TEXT NOT NULL,
+    state TEXT NOT NULL,
+    attempts INTEGER NOT NULL,
+    response_status INTEGER,
+    error TEXT,
+    created TEXT NOT NULL,
+    updated TEXT NOT NULL,
+    node TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS deliveries_job ON deliveries (job_id);
+CREATE INDEX IF NOT EXISTS deliveries_state ON deliveries (state);
+CREATE TABLE IF NOT EXISTS sync_runs (
+    id TEXT PRIMARY KEY,
+    name TEXT NOT NULL,
+    origin TEXT NOT NULL,
+    slot TEXT,
+    owner TEXT NOT NULL,
+    state TEXT NOT NULL,
+    exported INTEGER NOT NULL,
+    unchanged INTEGER NOT NULL,
+    failed INTEGER NOT NULL,
+    images TEXT NOT NULL,
+    error TEXT,
+    started TEXT NOT NULL,
+    finished TEXT,
+    node TEXT NOT NULL
+);
+CREATE UNIQUE INDEX IF NOT EXISTS sync_runs_slot ON sync_runs (name, slot);
+CREATE INDEX IF NOT EXISTS sync_runs_name ON sync_runs (name, started);
+CREATE TABLE IF NOT EXISTS sync_digests (
+    name TEXT NOT NULL,
+    reference TEXT NOT NULL,
+    digest TEXT NOT NULL,
+    updated TEXT NOT NULL,
+    PRIMARY KEY (name, reference)
+);
+CREATE TABLE IF NOT EXISTS tickets (
+    hash TEXT PRIMARY KEY,
+    name TEXT NOT NULL,
+    groups TEXT NOT NULL,
+    expires TEXT NOT NULL
+);
+";
+
+/// Version 5 adds the `tickets` table, version 4 the `sync_runs` and
+/// `sync_digests` tables, version 3 the `deliveries` table; the schema
+/// creates them.
+/// Version 2 records the replica holding each job and artifact.
+const MIGRATE_1: &str = "
+ALTER TABLE jobs ADD COLUMN node TEXT NOT NULL DEFAULT '';
+ALTER TABLE artifacts ADD COLUMN node TEXT NOT NULL DEFAULT '';
+";
+
+const ARTIFACT_COLUMNS: &str =
+    "id, path, filename, format, compression, repo, tag, reference, digest, sha256, size, owner, created, expires, node";
+
+const JOB_COLUMNS: &str = "id, kind, reference, format, owner, state, error, artifact_id, created, updated, node";
+
+const DELIVERY_COLUMNS: &str = "id, job_id, owner, webhook, url, event, payload, signature, state, attempts, \
+                                response_status, error, created, updated, node";
+
+const SYNC_RUN_COLUMNS: &str =
+    "id, name, origin, slot, owner, state, exported, unchanged, failed, images, error, started, finished, node";
+
+#[derive(Clone)]
+pub struct SqliteStore {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStore {
+    pub fn open(path: Option<&Path>) -> anyhow::Result<Self> {
+        let conn = match path {
+            Some(path) => {
+                if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+                    std::fs::create_dir_all(dir)
+                        .map_err(|e| anyhow::anyhow!("database: cannot create {}: {}", dir.display(), e))?;
+                }
+                let conn = Connection::open(path)
+                    .map_err(|e| anyhow::anyhow!("database: cannot open {}: {}", path.display(), e))?;
+                conn.pragma_update(None, "journal_mode", "WAL")?;
+                conn
+            }
+            None => Connection::open_in_memory()?,
+        };
+        conn.pragma_update(None, "foreign_keys", true)?;
+        conn.busy_timeout(std::time::Duration::from_secs(5))?;
+
+        let version: i64 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        if version > SCHEMA_VERSION {
+            anyhow::bail!("database: schema version {version} is newer than this server ({SCHEMA_VERSION})");
+        }
+        if version == 1 {
+            conn.execute_batch(MIGRATE_1)?;
+        }
+        conn.execute_batch(SCHEMA)?;
+        conn.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+        Ok(Self { conn: Arc::new(Mutex::new(conn)) })
+    }
+
+    /// Runs `f` on the connection, off the async runtime.
+    async fn call<T, F>(&self, f: F) -> Result<T, ApiError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T, Failure> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            let mut conn = conn.lock().unwrap_or_else(|e| e.into_inner());
+            f(&mut conn)
+        })
+        .await;
+        match result {
+            Ok(Ok(value)) => Ok(value),
+            Ok(Err(Failure::Refused(e))) => Err(e),
+            Ok(Err(Failure::Sql(e))) => Err(super::database_error(e)),
+            Err(e) => Err(ApiError::Internal(format!("database task failed: {e}"))),
+        }
+    }
+}
+
+/// Why a query closure gave up.
+enum Failure {
+    Sql(rusqlite::Error),
+    Refused(ApiError),
+}
+
+impl From<rusqlite::Error> for Failure {
+    fn from(e: rusqlite::Error) -> Self {
+        Failure::Sql(e)
+    }
+}
+
+/// Column `idx` parsed from its text form.
+fn parsed<T>(row: &Row, idx: usize) -> rusqlite::Result<T>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    let text: String = row.get(idx)?;
+    parse(&idx.to_string(), &text)
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(idx, Type::Text, e.into()))
+}
+
+fn artifact_row(row: &Row) -> rusqlite::Result<Artifact> {
+    Ok(Artifact {
+        id: row.get(0)?,
+        path: PathBuf::from(row.get::<_, String>(1)?),
+        filename: row.get(2)?,
+        format: parsed(row, 3)?,
+        compression: parsed(row, 4)?,
+        repo: row.get(5)?,
+        tag: row.get(6)?,
+        reference: row.get(7)?,
+        digest: row.get(8)?,
+        sha256: row.get(9)?,
+        size: row.get::<_, i64>(10)? as u64,
+        owner: row.get(11)?,
+        created: parsed(row, 12)?,
+        expires: parsed(row, 13)?,
+        node: row.get(14)?,
+    })
+}
+
+fn job_row(row: &Row) -> rusqlite::Result<Job> {
+    Ok(Job {
+        id: row.get(0)?,
+        kind: row.get(1)?,
+        reference: row.get(2)?,
+        format: row.get(3)?,
+        owner: row.get(4)?,
+        state: row.get(5)?,
+        error: error_value(row.get(6)?),
+        artifact_id: row.get(7)?,
+        created: parsed(row, 8)?,
+        updated: parsed(row, 9)?,
+        node: row.get(10)?,
+    })
+}
+
+fn delivery_row(row: &Row) -> rusqlite::Result<Delivery> {
+    Ok(Delivery {
+        id: row.get(0)?,
+        job_id: row.get(1)?,
+        owner: row.get(2)?,
+        webhook: row.get(3)?,
+        url: row.get(4)?,
+        event: row.get(5)?,
+        payload: row.get(6)?,
+        signature: row.get(7)?,
+        state: row.get(8)?,
+        attempts: row.get(9)?,
+        response_status: row.get(10)?,
+        error: row.get(11)?,
+        created: parsed(row, 12)?,
+        updated: parsed(row, 13)?,
+        node: row.get(14)?,
+    })
+}
+
+fn sync_run_row(row: &Row) -> rusqlite::Result<SyncRun> {
+    let images: String = row.get(9)?;
+    let optional_time = |idx: usize| -> rusqlite::Result<Option<DateTime<Utc>>> {
+        match row.get::<_, Option<String>>(idx)? {
+            Some(_) => parsed(row, idx).map(Some),
+            None => Ok(None),
+        }
+    };
+    Ok(SyncRun {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        origin: row.get(2)?,
+        slot: optional_time(3)?,
+        owner: row.get(4)?,
+        state: row.get(5)?,
+        exported: row.get(6)?,
+        unchanged: row.get(7)?,
+        failed: row.get(8)?,
+        images: images_value(&images)
+            .map_err(|e| rusqlite::Error::FromSqlConversionFailure(9, Type::Text, e.into()))?,
+        error: error_value(row.get(10)?),
+        started: parsed(row, 11)?,
+        finished: optional_time(12)?,
+        node: row.get(13)?,
+    })
+}
+
+#[async_trait]
+impl Store for SqliteStore {
+    fn name(&self) -> &'static str {
+        "sqlite"
+    }
+
+    fn shared(&self) -> bool {
+        false
+    }
+
+    async fn heartbeat(&self, _node: &Node) -> Result<(), ApiError> {
+        Ok(())
+    }
+
+    async fn node_url(&self, _node: &str) -> Result<Option<String>, ApiError> {
+        Ok(None)
+    }
+
+    async fn insert_artifact(&self, artifact: &Artifact) -> Result<(), ApiError> {
+        let artifact = artifact.clone();
+        self.call(move |conn| {
+            conn.execute(
+                &format!(
+                    "INSERT INTO artifacts ({ARTIFACT_COLUMNS})
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15)"
+                ),
+                params![
+                    artifact.id,
+                    artifact.path.to_string_lossy(),
+                    artifact.filename,
+                    artifact.format.as_str(),
+                    artifact.compression.as_str(),
+                    artifact.repo,
+                    artifact.tag,
+                    artifact.reference,
+                    artifact.digest,
+                    artifact.sha256,
+                    artifact.size as i64,
+                    artifact.owner,
+                    timestamp(artifact.created),
+                    timestamp(artifact.expires),
+                    artifact.node,
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn artifact(&self, id: &str) -> Result<Option<Artifact>, ApiError> {
+        let (id, now) = (id.to_string(), timestamp(Utc::now()));
+        self.call(move |conn| {
+            Ok(conn
+                .query_row(
+                    &format!("SELECT {ARTIFACT_COLUMNS} FROM artifacts WHERE id = ?1 AND expires > ?2"),
+                    params![id, now],
+                    artifact_row,
+                )
+                .optional()?)
+        })
+        .await
+    }
+
+    async fn expire_artifacts(&self, _node: &str) -> Result<Vec<Artifact>, ApiError> {
+        let now = timestamp(Utc::now());
+        self.call(move |conn| {
+            let expired = conn
+                .prepare(&format!("DELETE FROM artifacts WHERE expires <= ?1 RETURNING {ARTIFACT_COLUMNS}"))?
+                .query_map([&now], artifact_row)?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok(expired)
+        })
+        .await
+    }
+
+    async fn create_job(&self, job: &NewJob, node: &str, max_running: u32) -> Result<(), ApiError> {
+        let (job, node) = (job.clone(), node.to_string());
+        self.call(move |conn| {
+            let now = timestamp(Utc::now());
+            let tx = conn.transaction()?;
+            if max_running > 0 {
+                let running: u32 =
+                    tx.query_row("SELECT count(*) FROM jobs WHERE state = 'running'", [], |row| row.get(0))?;
+                if running >= max_running {
+                    return Err(Failure::Refused(too_many_jobs(max_running)));
+                }
+            }
+            tx.execute(
+                &format!(
+                    "INSERT INTO jobs ({JOB_COLUMNS})
+                     VALUES (?1, ?2, ?3, ?4, ?5, 'running', NULL, NULL, ?6, ?6, ?7)"
+                ),
+                params![job.id, job.kind.as_str(), job.reference, job.format, job.owner, now, node],
+            )?;
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn append_event(
+        &self,
+        id: &str,
+        seq: i64,
+        event: &JobEvent,
+        outcome: Option<Outcome>,
+    ) -> Result<(), ApiError> {
+        let (id, event) = (id.to_string(), event.clone());
+        self.call(move |conn| {
+            let now = timestamp(Utc::now());
+            let tx = conn.transaction()?;
+            tx.execute(
+                "INSERT INTO job_events (job_id, seq, event, data, time) VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![id, seq, event.event, event.data, now],
+            )?;
+            match outcome {
+                Some(outcome) => tx.execute(
+                    "UPDATE jobs SET state = ?2, error = ?3, artifact_id = ?4, updated = ?5 WHERE id = ?1",
+                    params![id, outcome.state, outcome.error, outcome.artifact_id, now],
+                )?,
+                None => tx.execute("UPDATE jobs SET updated = ?2 WHERE id = ?1", params![id, now])?,
+            };
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// A single replica has no one else's jobs: at startup, every job and
+    /// sync run left running is an orphan.
+    async fn fail_orphans(&self, restarted: Option<&str>, problem: &str) -> Result<Vec<Job>, ApiError> {
+        if restarted.is_none() {
+            return Ok(Vec::new());
+        }
+        let problem = problem.to_string();
+        self.call(move |conn| {
+            let now = timestamp(Utc::now());
+            let tx = conn.transaction()?;
+            let jobs = tx
+                .prepare(&format!("SELECT {JOB_COLUMNS} FROM jobs WHERE state = 'running'"))?
+                .query_map([], job_row)?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            for job in &jobs {
+                tx.execute(
+                    "INSERT INTO job_events (job_id, seq, event, data, time)
+                     SELECT ?1, COALESCE(MAX(seq), 0) + 1, 'error', ?2, ?3 FROM job_events WHERE job_id = ?1",
+                    params![job.id, problem, now],
+                )?;
+                tx.execute(
+                    "UPDATE jobs SET state = 'failed', error = ?2, updated = ?3 WHERE id = ?1",
+                    params![job.id, problem, now],
+                )?;
+            }
+            tx.execute(
+                "UPDATE sync_runs SET state = 'failed', error = ?1, finished = ?2 WHERE state = 'running'",
+                params![problem, now],
+            )?;
+            tx.commit()?;
+            Ok(jobs)
+        })
+        .await
+    }
+
+    async fn purge_jobs(&self, before: DateTime<Utc>) -> Result<usize, ApiError> {
+        let before = timestamp(before);
+        self.call(move |conn| {
+            conn.execute("DELETE FROM sync_runs WHERE state != 'running' AND finished < ?1", [&before])?;
+            Ok(conn.execute("DELETE FROM jobs WHERE state != 'running' AND updated < ?1", [&before])?)
+        })
+        .await
+    }
+
+    async fn jobs(&self, filter: JobFilter) -> Result<Vec<Job>, ApiError> {
+        self.call(move |conn| {
+            let jobs = conn
+                .prepare(&format!(
+                    "SELECT {JOB_COLUMNS} FROM jobs WHERE (?1 IS NULL OR owner = ?1) AND (?2 IS NULL OR state = ?2)
+                     ORDER BY created DESC LIMIT ?3"
+                ))?
+                .query_map(params![filter.owner, filter.state, filter.limit], job_row)?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok(jobs)
+        })
+        .await
+    }
+
+    async fn job(&self, id: &str) -> Result<Option<Job>, ApiError> {
+        let id = id.to_string();
+        self.call(move |conn| {
+            Ok(conn
+                .query_row(&format!("SELECT {JOB_COLUMNS} FROM jobs WHERE id = ?1"), [&id], job_row)
+                .optional()?)
+        })
+        .await
+    }
+
+    async fn job_events(&self, id: &str) -> Result<Vec<StoredEvent>, ApiError> {
+        let id = id.to_string();
+        self.call(move |conn| {
+            let events = conn
+                .prepare("SELECT seq, event, data, time FROM job_events WHERE job_id = ?1 ORDER BY seq")?
+                .query_map([&id], |row| {
+                    Ok(StoredEvent { seq: row.get(0)?, event: row.get(1)?, data: row.get(2)?, time: parsed(row, 3)? })
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok(events)
+        })
+        .await
+    }
+
+    async fn insert_delivery(&self, delivery: &Delivery) -> Result<(), ApiError> {
+        let d = delivery.clone();
+        self.call(move |conn| {
+            conn.execute(
+                &format!(
+                    "INSERT INTO deliveries ({DELIVERY_COLUMNS})
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15)"
+                ),
+                params![
+                    d.id,
+                    d.job_id,
+                    d.owner,
+                    d.webhook,
+                    d.url,
+                    d.event,
+                    d.payload,
+                    d.signature,
+                    d.state,
+                    d.attempts,
+                    d.response_status,
+                    d.error,
+                    timestamp(d.created),
+                    timestamp(d.updated),
+                    d.node,
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn update_delivery(&self, id: &str, attempt: &Attempt) -> Result<(), ApiError> {
+        let id = id.to_string();
+        let (state, attempts, status, error) =
+            (attempt.state, attempt.attempts, attempt.response_status, attempt.error.clone());
+        self.call(move |conn| {
+            conn.execute(
+                "UPDATE deliveries SET state = ?2, attempts = ?3, response_status = ?4, error = ?5, updated = ?6
+                 WHERE id = ?1",
+                params![id, state, attempts, status, error, timestamp(Utc::now())],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// A single replica has no one else's deliveries: at startup, every
+    /// pending one was left by its previous run.
+    async fn claim_deliveries(&self, node: &str, startup: bool) -> Result<Vec<Delivery>, ApiError> {
+        if !startup {
+            return Ok(Vec::new());
+        }
+        let node = node.to_string();
+        self.call(move |conn| {
+            let claimed = conn
+                .prepare(&format!(
+                    "UPDATE deliveries SET node = ?1 WHERE state = 'pending' RETURNING {DELIVERY_COLUMNS}"
+                ))?
+                .query_map([&node], delivery_row)?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok(claimed)
+        })
+        .await
+    }
+
+    async fn deliveries(&self, filter: DeliveryFilter) -> Result<Vec<Delivery>, ApiError> {
+        self.call(move |conn| {
+            let deliveries = conn
+                .prepare(&format!(
+                    "SELECT {DELIVERY_COLUMNS} FROM deliveries
+                     WHERE (?1 IS NULL OR (owner = ?1 AND webhook IS NULL)) AND (?2 IS NULL OR job_id = ?2)
+                       AND (?3 IS NULL OR state = ?3)
+                     ORDER BY created DESC LIMIT ?4"
+                ))?
+                .query_map(params![filter.owner, filter.job, filter.state, filter.limit], delivery_row)?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok(deliveries)
+        })
+        .await
+    }
+
+    async fn claim_sync_run(&self, run: &SyncRun) -> Result<bool, ApiError> {
+        let run = run.clone();
+        self.call(move |conn| {
+            let inserted = conn.execute(
+                &format!(
+                    "INSERT INTO sync_runs ({SYNC_RUN_COLUMNS})
+                     SELECT ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14
+                     WHERE NOT EXISTS (SELECT 1 FROM sync_runs WHERE name = ?2 AND state = 'running')
+                     ON CONFLICT DO NOTHING"
+                ),
+                params![
+                    run.id,
+                    run.name,
+                    run.origin,
+                    run.slot.map(timestamp),
+                    run.owner,
+                    run.state,
+                    run.exported,
+                    run.unchanged,
+                    run.failed,
+                    images_text(&run.images),
+                    run.error.as_ref().map(|e| e.to_string()),
+                    timestamp(run.started),
+                    run.finished.map(timestamp),
+                    run.node,
+                ],
+            )?;
+            Ok(inserted > 0)
+        })
+        .await
+    }
+
+    async fn update_sync_run(&self, run: &SyncRun) -> Result<(), ApiError> {
+        let run = run.clone();
+        self.call(move |conn| {
+            conn.execute(
+                "UPDATE sync_runs SET state = ?2, exported = ?3, unchanged = ?4, failed = ?5, images = ?6, error = ?7,
+                 finished = ?8 WHERE id = ?1",
+                params![
+                    run.id,
+                    run.state,
+                    run.exported,
+                    run.unchanged,
+                    run.failed,
+                    images_text(&run.images),
+                    run.error.as_ref().map(|e| e.to_string()),
+                    run.finished.map(timestamp),
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn sync_runs(&self, name: &str, limit: u32) -> Result<Vec<SyncRun>, ApiError> {
+        let name = name.to_string();
+        self.call(move |conn| {
+            let runs = conn
+                .prepare(&format!(
+                    "SELECT {SYNC_RUN_COLUMNS} FROM sync_runs WHERE name = ?1 ORDER BY started DESC LIMIT ?2"
+                ))?
+                .query_map(params![name, limit], sync_run_row)?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            Ok(runs)
+        })
+        .await
+    }
+
+    async fn sync_digests(&self, name: &str) -> Result<HashMap<String, String>, ApiError> {
+        let name = name.to_string();
+        self.call(move |conn| {
+            let digests = conn
+                .prepare("SELECT reference, digest FROM sync_digests WHERE name = ?1")?
+                .query_map([&name], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<rusqlite::Result<HashMap<_, _>>>()?;
+            Ok(digests)
+        })
+        .await
+    }
+
+    async fn set_sync_digest(&self, name: &str, reference: &str, digest: &str) -> Result<(), ApiError> {
+        let (name, reference, digest) = (name.to_string(), reference.to_string(), digest.to_string());
+        self.call(move |conn| {
+            conn.execute(
+                "INSERT INTO sync_digests (name, reference, digest, updated) VALUES (?1, ?2, ?3, ?4)
+                 ON CONFLICT (name, reference) DO UPDATE SET digest = excluded.digest, updated = excluded.updated",
+                params![name, reference, digest, timestamp(Utc::now())],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn insert_ticket(&self, hash: &str, principal: &Principal, expires: DateTime<Utc>) -> Result<(), ApiError> {
+        let (hash, name, groups) = (hash.to_string(), principal.name.clone(), groups_text(principal));
+        self.call(move |conn| {
+            conn.execute(
+                "INSERT INTO tickets (hash, name, groups, expires) VALUES (?1, ?2, ?3, ?4)",
+                params![hash, name, groups, timestamp(expires)],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn redeem_ticket(&self, hash: &str) -> Result<Option<Principal>, ApiError> {
+        let hash = hash.to_string();
+        self.call(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute("DELETE FROM tickets WHERE expires < ?1", [timestamp(Utc::now())])?;
+            let ticket = tx
+                .query_row("SELECT name, groups FROM tickets WHERE hash = ?1", [&hash], |row| {
+                    let (name, groups): (String, String) = (row.get(0)?, row.get(1)?);
+                    ticket_principal(name, &groups)
+                        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(1, Type::Text, e.into()))
+                })
+                .optional()?;
+            tx.execute("DELETE FROM tickets WHERE hash = ?1", [&hash])?;
+            tx.commit()?;
+            Ok(ticket)
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jobs::JobKind;
+    use chrono::TimeZone;
+
+    fn job(id: &str) -> NewJob {
+        NewJob {
+            id: id.to_string(),
+            kind: JobKind::Pull,
+            reference: "registry.example/app:1".to_string(),
+            format: "docker-archive".to_string(),
+            owner: "alice".to_string(),
+        }
+    }
+
+    fn run(id: &str, slot: Option<DateTime<Utc>>) -> SyncRun {
+        SyncRun {
+            id: id.to_string(),
+            name: "nightly".to_string(),
+            origin: if slot.is_some() { "schedule" } else { "manual" }.to_string(),
+            slot,
+            owner: "alice".to_string(),
+            state: "running".to_string(),
+            exported: 0,
+            unchanged: 0,
+            failed: 0,
+            images: Vec::new(),
+            error: None,
+            started: Utc::now(),
+            finished: None,
+            node: "node-a".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn jobs_only_start_under_max_running() {
+        let store = SqliteStore::open(None).unwrap();
+        store.create_job(&job("a"), "node-a", 2).await.unwrap();
+        store.create_job(&job("b"), "node-a", 2).await.unwrap();
+        let refused = store.create_job(&job("c"), "node-a", 2).await;
+        assert!(matches!(refused, Err(ApiError::Busy(_))));
+        assert!(store.job("c").await.unwrap().is_none());
+
+        // 0 is no limit.
+        store.create_job(&job("c"), "node-a", 0).await.unwrap();
+
+        let done = JobEvent { event: "ready".to_string(), data: "{}".to_string() };
+        let outcome = Outcome { state: "succeeded", error: None, artifact_id: None };
+        store.append_event("a", 1, &done, Some(outcome)).await.unwrap();
+        store.append_event("b", 1, &done, None).await.unwrap();
+        store.create_job(&job("d"), "node-a", 4).await.unwrap();
+        assert!(matches!(store.create_job(&job("e"), "node-a", 3).await, Err(ApiError::Busy(_))));
+    }
+
+    #[tokio::test]
+    async fn a_restart_fails_what_was_running() {
+        let store = SqliteStore::open(None).unwrap();
+        store.create_job(&job("a"), "node-a", 0).await.unwrap();
+        let progress = JobEvent { event: "progress".to_string(), data: "copying".to_string() };
+        store.append_event("a", 1, &progress, None).await.unwrap();
+        store.create_job(&job("b"), "node-a", 0).await.unwrap();
+        let cancelled = Outcome { state: "cancelled", error: None, artifact_id: None };
+        let event = JobEvent { event: "cancelled".to_string(), data: "cancelled".to_string() };
+        store.append_event("b", 1, &event, Some(cancelled)).await.unwrap();
+        assert!(store.claim_sync_run(&run("c", None)).await.unwrap());
+
+        // A single replica has no other replica to outlive.
+        assert!(store.fail_orphans(None, "{}").await.unwrap().is_empty());
+
+        let problem = r#"{"code":"interrupted"}"#;
+        let failed = store.fail_orphans(Some("node-a"), problem).await.unwrap();
+        assert_eq!(failed.iter().map(|j| j.id.as_str()).collect::<Vec<_>>(), ["a"]);
+        let a = store.job("a").await.unwrap().unwrap();
+        assert_eq!(a.state, "failed");
+        assert_eq!(a.error.unwrap()["code"], "interrupted");
+        let events = store.job_events("a").await.unwrap();
+        assert_eq!(events.last().map(|e| (e.seq, e.event.as_str())), Some((2, "error")));
+        assert_eq!(store.job("b").await.unwrap().unwrap().state, "cancelled");
+        let runs = store.sync_runs("nightly", 10).await.unwrap();
+        assert_eq!(runs[0].state, "failed");
+        assert!(runs[0].finished.is_some());
+
+        assert!(store.fail_orphans(Some("node-a"), problem).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn each_slot_of_a_sync_is_claimed_once() {
+        let store = SqliteStore::open(None).unwrap();
+        let slot = Utc.with_ymd_and_hms(2026, 1, 1, 3, 0, 0).unwrap();
+        let mut first = run("a", Some(slot));
+        assert!(store.claim_sync_run(&first).await.unwrap());
+        // Another replica, same minute.
+        assert!(!store.claim_sync_run(&run("b", Some(slot))).await.unwrap());
+        // Nothing starts while a run of the sync is going.
+        assert!(!store.claim_sync_run(&run("c", None)).await.unwrap());
+        assert!(!store.claim_sync_run(&run("d", Some(slot + chrono::Duration::minutes(1)))).await.unwrap());
+
+        first.state = "succeeded".to_string();
+        first.finished = Some(Utc::now());
+        store.update_sync_run(&first).await.unwrap();
+        assert!(!store.claim_sync_run(&run("e", Some(slot))).await.unwrap());
+        assert!(store.claim_sync_run(&run("f", None)).await.unwrap());
+
+        let ids: Vec<_> = store.sync_runs("nightly", 10).await.unwrap().into_iter().map(|r| r.id).collect();
+        assert_eq!(ids.len(), 2);
+        assert!(ids.contains(&"a".to_string()) && ids.contains(&"f".to_string()));
+    }
+}
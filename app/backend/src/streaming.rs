@@ -0,0 +1,233 @@
+//! Serving an archive to the client while it is still being pulled.
+//!
+//! Both pullers write `docker-archive` destinations sequentially, so they
+//! are pointed at a FIFO instead of a regular file. A blocking reader
+//! drains the FIFO, validates the tar on the fly, compresses it if asked and
+//! forwards chunks to a chunked HTTP response. Nothing is stored on disk.
+
+use crate::{
+    checksum::HashingWriter,
+    compression::{CompressionOptions, StreamEncoder},
+    inspect::{inspect, InspectError},
+    puller::PullError,
+    timeouts::{supervise, Activity, Expired, Limits},
+};
+use axum::{
+    body::{Body, Bytes},
+    http::{HeaderMap, HeaderName, HeaderValue},
+};
+use futures_util::future::BoxFuture;
+use http_body::Frame;
+use http_body_util::StreamBody;
+use std::{
+    io::{BufWriter, Read, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::timeout,
+};
+use tokio_stream::{wrappers::ReceiverStream, StreamExt};
+
+type FrameSender = mpsc::Sender<Result<Frame<Bytes>, std::io::Error>>;
+
+/// Chunk size forwarded to the client; small enough to keep memory flat.
+const CHUNK: usize = 64 * 1024;
+
+/// How a streamed copy ended.
+pub enum Outcome {
+    Done(Streamed),
+    TimedOut(Expired),
+    Failed(PullError),
+    Invalid(String),
+    Io(std::io::Error),
+}
+
+/// What was sent to the client.
+pub struct Streamed {
+    pub sha256: String,
+    /// As in [`crate::inspect::ArchiveReport::image_digest`].
+    pub digest: Option<String>,
+    pub size: u64,
+}
+
+/// What a caller gets back: either the first bytes are flowing, with the
+/// outcome to follow once the copy ends, or the copy ended before producing
+/// any and the outcome says why.
+pub enum Started {
+    Streaming(Body, oneshot::Receiver<Outcome>),
+    Failed(Outcome),
+}
+
+/// Creates the FIFO the puller will write the archive into.
+pub fn make_fifo(path: &Path) -> std::io::Result<()> {
+    nix::unistd::mkfifo(path, nix::sys::stat::Mode::S_IRUSR | nix::sys::stat::Mode::S_IWUSR)
+        .map_err(std::io::Error::from)
+}
+
+/// Runs `job` (a pull writing into `fifo`) and streams what it writes.
+///
+/// Resolves once the first chunk is ready or the pull has failed, so callers
+/// can still answer with a proper error status when nothing was produced.
+/// Bytes coming out of the FIFO count as `activity` for the idle limit.
+pub async fn start(
+    job: BoxFuture<'static, Result<(), PullError>>,
+    fifo: PathBuf,
+    compression: CompressionOptions,
+    limits: Limits,
+    activity: Activity,
+) -> Started {
+    let (tx, mut rx) = mpsc::channel::<Result<Frame<Bytes>, std::io::Error>>(16);
+    let (outcome_tx, outcome_rx) = oneshot::channel::<Outcome>();
+
+    let producer = {
+        let tx = tx.clone();
+        let fifo = fifo.clone();
+        let activity = activity.clone();
+        tokio::task::spawn_blocking(move || produce(&fifo, tx, compression, activity))
+    };
+
+    tokio::spawn(async move {
+        // Dropping the job on timeout kills skopeo or cancels the downloads.
+        let pulled: Result<(), Outcome> = match supervise(job, limits, &activity, &fifo).await {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(e)) => Err(Outcome::Failed(e)),
+            Err(expired) => Err(Outcome::TimedOut(expired)),
+        };
+        // If the puller never opened the FIFO the reader is blocked in open(), or
+        // about to be: keep knocking until it has returned.
+        let mut producer = producer;
+        let produced = loop {
+            release_reader(&fifo);
+            if let Ok(joined) = timeout(Duration::from_millis(100), &mut producer).await {
+                break joined.unwrap_or_else(|e| Err(Produce::Io(std::io::Error::other(e))));
+            }
+        };
+        let _ = std::fs::remove_file(&fifo);
+
+        let outcome = match (pulled, produced) {
+            (Err(outcome), _) => outcome,
+            (Ok(_), Err(Produce::Invalid(msg))) => Outcome::Invalid(msg),
+            (Ok(_), Err(Produce::Io(e))) => Outcome::Io(e),
+            (Ok(_), Ok(streamed)) => {
+                let mut trailers = HeaderMap::new();
+                if let Ok(v) = HeaderValue::from_str(&streamed.sha256) {
+                    trailers.insert(HeaderName::from_static(crate::CHECKSUM_HEADER), v);
+                }
+                let _ = tx.send(Ok(Frame::trailers(trailers))).await;
+                Outcome::Done(streamed)
+            }
+        };
+        if !matches!(outcome, Outcome::Done(_)) {
+            // Aborts the chunked response so the client never sees a clean end.
+            let _ = tx.send(Err(std::io::Error::other(describe(&outcome)))).await;
+        }
+        let _ = outcome_tx.send(outcome);
+    });
+
+    match rx.recv().await {
+        Some(Ok(first)) => {
+            let rest = ReceiverStream::new(rx);
+            let frames = tokio_stream::once(Ok(first)).chain(rest);
+            Started::Streaming(Body::new(StreamBody::new(frames)), outcome_rx)
+        }
+        _ => Started::Failed(outcome_rx.await.unwrap_or(Outcome::Failed(PullError::Failed(String::new())))),
+    }
+}
+
+fn describe(outcome: &Outcome) -> String {
+    match outcome {
+        Outcome::Done(_) => "done".to_string(),
+        Outcome::TimedOut(Expired::Job(_)) => "timeout while copying image".to_string(),
+        Outcome::TimedOut(Expired::Idle(_)) => "no progress while copying image".to_string(),
+        Outcome::Failed(e) => format!("pull error: {}", e.to_string().trim()),
+        Outcome::Invalid(msg) => msg.clone(),
+        Outcome::Io(e) => format!("stream error: {e}"),
+    }
+}
+
+enum Produce {
+    Invalid(String),
+    Io(std::io::Error),
+}
+
+impl From<std::io::Error> for Produce {
+    fn from(e: std::io::Error) -> Self {
+        Produce::Io(e)
+    }
+}
+
+/// Reads the FIFO to the end, validating and forwarding as it goes.
+/// Returns the SHA-256 and size of the bytes sent to the client.
+fn produce(
+    fifo: &Path,
+    tx: FrameSender,
+    compression: CompressionOptions,
+    activity: Activity,
+) -> Result<Streamed, Produce> {
+    let file = std::fs::File::open(fifo)?;
+    let sink = HashingWriter::new(BufWriter::with_capacity(CHUNK, FrameWriter { tx }));
+    let mut tee = TeeReader { inner: file, out: StreamEncoder::new(sink, compression)?, activity };
+
+    let report = match inspect(&mut tee) {
+        Ok(report) => report,
+        Err(InspectError::Io(e)) => return Err(Produce::Io(e)),
+        Err(e) => return Err(Produce::Invalid(e.to_string())),
+    };
+    if !report.valid {
+        return Err(Produce::Invalid(format!(
+            "archive validation failed: {}",
+            report.problems.join("; ")
+        )));
+    }
+
+    let mut sink = tee.out.finish()?;
+    sink.flush()?;
+    let size = sink.written();
+    Ok(Streamed { sha256: sink.finish(), digest: report.image_digest(), size })
+}
+
+/// Opens and closes the write side so a reader blocked in `open` returns.
+fn release_reader(fifo: &Path) {
+    let _ = std::fs::OpenOptions::new()
+        .write(true)
+        .custom_flags(nix::fcntl::OFlag::O_NONBLOCK.bits())
+        .open(fifo);
+}
+
+/// Copies everything read from `inner` into `out`.
+struct TeeReader<R, W> {
+    inner: R,
+    out: W,
+    activity: Activity,
+}
+
+impl<R: Read, W: Write> Read for TeeReader<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.activity.advance(n as u64);
+        self.out.write_all(&buf[..n])?;
+        Ok(n)
+    }
+}
+
+/// Blocking writer feeding data frames to the response body.
+struct FrameWriter {
+    tx: FrameSender,
+}
+
+impl Write for FrameWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let frame = Frame::data(Bytes::copy_from_slice(buf));
+        self.tx
+            .blocking_send(Ok(frame))
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::BrokenPipe, "client went away"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,702 @@
+//! Image sets kept fresh on a schedule: the `[[syncs]]` of the configuration.
+//!
+//! A sync names images, and repositories whose tags matching its globs are
+//! listed at each run. On every minute its cron expression matches, the
+//! scheduler starts a run, unless the previous one still runs; replicas
+//! sharing a store claim each slot once. A run resolves every image to its
+//! current digest and exports only those whose digest changed since the last
+//! run: as artifacts, or copied to the `destination` registry. Runs are jobs
+//! (`kind = sync`), followed and cancelled like any other; their per-image
+//! outcomes are kept in the run history (`GET /api/syncs/:name/runs`).
+//!
+//! The images are set by the configuration: only the `[references]` rules
+//! apply to them, not the policies of whoever starts a run. One image
+//! failing does not stop the run, which then ends with a `partial_failure`
+//! error naming how many failed.
+
+use crate::{
+    archive::ArchiveFormat,
+    audit::{Caller, Produced},
+    auth::Principal,
+    bandwidth::Bandwidth,
+    compression::ExportCompression,
+    config::{Settings, SyncConfig},
+    cron::Schedule,
+    error::{send_error, ApiError},
+    jobs::{remove_partial, JobKind, NewJob, JOB_ID},
+    metrics::PullMetrics,
+    parse_repo_tag,
+    policy::glob_regex,
+    puller::{MirrorJob, PullJob},
+    reference::ImageReference,
+    skopeo::{self, EventSender},
+    timeouts::{Activity, Expired},
+    pull_artifact, valid_ref, AppState, NewArtifact, PullRecord,
+};
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Timelike, Utc};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tracing::Instrument;
+use uuid::Uuid;
+
+const DEFAULT_LIMIT: u32 = 20;
+const MAX_LIMIT: u32 = 1000;
+
+/// Slots missed while the scheduler could not run (a suspended process, a
+/// slow store) are caught up within this many minutes.
+const CATCH_UP_MINUTES: i64 = 60;
+
+/// The syncs of one configuration load, schedules and globs compiled.
+#[derive(Default)]
+pub struct Syncs {
+    jobs: Vec<Arc<SyncJob>>,
+}
+
+pub struct SyncJob {
+    config: SyncConfig,
+    schedule: Schedule,
+    repositories: Vec<(String, Vec<Regex>)>,
+    format: ArchiveFormat,
+    compression: ExportCompression,
+}
+
+impl Syncs {
+    pub fn build(configs: &[SyncConfig]) -> anyhow::Result<Self> {
+        let jobs = configs
+            .iter()
+            .map(|c| SyncJob::build(c).map(Arc::new))
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self { jobs })
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<SyncJob>> {
+        self.jobs.iter().find(|j| j.config.name == name).cloned()
+    }
+}
+
+impl SyncJob {
+    fn build(c: &SyncConfig) -> anyhow::Result<Self> {
+        let name = &c.name;
+        let schedule = Schedule::parse(&c.schedule).map_err(|e| anyhow::anyhow!("syncs: '{name}' schedule: {e}"))?;
+        for image in &c.images {
+            let parsed = parse_image(image).map_err(|e| anyhow::anyhow!("syncs: '{name}' image '{image}': {e}"))?;
+            if c.destination.is_some() && parsed.tag.is_none() {
+                anyhow::bail!("syncs: '{name}' image '{image}' has no tag to copy it under");
+            }
+        }
+        let repositories = c
+            .repositories
+            .iter()
+            .map(|r| {
+                let repository = &r.repository;
+                parse_image(repository)
+                    .and_then(|_| if tagged(repository) { Err("takes no tag or digest".into()) } else { Ok(()) })
+                    .map_err(|e| anyhow::anyhow!("syncs: '{name}' repository '{repository}': {e}"))?;
+                let globs = r
+                    .tags
+                    .iter()
+                    .map(|glob| {
+                        glob_regex(glob).map_err(|e| anyhow::anyhow!("syncs: '{name}' invalid tag glob '{glob}': {e}"))
+                    })
+                    .collect::<anyhow::Result<_>>()?;
+                Ok((repository.clone(), globs))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        if let Some(destination) = &c.destination {
+            if !valid_destination(destination) {
+                anyhow::bail!("syncs: '{name}' destination '{destination}' must be registry/path, without tag");
+            }
+        }
+        let format = c.format.parse::<ArchiveFormat>().map_err(|e| anyhow::anyhow!("syncs: '{name}' format: {e}"))?;
+        let compression = ExportCompression::parse(format, c.compression.as_deref(), None, None)
+            .map_err(|e| anyhow::anyhow!("syncs: '{name}' compression: {e}"))?;
+        Ok(Self { config: c.clone(), schedule, repositories, format, compression })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    /// Job format of the runs: the archive format, or `registry`.
+    fn job_format(&self) -> &str {
+        match self.config.destination {
+            Some(_) => "registry",
+            None => self.format.as_str(),
+        }
+    }
+}
+
+fn parse_image(reference: &str) -> Result<ImageReference, String> {
+    if !valid_ref(reference) {
+        return Err("invalid characters".into());
+    }
+    ImageReference::parse(reference)
+}
+
+/// Whether `reference` names a tag or a digest (a colon after the last
+/// slash, or an `@`).
+pub(crate) fn tagged(reference: &str) -> bool {
+    reference.contains('@') || reference.rsplit('/').next().is_some_and(|last| last.contains(':'))
+}
+
+/// Whether `destination` names a registry and a path in it, without tag.
+pub(crate) fn valid_destination(destination: &str) -> bool {
+    let host_like = |h: &str| h.contains('.') || h.contains(':') || h == "localhost";
+    valid_ref(destination)
+        && !tagged(destination)
+        && destination.split_once('/').is_some_and(|(host, path)| host_like(host) && !path.is_empty())
+}
+
+/// One run of a sync.
+#[derive(Debug, Clone, Serialize)]
+pub struct SyncRun {
+    /// Also the id of its job.
+    pub id: String,
+    pub name: String,
+    /// `schedule` or `manual`.
+    pub origin: String,
+    /// Minute of the schedule the run is for; none for manual runs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub slot: Option<DateTime<Utc>>,
+    pub owner: String,
+    /// `running`, `succeeded`, `failed` or `cancelled`.
+    pub state: String,
+    pub exported: u32,
+    pub unchanged: u32,
+    pub failed: u32,
+    pub images: Vec<SyncedImage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<serde_json::Value>,
+    pub started: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finished: Option<DateTime<Utc>>,
+    pub node: String,
+}
+
+/// What a run did with one image.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncedImage {
+    #[serde(rename = "ref")]
+    pub reference: String,
+    /// `exported`, `unchanged` or `failed`.
+    pub outcome: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>,
+    /// Digest exported by an earlier run.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub previous: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub artifact_id: Option<String>,
+    /// Where it was copied, for syncs to a registry.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub destination: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<serde_json::Value>,
+}
+
+impl SyncedImage {
+    fn new(reference: &str, outcome: &str) -> Self {
+        Self {
+            reference: reference.to_string(),
+            outcome: outcome.to_string(),
+            digest: None,
+            previous: None,
+            artifact_id: None,
+            destination: None,
+            error: None,
+        }
+    }
+
+    fn failed(reference: &str, err: &ApiError) -> Self {
+        Self { error: serde_json::to_value(err.problem()).ok(), ..Self::new(reference, "failed") }
+    }
+}
+
+/// The run as it goes, and the artifact being written, removed if the run
+/// is cancelled.
+struct Progress {
+    run: SyncRun,
+    partial: Option<String>,
+}
+
+fn lock(progress: &Mutex<Progress>) -> std::sync::MutexGuard<'_, Progress> {
+    progress.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Starts the scheduler, which reads the syncs of the current configuration
+/// every minute.
+pub fn schedule(state: AppState) {
+    tokio::spawn(async move {
+        let mut last = minute(Utc::now());
+        loop {
+            let now = Utc::now();
+            let next = minute(now) + chrono::Duration::minutes(1);
+            tokio::time::sleep((next - now).to_std().unwrap_or_default()).await;
+            let current = minute(Utc::now());
+            let from = last.max(current - chrono::Duration::minutes(CATCH_UP_MINUTES));
+            last = current;
+            let settings = state.config.current();
+            for sync in &settings.syncs.jobs {
+                // A sync matching several missed slots runs once, for the latest.
+                let Some(slot) = slots(from, current).rev().find(|slot| sync.schedule.matches(*slot)) else {
+                    continue;
+                };
+                match start(&state, settings.clone(), sync.clone(), scheduler(), Some(slot)).await {
+                    Ok(run) => tracing::info!(sync = %sync.name(), run = %run.id, "syncs: scheduled run started"),
+                    Err(ApiError::Busy(reason)) => tracing::debug!(sync = %sync.name(), "syncs: {}", reason),
+                    Err(e) => tracing::warn!(sync = %sync.name(), "syncs: cannot start the scheduled run: {}", e),
+                }
+            }
+        }
+    });
+}
+
+fn minute(time: DateTime<Utc>) -> DateTime<Utc> {
+    time.with_second(0).and_then(|t| t.with_nanosecond(0)).unwrap_or(time)
+}
+
+/// The minutes after `from`, up to `to` included.
+fn slots(from: DateTime<Utc>, to: DateTime<Utc>) -> impl DoubleEndedIterator<Item = DateTime<Utc>> {
+    (1..=(to - from).num_minutes()).map(move |n| from + chrono::Duration::minutes(n))
+}
+
+/// Who the scheduled runs are recorded as, in the jobs and the audit log.
+fn scheduler() -> Caller {
+    let principal = Principal { name: "scheduler".to_string(), groups: Vec::new(), method: "scheduler" };
+    Caller { principal, ip: None, forwarded_for: None, request_id: None }
+}
+
+/// Claims and starts a run of `sync` for `caller`, for schedule `slot` or,
+/// without one, on demand.
+async fn start(
+    state: &AppState,
+    settings: Arc<Settings>,
+    sync: Arc<SyncJob>,
+    caller: Caller,
+    slot: Option<DateTime<Utc>>,
+) -> Result<SyncRun, ApiError> {
+    let mut run = SyncRun {
+        id: Uuid::new_v4().to_string(),
+        name: sync.name().to_string(),
+        origin: if slot.is_some() { "schedule" } else { "manual" }.to_string(),
+        slot,
+        owner: caller.principal.name.clone(),
+        state: "running".to_string(),
+        exported: 0,
+        unchanged: 0,
+        failed: 0,
+        images: Vec::new(),
+        error: None,
+        started: Utc::now(),
+        finished: None,
+        node: state.node.id.clone(),
+    };
+    if !state.store.claim_sync_run(&run).await? {
+        return Err(ApiError::Busy(format!("sync '{}' is already running", run.name)));
+    }
+    let job = NewJob {
+        id: run.id.clone(),
+        kind: JobKind::Sync,
+        reference: run.name.clone(),
+        format: sync.job_format().to_string(),
+        owner: run.owner.clone(),
+    };
+    let mut handle = match state.jobs.start(job, settings.config.jobs.max_running, None).await {
+        Ok(handle) => handle,
+        Err(e) => {
+            run.state = "failed".to_string();
+            run.error = serde_json::to_value(e.problem()).ok();
+            run.finished = Some(Utc::now());
+            state.store.update_sync_run(&run).await?;
+            return Err(e);
+        }
+    };
+    let bandwidth = Bandwidth::new(None);
+    if settings.puller.limits_bandwidth() {
+        handle.limit_bandwidth(bandwidth.clone());
+    }
+    let progress = Arc::new(Mutex::new(Progress { run: run.clone(), partial: None }));
+    let runner = Runner {
+        state: state.clone(),
+        settings,
+        sync: sync.clone(),
+        caller,
+        tx: handle.sender(),
+        bandwidth,
+        progress: progress.clone(),
+    };
+    let span = tracing::info_span!("sync", sync = %run.name, run = %run.id);
+    let mut events = handle.spawn(runner.run().instrument(span));
+    let state = state.clone();
+    tokio::spawn(async move {
+        // The job's events are stored as they come; the stream ends with it.
+        while tokio_stream::StreamExt::next(&mut events).await.is_some() {}
+        let (mut run, partial) = {
+            let progress = lock(&progress);
+            (progress.run.clone(), progress.partial.clone())
+        };
+        if run.state != "running" {
+            return;
+        }
+        // Cancelled, or stopped without a result.
+        let job = state.store.job(&run.id).await.ok().flatten();
+        run.state = job.as_ref().map_or("failed", |j| j.state.as_str()).to_string();
+        run.error = job.and_then(|j| j.error);
+        run.finished = Some(Utc::now());
+        if let Some(id) = partial {
+            remove_partial(&id, sync.format.as_str()).await;
+        }
+        if let Err(e) = state.store.update_sync_run(&run).await {
+            tracing::warn!(run = %run.id, "syncs: cannot record the end of the run: {}", e);
+        }
+    });
+    Ok(run)
+}
+
+/// The work of one run.
+struct Runner {
+    state: AppState,
+    settings: Arc<Settings>,
+    sync: Arc<SyncJob>,
+    caller: Caller,
+    tx: EventSender,
+    bandwidth: Bandwidth,
+    progress: Arc<Mutex<Progress>>,
+}
+
+impl Runner {
+    async fn run(self) {
+        let name = self.sync.name();
+        skopeo::send_event(&self.tx, "start", format!("syncing {name}")).await;
+        let previous = self.state.store.sync_digests(name).await.unwrap_or_else(|e| {
+            tracing::warn!("syncs: cannot read the digests of the last runs, exporting everything: {}", e);
+            HashMap::new()
+        });
+        let targets = self.targets().await;
+        let total = targets.len();
+        for (i, target) in targets.into_iter().enumerate() {
+            let image = match target {
+                Ok(reference) => {
+                    skopeo::send_event(&self.tx, "progress", format!("[{}/{}] {}", i + 1, total, reference)).await;
+                    self.sync_image(&reference, previous.get(&reference).cloned()).await
+                }
+                Err(failed) => failed,
+            };
+            if let (Some(digest), "exported") = (&image.digest, image.outcome.as_str()) {
+                if let Err(e) = self.state.store.set_sync_digest(name, &image.reference, digest).await {
+                    tracing::warn!("syncs: cannot record the digest of {}: {}", image.reference, e);
+                }
+            }
+            skopeo::send_event(&self.tx, "image", serde_json::to_string(&image).unwrap_or_default()).await;
+            let run = {
+                let mut progress = lock(&self.progress);
+                let run = &mut progress.run;
+                match image.outcome.as_str() {
+                    "exported" => run.exported += 1,
+                    "unchanged" => run.unchanged += 1,
+                    _ => run.failed += 1,
+                }
+                run.images.push(image);
+                run.clone()
+            };
+            self.record(&run).await;
+        }
+
+        let (run, failure) = {
+            let mut progress = lock(&self.progress);
+            let run = &mut progress.run;
+            let failure = (run.failed > 0)
+                .then(|| ApiError::PartialFailure(format!("{} of {} images failed", run.failed, run.images.len())));
+            run.state = if failure.is_some() { "failed" } else { "succeeded" }.to_string();
+            run.error = failure.as_ref().and_then(|e| serde_json::to_value(e.problem()).ok());
+            run.finished = Some(Utc::now());
+            (run.clone(), failure)
+        };
+        // Recorded first: the job ends with the next event.
+        self.record(&run).await;
+        match failure {
+            Some(err) => send_error(&self.tx, &err).await,
+            None => {
+                let ready = serde_json::json!({
+                    "run": run.id,
+                    "exported": run.exported,
+                    "unchanged": run.unchanged,
+                    "failed": run.failed,
+                });
+                skopeo::send_event(&self.tx, "ready", ready.to_string()).await;
+                skopeo::send_event(&self.tx, "end", "done").await;
+            }
+        }
+    }
+
+    async fn record(&self, run: &SyncRun) {
+        if let Err(e) = self.state.store.update_sync_run(run).await {
+            tracing::warn!("syncs: cannot record the run: {}", e);
+        }
+    }
+
+    /// The images of the sync, then the matching tags of its repositories,
+    /// each once; a repository whose tags cannot be listed is a failure.
+    async fn targets(&self) -> Vec<Result<String, SyncedImage>> {
+        let mut seen = HashSet::new();
+        let mut targets: Vec<Result<String, SyncedImage>> = Vec::new();
+        for image in &self.sync.config.images {
+            if seen.insert(image.clone()) {
+                targets.push(Ok(image.clone()));
+            }
+        }
+        for (repository, globs) in &self.sync.repositories {
+            let credentials = ImageReference::parse(repository)
+                .ok()
+                .and_then(|image| self.settings.registry_credentials(&image));
+            let listed = tokio::time::timeout(
+                self.settings.timeouts.job,
+                self.settings.puller.tags(repository, credentials.as_ref()),
+            )
+            .await;
+            let tags = match listed {
+                Ok(Ok(tags)) => tags,
+                Ok(Err(e)) => {
+                    targets.push(Err(SyncedImage::failed(repository, &ApiError::pull(repository, e))));
+                    continue;
+                }
+                Err(_) => {
+                    let err = ApiError::Timeout(format!("timeout while listing the tags of {repository}"));
+                    targets.push(Err(SyncedImage::failed(repository, &err)));
+                    continue;
+                }
+            };
+            for tag in tags.iter().filter(|t| globs.is_empty() || globs.iter().any(|g| g.is_match(t))) {
+                let reference = format!("{repository}:{tag}");
+                if seen.insert(reference.clone()) {
+                    targets.push(Ok(reference));
+                }
+            }
+        }
+        targets
+    }
+
+    /// Resolves `reference` and exports it if its digest is not `previous`.
+    async fn sync_image(&self, reference: &str, previous: Option<String>) -> SyncedImage {
+        let settings = &self.settings;
+        let image = match settings.admission.check(reference).and_then(|()| {
+            ImageReference::parse(reference).map_err(|_| ApiError::InvalidReference(reference.to_string()))
+        }) {
+            Ok(image) => image,
+            Err(e) => return SyncedImage::failed(reference, &e),
+        };
+        let credentials = settings.registry_credentials(&image);
+        let resolved = settings.puller.resolve(reference, credentials.as_ref());
+        let digest = match tokio::time::timeout(settings.timeouts.job, resolved).await {
+            Ok(Ok(digest)) => digest,
+            Ok(Err(e)) => return SyncedImage::failed(reference, &ApiError::pull(reference, e)),
+            Err(_) => {
+                let err = ApiError::Timeout(Expired::Job(settings.timeouts.job).describe(reference));
+                return SyncedImage::failed(reference, &err);
+            }
+        };
+        if previous.as_deref() == Some(digest.as_str()) {
+            return SyncedImage { digest: Some(digest), previous, ..SyncedImage::new(reference, "unchanged") };
+        }
+
+        // Pinned, so that what is exported is what was compared.
+        let pinned = ImageReference { tag: None, digest: Some(digest.clone()), ..image.clone() }.to_string();
+        let exported = match &self.sync.config.destination {
+            Some(destination) => self.mirror(reference, &image, &pinned, destination).await,
+            None => self.export(reference, &pinned, credentials).await,
+        };
+        match exported {
+            Ok(image) => SyncedImage { digest: Some(digest), previous, ..image },
+            Err(e) => SyncedImage { digest: Some(digest), previous, ..SyncedImage::failed(reference, &e) },
+        }
+    }
+
+    /// Keeps `pinned` as an artifact named after `reference`.
+    async fn export(
+        &self,
+        reference: &str,
+        pinned: &str,
+        credentials: Option<crate::puller::Credentials>,
+    ) -> Result<SyncedImage, ApiError> {
+        let (sync, settings) = (&self.sync, &self.settings);
+        let entry = self.state.audit.pull(self.caller.clone(), reference, sync.format.as_str());
+        let pull = PullRecord { metrics: PullMetrics::start(reference), audit: entry };
+        let id = Uuid::new_v4().to_string();
+        let (repo, tag) = parse_repo_tag(reference);
+        let job = PullJob {
+            reference: pinned.to_string(),
+            format: sync.format,
+            staging: sync.format.staging_path(&crate::artifacts::artifact_path(&id)),
+            repo,
+            tag,
+            credentials,
+            layer_compression: sync.compression.layers,
+            activity: Activity::default(),
+            bandwidth: self.bandwidth.clone(),
+        };
+        let kept = NewArtifact {
+            id: id.clone(),
+            owner: self.caller.principal.name.clone(),
+            ttl: sync.config.artifact_ttl_secs.map_or(settings.artifact_ttl, Duration::from_secs),
+            compression: sync.compression.archive,
+        };
+        lock(&self.progress).partial = Some(id.clone());
+        let limits = settings.timeouts.for_request(None, None);
+        let kept = pull_artifact(&self.state, settings, &job, kept, limits, &self.tx).await;
+        lock(&self.progress).partial = None;
+        match kept {
+            Ok(artifact) => {
+                pull.succeeded(Produced {
+                    digest: artifact.digest,
+                    size: Some(artifact.size),
+                    sha256: Some(artifact.sha256),
+                    artifact_id: Some(id.clone()),
+                });
+                Ok(SyncedImage { artifact_id: Some(id), ..SyncedImage::new(reference, "exported") })
+            }
+            Err(e) => {
+                pull.failed(&e);
+                Err(e)
+            }
+        }
+    }
+
+    /// Copies `pinned` to the destination registry, under the repository
+    /// path and tag of `reference`.
+    async fn mirror(
+        &self,
+        reference: &str,
+        image: &ImageReference,
+        pinned: &str,
+        destination: &str,
+    ) -> Result<SyncedImage, ApiError> {
+        let settings = &self.settings;
+        let entry = self.state.audit.pull(self.caller.clone(), reference, "registry");
+        let pull = PullRecord { metrics: PullMetrics::start(reference), audit: entry };
+        let target = format!("{}/{}:{}", destination, image.repository, image.tag.as_deref().unwrap_or("latest"));
+        let job = MirrorJob {
+            reference: pinned.to_string(),
+            credentials: settings.registry_credentials(image),
+            destination: target.clone(),
+            destination_credentials: ImageReference::parse(&target)
+                .ok()
+                .and_then(|d| settings.registry_credentials(&d)),
+            activity: Activity::default(),
+            bandwidth: self.bandwidth.clone(),
+        };
+        let limit = settings.timeouts.job;
+        let copied = match tokio::time::timeout(limit, settings.puller.mirror(&job, Some(&self.tx))).await {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(e)) => Err(ApiError::pull(reference, e)),
+            Err(_) => Err(ApiError::Timeout(Expired::Job(limit).describe(reference))),
+        };
+        match copied {
+            Ok(()) => {
+                let digest = pinned.rsplit_once('@').map(|(_, d)| d.to_string());
+                pull.succeeded(Produced { digest, size: Some(job.activity.bytes()), sha256: None, artifact_id: None });
+                Ok(SyncedImage { destination: Some(target), ..SyncedImage::new(reference, "exported") })
+            }
+            Err(e) => {
+                pull.failed(&e);
+                Err(e)
+            }
+        }
+    }
+}
+
+/// A configured sync, as listed by the API.
+#[derive(Serialize)]
+struct SyncSummary<'a> {
+    name: &'a str,
+    schedule: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_run: Option<DateTime<Utc>>,
+    images: &'a [String],
+    repositories: &'a [crate::config::SyncRepositoryConfig],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    destination: Option<&'a str>,
+    format: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_run: Option<SyncRun>,
+}
+
+/// `GET /api/syncs`: the configured syncs, when they run next and how their
+/// last run went.
+pub async fn list_syncs(State(state): State<AppState>, caller: Caller) -> Response {
+    let settings = state.config.current();
+    if let Err(e) = settings.policy.check_syncs(&caller.principal) {
+        return e.into_response();
+    }
+    let now = Utc::now();
+    let mut syncs = Vec::new();
+    for sync in &settings.syncs.jobs {
+        let last_run = match state.store.sync_runs(sync.name(), 1).await {
+            Ok(mut runs) => runs.pop(),
+            Err(e) => return e.into_response(),
+        };
+        let c = &sync.config;
+        syncs.push(SyncSummary {
+            name: &c.name,
+            schedule: &c.schedule,
+            next_run: sync.schedule.next_after(now),
+            images: &c.images,
+            repositories: &c.repositories,
+            destination: c.destination.as_deref(),
+            format: sync.job_format(),
+            last_run,
+        });
+    }
+    Json(syncs).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct RunsQuery {
+    limit: Option<u32>,
+}
+
+/// `GET /api/syncs/:name/runs`: the run history of a sync, most recent first.
+pub async fn list_runs(
+    State(state): State<AppState>,
+    caller: Caller,
+    Path(name): Path<String>,
+    Query(query): Query<RunsQuery>,
+) -> Response {
+    let settings = state.config.current();
+    if let Err(e) = settings.policy.check_syncs(&caller.principal) {
+        return e.into_response();
+    }
+    if settings.syncs.get(&name).is_none() {
+        return ApiError::NotFound(format!("no sync named '{name}'")).into_response();
+    }
+    match state.store.sync_runs(&name, query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT)).await {
+        Ok(runs) => Json(runs).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// `POST /api/syncs/:name/run`: starts a run now. Its job id is in the
+/// `x-job-id` header, to follow it with `GET /api/jobs/:id/events`.
+pub async fn run_sync(State(state): State<AppState>, caller: Caller, Path(name): Path<String>) -> Response {
+    let settings = state.config.current();
+    if let Err(e) = settings.policy.check_syncs(&caller.principal) {
+        return e.into_response();
+    }
+    let Some(sync) = settings.syncs.get(&name) else {
+        return ApiError::NotFound(format!("no sync named '{name}'")).into_response();
+    };
+    match start(&state, settings.clone(), sync, caller, None).await {
+        Ok(run) => (StatusCode::ACCEPTED, [(JOB_ID, run.id.clone())], Json(run)).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
@@ -0,0 +1,106 @@
+//! Logging and the per-request trail.
+//!
+//! Logs go to stderr, filtered by `RUST_LOG` (default `info`), as text or,
+//! with `LOG_FORMAT=json`, one JSON object per line. Every API request gets
+//! an `X-Request-Id`, the caller's or a new one, echoed in the response and
+//! attached to everything logged while serving it, pulls included.
+//!
+//! Nothing secret may reach the logs: request URIs and messages that can
+//! carry credentials go through [`redact`] first.
+
+use axum::{body::Body, http::Request, http::HeaderName, response::Response, Router};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use std::{io::IsTerminal, time::Duration};
+use tower_http::{
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+    trace::TraceLayer,
+};
+use tracing::Span;
+use tracing_subscriber::EnvFilter;
+
+pub const REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Query parameters whose values are never logged.
+const SECRET_PARAMS: &[&str] = &["password", "token", "access_token", "secret", "auth"];
+
+pub fn init() -> anyhow::Result<()> {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .with_ansi(std::io::stderr().is_terminal());
+    match std::env::var("LOG_FORMAT").as_deref() {
+        Ok("json") => builder.json().with_current_span(false).with_span_list(true).init(),
+        Ok("text") | Ok("") | Err(_) => builder.init(),
+        Ok(other) => anyhow::bail!("LOG_FORMAT must be text or json, not '{}'", other),
+    }
+    Ok(())
+}
+
+/// Logs every request of `api` with its id, method, redacted URI, status
+/// and latency.
+pub fn trace(api: Router) -> Router {
+    api.layer(
+        TraceLayer::new_for_http()
+            .make_span_with(|req: &Request<Body>| {
+                let id = req.headers().get(&REQUEST_ID).and_then(|v| v.to_str().ok()).unwrap_or("-");
+                tracing::info_span!(
+                    "request",
+                    request_id = %id,
+                    method = %req.method(),
+                    uri = %redact_uri(req.uri()),
+                    user = tracing::field::Empty,
+                )
+            })
+            .on_request(())
+            .on_response(|res: &Response, latency: Duration, _: &Span| {
+                tracing::info!(status = res.status().as_u16(), latency_ms = latency.as_millis() as u64, "request served");
+            })
+            .on_failure(()),
+    )
+}
+
+/// Assigns request ids (keeping the caller's) and echoes them back. Goes
+/// outside [`trace`] so that the span sees the id.
+pub fn request_ids(app: Router) -> Router {
+    app.layer(PropagateRequestIdLayer::new(REQUEST_ID))
+        .layer(SetRequestIdLayer::new(REQUEST_ID, MakeRequestUuid))
+}
+
+/// The path and query of `uri`, with secret parameters masked and URLs in
+/// parameter values stripped of their user info.
+fn redact_uri(uri: &axum::http::Uri) -> String {
+    let Some(query) = uri.query() else {
+        return uri.path().to_string();
+    };
+    let params: Vec<String> = url::form_urlencoded::parse(query.as_bytes())
+        .map(|(key, value)| {
+            if SECRET_PARAMS.contains(&key.to_ascii_lowercase().as_str()) {
+                format!("{key}=***")
+            } else {
+                format!("{key}={}", redact(&value))
+            }
+        })
+        .collect();
+    format!("{}?{}", uri.path(), params.join("&"))
+}
+
+/// Masks credentials embedded in free text: user info in URLs, HTTP
+/// authorization values, base64 `auth` entries of auth files and passwords
+/// quoted back by config parse errors.
+pub fn redact(text: &str) -> String {
+    static PATTERNS: Lazy<[(Regex, &str); 4]> = Lazy::new(|| {
+        [
+            (Regex::new(r"([A-Za-z][A-Za-z0-9+.-]*://)[^/@\s]+@").unwrap(), "${1}***@"),
+            (Regex::new(r"(?i)\b(basic|bearer)\s+[A-Za-z0-9._~+/=-]+").unwrap(), "$1 ***"),
+            (Regex::new(r#""auth"\s*:\s*"[^"]*""#).unwrap(), r#""auth":"***""#),
+            (Regex::new(r#"(?i)(password\s*=\s*)"[^"]*""#).unwrap(), r#"$1"***""#),
+        ]
+    });
+    let mut out = text.to_string();
+    for (pattern, replacement) in PATTERNS.iter() {
+        out = pattern.replace_all(&out, *replacement).into_owned();
+    }
+    out
+}
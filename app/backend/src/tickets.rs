@@ -0,0 +1,136 @@
+//! Short-lived tickets, for clients that cannot send credentials in headers:
+//! a browser opening the jobs WebSocket, or an `EventSource`.
+//!
+//! An authenticated `POST /api/tickets` returns a random ticket that stands
+//! for the caller once, within [`TTL`]. It is given in the `ticket` query
+//! parameter or, opening a WebSocket, as a `ticket.<ticket>` entry of
+//! `Sec-WebSocket-Protocol`. The store keeps its SHA-256 only, so that any
+//! replica can take it.
+
+use crate::{audit::Caller, auth::Principal, error::ApiError, AppState};
+use axum::{
+    extract::{Query, State},
+    http::{header, HeaderMap, Uri},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::Utc;
+use rand::RngCore;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+
+pub const TTL: Duration = Duration::from_secs(30);
+
+/// Prefix of the WebSocket subprotocol carrying a ticket.
+pub const PROTOCOL_PREFIX: &str = "ticket.";
+
+#[derive(Deserialize)]
+struct TicketQuery {
+    ticket: Option<String>,
+}
+
+fn hash(ticket: &str) -> String {
+    hex::encode(Sha256::digest(ticket.as_bytes()))
+}
+
+/// Issues a ticket standing for `principal`.
+pub async fn issue(state: &AppState, principal: &Principal) -> Result<String, ApiError> {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let ticket = hex::encode(bytes);
+    let expires = Utc::now() + chrono::Duration::from_std(TTL).unwrap_or_default();
+    state.store.insert_ticket(&hash(&ticket), principal, expires).await?;
+    Ok(ticket)
+}
+
+pub async fn create_ticket(State(state): State<AppState>, caller: Caller) -> Response {
+    match issue(&state, &caller.principal).await {
+        Ok(ticket) => Json(serde_json::json!({ "ticket": ticket, "expires_in": TTL.as_secs() })).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// The ticket a request carries, if any.
+pub fn presented(uri: &Uri, headers: &HeaderMap) -> Option<String> {
+    if let Ok(Query(TicketQuery { ticket: Some(ticket) })) = Query::<TicketQuery>::try_from_uri(uri) {
+        return Some(ticket);
+    }
+    protocols(headers).find_map(|p| p.strip_prefix(PROTOCOL_PREFIX).map(str::to_string))
+}
+
+/// The subprotocols a WebSocket client offers.
+pub fn protocols(headers: &HeaderMap) -> impl Iterator<Item = &str> {
+    headers
+        .get_all(header::SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+}
+
+/// Takes `ticket`, returning whom it stands for.
+pub async fn redeem(state: &AppState, ticket: &str) -> Result<Principal, ApiError> {
+    state
+        .store
+        .redeem_ticket(&hash(ticket))
+        .await?
+        .ok_or_else(|| ApiError::Unauthorized("invalid or expired ticket".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{auth::hash_api_key, tests};
+    use axum::http::StatusCode;
+
+    #[tokio::test]
+    async fn a_ticket_authenticates_one_request() {
+        let config = format!(
+            "[puller]\nkind = \"native\"\n\n[[auth.api_keys]]\nname = \"alice\"\nsha256 = \"{}\"\n",
+            hash_api_key("alice")
+        );
+        let url = tests::serve(tests::state(toml::from_str(&config).unwrap()).await).await;
+        let client = reqwest::Client::new();
+        assert_eq!(client.post(format!("{url}/api/tickets")).send().await.unwrap().status(), StatusCode::UNAUTHORIZED);
+
+        let issued: serde_json::Value = client
+            .post(format!("{url}/api/tickets"))
+            .header("x-api-key", "alice")
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(issued["expires_in"], TTL.as_secs());
+        let ticket = issued["ticket"].as_str().unwrap();
+        let jobs = |ticket: &str| client.get(format!("{url}/api/jobs?ticket={ticket}")).send();
+        assert_eq!(jobs(ticket).await.unwrap().status(), StatusCode::OK);
+        assert_eq!(jobs(ticket).await.unwrap().status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(jobs("0000").await.unwrap().status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn expired_tickets_are_refused() {
+        let state = tests::state(toml::from_str("[puller]\nkind = \"native\"").unwrap()).await;
+        let alice = Principal { name: "alice".to_string(), groups: vec!["ops".to_string()], method: "api_key" };
+        let ticket = issue(&state, &alice).await.unwrap();
+        let redeemed = redeem(&state, &ticket).await.unwrap();
+        assert_eq!((redeemed.name.as_str(), redeemed.method), ("alice", "ticket"));
+        assert_eq!(redeemed.groups, ["ops"]);
+
+        let expired = Utc::now() - chrono::Duration::seconds(1);
+        state.store.insert_ticket(&hash("old"), &alice, expired).await.unwrap();
+        assert!(matches!(redeem(&state, "old").await, Err(ApiError::Unauthorized(_))));
+    }
+
+    #[test]
+    fn tickets_come_from_the_query_or_the_subprotocols() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(presented(&"/api/jobs/ws?ticket=abc".parse().unwrap(), &headers).as_deref(), Some("abc"));
+        headers.insert(header::SEC_WEBSOCKET_PROTOCOL, "jobs, ticket.def".parse().unwrap());
+        assert_eq!(presented(&"/api/jobs/ws".parse().unwrap(), &headers).as_deref(), Some("def"));
+        assert_eq!(presented(&"/api/jobs/ws".parse().unwrap(), &HeaderMap::new()), None);
+    }
+}
@@ -0,0 +1,288 @@
+//! Time limits for pulls: a cap on the whole job, and an idle limit that
+//! ends pulls which stopped making progress.
+//!
+//! Admins set the defaults and the upper bounds; a request may ask for other
+//! values, which are clamped to those bounds. Every pull path runs its job
+//! through [`supervise`] so the limits mean the same thing everywhere.
+
+use std::{
+    future::Future,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+/// How often the idle limit is checked and the destination measured.
+const POLL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone)]
+pub struct TimeoutConfig {
+    pub job: Duration,
+    /// Zero disables the idle limit.
+    pub idle: Duration,
+    pub readiness: Duration,
+    pub max_job: Duration,
+    pub max_idle: Duration,
+}
+
+/// The limits applied to one pull.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub job: Duration,
+    pub idle: Duration,
+}
+
+impl TimeoutConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.job.is_zero() || self.readiness.is_zero() {
+            anyhow::bail!("timeouts.job_secs and timeouts.readiness_secs must be positive");
+        }
+        if self.job > self.max_job {
+            anyhow::bail!("timeouts.job_secs exceeds timeouts.max_job_secs");
+        }
+        if self.idle > self.max_idle {
+            anyhow::bail!("timeouts.idle_secs exceeds timeouts.max_idle_secs");
+        }
+        Ok(())
+    }
+
+    /// Limits for a request asking for `job` and `idle` seconds, if anything.
+    ///
+    /// Requested values are clamped to the configured maximums. A request
+    /// cannot turn the idle limit off when the admin has it on.
+    pub fn for_request(&self, job: Option<u64>, idle: Option<u64>) -> Limits {
+        let job = job.map_or(self.job, |s| Duration::from_secs(s.max(1)).min(self.max_job));
+        let idle = match idle {
+            Some(s) if !self.idle.is_zero() => Duration::from_secs(s.max(1)).min(self.max_idle),
+            _ => self.idle,
+        };
+        Limits { job, idle }
+    }
+}
+
+/// When a pull last made progress, and how many bytes it moved. Cheap to
+/// clone and to touch from any thread: pullers touch it as bytes arrive.
+#[derive(Debug, Clone)]
+pub struct Activity(Arc<ActivityInner>);
+
+#[derive(Debug)]
+struct ActivityInner {
+    start: Instant,
+    last_ms: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl Default for Activity {
+    fn default() -> Self {
+        Self(Arc::new(ActivityInner { start: Instant::now(), last_ms: AtomicU64::new(0), bytes: AtomicU64::new(0) }))
+    }
+}
+
+impl Activity {
+    pub fn touch(&self) {
+        let now = self.0.start.elapsed().as_millis() as u64;
+        self.0.last_ms.fetch_max(now, Ordering::Relaxed);
+    }
+
+    /// Records `n` bytes received or written.
+    pub fn advance(&self, n: u64) {
+        self.0.bytes.fetch_add(n, Ordering::Relaxed);
+        self.touch();
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.0.bytes.load(Ordering::Relaxed)
+    }
+
+    fn idle(&self) -> Duration {
+        let last = Duration::from_millis(self.0.last_ms.load(Ordering::Relaxed));
+        self.0.start.elapsed().saturating_sub(last)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Expired {
+    Job(Duration),
+    Idle(Duration),
+}
+
+impl Expired {
+    pub fn describe(&self, reference: &str) -> String {
+        match self {
+            Expired::Job(limit) => {
+                format!("timeout while copying image: {} (limit {}s)", reference, limit.as_secs())
+            }
+            Expired::Idle(limit) => {
+                format!("no progress for {}s while copying image: {}", limit.as_secs(), reference)
+            }
+        }
+    }
+}
+
+/// Runs `job` under `limits`. Besides explicit touches of `activity` (bytes
+/// received, lines skopeo prints, growth of its scratch directory), growth of
+/// `destination` (a file or a layout directory) counts as progress. Dropping
+/// the job on expiry kills the puller.
+pub async fn supervise<F: Future>(
+    job: F,
+    limits: Limits,
+    activity: &Activity,
+    destination: &Path,
+) -> Result<F::Output, Expired> {
+    let deadline = tokio::time::sleep(limits.job);
+    let mut ticks = tokio::time::interval(POLL);
+    let mut size = 0u64;
+    tokio::pin!(job, deadline);
+    loop {
+        tokio::select! {
+            out = &mut job => return Ok(out),
+            _ = &mut deadline => return Err(Expired::Job(limits.job)),
+            _ = ticks.tick() => {
+                if limits.idle.is_zero() {
+                    continue;
+                }
+                let current = disk_size(destination.to_path_buf()).await;
+                if current != size {
+                    size = current;
+                    activity.touch();
+                }
+                if activity.idle() >= limits.idle {
+                    return Err(Expired::Idle(limits.idle));
+                }
+            }
+        }
+    }
+}
+
+/// Touches `activity` whenever what lies under `path` changes size; never
+/// returns. For pullers staging data away from the destination.
+pub async fn watch_growth(path: &Path, activity: &Activity) {
+    let mut ticks = tokio::time::interval(POLL);
+    let mut size = 0u64;
+    loop {
+        ticks.tick().await;
+        let current = disk_size(path.to_path_buf()).await;
+        if current != size {
+            size = current;
+            activity.touch();
+        }
+    }
+}
+
+/// Size of a file, or of everything under a directory; 0 if it is gone.
+pub async fn disk_size(path: PathBuf) -> u64 {
+    tokio::task::spawn_blocking(move || size_of(&path)).await.unwrap_or(0)
+}
+
+fn size_of(path: &Path) -> u64 {
+    let Ok(meta) = std::fs::symlink_metadata(path) else { return 0 };
+    if !meta.is_dir() {
+        return meta.len();
+    }
+    std::fs::read_dir(path)
+        .map(|entries| entries.flatten().map(|e| size_of(&e.path())).sum())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(idle: u64) -> TimeoutConfig {
+        TimeoutConfig {
+            job: Duration::from_secs(300),
+            idle: Duration::from_secs(idle),
+            readiness: Duration::from_secs(5),
+            max_job: Duration::from_secs(3600),
+            max_idle: Duration::from_secs(900),
+        }
+    }
+
+    fn limits(job_ms: u64, idle_ms: u64) -> Limits {
+        Limits { job: Duration::from_millis(job_ms), idle: Duration::from_millis(idle_ms) }
+    }
+
+    #[test]
+    fn requested_limits_are_clamped() {
+        let bounds = config(120);
+        let asked = bounds.for_request(None, None);
+        assert_eq!((asked.job.as_secs(), asked.idle.as_secs()), (300, 120));
+        let asked = bounds.for_request(Some(60), Some(30));
+        assert_eq!((asked.job.as_secs(), asked.idle.as_secs()), (60, 30));
+        let asked = bounds.for_request(Some(1 << 40), Some(1 << 40));
+        assert_eq!((asked.job.as_secs(), asked.idle.as_secs()), (3600, 900));
+        let asked = bounds.for_request(Some(0), Some(0));
+        assert_eq!((asked.job.as_secs(), asked.idle.as_secs()), (1, 1));
+
+        // Off stays off, whatever the request asks.
+        assert!(config(0).for_request(None, Some(30)).idle.is_zero());
+    }
+
+    #[test]
+    fn defaults_must_fit_their_bounds() {
+        assert!(config(120).validate().is_ok());
+        assert!(config(901).validate().is_err());
+        assert!(TimeoutConfig { job: Duration::from_secs(3601), ..config(120) }.validate().is_err());
+    }
+
+    #[tokio::test]
+    async fn job_limit_expires() {
+        let out = supervise(std::future::pending::<()>(), limits(200, 0), &Activity::default(), Path::new("")).await;
+        assert!(matches!(out, Err(Expired::Job(_))));
+    }
+
+    #[tokio::test]
+    async fn idle_limit_expires_without_progress() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = supervise(std::future::pending::<()>(), limits(10_000, 1000), &Activity::default(), dir.path()).await;
+        assert!(matches!(out, Err(Expired::Idle(_))));
+    }
+
+    #[tokio::test]
+    async fn touches_and_destination_growth_keep_a_pull_alive() {
+        let activity = Activity::default();
+        let touching = async {
+            for _ in 0..8 {
+                tokio::time::sleep(Duration::from_millis(300)).await;
+                activity.touch();
+            }
+            "touched"
+        };
+        let out = supervise(touching, limits(10_000, 1000), &activity, Path::new("")).await;
+        assert_eq!(out.unwrap(), "touched");
+
+        let dir = tempfile::tempdir().unwrap();
+        let growing = async {
+            for i in 0..4 {
+                tokio::time::sleep(Duration::from_millis(600)).await;
+                std::fs::write(dir.path().join(i.to_string()), b"blob").unwrap();
+            }
+            "grown"
+        };
+        let out = supervise(growing, limits(10_000, 1500), &Activity::default(), dir.path()).await;
+        assert_eq!(out.unwrap(), "grown");
+    }
+
+    #[tokio::test]
+    async fn growth_elsewhere_counts_once_watched() {
+        let (activity, dir) = (Activity::default(), tempfile::tempdir().unwrap());
+        let growing = async {
+            for i in 0..4 {
+                tokio::time::sleep(Duration::from_millis(600)).await;
+                std::fs::write(dir.path().join(i.to_string()), b"blob").unwrap();
+            }
+            "grown"
+        };
+        let watched = async {
+            tokio::select! {
+                out = growing => out,
+                () = watch_growth(dir.path(), &activity) => unreachable!(),
+            }
+        };
+        let out = supervise(watched, limits(10_000, 1500), &activity, Path::new("")).await;
+        assert_eq!(out.unwrap(), "grown");
+    }
+}
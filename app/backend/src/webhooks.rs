@@ -0,0 +1,606 @@
+//! Job outcomes posted to the configured `[[webhooks.endpoints]]` and to the
+//! `callback` a job was started with, so that a client need not keep the
+//! job's stream open to learn how it ended.
+//!
+//! When a job ends, one delivery per interested endpoint is recorded in the
+//! [`Store`] with its JSON payload, then posted with an `X-Tessark-Signature`
+//! header: `sha256=` and the hex HMAC-SHA256 of the body under the
+//! endpoint's secret. Network errors, timeouts, 408, 429 and 5xx answers are
+//! retried with backoff; the delivery log (`GET /api/webhooks/deliveries`)
+//! keeps each attempt's outcome. Deliveries left pending when a replica
+//! stops are resumed by the replica itself on its next start, or by the
+//! others once it is gone.
+
+use crate::{
+    artifacts::Artifact,
+    audit::Caller,
+    classify::Failure,
+    config::{LiveConfig, WebhooksConfig},
+    error::ApiError,
+    jobs::{visible_owner, Job},
+    policy::glob_regex,
+    registry::error_chain,
+    retry::RetryPolicy,
+    store::{Attempt, DeliveryFilter, Node, Store},
+    telemetry::redact,
+    AppState,
+};
+use axum::{
+    extract::{Query, State},
+    http::header,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::{sync::Arc, time::Duration};
+use uuid::Uuid;
+
+const DEFAULT_LIMIT: u32 = 100;
+const MAX_LIMIT: u32 = 1000;
+
+const SIGNATURE: &str = "x-tessark-signature";
+const EVENT: &str = "x-tessark-event";
+const DELIVERY: &str = "x-tessark-delivery";
+
+/// Where a job asked its outcome to be posted.
+#[derive(Clone, Deserialize)]
+pub struct Callback {
+    pub url: String,
+    /// Key of the signature; never stored.
+    pub secret: String,
+}
+
+/// The webhook settings of one configuration load, secrets read.
+pub struct Webhooks {
+    endpoints: Vec<Endpoint>,
+    callback_hosts: Vec<Regex>,
+    retry: RetryPolicy,
+    timeout: Duration,
+}
+
+struct Endpoint {
+    name: String,
+    url: String,
+    secret: String,
+    /// Job states posted; all when empty.
+    events: Vec<String>,
+}
+
+impl Endpoint {
+    fn wants(&self, state: &str) -> bool {
+        self.events.is_empty() || self.events.iter().any(|e| e == state)
+    }
+}
+
+impl Webhooks {
+    pub fn build(config: &WebhooksConfig) -> anyhow::Result<Self> {
+        let endpoints = config
+            .endpoints
+            .iter()
+            .map(|e| {
+                let secret = match (&e.secret, &e.secret_file) {
+                    (Some(secret), _) => secret.clone(),
+                    (None, Some(file)) => std::fs::read_to_string(file)
+                        .map_err(|err| anyhow::anyhow!("webhooks: cannot read {}: {}", file.display(), err))?
+                        .trim()
+                        .to_string(),
+                    (None, None) => String::new(),
+                };
+                if secret.is_empty() {
+                    anyhow::bail!("webhooks: '{}' has an empty secret", e.name);
+                }
+                Ok(Endpoint { name: e.name.clone(), url: e.url.clone(), secret, events: e.events.clone() })
+            })
+            .collect::<anyhow::Result<_>>()?;
+        let callback_hosts = config
+            .callback_hosts
+            .iter()
+            .map(|glob| {
+                glob_regex(glob).map_err(|e| anyhow::anyhow!("webhooks: invalid callback host '{}': {}", glob, e))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self {
+            endpoints,
+            callback_hosts,
+            retry: RetryPolicy::new(
+                config.retries,
+                Duration::from_millis(config.base_ms),
+                Duration::from_millis(config.max_ms),
+            ),
+            timeout: Duration::from_secs(config.timeout_secs),
+        })
+    }
+
+    /// Refuses a callback that is not an http(s) URL on an allowed host, or
+    /// that could not be signed. Without `callback_hosts`, a job could make
+    /// the server post to any address it reaches: callbacks are then off.
+    pub fn check_callback(&self, callback: &Callback) -> Result<(), ApiError> {
+        if self.callback_hosts.is_empty() {
+            return Err(ApiError::InvalidRequest(
+                "callbacks are disabled: webhooks.callback_hosts lists no host".into(),
+            ));
+        }
+        let url = url::Url::parse(&callback.url)
+            .ok()
+            .filter(|u| matches!(u.scheme(), "http" | "https"))
+            .ok_or_else(|| ApiError::InvalidRequest("callback.url must be an http(s) URL".into()))?;
+        let host = url.host_str().unwrap_or_default();
+        if !self.callback_hosts.iter().any(|re| re.is_match(host)) {
+            return Err(ApiError::InvalidRequest(format!(
+                "callback host '{host}' is not allowed (webhooks.callback_hosts)"
+            )));
+        }
+        if callback.secret.is_empty() {
+            return Err(ApiError::InvalidRequest("callback.secret must not be empty".into()));
+        }
+        Ok(())
+    }
+}
+
+/// A job outcome posted, or to be posted, to one endpoint.
+#[derive(Debug, Clone, Serialize)]
+pub struct Delivery {
+    pub id: String,
+    pub job_id: String,
+    /// Owner of the job.
+    pub owner: String,
+    /// Endpoint name; none for the job's own callback.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub webhook: Option<String>,
+    pub url: String,
+    /// `job.succeeded`, `job.failed` or `job.cancelled`.
+    pub event: String,
+    /// Body sent at every attempt, as signed.
+    #[serde(skip)]
+    pub payload: String,
+    #[serde(skip)]
+    pub signature: String,
+    /// `pending`, `delivered` or `failed`.
+    pub state: String,
+    pub attempts: u32,
+    /// Status of the last answer.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_status: Option<u16>,
+    /// Why the last attempt failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub created: DateTime<Utc>,
+    pub updated: DateTime<Utc>,
+    /// Replica sending it.
+    pub node: String,
+}
+
+/// Body of a delivery.
+#[derive(Serialize)]
+struct Payload<'a> {
+    event: &'a str,
+    job_id: &'a str,
+    kind: &'a str,
+    #[serde(rename = "ref")]
+    reference: &'a str,
+    format: &'a str,
+    state: &'a str,
+    owner: &'a str,
+    artifact_id: Option<&'a str>,
+    digest: Option<&'a str>,
+    sha256: Option<&'a str>,
+    filename: Option<&'a str>,
+    size: Option<u64>,
+    /// `code` of the problem the job failed with.
+    error_class: Option<&'a str>,
+    error: Option<&'a serde_json::Value>,
+    time: DateTime<Utc>,
+}
+
+impl<'a> Payload<'a> {
+    fn new(event: &'a str, job: &'a Job, artifact: Option<&'a Artifact>) -> Self {
+        Self {
+            event,
+            job_id: &job.id,
+            kind: &job.kind,
+            reference: &job.reference,
+            format: &job.format,
+            state: &job.state,
+            owner: &job.owner,
+            artifact_id: job.artifact_id.as_deref(),
+            digest: artifact.and_then(|a| a.digest.as_deref()),
+            sha256: artifact.map(|a| a.sha256.as_str()),
+            filename: artifact.map(|a| a.filename.as_str()),
+            size: artifact.map(|a| a.size),
+            error_class: job.error.as_ref().and_then(|e| e.get("code")).and_then(|c| c.as_str()),
+            error: job.error.as_ref(),
+            time: job.updated,
+        }
+    }
+}
+
+/// `sha256=` and the hex HMAC-SHA256 of `payload` under `secret`.
+fn sign(secret: &str, payload: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(payload.as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Records and sends the deliveries of this replica.
+#[derive(Clone)]
+pub struct Notifier {
+    store: Arc<dyn Store>,
+    node: Arc<Node>,
+    config: LiveConfig,
+    client: reqwest::Client,
+}
+
+impl Notifier {
+    pub fn new(store: Arc<dyn Store>, node: Arc<Node>, config: LiveConfig, client: reqwest::Client) -> Self {
+        Self { store, node, config, client }
+    }
+
+    pub fn check_callback(&self, callback: &Callback) -> Result<(), ApiError> {
+        self.config.current().webhooks.check_callback(callback)
+    }
+
+    /// Posts the outcome of job `id`, which just ended.
+    pub async fn job_ended(&self, id: &str, callback: Option<Callback>) {
+        if callback.is_none() && self.config.current().webhooks.endpoints.is_empty() {
+            return;
+        }
+        match self.store.job(id).await {
+            Ok(Some(job)) => self.notify(&job, callback).await,
+            Ok(None) => {}
+            Err(e) => tracing::warn!(job = %id, "webhooks: cannot read job: {}", e),
+        }
+    }
+
+    /// Records a delivery of the outcome of `job` to each endpoint that
+    /// wants it and to `callback`, and sends them.
+    async fn notify(&self, job: &Job, callback: Option<Callback>) {
+        let settings = self.config.current();
+        let state = job.state.as_str();
+        if state == "running" {
+            return;
+        }
+        let targets: Vec<_> = settings
+            .webhooks
+            .endpoints
+            .iter()
+            .filter(|e| e.wants(state))
+            .map(|e| (Some(e.name.clone()), e.url.clone(), e.secret.clone()))
+            .chain(callback.map(|c| (None, c.url, c.secret)))
+            .collect();
+        if targets.is_empty() {
+            return;
+        }
+        let artifact = match &job.artifact_id {
+            Some(id) => self.store.artifact(id).await.ok().flatten(),
+            None => None,
+        };
+        let event = format!("job.{state}");
+        let payload = serde_json::to_string(&Payload::new(&event, job, artifact.as_ref())).unwrap_or_default();
+        for (webhook, url, secret) in targets {
+            let now = Utc::now();
+            let delivery = Delivery {
+                id: Uuid::new_v4().to_string(),
+                job_id: job.id.clone(),
+                owner: job.owner.clone(),
+                webhook,
+                url,
+                event: event.clone(),
+                signature: sign(&secret, &payload),
+                payload: payload.clone(),
+                state: "pending".into(),
+                attempts: 0,
+                response_status: None,
+                error: None,
+                created: now,
+                updated: now,
+                node: self.node.id.clone(),
+            };
+            // Sent even if it cannot be logged: the outcome matters more.
+            if let Err(e) = self.store.insert_delivery(&delivery).await {
+                tracing::warn!(job = %job.id, "webhooks: cannot record delivery: {}", e);
+            }
+            self.send(delivery);
+        }
+    }
+
+    /// Sends again the pending deliveries of replicas that are gone and, at
+    /// startup, those this one left.
+    pub async fn resume(&self, startup: bool) -> Result<usize, ApiError> {
+        let claimed = self.store.claim_deliveries(&self.node.id, startup).await?;
+        let count = claimed.len();
+        for delivery in claimed {
+            tracing::info!(delivery = %delivery.id, job = %delivery.job_id, "webhooks: resuming delivery");
+            self.send(delivery);
+        }
+        Ok(count)
+    }
+
+    /// Attempts `delivery` until it is delivered or retries run out.
+    fn send(&self, mut delivery: Delivery) {
+        let notifier = self.clone();
+        tokio::spawn(async move {
+            let settings = notifier.config.current();
+            let hooks = &settings.webhooks;
+            loop {
+                delivery.attempts += 1;
+                let (response_status, failure) = notifier.attempt(&delivery, hooks.timeout).await;
+                let delay = failure.as_ref().and_then(|(f, _)| hooks.retry.delay(delivery.attempts, *f));
+                let state = match (&failure, delay) {
+                    (None, _) => "delivered",
+                    (Some(_), Some(_)) => "pending",
+                    (Some(_), None) => "failed",
+                };
+                let error = failure.map(|(_, e)| e);
+                match &error {
+                    None => tracing::info!(
+                        delivery = %delivery.id, job = %delivery.job_id, attempts = delivery.attempts,
+                        "webhooks: delivered"
+                    ),
+                    Some(e) => tracing::warn!(
+                        delivery = %delivery.id, job = %delivery.job_id, attempts = delivery.attempts, state,
+                        "webhooks: delivery failed: {}", redact(e)
+                    ),
+                }
+                let attempt = Attempt { state, attempts: delivery.attempts, response_status, error };
+                if let Err(e) = notifier.store.update_delivery(&delivery.id, &attempt).await {
+                    tracing::warn!(delivery = %delivery.id, "webhooks: cannot record attempt: {}", e);
+                }
+                match delay {
+                    Some(delay) => tokio::time::sleep(delay).await,
+                    None => return,
+                }
+            }
+        });
+    }
+
+    /// Posts `delivery` once: the status answered, and how it failed.
+    async fn attempt(&self, delivery: &Delivery, timeout: Duration) -> (Option<u16>, Option<(Failure, String)>) {
+        let sent = self
+            .client
+            .post(&delivery.url)
+            .timeout(timeout)
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(SIGNATURE, &delivery.signature)
+            .header(EVENT, &delivery.event)
+            .header(DELIVERY, &delivery.id)
+            .body(delivery.payload.clone())
+            .send()
+            .await;
+        let response = match sent {
+            Ok(response) => response,
+            Err(e) => return (None, Some((Failure::Network, error_chain(&e.without_url())))),
+        };
+        let status = response.status();
+        let failure = match status.as_u16() {
+            _ if status.is_success() => return (Some(status.as_u16()), None),
+            429 => {
+                let retry_after = response
+                    .headers()
+                    .get(header::RETRY_AFTER)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| v.trim().parse().ok());
+                Failure::RateLimited { retry_after }
+            }
+            408 | 500..=599 => Failure::Network,
+            _ => Failure::Unknown,
+        };
+        (Some(status.as_u16()), Some((failure, format!("answered {status}"))))
+    }
+}
+
+#[derive(Deserialize)]
+pub struct DeliveriesQuery {
+    job: Option<String>,
+    state: Option<String>,
+    limit: Option<u32>,
+}
+
+/// The delivery log: everything for those who may read the audit log, else
+/// the callbacks of the caller's own jobs.
+pub async fn list_deliveries(
+    State(state): State<AppState>,
+    caller: Caller,
+    Query(query): Query<DeliveriesQuery>,
+) -> Response {
+    if let Some(s) = query.state.as_deref() {
+        if !matches!(s, "pending" | "delivered" | "failed") {
+            return ApiError::InvalidRequest(format!("unknown state '{s}'")).into_response();
+        }
+    }
+    let filter = DeliveryFilter {
+        owner: visible_owner(&state, &caller),
+        job: query.job,
+        state: query.state,
+        limit: query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT),
+    };
+    match state.store.deliveries(filter).await {
+        Ok(deliveries) => Json(deliveries).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        auth::hash_api_key,
+        jobs::{JobEvent, JobKind, NewJob},
+        store::Outcome,
+        tests,
+    };
+    use axum::{
+        http::{HeaderMap, StatusCode},
+        routing::post,
+        Router,
+    };
+    use std::{collections::VecDeque, sync::Mutex};
+
+    /// What the receiver answers, in turn, and what it was sent.
+    #[derive(Default)]
+    struct Receiver {
+        answers: Mutex<VecDeque<StatusCode>>,
+        received: Mutex<Vec<(HeaderMap, String)>>,
+    }
+
+    /// Serves `answers` on a local port, 200 once they run out.
+    async fn receive(answers: &[StatusCode]) -> (String, Arc<Receiver>) {
+        let answers = Mutex::new(answers.iter().copied().collect());
+        let receiver = Arc::new(Receiver { answers, ..Default::default() });
+        let app = Router::new()
+            .route(
+                "/hook",
+                post(|State(r): State<Arc<Receiver>>, headers: HeaderMap, body: String| async move {
+                    r.received.lock().unwrap().push((headers, body));
+                    let status = r.answers.lock().unwrap().pop_front().unwrap_or(StatusCode::OK);
+                    (status, [(header::RETRY_AFTER, "0")])
+                }),
+            )
+            .with_state(receiver.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (url, receiver)
+    }
+
+    fn config(url: &str, retries: u32) -> crate::config::Config {
+        let toml = format!(
+            r#"
+            [puller]
+            kind = "native"
+
+            [[auth.api_keys]]
+            name = "alice"
+            sha256 = "{}"
+
+            [webhooks]
+            retries = {retries}
+            base_ms = 1
+            max_ms = 5
+
+            [[webhooks.endpoints]]
+            name = "ci"
+            url = "{url}"
+            secret = "s3cret"
+            "#,
+            hash_api_key("alice")
+        );
+        toml::from_str(&toml).unwrap()
+    }
+
+    /// Ends a job of alice's as failed and waits for its delivery to settle.
+    async fn deliver(state: &AppState) -> Delivery {
+        let job = NewJob {
+            id: Uuid::new_v4().to_string(),
+            kind: JobKind::Pull,
+            reference: "registry.example/app:1".to_string(),
+            format: "docker-archive".to_string(),
+            owner: "alice".to_string(),
+        };
+        state.store.create_job(&job, &state.node.id, 0).await.unwrap();
+        let error = r#"{"code":"image_not_found"}"#.to_string();
+        let event = JobEvent { event: "error".to_string(), data: error.clone() };
+        let outcome = Outcome { state: "failed", error: Some(error), artifact_id: None };
+        state.store.append_event(&job.id, 1, &event, Some(outcome)).await.unwrap();
+        let notifier =
+            Notifier::new(state.store.clone(), state.node.clone(), state.config.clone(), state.client.clone());
+        notifier.job_ended(&job.id, None).await;
+        for _ in 0..200 {
+            let filter = DeliveryFilter { owner: None, job: Some(job.id.clone()), state: None, limit: 10 };
+            let deliveries = state.store.deliveries(filter).await.unwrap();
+            if let [delivery] = &deliveries[..] {
+                if delivery.state != "pending" {
+                    return delivery.clone();
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("the delivery did not settle");
+    }
+
+    #[test]
+    fn signatures_are_the_hmac_sha256_of_the_body() {
+        assert_eq!(
+            sign("key", "The quick brown fox jumps over the lazy dog"),
+            "sha256=f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+    }
+
+    #[test]
+    fn callbacks_need_an_allowed_host() {
+        let callback = |url: &str, secret: &str| Callback { url: url.to_string(), secret: secret.to_string() };
+        let open = Webhooks::build(&WebhooksConfig::default()).unwrap();
+        assert!(open.check_callback(&callback("https://ci.example.com/hook", "s")).is_err());
+
+        let callback_hosts = vec!["ci.example.com".to_string(), "*.internal".to_string()];
+        let config = WebhooksConfig { callback_hosts, ..Default::default() };
+        let hooks = Webhooks::build(&config).unwrap();
+        assert!(hooks.check_callback(&callback("https://ci.example.com/hook", "s")).is_ok());
+        assert!(hooks.check_callback(&callback("http://build.internal:8080/hook", "s")).is_ok());
+        assert!(hooks.check_callback(&callback("https://169.254.169.254/latest", "s")).is_err());
+        assert!(hooks.check_callback(&callback("ftp://ci.example.com/hook", "s")).is_err());
+        assert!(hooks.check_callback(&callback("https://ci.example.com/hook", "")).is_err());
+    }
+
+    #[tokio::test]
+    async fn timeouts_rate_limits_and_server_errors_are_retried() {
+        let answers = [StatusCode::SERVICE_UNAVAILABLE, StatusCode::TOO_MANY_REQUESTS, StatusCode::REQUEST_TIMEOUT];
+        let (url, receiver) = receive(&answers).await;
+        let state = tests::state(config(&url, 3)).await;
+        let delivery = deliver(&state).await;
+        assert_eq!((delivery.state.as_str(), delivery.attempts), ("delivered", 4));
+        assert_eq!((delivery.response_status, delivery.error), (Some(200), None));
+
+        let received = receiver.received.lock().unwrap();
+        assert_eq!(received.len(), 4);
+        for (headers, body) in received.iter() {
+            assert_eq!(headers[SIGNATURE], sign("s3cret", body));
+            assert_eq!(headers[EVENT], "job.failed");
+            assert_eq!(headers[DELIVERY], delivery.id.as_str());
+            let payload: serde_json::Value = serde_json::from_str(body).unwrap();
+            assert_eq!(payload["job_id"], delivery.job_id.as_str());
+            assert_eq!(payload["error_class"], "image_not_found");
+        }
+    }
+
+    #[tokio::test]
+    async fn other_answers_and_spent_retries_fail_the_delivery() {
+        let (url, receiver) = receive(&[StatusCode::NOT_FOUND]).await;
+        let delivery = deliver(&tests::state(config(&url, 3)).await).await;
+        assert_eq!((delivery.state.as_str(), delivery.attempts, delivery.response_status), ("failed", 1, Some(404)));
+        assert_eq!(delivery.error.as_deref(), Some("answered 404 Not Found"));
+        assert_eq!(receiver.received.lock().unwrap().len(), 1);
+
+        let (url, receiver) = receive(&[StatusCode::BAD_GATEWAY; 3]).await;
+        let delivery = deliver(&tests::state(config(&url, 1)).await).await;
+        assert_eq!((delivery.state.as_str(), delivery.attempts, delivery.response_status), ("failed", 2, Some(502)));
+        assert_eq!(receiver.received.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn the_delivery_log_lists_the_attempts() {
+        let (url, _receiver) = receive(&[StatusCode::INTERNAL_SERVER_ERROR]).await;
+        let state = tests::state(config(&url, 2)).await;
+        let delivery = deliver(&state).await;
+        let base = tests::serve(state).await;
+        let client = reqwest::Client::new();
+        let list = |query: String| {
+            client.get(format!("{base}/api/webhooks/deliveries?{query}")).header("x-api-key", "alice").send()
+        };
+
+        let log: serde_json::Value = list(format!("job={}", delivery.job_id)).await.unwrap().json().await.unwrap();
+        assert_eq!(log.as_array().unwrap().len(), 1);
+        let entry = &log[0];
+        assert_eq!((entry["webhook"].as_str(), entry["url"].as_str()), (Some("ci"), Some(url.as_str())));
+        assert_eq!((entry["event"].as_str(), entry["state"].as_str()), (Some("job.failed"), Some("delivered")));
+        assert_eq!((entry["attempts"].as_u64(), entry["response_status"].as_u64()), (Some(2), Some(200)));
+        assert!(entry.get("payload").is_none() && entry.get("signature").is_none());
+
+        let failed: serde_json::Value = list("state=failed".to_string()).await.unwrap().json().await.unwrap();
+        assert!(failed.as_array().unwrap().is_empty());
+        assert_eq!(list("state=lost".to_string()).await.unwrap().status(), StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,41 @@
+import { NextRequest } from 'next/server';
+import { backendAuthHeaders } from '@/lib/backendAuth';
+
+const backendBaseUrl = (() => {
+  const candidates = [
+    process.env.BACKEND_URL,
+    process.env.NEXT_PUBLIC_BACKEND_URL,
+    process.env.NODE_ENV === 'development' ? 'http://localhost:8080' : undefined,
+    'http://helmer-api:8080',
+    'http://tessark-backend-service:8080',
+  ].filter(Boolean) as string[];
+  return candidates[0] || 'http://localhost:8080';
+})();
+
+export async function GET(
+  req: NextRequest,
+  { params }: { params: { id: string } },
+) {
+  const id = params.id?.trim();
+  if (!id) {
+    return new Response('Missing job id', { status: 400 });
+  }
+
+  const headers: Record<string, string> = { ...backendAuthHeaders(req) };
+  const lastEventId = req.headers.get('last-event-id');
+  if (lastEventId) headers['Last-Event-ID'] = lastEventId;
+
+  const upstream = await fetch(
+    `${backendBaseUrl}/api/jobs/${encodeURIComponent(id)}/events${req.nextUrl.search}`,
+    { method: 'GET', headers, cache: 'no-store' },
+  );
+
+  // Proxy stream as-is (SSE-style)
+  return new Response(upstream.body, {
+    status: upstream.status,
+    headers: {
+      'Content-Type': upstream.headers.get('content-type') || 'text/event-stream',
+      'Cache-Control': 'no-store',
+    },
+  });
+}
@@ -0,0 +1,44 @@
+import { NextRequest } from 'next/server';
+import { backendAuthHeaders } from '@/lib/backendAuth';
+
+export const dynamic = 'force-dynamic';
+
+const backendBaseUrl = (() => {
+  const candidates = [
+    process.env.BACKEND_URL,
+    process.env.NEXT_PUBLIC_BACKEND_URL,
+    process.env.NODE_ENV === 'development' ? 'http://localhost:8080' : undefined,
+    'http://helmer-api:8080',
+    'http://tessark-backend-service:8080',
+  ].filter(Boolean) as string[];
+  return candidates[0] || 'http://localhost:8080';
+})();
+
+// Where browsers reach the backend's WebSocket: Next.js cannot relay one, so
+// the page connects to the backend itself.
+const backendWsUrl = (() => {
+  const base = process.env.NEXT_PUBLIC_BACKEND_WS_URL
+    || process.env.NEXT_PUBLIC_BACKEND_URL?.replace(/^http/, 'ws');
+  return base ? `${base.replace(/\/$/, '')}/api/jobs/ws` : null;
+})();
+
+// Gets a ticket for the jobs WebSocket with the UI's credentials, which stay
+// on the server. The page opens `url` offering `protocols`.
+export async function POST(req: NextRequest) {
+  const upstream = await fetch(`${backendBaseUrl}/api/tickets`, {
+    method: 'POST',
+    headers: backendAuthHeaders(req),
+    cache: 'no-store',
+  });
+  if (!upstream.ok) {
+    return new Response(upstream.body, {
+      status: upstream.status,
+      headers: { 'Content-Type': upstream.headers.get('content-type') || 'application/problem+json' },
+    });
+  }
+  const { ticket, expires_in } = await upstream.json();
+  return Response.json(
+    { url: backendWsUrl, protocols: ['jobs', `ticket.${ticket}`], expires_in },
+    { headers: { 'Cache-Control': 'no-store' } },
+  );
+}
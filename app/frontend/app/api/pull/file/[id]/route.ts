@@ -1,4 +1,5 @@
 import { NextRequest } from 'next/server';
+import { backendAuthHeaders } from '@/lib/backendAuth';
 
 const backendBaseUrl = (() => {
   const candidates = [
@@ -12,7 +13,7 @@ const backendBaseUrl = (() => {
 })();
 
 export async function GET(
-  _req: NextRequest,
+  req: NextRequest,
   { params }: { params: { id: string } },
 ) {
   const id = params.id?.trim();
@@ -22,12 +23,17 @@ export async function GET(
 
   const upstream = await fetch(`${backendBaseUrl}/api/pull/file/${encodeURIComponent(id)}`, {
     method: 'GET',
+    headers: backendAuthHeaders(req),
     cache: 'no-store',
   });
 
   if (!upstream.ok) {
     const msg = await upstream.text();
-    return new Response(msg || 'Upstream error', { status: upstream.status });
+    const errorType = upstream.headers.get('content-type');
+    return new Response(msg || 'Upstream error', {
+      status: upstream.status,
+      headers: errorType ? { 'Content-Type': errorType } : undefined,
+    });
   }
 
   return new Response(upstream.body, {
@@ -1,6 +1,7 @@
 import { NextRequest } from 'next/server';
 import http from 'http';
 import { URL } from 'url';
+import { backendAuthHeaders } from '@/lib/backendAuth';
 
 export const runtime = 'nodejs';
 export const dynamic = 'force-dynamic';
@@ -44,6 +45,7 @@ export async function GET(req: NextRequest) {
       method: 'GET',
       headers: {
         'Accept': 'application/x-tar',
+        ...backendAuthHeaders(req),
       },
       timeout: 5 * 60 * 1000, // 5 minutes
     }, (httpRes) => {
@@ -51,7 +53,11 @@ export async function GET(req: NextRequest) {
         let errorBody = '';
         httpRes.on('data', (chunk) => { errorBody += chunk; });
         httpRes.on('end', () => {
-          resolve(new Response(errorBody || 'Erreur backend', { status: httpRes.statusCode || 502 }));
+          const errorType = httpRes.headers['content-type'];
+          resolve(new Response(errorBody || 'Erreur backend', {
+            status: httpRes.statusCode || 502,
+            headers: errorType ? { 'content-type': errorType } : undefined,
+          }));
         });
         return;
       }
@@ -150,6 +156,7 @@ export async function POST(req: NextRequest) {
         'Accept': 'application/x-tar',
         'Content-Type': 'application/json',
         'Content-Length': Buffer.byteLength(requestBody),
+        ...backendAuthHeaders(req),
       },
       timeout: 5 * 60 * 1000, // 5 minutes
     }, (httpRes) => {
@@ -157,7 +164,11 @@ export async function POST(req: NextRequest) {
         let errorBody = '';
         httpRes.on('data', (chunk) => { errorBody += chunk; });
         httpRes.on('end', () => {
-          resolve(new Response(errorBody || 'Erreur backend', { status: httpRes.statusCode || 502 }));
+          const errorType = httpRes.headers['content-type'];
+          resolve(new Response(errorBody || 'Erreur backend', {
+            status: httpRes.statusCode || 502,
+            headers: errorType ? { 'content-type': errorType } : undefined,
+          }));
         });
         return;
       }
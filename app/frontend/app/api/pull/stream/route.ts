@@ -1,4 +1,5 @@
 import { NextRequest } from 'next/server';
+import { backendAuthHeaders } from '@/lib/backendAuth';
 
 const fallbackBackendUrl = (() => {
   const candidates = [
@@ -16,16 +17,18 @@ export async function POST(req: NextRequest) {
 
   const res = await fetch(`${fallbackBackendUrl}/api/pull/stream`, {
     method: 'POST',
-    headers: { 'Content-Type': 'application/json' },
+    headers: { 'Content-Type': 'application/json', ...backendAuthHeaders(req) },
     body,
   });
 
-  // Proxy stream as-is (SSE-style)
+  // Proxy stream as-is (SSE-style); the job id lets the page reattach
+  const jobId = res.headers.get('x-job-id');
   return new Response(res.body, {
     status: res.status,
     headers: {
       'Content-Type': res.headers.get('content-type') || 'text/event-stream',
       'Cache-Control': 'no-store',
+      ...(jobId ? { 'X-Job-Id': jobId } : {}),
     },
   });
 }
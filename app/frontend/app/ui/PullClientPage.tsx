@@ -1,6 +1,6 @@
 'use client';
 
-import { useState, useEffect, useMemo } from 'react';
+import { useState, useEffect, useMemo, useRef } from 'react';
 import { Download, Lock, ChevronDown, ChevronUp, Loader2, AlertCircle, X } from 'lucide-react';
 
 import { useI18n } from '../i18n/I18nProvider';
@@ -14,8 +14,14 @@ import { Card, CardContent, CardDescription, CardHeader, CardTitle } from '@/com
 import { Alert } from '@/components/ui/alert';
 import { Fieldset } from '@/components/ui/fieldset';
 import { Badge } from '@/components/ui/badge';
+import { describeProblem, parseProblem, ProblemError } from '@/lib/problem';
 
 const IMAGE_RE = /^[A-Za-z0-9./:@_\-]+$/;
+// Job being streamed, so that a reloaded page can reattach to it
+const PENDING_JOB_KEY = 'tessark.pendingJob';
+const MAX_REATTACH = 5;
+
+type PendingJob = { jobId: string; ref: string; lastEventId: string };
 
 export default function PullClientPage() {
   const { t } = useI18n();
@@ -37,6 +43,7 @@ export default function PullClientPage() {
   const [buttonStatus, setButtonStatus] = useState('');
   const [leavingButtonStatus, setLeavingButtonStatus] = useState<string | null>(null);
   const [statusAnimStep, setStatusAnimStep] = useState(0);
+  const resumed = useRef(false);
 
   const rawImageList = refs
     .split('\n')
@@ -116,8 +123,9 @@ export default function PullClientPage() {
     setShowConfirm(true);
   };
 
-  const executeStreamingDownload = async () => {
-    if (imageList.length === 0) {
+  const executeStreamingDownload = async (resume?: PendingJob) => {
+    const images = resume ? [resume.ref] : imageList;
+    if (images.length === 0) {
       setError(t('pull.errors.required'));
       return;
     }
@@ -132,6 +140,8 @@ export default function PullClientPage() {
       const res = await fetch(`/api/pull/file/${id}`);
       if (!res.ok) {
         const errText = await res.text().catch(() => '');
+        const problem = parseProblem(errText);
+        if (problem) throw new ProblemError(problem);
         throw new Error(errText || res.statusText || 'download failed');
       }
 
@@ -146,7 +156,7 @@ export default function PullClientPage() {
       document.body.removeChild(a);
     };
 
-    const streamOneImage = async (imageRef: string, current: number, total: number) => {
+    const streamOneImage = async (imageRef: string, current: number, total: number, pending?: PendingJob) => {
       const stepPrefix = `[${current}/${total}]`;
       let stageProgress = 0;
       let copiedBlobCount = 0;
@@ -157,28 +167,48 @@ export default function PullClientPage() {
 
       setShowConfirm(false);
       bumpStage(0.03);
-      const response = await fetch('/api/pull/stream', {
-        method: 'POST',
-        headers: { 'Content-Type': 'application/json' },
-        body: JSON.stringify({
-          ref: imageRef,
-          format,
-          username: username.trim() || undefined,
-          password: password.trim() || undefined,
-        }),
-      });
-
-      if (!response.ok || !response.body) {
-        const errText = await response.text().catch(() => '');
-        throw new Error(`${imageRef}: ${errText || response.statusText}`);
-      }
+      let jobId = pending?.jobId || '';
+      let lastEventId = pending?.lastEventId || '';
+      const remember = () => {
+        if (!jobId) return;
+        const saved: PendingJob = { jobId, ref: imageRef, lastEventId };
+        sessionStorage.setItem(PENDING_JOB_KEY, JSON.stringify(saved));
+      };
+      // Starts the pull, or reattaches to it once it has an id
+      const connect = () => jobId
+        ? fetch(`/api/jobs/${encodeURIComponent(jobId)}/events`, {
+          headers: lastEventId ? { 'Last-Event-ID': lastEventId } : {},
+        })
+        : fetch('/api/pull/stream', {
+          method: 'POST',
+          headers: { 'Content-Type': 'application/json' },
+          body: JSON.stringify({
+            ref: imageRef,
+            format,
+            username: username.trim() || undefined,
+            password: password.trim() || undefined,
+          }),
+        });
+      const opened = async (response: Response) => {
+        if (!response.ok || !response.body) {
+          const errText = await response.text().catch(() => '');
+          const problem = parseProblem(errText);
+          if (problem) throw new ProblemError(problem, imageRef);
+          throw new Error(`${imageRef}: ${errText || response.statusText}`);
+        }
+        jobId = response.headers.get('x-job-id') || jobId;
+        remember();
+        return response.body;
+      };
 
-      const reader = response.body.getReader();
+      let body: ReadableStream<Uint8Array> | null = await opened(await connect());
       const decoder = new TextDecoder();
       let buffer = '';
       let streamError: string | null = null;
+      let streamProblem: ReturnType<typeof parseProblem> = null;
       let readyId = '';
       let readyFilename = '';
+      let ended = false;
 
       const processChunk = (chunk: string) => {
         buffer += chunk;
@@ -193,11 +223,13 @@ export default function PullClientPage() {
           let data = '';
 
           for (const l of lines) {
+            if (l.startsWith('id:')) lastEventId = l.replace('id:', '').trim();
             if (l.startsWith('event:')) event = l.replace('event:', '').trim();
             if (l.startsWith('data:')) data += `${l.replace('data:', '').trim()}\n`;
           }
 
           data = data.trim();
+          remember();
 
           if (event === 'start') {
             bumpStage(0.08);
@@ -220,6 +252,17 @@ export default function PullClientPage() {
             }
             setStreamMessage(`${stepPrefix} ${data}`);
           }
+          if (event === 'retry') {
+            try {
+              const parsed = JSON.parse(data) as { attempt?: number; max_attempts?: number; delay_ms?: number };
+              const delay = parsed.delay_ms ? ` dans ${Math.ceil(parsed.delay_ms / 1000)}s` : '';
+              setStreamMessage(
+                `${stepPrefix} Erreur temporaire, nouvel essai ${parsed.attempt ?? '?'}/${parsed.max_attempts ?? '?'}${delay}...`,
+              );
+            } catch {
+              setStreamMessage(`${stepPrefix} Erreur temporaire, nouvel essai...`);
+            }
+          }
           if (event === 'auth') {
             bumpStage(0.12);
             setStreamMessage(`${stepPrefix} auth: ${data}`);
@@ -240,23 +283,49 @@ export default function PullClientPage() {
             }
           }
           if (event === 'error') {
-            streamError = data || 'Erreur backend';
+            streamProblem = parseProblem(data);
+            streamError = streamProblem?.detail || data || 'Erreur backend';
+          }
+          if (event === 'cancelled') {
+            streamError = 'Tache annulee';
+          }
+          if (event === 'end') {
+            ended = true;
           }
         }
       };
 
+      // A dropped connection is reattached to the job, from the last event seen
+      let attempts = 0;
       while (true) {
-        const { done, value } = await reader.read();
-        if (value) processChunk(decoder.decode(value, { stream: true }));
-        if (streamError) {
+        if (body) {
+          const reader = body.getReader();
+          buffer = '';
           try {
-            await reader.cancel();
+            while (true) {
+              const { done, value } = await reader.read();
+              if (value) processChunk(decoder.decode(value, { stream: true }));
+              if (streamError) {
+                try {
+                  await reader.cancel();
+                } catch {}
+                break;
+              }
+              if (done) break;
+            }
           } catch {}
-          break;
         }
-        if (done) break;
+        if (streamError || readyId || ended || !jobId || attempts >= MAX_REATTACH) break;
+        attempts += 1;
+        setStreamMessage(`${stepPrefix} Reconnexion...`);
+        await wait(1000 * attempts);
+        const response = await connect().catch(() => null);
+        body = response ? await opened(response) : null;
       }
 
+      if (streamProblem) {
+        throw new ProblemError(streamProblem, imageRef);
+      }
       if (streamError) {
         throw new Error(`${imageRef}: ${streamError}`);
       }
@@ -276,33 +345,40 @@ export default function PullClientPage() {
     setStreaming(true);
     setProgressDisplayPct(0);
     setProgressTargetPct(0);
-    setProgress({ current: 0, total: imageList.length });
+    setProgress({ current: 0, total: images.length });
     setStreamMessage('starting...');
 
     let successCount = 0;
 
     try {
-      for (let i = 0; i < imageList.length; i++) {
-        const imageRef = imageList[i];
-        setProgress({ current: i + 1, total: imageList.length });
-        await streamOneImage(imageRef, i + 1, imageList.length);
+      for (let i = 0; i < images.length; i++) {
+        const imageRef = images[i];
+        setProgress({ current: i + 1, total: images.length });
+        await streamOneImage(imageRef, i + 1, images.length, resume);
+        sessionStorage.removeItem(PENDING_JOB_KEY);
         successCount += 1;
 
-        if (i < imageList.length - 1) {
+        if (i < images.length - 1) {
           await wait(300);
         }
       }
 
-      if (imageList.length === 1) {
+      if (images.length === 1) {
         addToast(t('pull.success'), 'success');
       } else {
         addToast(t('pull.successMultiple', { n: successCount }), 'success');
       }
     } catch (err) {
+      if (err instanceof ProblemError) {
+        const message = describeProblem(t, err.problem);
+        setError(err.subject ? `${err.subject}: ${message}` : message);
+        return;
+      }
       setError(t('pull.errors.server', {
         message: err instanceof Error ? err.message : 'Unknown',
       }));
     } finally {
+      sessionStorage.removeItem(PENDING_JOB_KEY);
       setStreaming(false);
       setProgress(null);
       setProgressTargetPct(null);
@@ -310,6 +386,20 @@ export default function PullClientPage() {
     }
   };
 
+  useEffect(() => {
+    // Reattach to the pull a reload interrupted
+    if (resumed.current) return;
+    resumed.current = true;
+    const saved = sessionStorage.getItem(PENDING_JOB_KEY);
+    if (!saved) return;
+    try {
+      const pending = JSON.parse(saved) as PendingJob;
+      if (pending.jobId && pending.ref) void executeStreamingDownload(pending);
+    } catch {
+      sessionStorage.removeItem(PENDING_JOB_KEY);
+    }
+  }, []);
+
   return (
     <div className="space-y-6">
       <Card className="animate-fade-up">
@@ -490,7 +580,7 @@ export default function PullClientPage() {
                   {t('common.cancel')}
                 </Button>
                 <Button
-                  onClick={executeStreamingDownload}
+                  onClick={() => executeStreamingDownload()}
                   className="flex-1"
                 >
                   <Download className="mr-2 h-4 w-4" />
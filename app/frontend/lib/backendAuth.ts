@@ -0,0 +1,9 @@
+// Credentials for the backend API. The caller's own `Authorization` or
+// `X-API-Key` header is passed through, e.g. when an auth proxy sits in front
+// of the UI; otherwise BACKEND_API_KEY, if set, identifies the UI itself.
+export function backendAuthHeaders(req: Request): Record<string, string> {
+  const authorization = req.headers.get('authorization');
+  if (authorization) return { Authorization: authorization };
+  const apiKey = req.headers.get('x-api-key') || process.env.BACKEND_API_KEY;
+  return apiKey ? { 'X-API-Key': apiKey } : {};
+}
@@ -0,0 +1,42 @@
+// Backend errors are application/problem+json documents, both as HTTP
+// responses and as the data of SSE `error` events.
+export type ApiProblem = {
+  type?: string;
+  title?: string;
+  status?: number;
+  code: string;
+  detail?: string;
+  retryable?: boolean;
+  retry_after?: number;
+};
+
+export function parseProblem(text: string): ApiProblem | null {
+  try {
+    const value = JSON.parse(text);
+    if (value && typeof value === 'object' && typeof value.code === 'string') {
+      return value as ApiProblem;
+    }
+  } catch {}
+  return null;
+}
+
+export class ProblemError extends Error {
+  constructor(public problem: ApiProblem, public subject?: string) {
+    super(problem.detail || problem.title || problem.code);
+    this.name = 'ProblemError';
+  }
+}
+
+type Translate = (key: string, vars?: Record<string, string | number>) => string;
+
+// Localised message for a problem, falling back to the backend detail for
+// codes the UI does not know yet.
+export function describeProblem(t: Translate, problem: ApiProblem): string {
+  const key = `errors.codes.${problem.code}`;
+  const message = t(key, {
+    detail: problem.detail || '',
+    retryAfter: problem.retry_after ?? '',
+  });
+  if (message === key) return problem.detail || problem.title || problem.code;
+  return message;
+}